@@ -0,0 +1,136 @@
+//! Broadcasting BLE advertisements from a local adapter, by exporting a D-Bus object implementing
+//! `org.bluez.LEAdvertisement1` and registering it with BlueZ.
+
+use crate::AdapterId;
+use dbus::arg::{RefArg, Variant};
+use dbus::message::MatchRule;
+use dbus::strings::ErrorName;
+use dbus::{Message, Path};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const ADVERTISEMENT_INTERFACE: &str = "org.bluez.LEAdvertisement1";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+/// The prefix of the D-Bus object paths of advertisements exported by this crate.
+const ADVERTISEMENT_PATH_PREFIX: &str = "/org/bluez_async/advertisement";
+
+/// Used to give each exported advertisement a unique object path.
+static NEXT_ADVERTISEMENT_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+/// The contents of a BLE advertisement to broadcast from a local adapter.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Advertisement {
+    /// The GATT service data to include, as a map from service UUID to data.
+    pub service_data: HashMap<String, Vec<u8>>,
+    /// The local name to include, if any.
+    pub local_name: Option<String>,
+}
+
+impl Advertisement {
+    /// Get the D-Bus properties of the advertisement, as BlueZ expects them.
+    fn properties(&self) -> HashMap<&'static str, Variant<Box<dyn RefArg>>> {
+        let mut properties: HashMap<&'static str, Variant<Box<dyn RefArg>>> = HashMap::new();
+        // Other devices can't connect to a broadcast advertisement, only receive it.
+        properties.insert("Type", Variant(Box::new("broadcast".to_owned())));
+        if !self.service_data.is_empty() {
+            let service_data: HashMap<String, Variant<Box<dyn RefArg>>> = self
+                .service_data
+                .iter()
+                .map(|(uuid, data)| {
+                    (
+                        uuid.to_owned(),
+                        Variant(Box::new(data.to_owned()) as Box<dyn RefArg>),
+                    )
+                })
+                .collect();
+            properties.insert("ServiceData", Variant(Box::new(service_data)));
+        }
+        if let Some(local_name) = &self.local_name {
+            properties.insert("LocalName", Variant(Box::new(local_name.to_owned())));
+        }
+        properties
+    }
+
+    /// Respond to a method call from BlueZ on the exported advertisement object.
+    pub(crate) fn handle_method_call(&self, message: &Message) -> Message {
+        match (message.interface().as_deref(), message.member().as_deref()) {
+            (Some(PROPERTIES_INTERFACE), Some("GetAll")) => {
+                message.method_return().append1(self.properties())
+            }
+            (Some(PROPERTIES_INTERFACE), Some("Get")) => {
+                let name = message.read2::<&str, &str>().ok().map(|(_, name)| name);
+                match name.and_then(|name| self.properties().remove(name)) {
+                    Some(value) => message.method_return().append1(value),
+                    None => error_reply(
+                        message,
+                        "org.freedesktop.DBus.Error.InvalidArgs",
+                        "No such property",
+                    ),
+                }
+            }
+            // BlueZ calls this when it stops broadcasting the advertisement of its own accord, such
+            // as when the adapter is removed. There is nothing to clean up.
+            (Some(ADVERTISEMENT_INTERFACE), Some("Release")) => message.method_return(),
+            _ => error_reply(
+                message,
+                "org.freedesktop.DBus.Error.UnknownMethod",
+                "Unknown method",
+            ),
+        }
+    }
+}
+
+fn error_reply(message: &Message, name: &'static str, description: &str) -> Message {
+    message.error(&ErrorName::from(name), &CString::new(description).unwrap())
+}
+
+/// Opaque identifier for an advertisement which has been registered with BlueZ, which may be used to
+/// unregister it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AdvertisementId {
+    pub(crate) adapter: AdapterId,
+    pub(crate) object_path: Path<'static>,
+    /// The token of the callback handling method calls on the exported object.
+    pub(crate) token: usize,
+}
+
+/// Pick a new unique object path for an advertisement.
+pub(crate) fn new_advertisement_path() -> Path<'static> {
+    let index = NEXT_ADVERTISEMENT_INDEX.fetch_add(1, Ordering::Relaxed);
+    Path::new(format!("{}{}", ADVERTISEMENT_PATH_PREFIX, index)).unwrap()
+}
+
+/// The match rule for method calls on the exported advertisement object with the given path.
+pub(crate) fn advertisement_rule(object_path: Path<'static>) -> MatchRule<'static> {
+    let mut rule = MatchRule::new_method_call();
+    rule.path = Some(object_path);
+    rule
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn properties() {
+        let mut service_data = HashMap::new();
+        service_data.insert(
+            "0000fcd2-0000-1000-8000-00805f9b34fb".to_owned(),
+            vec![0x40, 0x02, 0xca, 0x09],
+        );
+        let advertisement = Advertisement {
+            service_data,
+            local_name: None,
+        };
+        let properties = advertisement.properties();
+        assert_eq!(properties["Type"].0.as_str(), Some("broadcast"));
+        assert!(properties.contains_key("ServiceData"));
+        assert!(!properties.contains_key("LocalName"));
+    }
+
+    #[test]
+    fn unique_paths() {
+        assert_ne!(new_advertisement_path(), new_advertisement_path());
+    }
+}
@@ -0,0 +1,152 @@
+//! Detecting which version of BlueZ is running and what it supports, so that using a feature which
+//! it is too old for can fail with a clear error rather than an opaque D-Bus one.
+
+use crate::{get_string_property, BluetoothError, ManagedObjects, ADAPTER_INTERFACE};
+use std::collections::BTreeSet;
+use std::fmt::{self, Display, Formatter};
+
+/// The USB vendor ID of the Linux Foundation, which BlueZ uses in its default Device ID.
+const LINUX_FOUNDATION_VENDOR_ID: u16 = 0x1d6b;
+/// The product ID which BlueZ uses in its default Device ID.
+const BLUEZ_PRODUCT_ID: u16 = 0x0246;
+
+/// A version of BlueZ, such as 5.50.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct BluezVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+impl BluezVersion {
+    pub const fn new(major: u8, minor: u8) -> Self {
+        Self { major, minor }
+    }
+
+    /// Get the BlueZ version from the modalias of an adapter, such as `usb:v1D6Bp0246d0532`. BlueZ
+    /// sets this to a Device ID containing its version unless it has been configured otherwise, in
+    /// which case this returns `None`.
+    fn from_modalias(modalias: &str) -> Option<Self> {
+        let ids = modalias.strip_prefix("usb:v")?;
+        if ids.len() != 14 || &ids[4..5] != "p" || &ids[9..10] != "d" {
+            return None;
+        }
+        let vendor = u16::from_str_radix(&ids[0..4], 16).ok()?;
+        let product = u16::from_str_radix(&ids[5..9], 16).ok()?;
+        let device = u16::from_str_radix(&ids[10..14], 16).ok()?;
+        if vendor != LINUX_FOUNDATION_VENDOR_ID || product != BLUEZ_PRODUCT_ID {
+            return None;
+        }
+        Some(Self::new((device >> 8) as u8, device as u8))
+    }
+}
+
+impl Display for BluezVersion {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// What the running BlueZ daemon supports.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BluezCapabilities {
+    /// The version of BlueZ, if it could be determined. It can't be if there are no adapters, or
+    /// the Device ID has been changed in BlueZ's `main.conf`.
+    pub version: Option<BluezVersion>,
+    /// The D-Bus interfaces which BlueZ currently exposes on any of its objects, such as
+    /// `org.bluez.LEAdvertisingManager1`.
+    pub interfaces: BTreeSet<String>,
+}
+
+impl BluezCapabilities {
+    /// Work out the capabilities of BlueZ from the objects which it exposes.
+    pub(crate) fn from_managed_objects(tree: &ManagedObjects) -> Self {
+        let interfaces = tree
+            .values()
+            .flat_map(|interfaces| interfaces.keys().cloned())
+            .collect();
+        let version = tree
+            .values()
+            .filter_map(|interfaces| interfaces.get(ADAPTER_INTERFACE))
+            .find_map(|adapter_properties| {
+                BluezVersion::from_modalias(&get_string_property(adapter_properties, "Modalias")?)
+            });
+        Self {
+            version,
+            interfaces,
+        }
+    }
+
+    /// Returns whether any of BlueZ's objects currently implement the given D-Bus interface.
+    pub fn has_interface(&self, interface: &str) -> bool {
+        self.interfaces.contains(interface)
+    }
+
+    /// Check that the running version of BlueZ is at least the one required for the given
+    /// feature. If the version couldn't be determined then this assumes that it is new enough.
+    pub fn require(&self, feature: &str, required: BluezVersion) -> Result<(), BluetoothError> {
+        match self.version {
+            Some(version) if version < required => Err(BluetoothError::UnsupportedBluezVersion {
+                feature: feature.to_owned(),
+                required,
+                version,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_from_modalias() {
+        assert_eq!(
+            BluezVersion::from_modalias("usb:v1D6Bp0246d0532"),
+            Some(BluezVersion::new(5, 50))
+        );
+        assert_eq!(
+            BluezVersion::from_modalias("usb:v1d6bp0246d0537"),
+            Some(BluezVersion::new(5, 55))
+        );
+        // A Device ID configured to something other than BlueZ's own.
+        assert_eq!(BluezVersion::from_modalias("usb:v05ACp8294d0100"), None);
+        assert_eq!(
+            BluezVersion::from_modalias("bluetooth:v1D6Bp0246d0532"),
+            None
+        );
+        assert_eq!(BluezVersion::from_modalias("usb:v1D6Bp0246"), None);
+    }
+
+    #[test]
+    fn version_display_and_order() {
+        assert_eq!(BluezVersion::new(5, 50).to_string(), "5.50");
+        assert!(BluezVersion::new(5, 48) < BluezVersion::new(5, 50));
+        assert!(BluezVersion::new(5, 50) < BluezVersion::new(6, 0));
+    }
+
+    #[test]
+    fn require_version() {
+        let capabilities = BluezCapabilities {
+            version: Some(BluezVersion::new(5, 48)),
+            ..Default::default()
+        };
+        assert!(capabilities
+            .require("scanning", BluezVersion::new(5, 48))
+            .is_ok());
+        assert_eq!(
+            capabilities
+                .require(
+                    "the discoverable discovery filter",
+                    BluezVersion::new(5, 50)
+                )
+                .unwrap_err()
+                .to_string(),
+            "BlueZ >= 5.50 required for the discoverable discovery filter, but 5.48 is running."
+        );
+        // If the version is unknown, assume it is new enough.
+        assert!(BluezCapabilities::default()
+            .require("anything", BluezVersion::new(99, 0))
+            .is_ok());
+    }
+}
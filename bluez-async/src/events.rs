@@ -0,0 +1,322 @@
+// This file is copied from:
+//     https://github.com/szeged/blurz/blob/master/src/bluetooth_event.rs
+//
+// Copyright (c) 2016, University of Szeged
+// Copyright (c) 2016, Attila Dusnoki <adusnoki@inf.u-szeged.hu>
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// * Redistributions of source code must retain the above copyright notice, this
+//   list of conditions and the following disclaimer.
+//
+// * Redistributions in binary form must reproduce the above copyright notice,
+//   this list of conditions and the following disclaimer in the documentation
+//   and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::{get_manufacturer_data, get_service_data};
+use dbus::{arg::cast, arg::RefArg, arg::TypeMismatchError, arg::Variant, Message, Path};
+use std::collections::HashMap;
+
+/// An event from the BlueZ daemon, parsed from a D-Bus signal message.
+#[derive(Clone, Debug)]
+pub enum BluetoothEvent {
+    Powered {
+        object_path: String,
+        powered: bool,
+    },
+    Discovering {
+        object_path: String,
+        discovering: bool,
+    },
+    Connected {
+        object_path: String,
+        connected: bool,
+    },
+    ServicesResolved {
+        object_path: String,
+        services_resolved: bool,
+    },
+    Value {
+        object_path: String,
+        value: Box<[u8]>,
+    },
+    RSSI {
+        object_path: String,
+        rssi: i16,
+    },
+    /// The service data in the device's advertisements has changed. This is a map from service
+    /// UUID to the data.
+    ServiceData {
+        object_path: String,
+        service_data: HashMap<String, Vec<u8>>,
+    },
+    /// The manufacturer-specific data in the device's advertisements has changed. This is a map
+    /// from company identifier to the data.
+    ManufacturerData {
+        object_path: String,
+        manufacturer_data: HashMap<u16, Vec<u8>>,
+    },
+    InterfacesAdded {
+        object_path: String,
+        interfaces: Vec<String>,
+    },
+    InterfacesRemoved {
+        object_path: String,
+        interfaces: Vec<String>,
+    },
+    None,
+}
+
+impl BluetoothEvent {
+    /// Parse the given D-Bus message into a Bluetooth event, if it is one which is recognised.
+    pub fn from(conn_msg: Message) -> Option<BluetoothEvent> {
+        match conn_msg.member().as_deref() {
+            Some("InterfacesAdded") => return Self::interfaces_added(&conn_msg),
+            Some("InterfacesRemoved") => return Self::interfaces_removed(&conn_msg),
+            _ => {}
+        }
+
+        #[allow(clippy::type_complexity)]
+        let result: Result<
+            (&str, HashMap<String, Variant<Box<dyn RefArg>>>),
+            TypeMismatchError,
+        > = conn_msg.read2();
+
+        match result {
+            Ok((_, properties)) => {
+                let object_path = conn_msg.path().unwrap().to_string();
+
+                if let Some(value) = properties.get("Powered") {
+                    if let Some(powered) = cast::<bool>(&value.0) {
+                        let event = BluetoothEvent::Powered {
+                            object_path,
+                            powered: *powered,
+                        };
+
+                        return Some(event);
+                    }
+                }
+
+                if let Some(value) = properties.get("Discovering") {
+                    if let Some(discovering) = cast::<bool>(&value.0) {
+                        let event = BluetoothEvent::Discovering {
+                            object_path,
+                            discovering: *discovering,
+                        };
+
+                        return Some(event);
+                    }
+                }
+
+                if let Some(value) = properties.get("Connected") {
+                    if let Some(connected) = cast::<bool>(&value.0) {
+                        let event = BluetoothEvent::Connected {
+                            object_path,
+                            connected: *connected,
+                        };
+
+                        return Some(event);
+                    }
+                }
+
+                if let Some(value) = properties.get("ServicesResolved") {
+                    if let Some(services_resolved) = cast::<bool>(&value.0) {
+                        let event = BluetoothEvent::ServicesResolved {
+                            object_path,
+                            services_resolved: *services_resolved,
+                        };
+
+                        return Some(event);
+                    }
+                }
+
+                if let Some(value) = properties.get("Value") {
+                    if let Some(value) = cast::<Vec<u8>>(&value.0) {
+                        let event = BluetoothEvent::Value {
+                            object_path,
+                            value: value.clone().into_boxed_slice(),
+                        };
+
+                        return Some(event);
+                    }
+                }
+
+                if let Some(service_data) = get_service_data(&properties) {
+                    let event = BluetoothEvent::ServiceData {
+                        object_path,
+                        service_data,
+                    };
+
+                    return Some(event);
+                }
+
+                if let Some(manufacturer_data) = get_manufacturer_data(&properties) {
+                    let event = BluetoothEvent::ManufacturerData {
+                        object_path,
+                        manufacturer_data,
+                    };
+
+                    return Some(event);
+                }
+
+                if let Some(value) = properties.get("RSSI") {
+                    if let Some(rssi) = cast::<i16>(&value.0) {
+                        let event = BluetoothEvent::RSSI {
+                            object_path,
+                            rssi: *rssi,
+                        };
+
+                        return Some(event);
+                    }
+                }
+
+                Some(BluetoothEvent::None)
+            }
+            Err(_err) => None,
+        }
+    }
+
+    fn interfaces_added(conn_msg: &Message) -> Option<BluetoothEvent> {
+        #[allow(clippy::type_complexity)]
+        let result: Result<
+            (
+                Path,
+                HashMap<String, HashMap<String, Variant<Box<dyn RefArg>>>>,
+            ),
+            TypeMismatchError,
+        > = conn_msg.read2();
+
+        let (object_path, interfaces) = result.ok()?;
+        Some(BluetoothEvent::InterfacesAdded {
+            object_path: object_path.to_string(),
+            interfaces: interfaces.keys().cloned().collect(),
+        })
+    }
+
+    fn interfaces_removed(conn_msg: &Message) -> Option<BluetoothEvent> {
+        let (object_path, interfaces): (Path, Vec<String>) = conn_msg.read2().ok()?;
+        Some(BluetoothEvent::InterfacesRemoved {
+            object_path: object_path.to_string(),
+            interfaces,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn service_data_changed() {
+        let mut service_data: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+        service_data.insert(
+            "0000fe95-0000-1000-8000-00805f9b34fb".to_owned(),
+            Variant(Box::new(vec![1u8, 2, 3])),
+        );
+        let mut properties: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+        properties.insert("ServiceData".to_owned(), Variant(Box::new(service_data)));
+        properties.insert("RSSI".to_owned(), Variant(Box::new(-60i16)));
+        let message = Message::new_signal(
+            "/org/bluez/hci0/dev_11_22_33_44_55_66",
+            "org.freedesktop.DBus.Properties",
+            "PropertiesChanged",
+        )
+        .unwrap()
+        .append3("org.bluez.Device1", properties, Vec::<String>::new());
+
+        match BluetoothEvent::from(message) {
+            Some(BluetoothEvent::ServiceData {
+                object_path,
+                service_data,
+            }) => {
+                assert_eq!(object_path, "/org/bluez/hci0/dev_11_22_33_44_55_66");
+                assert_eq!(service_data.len(), 1);
+                assert_eq!(
+                    service_data["0000fe95-0000-1000-8000-00805f9b34fb"],
+                    vec![1, 2, 3]
+                );
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn advertising_data_changed() {
+        // An extended advertisement with service data longer than fits in a legacy advertisement,
+        // which BlueZ only exposes as raw advertising data, with a 128-bit UUID.
+        let long_data: Vec<u8> = (0..60).collect();
+        let mut ad_data = vec![
+            0xfb, 0x34, 0x9b, 0x5f, 0x80, 0x00, 0x00, 0x80, 0x00, 0x10, 0x00, 0x00, 0xd2, 0xfc,
+            0x00, 0x00,
+        ];
+        ad_data.extend_from_slice(&long_data);
+        let mut advertising_data: HashMap<u8, Variant<Box<dyn RefArg>>> = HashMap::new();
+        advertising_data.insert(0x21, Variant(Box::new(ad_data)));
+        advertising_data.insert(0x09, Variant(Box::new(b"ATC_D72117".to_vec())));
+        let mut properties: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+        properties.insert(
+            "AdvertisingData".to_owned(),
+            Variant(Box::new(advertising_data)),
+        );
+        let message = Message::new_signal(
+            "/org/bluez/hci0/dev_11_22_33_44_55_66",
+            "org.freedesktop.DBus.Properties",
+            "PropertiesChanged",
+        )
+        .unwrap()
+        .append3("org.bluez.Device1", properties, Vec::<String>::new());
+
+        match BluetoothEvent::from(message) {
+            Some(BluetoothEvent::ServiceData { service_data, .. }) => {
+                assert_eq!(service_data.len(), 1);
+                assert_eq!(
+                    service_data["0000fcd2-0000-1000-8000-00805f9b34fb"],
+                    long_data
+                );
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn manufacturer_data_changed() {
+        let mut manufacturer_data: HashMap<u16, Variant<Box<dyn RefArg>>> = HashMap::new();
+        manufacturer_data.insert(0x0499, Variant(Box::new(vec![5u8, 0x12, 0xfc])));
+        let mut properties: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+        properties.insert(
+            "ManufacturerData".to_owned(),
+            Variant(Box::new(manufacturer_data)),
+        );
+        let message = Message::new_signal(
+            "/org/bluez/hci0/dev_11_22_33_44_55_66",
+            "org.freedesktop.DBus.Properties",
+            "PropertiesChanged",
+        )
+        .unwrap()
+        .append3("org.bluez.Device1", properties, Vec::<String>::new());
+
+        match BluetoothEvent::from(message) {
+            Some(BluetoothEvent::ManufacturerData {
+                object_path,
+                manufacturer_data,
+            }) => {
+                assert_eq!(object_path, "/org/bluez/hci0/dev_11_22_33_44_55_66");
+                assert_eq!(manufacturer_data[&0x0499], vec![5, 0x12, 0xfc]);
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+}
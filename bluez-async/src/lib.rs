@@ -0,0 +1,1970 @@
+//! An async wrapper around the D-Bus interface of BlueZ (the Linux Bluetooth daemon), supporting
+//! GATT client (central) functionality.
+
+mod advertising;
+mod capabilities;
+mod events;
+pub mod metric_names;
+pub mod runtime;
+
+use bluez_generated::{
+    OrgBluezAdapter1, OrgBluezDevice1, OrgBluezGattCharacteristic1, OrgBluezLEAdvertisingManager1,
+};
+use core::fmt::Debug;
+use core::future::Future;
+use dbus::arg::{cast, RefArg, Variant};
+use dbus::channel::{MatchingReceiver, Sender, Token};
+use dbus::message::{MatchRule, MessageType};
+use dbus::nonblock::stdintf::org_freedesktop_dbus::{ObjectManager, Properties};
+use dbus::nonblock::{MsgMatch, Proxy, SyncConnection};
+use dbus::strings::{BusName, Interface, Member};
+use dbus::{Message, Path};
+use futures::future::{self, Either};
+use futures::{stream, FutureExt, Stream, StreamExt};
+use itertools::Itertools;
+use runtime::DbusResource;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::hash::Hash;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::{broadcast, Semaphore};
+
+pub use advertising::{Advertisement, AdvertisementId};
+pub use capabilities::{BluezCapabilities, BluezVersion};
+pub use events::BluetoothEvent;
+
+const DBUS_METHOD_CALL_TIMEOUT: Duration = Duration::from_secs(30);
+/// The number of times to retry a GATT operation which BlueZ rejects because another operation on
+/// the same device is in progress.
+const GATT_IN_PROGRESS_RETRIES: u32 = 5;
+const GATT_IN_PROGRESS_RETRY_DELAY: Duration = Duration::from_millis(200);
+const IN_PROGRESS_ERROR_NAME: &str = "org.bluez.Error.InProgress";
+const ACCESS_DENIED_ERROR_NAME: &str = "org.freedesktop.DBus.Error.AccessDenied";
+/// How long to wait between attempts to reconnect to the D-Bus system bus after losing the
+/// connection to it.
+const DBUS_RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+/// How many bus resets may be buffered for each subscriber before the slowest ones start missing
+/// them.
+const BUS_RESETS_CAPACITY: usize = 4;
+
+/// The default maximum number of connection attempts which a `BluetoothSession` will make at once.
+/// Many Bluetooth controllers start failing connections if there are more than about 7 in progress.
+pub const DEFAULT_MAX_CONCURRENT_CONNECTS: usize = 4;
+const ADAPTER_INTERFACE: &str = "org.bluez.Adapter1";
+const DEVICE_INTERFACE: &str = "org.bluez.Device1";
+const GATT_SERVICE_INTERFACE: &str = "org.bluez.GattService1";
+const GATT_CHARACTERISTIC_INTERFACE: &str = "org.bluez.GattCharacteristic1";
+
+type ManagedObjects =
+    HashMap<Path<'static>, HashMap<String, HashMap<String, Variant<Box<dyn RefArg>>>>>;
+
+/// An error carrying out a Bluetooth operation.
+#[derive(Debug, Error)]
+pub enum BluetoothError {
+    /// No Bluetooth adapters were found on the system.
+    #[error("No Bluetooth adapters found.")]
+    NoBluetoothAdapters,
+    /// A required property of a device was missing.
+    #[error("Required property {0} missing.")]
+    RequiredPropertyMissing(String),
+    /// No device with the given MAC address has been discovered.
+    #[error("Device {0} not found.")]
+    DeviceNotFound(MacAddress),
+    /// The device has no GATT service with the given UUID. This may be because it isn't connected
+    /// yet, or its services haven't been resolved.
+    #[error("Service {0} not found.")]
+    ServiceNotFound(String),
+    /// The GATT service has no characteristic with the given UUID.
+    #[error("Characteristic {0} not found.")]
+    CharacteristicNotFound(String),
+    /// There was an error talking to the BlueZ daemon over D-Bus.
+    #[error(transparent)]
+    DbusError(dbus::Error),
+    /// The D-Bus policy doesn't allow this process to talk to the BlueZ daemon.
+    #[error(
+        "Permission denied talking to BlueZ over D-Bus: {0}. Run as root, add the user to the \
+         `bluetooth` group if the D-Bus policy for org.bluez allows that group (as it does on \
+         Debian and Raspberry Pi OS), or add a policy to /etc/dbus-1/system.d allowing the user to \
+         send messages to org.bluez."
+    )]
+    PermissionDenied(dbus::Error),
+    /// The running version of BlueZ is too old for the requested feature.
+    #[error("BlueZ >= {required} required for {feature}, but {version} is running.")]
+    UnsupportedBluezVersion {
+        feature: String,
+        required: BluezVersion,
+        version: BluezVersion,
+    },
+    /// The maximum number of concurrent connection attempts was set to 0, so no connection could
+    /// ever be made.
+    #[error("The maximum number of concurrent connection attempts must be at least 1.")]
+    InvalidConnectLimit,
+}
+
+impl From<dbus::Error> for BluetoothError {
+    fn from(error: dbus::Error) -> Self {
+        metrics::counter!(metric_names::DBUS_ERRORS, 1);
+        if error.name() == Some(ACCESS_DENIED_ERROR_NAME) {
+            BluetoothError::PermissionDenied(error)
+        } else {
+            BluetoothError::DbusError(error)
+        }
+    }
+}
+
+/// Error type for futures representing tasks spawned by this crate.
+#[derive(Error, Debug)]
+pub enum SpawnError {
+    #[error("D-Bus connection lost: {0}")]
+    DbusConnectionLost(#[source] Box<dyn Error + Send + Sync>),
+    #[error("Task failed: {0}")]
+    Join(#[source] Box<dyn Error + Send + Sync>),
+}
+
+/// The prefix of the D-Bus object paths of BlueZ adapters and devices.
+const BLUEZ_PATH_PREFIX: &str = "/org/bluez/";
+/// The prefix of the last component of the D-Bus object path of a BlueZ device.
+const DEVICE_PATH_PREFIX: &str = "dev_";
+
+/// Opaque identifier for a Bluetooth device which the system knows about. This includes a reference
+/// to which Bluetooth adapter it was discovered on, which means that any attempt to connect to it
+/// will also happen from that adapter (in case the system has more than one).
+///
+/// A device ID is made up of the name of the adapter and the MAC address of the device, so it stays
+/// the same across restarts of BlueZ and of the system as long as the adapter keeps the same name
+/// (such as `hci0`). It may be persisted either as a string, which can be parsed with `FromStr`, or
+/// with Serde if the `serde` feature is enabled. Before using a persisted ID, make sure that BlueZ
+/// knows about the device, such as by checking `BluetoothSession::get_device_info`, as it may have
+/// been removed since.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(try_from = "String", into = "String")
+)]
+pub struct DeviceId {
+    object_path: String,
+}
+
+impl DeviceId {
+    /// Construct a device ID from the D-Bus object path of the device, such as
+    /// `"/org/bluez/hci0/dev_11_22_33_44_55_66"`.
+    pub fn new(object_path: &str) -> Self {
+        Self {
+            object_path: object_path.to_owned(),
+        }
+    }
+
+    /// Construct the ID of the device with the given MAC address on the given adapter, as BlueZ
+    /// would name it.
+    pub fn from_mac_address(adapter: &AdapterId, mac_address: &MacAddress) -> Self {
+        Self {
+            object_path: format!(
+                "{}/{}{}",
+                adapter.object_path,
+                DEVICE_PATH_PREFIX,
+                mac_address.to_string().replace(':', "_")
+            ),
+        }
+    }
+
+    /// Get the MAC address of the device, if its ID is in the format which BlueZ uses.
+    pub fn mac_address(&self) -> Option<MacAddress> {
+        let index = self.object_path.rfind('/')?;
+        self.object_path[index + 1..]
+            .strip_prefix(DEVICE_PATH_PREFIX)?
+            .replace('_', ":")
+            .parse()
+            .ok()
+    }
+
+    /// Get the ID of the Bluetooth adapter on which the device was discovered.
+    pub fn adapter(&self) -> AdapterId {
+        let index = self
+            .object_path
+            .rfind('/')
+            .expect("DeviceId object_path must contain a slash.");
+        AdapterId::new(&self.object_path[0..index])
+    }
+}
+
+impl Display for DeviceId {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            self.object_path
+                .strip_prefix(BLUEZ_PATH_PREFIX)
+                .unwrap_or(&self.object_path)
+        )
+    }
+}
+
+/// An error parsing a `DeviceId` or `AdapterId` from a string.
+#[derive(Clone, Debug, Error, Eq, PartialEq)]
+#[error("Invalid BlueZ object path '{0}'")]
+pub struct ParseIdError(String);
+
+impl FromStr for DeviceId {
+    type Err = ParseIdError;
+
+    /// Parses a device ID from either its D-Bus object path, such as
+    /// `/org/bluez/hci0/dev_11_22_33_44_55_66`, or the shorter form it is displayed as, such as
+    /// `hci0/dev_11_22_33_44_55_66`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let object_path = object_path_from_str(s);
+        let device_id = Self { object_path };
+        match (device_id.mac_address(), device_id.object_path.rfind('/')) {
+            (Some(_), Some(index)) if index > BLUEZ_PATH_PREFIX.len() => Ok(device_id),
+            _ => Err(ParseIdError(s.to_owned())),
+        }
+    }
+}
+
+impl TryFrom<String> for DeviceId {
+    type Error = ParseIdError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<DeviceId> for String {
+    fn from(id: DeviceId) -> Self {
+        id.object_path
+    }
+}
+
+/// Get the full D-Bus object path for a BlueZ object from either the path itself or the path
+/// without the BlueZ prefix.
+fn object_path_from_str(s: &str) -> String {
+    if s.starts_with('/') {
+        s.to_owned()
+    } else {
+        format!("{}{}", BLUEZ_PATH_PREFIX, s)
+    }
+}
+
+/// Opaque identifier for a Bluetooth adapter on the system.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(try_from = "String", into = "String")
+)]
+pub struct AdapterId {
+    object_path: String,
+}
+
+impl AdapterId {
+    /// Construct an adapter ID from the D-Bus object path of the adapter, such as
+    /// `"/org/bluez/hci0"`.
+    pub fn new(object_path: &str) -> Self {
+        Self {
+            object_path: object_path.to_owned(),
+        }
+    }
+}
+
+impl Display for AdapterId {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            self.object_path
+                .strip_prefix(BLUEZ_PATH_PREFIX)
+                .unwrap_or(&self.object_path)
+        )
+    }
+}
+
+impl FromStr for AdapterId {
+    type Err = ParseIdError;
+
+    /// Parses an adapter ID from either its D-Bus object path, such as `/org/bluez/hci0`, or its
+    /// name, such as `hci0`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let object_path = object_path_from_str(s);
+        match object_path.strip_prefix(BLUEZ_PATH_PREFIX) {
+            Some(name) if !name.is_empty() && !name.contains('/') => Ok(Self { object_path }),
+            _ => Err(ParseIdError(s.to_owned())),
+        }
+    }
+}
+
+impl TryFrom<String> for AdapterId {
+    type Error = ParseIdError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<AdapterId> for String {
+    fn from(id: AdapterId) -> Self {
+        id.object_path
+    }
+}
+
+/// MAC address of a Bluetooth device.
+///
+/// MAC addresses are compared, ordered and hashed by their octets, so they are suitable for use
+/// as keys of both `HashMap`s and `BTreeMap`s, and are ordered the same way as their string
+/// representations. With the `serde` feature they are serialized as strings like
+/// `A4:C1:38:D7:21:17`.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(try_from = "String", into = "String")
+)]
+pub struct MacAddress([u8; 6]);
+
+impl MacAddress {
+    /// Construct a MAC address from its octets, most significant first.
+    pub const fn new(octets: [u8; 6]) -> Self {
+        Self(octets)
+    }
+
+    /// Get the octets of the MAC address, most significant first.
+    pub const fn octets(&self) -> [u8; 6] {
+        self.0
+    }
+}
+
+impl From<[u8; 6]> for MacAddress {
+    fn from(octets: [u8; 6]) -> Self {
+        Self(octets)
+    }
+}
+
+impl From<MacAddress> for [u8; 6] {
+    fn from(mac_address: MacAddress) -> Self {
+        mac_address.0
+    }
+}
+
+impl Display for MacAddress {
+    /// Formats the MAC address as upper-case hexadecimal octets separated by colons, such as
+    /// `A4:C1:38:D7:21:17`, as BlueZ does.
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+impl TryFrom<String> for MacAddress {
+    type Error = ParseMacAddressError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<MacAddress> for String {
+    fn from(mac_address: MacAddress) -> Self {
+        mac_address.to_string()
+    }
+}
+
+/// An error parsing a MAC address from a string.
+#[derive(Clone, Debug, Error, Eq, PartialEq)]
+#[error("Invalid MAC address")]
+pub struct ParseMacAddressError();
+
+impl FromStr for MacAddress {
+    type Err = ParseMacAddressError;
+
+    /// Parses a MAC address from six hexadecimal octets, in either case, either all separated by
+    /// colons or hyphens or with no separators, such as `A4:C1:38:D7:21:17`, `a4-c1-38-d7-21-17` or
+    /// `A4C138D72117`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex: String = if s.len() == 17 {
+            let separator = s.as_bytes()[2];
+            if separator != b':' && separator != b'-' {
+                return Err(ParseMacAddressError());
+            }
+            let separator = char::from(separator);
+            let octets: Vec<_> = s.split(separator).collect();
+            if octets.len() != 6 || octets.iter().any(|octet| octet.len() != 2) {
+                return Err(ParseMacAddressError());
+            }
+            octets.concat()
+        } else {
+            s.to_owned()
+        };
+        if hex.len() != 12 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(ParseMacAddressError());
+        }
+        let mut octets = [0; 6];
+        for (i, octet) in octets.iter_mut().enumerate() {
+            *octet = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .map_err(|_| ParseMacAddressError())?;
+        }
+        Ok(MacAddress(octets))
+    }
+}
+
+/// The type of write operation to use when writing a GATT characteristic value.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WriteType {
+    /// A write request, which the device acknowledges.
+    WithResponse,
+    /// A write command, which the device doesn't acknowledge. This is faster, but only works for
+    /// characteristics which support it, and there is no indication of whether it succeeded.
+    WithoutResponse,
+    /// A reliable write, where the device echoes back the value so that it can be checked before
+    /// it is applied.
+    Reliable,
+}
+
+impl WriteType {
+    fn to_bluez_str(self) -> &'static str {
+        match self {
+            Self::WithResponse => "request",
+            Self::WithoutResponse => "command",
+            Self::Reliable => "reliable",
+        }
+    }
+}
+
+/// Options for writing a GATT characteristic value.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct WriteOptions {
+    /// The offset within the characteristic value at which to start writing.
+    pub offset: u16,
+    /// The type of write operation to use, or `None` to let BlueZ choose based on the
+    /// characteristic's flags.
+    pub write_type: Option<WriteType>,
+}
+
+impl WriteOptions {
+    fn to_bluez_options(self) -> HashMap<&'static str, Variant<Box<dyn RefArg>>> {
+        let mut options: HashMap<&'static str, Variant<Box<dyn RefArg>>> = HashMap::new();
+        if self.offset != 0 {
+            options.insert("offset", Variant(Box::new(self.offset)));
+        }
+        if let Some(write_type) = self.write_type {
+            options.insert(
+                "type",
+                Variant(Box::new(write_type.to_bluez_str().to_owned())),
+            );
+        }
+        options
+    }
+}
+
+/// The transport to scan for devices on.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Transport {
+    /// Interleaved scanning on both transports if the adapter supports it.
+    Auto,
+    /// Bluetooth Classic only.
+    BrEdr,
+    /// Bluetooth Low Energy only.
+    Le,
+}
+
+impl Transport {
+    fn to_bluez_str(self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::BrEdr => "bredr",
+            Self::Le => "le",
+        }
+    }
+}
+
+/// Options for which devices to report while scanning. The default is to report all of them.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DiscoveryFilter {
+    /// If not empty, only devices which advertise at least one of these service UUIDs are
+    /// reported.
+    pub service_uuids: Vec<String>,
+    /// Only report devices whose advertisements are received with at least this signal strength, in
+    /// dBm.
+    pub rssi: Option<i16>,
+    /// The transport to scan on, or `None` to let BlueZ choose.
+    pub transport: Option<Transport>,
+    /// Whether to make the adapter discoverable while scanning. This needs BlueZ 5.50 or later.
+    pub discoverable: Option<bool>,
+}
+
+impl DiscoveryFilter {
+    /// The version of BlueZ which added the `Discoverable` filter option.
+    const DISCOVERABLE_VERSION: BluezVersion = BluezVersion::new(5, 50);
+
+    /// Check that the running version of BlueZ supports all the options which are set.
+    fn check_supported(&self, capabilities: &BluezCapabilities) -> Result<(), BluetoothError> {
+        if self.discoverable.is_some() {
+            capabilities.require(
+                "the Discoverable discovery filter option",
+                Self::DISCOVERABLE_VERSION,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn to_bluez_filter(&self) -> HashMap<&'static str, Variant<Box<dyn RefArg>>> {
+        let mut filter: HashMap<&'static str, Variant<Box<dyn RefArg>>> = HashMap::new();
+        if !self.service_uuids.is_empty() {
+            filter.insert("UUIDs", Variant(Box::new(self.service_uuids.clone())));
+        }
+        if let Some(rssi) = self.rssi {
+            filter.insert("RSSI", Variant(Box::new(rssi)));
+        }
+        if let Some(transport) = self.transport {
+            filter.insert(
+                "Transport",
+                Variant(Box::new(transport.to_bluez_str().to_owned())),
+            );
+        }
+        if let Some(discoverable) = self.discoverable {
+            filter.insert("Discoverable", Variant(Box::new(discoverable)));
+        }
+        filter
+    }
+}
+
+/// Information about a Bluetooth adapter on the system.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AdapterInfo {
+    /// An opaque identifier for the adapter.
+    pub id: AdapterId,
+    /// The MAC address of the adapter.
+    pub mac_address: MacAddress,
+    /// Whether the adapter is currently powered on.
+    pub powered: bool,
+    /// Whether the adapter is currently scanning for devices.
+    pub discovering: bool,
+}
+
+impl AdapterInfo {
+    /// Construct an `AdapterInfo` from the D-Bus properties of an adapter, or return `None` if the
+    /// required properties are missing.
+    fn from_properties(
+        id: AdapterId,
+        adapter_properties: &HashMap<String, Variant<Box<dyn RefArg>>>,
+    ) -> Option<AdapterInfo> {
+        let mac_address = get_string_property(adapter_properties, "Address")?
+            .parse()
+            .ok()?;
+        Some(AdapterInfo {
+            id,
+            mac_address,
+            powered: get_bool_property(adapter_properties, "Powered"),
+            discovering: get_bool_property(adapter_properties, "Discovering"),
+        })
+    }
+}
+
+/// Information about a Bluetooth device which was discovered.
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    /// An opaque identifier for the device, including a reference to which adapter it was
+    /// discovered on. This can be used to connect to it.
+    pub id: DeviceId,
+    /// The MAC address of the device.
+    pub mac_address: MacAddress,
+    /// The human-readable name of the device, if available.
+    pub name: Option<String>,
+    /// The alias of the device, which defaults to its name or MAC address but can be changed by
+    /// the user, if available.
+    pub alias: Option<String>,
+    /// Whether the device is paired with this system.
+    pub paired: bool,
+    /// Whether the device is trusted, i.e. allowed to connect without authorisation.
+    pub trusted: bool,
+    /// The received signal strength of the device's advertisements in dBm, if available.
+    pub rssi: Option<i16>,
+    /// Whether the device is currently connected.
+    pub connected: bool,
+    /// The GATT service data from the device's advertisement, if any. This is a map from the
+    /// service UUID to its data.
+    pub service_data: HashMap<String, Vec<u8>>,
+    /// The manufacturer-specific data from the device's advertisement, if any. This is a map from
+    /// the Bluetooth SIG company identifier to the data.
+    pub manufacturer_data: HashMap<u16, Vec<u8>>,
+}
+
+impl DeviceInfo {
+    /// Construct a `DeviceInfo` from the D-Bus properties of a device, or return `None` if the
+    /// required properties are missing.
+    fn from_properties(
+        id: DeviceId,
+        device_properties: &HashMap<String, Variant<Box<dyn RefArg>>>,
+    ) -> Option<DeviceInfo> {
+        // FIXME: can we generate a strongly typed deserialiser for this,
+        // based on the introspection data?
+        let mac_address = get_string_property(device_properties, "Address")?
+            .parse()
+            .ok()?;
+        let rssi = device_properties
+            .get("RSSI")
+            .and_then(|rssi| cast::<i16>(&rssi.0))
+            .copied();
+        let service_data = get_service_data(device_properties).unwrap_or_default();
+        let manufacturer_data = get_manufacturer_data(device_properties).unwrap_or_default();
+
+        Some(DeviceInfo {
+            id,
+            mac_address,
+            name: get_string_property(device_properties, "Name"),
+            alias: get_string_property(device_properties, "Alias"),
+            paired: get_bool_property(device_properties, "Paired"),
+            trusted: get_bool_property(device_properties, "Trusted"),
+            rssi,
+            connected: get_bool_property(device_properties, "Connected"),
+            service_data,
+            manufacturer_data,
+        })
+    }
+}
+
+/// Get the string property with the given name, if it is present.
+fn get_string_property(
+    properties: &HashMap<String, Variant<Box<dyn RefArg>>>,
+    name: &str,
+) -> Option<String> {
+    Some(
+        properties
+            .get(name)?
+            .as_iter()?
+            .filter_map(|value| value.as_str())
+            .next()?
+            .to_string(),
+    )
+}
+
+/// Get the boolean property with the given name, or `false` if it is missing.
+fn get_bool_property(properties: &HashMap<String, Variant<Box<dyn RefArg>>>, name: &str) -> bool {
+    properties
+        .get(name)
+        .and_then(|value| cast::<bool>(&value.0))
+        .copied()
+        .unwrap_or(false)
+}
+
+/// A connection to the Bluetooth daemon. This can be cheaply cloned and passed around to be used
+/// from different places.
+#[derive(Clone)]
+pub struct BluetoothSession {
+    /// The underlying D-Bus connection, which is replaced if the connection is lost and
+    /// re-established.
+    connection: Arc<RwLock<Arc<SyncConnection>>>,
+    /// Notified whenever the D-Bus connection is re-established or the BlueZ daemon restarts.
+    bus_resets: broadcast::Sender<()>,
+    /// What the running BlueZ daemon supports, if it has been queried since it last started.
+    capabilities: Arc<Mutex<Option<BluezCapabilities>>>,
+    /// A lock for each device which currently has GATT operations queued, used to serialise them.
+    device_locks: Arc<Mutex<HashMap<DeviceId, Arc<tokio::sync::Mutex<()>>>>>,
+    /// Limits how many connection attempts are made at once.
+    connect_semaphore: Arc<Semaphore>,
+}
+
+impl Debug for BluetoothSession {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "BluetoothSession")
+    }
+}
+
+impl BluetoothSession {
+    /// Returns a tuple of (join handle, Self).
+    /// If the D-Bus connection is lost then it is re-established, and `bus_resets` notified. If the
+    /// join handle ever completes then you're in trouble and should probably restart the process.
+    pub async fn new(
+    ) -> Result<(impl Future<Output = Result<(), SpawnError>>, Self), BluetoothError> {
+        Self::new_with_connect_limit(DEFAULT_MAX_CONCURRENT_CONNECTS).await
+    }
+
+    /// Like `new`, but allowing at most the given number of connection attempts at once. Any more
+    /// calls to `connect` will wait until one of the earlier attempts has finished. Returns
+    /// `BluetoothError::InvalidConnectLimit` if the limit is 0.
+    pub async fn new_with_connect_limit(
+        max_concurrent_connects: usize,
+    ) -> Result<(impl Future<Output = Result<(), SpawnError>>, Self), BluetoothError> {
+        if max_concurrent_connects == 0 {
+            return Err(BluetoothError::InvalidConnectLimit);
+        }
+        let (dbus_resource, connection) = runtime::new_system_connection()?;
+        let session = BluetoothSession {
+            connection: Arc::new(RwLock::new(connection)),
+            bus_resets: broadcast::channel(BUS_RESETS_CAPACITY).0,
+            capabilities: Default::default(),
+            device_locks: Default::default(),
+            connect_semaphore: Arc::new(Semaphore::new(max_concurrent_connects)),
+        };
+        let dbus_handle = runtime::spawn(maintain_connection(
+            dbus_resource,
+            session.connection.clone(),
+            session.bus_resets.clone(),
+            session.capabilities.clone(),
+        ));
+        // BlueZ might not be running yet, in which case this will be tried again when it is needed.
+        match session.bluez_capabilities().await {
+            Ok(BluezCapabilities {
+                version: Some(version),
+                ..
+            }) => tracing::info!("BlueZ version {}", version),
+            Ok(_) => tracing::info!("BlueZ version unknown"),
+            Err(e) => tracing::warn!("Failed to query BlueZ capabilities: {}", e),
+        }
+        Ok((dbus_handle.map(|res| Ok(res??)), session))
+    }
+
+    /// Get the underlying D-Bus connection, which can be used to listen for signals such as
+    /// notifications of characteristic value changes. This changes if the connection is lost and
+    /// re-established, so shouldn't be kept for longer than necessary.
+    pub fn connection(&self) -> Arc<SyncConnection> {
+        self.connection.read().unwrap().clone()
+    }
+
+    /// Get a stream which yields an item whenever the D-Bus connection has been re-established or
+    /// the BlueZ daemon has restarted. Any devices which were connected and notifications which
+    /// were started will have been lost, so should be set up again, but match rules added with
+    /// `connection()` only need to be added again if the connection has changed.
+    pub fn bus_resets(&self) -> impl Stream<Item = ()> {
+        stream::unfold(self.bus_resets.subscribe(), |mut resets| async move {
+            match resets.recv().await {
+                Ok(()) | Err(broadcast::RecvError::Lagged(_)) => Some(((), resets)),
+                Err(broadcast::RecvError::Closed) => None,
+            }
+        })
+    }
+
+    /// Get the version of BlueZ and what it supports. This is queried when first needed after the
+    /// session is created or BlueZ restarts.
+    pub async fn bluez_capabilities(&self) -> Result<BluezCapabilities, BluetoothError> {
+        if let Some(capabilities) = &*self.capabilities.lock().unwrap() {
+            return Ok(capabilities.clone());
+        }
+        let bluez_root = Proxy::new(
+            "org.bluez",
+            "/",
+            DBUS_METHOD_CALL_TIMEOUT,
+            self.connection(),
+        );
+        let tree = bluez_root.get_managed_objects().await?;
+        let capabilities = BluezCapabilities::from_managed_objects(&tree);
+        *self.capabilities.lock().unwrap() = Some(capabilities.clone());
+        Ok(capabilities)
+    }
+
+    /// Power on all Bluetooth adapters and start scanning for devices.
+    pub async fn start_discovery(&self) -> Result<(), BluetoothError> {
+        self.start_discovery_with_filter(&DiscoveryFilter::default())
+            .await
+    }
+
+    /// Power on all Bluetooth adapters and start scanning for devices matching the given filter.
+    ///
+    /// Returns `BluetoothError::UnsupportedBluezVersion` if the filter uses an option which the
+    /// running version of BlueZ doesn't support.
+    pub async fn start_discovery_with_filter(
+        &self,
+        filter: &DiscoveryFilter,
+    ) -> Result<(), BluetoothError> {
+        let set_filter = *filter != DiscoveryFilter::default();
+        if set_filter {
+            filter.check_supported(&self.bluez_capabilities().await?)?;
+        }
+        let bluez_root = Proxy::new(
+            "org.bluez",
+            "/",
+            DBUS_METHOD_CALL_TIMEOUT,
+            self.connection(),
+        );
+        let tree = bluez_root.get_managed_objects().await?;
+        let adapters: Vec<_> = tree
+            .into_iter()
+            .filter_map(|(path, interfaces)| interfaces.get(ADAPTER_INTERFACE).map(|_| path))
+            .collect();
+
+        if adapters.is_empty() {
+            return Err(BluetoothError::NoBluetoothAdapters);
+        }
+
+        for path in adapters {
+            tracing::trace!("Starting discovery on adapter {}", path);
+            let adapter = Proxy::new(
+                "org.bluez",
+                path,
+                DBUS_METHOD_CALL_TIMEOUT,
+                self.connection(),
+            );
+            adapter.set_powered(true).await?;
+            if set_filter {
+                adapter
+                    .set_discovery_filter(filter.to_bluez_filter())
+                    .await?;
+            }
+            adapter.start_discovery().await.unwrap_or_else(|err| {
+                tracing::warn!(
+                    "Starting discovery on adapter {} failed: {:?}",
+                    adapter.path,
+                    err
+                )
+            });
+        }
+        Ok(())
+    }
+
+    /// Power on the given Bluetooth adapter and start scanning for devices on it, leaving any other
+    /// adapters alone.
+    pub async fn start_discovery_on_adapter(&self, id: &AdapterId) -> Result<(), BluetoothError> {
+        tracing::trace!("Starting discovery on adapter {}", id);
+        let adapter = self.adapter(id);
+        adapter.set_powered(true).await?;
+        adapter.start_discovery().await?;
+        Ok(())
+    }
+
+    /// Get a list of all Bluetooth adapters on the system.
+    pub async fn get_adapters(&self) -> Result<Vec<AdapterInfo>, BluetoothError> {
+        let bluez_root = Proxy::new(
+            "org.bluez",
+            "/",
+            DBUS_METHOD_CALL_TIMEOUT,
+            self.connection(),
+        );
+        let tree = bluez_root.get_managed_objects().await?;
+        Ok(tree
+            .into_iter()
+            .filter_map(|(path, interfaces)| {
+                let adapter_properties = interfaces.get(ADAPTER_INTERFACE)?;
+                AdapterInfo::from_properties(AdapterId::new(&path), adapter_properties)
+            })
+            .collect())
+    }
+
+    /// Get a list of all Bluetooth devices which have been discovered so far.
+    pub async fn get_devices(&self) -> Result<Vec<DeviceInfo>, BluetoothError> {
+        let bluez_root = Proxy::new(
+            "org.bluez",
+            "/",
+            DBUS_METHOD_CALL_TIMEOUT,
+            self.connection(),
+        );
+        let tree = bluez_root.get_managed_objects().await?;
+
+        let devices = tree
+            .into_iter()
+            .filter_map(|(path, interfaces)| {
+                let device_properties = interfaces.get(DEVICE_INTERFACE)?;
+                DeviceInfo::from_properties(DeviceId::new(&path), device_properties)
+            })
+            .collect();
+        Ok(devices)
+    }
+
+    /// Get information about the Bluetooth device with the given D-Bus object path.
+    #[tracing::instrument(skip(self, id), fields(device = %id))]
+    pub async fn get_device(&self, id: &DeviceId) -> Result<DeviceInfo, BluetoothError> {
+        let device = Proxy::new(
+            "org.bluez",
+            id.object_path.to_owned(),
+            DBUS_METHOD_CALL_TIMEOUT,
+            self.connection(),
+        );
+        let device_properties = device.get_all(DEVICE_INTERFACE).await?;
+        DeviceInfo::from_properties(id.to_owned(), &device_properties)
+            .ok_or_else(|| BluetoothError::RequiredPropertyMissing("Address".to_owned()))
+    }
+
+    fn adapter(&self, id: &AdapterId) -> impl OrgBluezAdapter1 {
+        Proxy::new(
+            "org.bluez",
+            id.object_path.to_owned(),
+            DBUS_METHOD_CALL_TIMEOUT,
+            self.connection(),
+        )
+    }
+
+    fn advertising_manager(&self, id: &AdapterId) -> impl OrgBluezLEAdvertisingManager1 {
+        Proxy::new(
+            "org.bluez",
+            id.object_path.to_owned(),
+            DBUS_METHOD_CALL_TIMEOUT,
+            self.connection(),
+        )
+    }
+
+    /// Start broadcasting the given advertisement from the given Bluetooth adapter, until it is
+    /// unregistered.
+    ///
+    /// The advertisement is served on the current D-Bus connection, so if the connection is
+    /// re-established or BlueZ restarts (as signalled by `bus_resets`) it must be registered again.
+    /// Most adapters can only broadcast a few advertisements at once.
+    pub async fn register_advertisement(
+        &self,
+        adapter: &AdapterId,
+        advertisement: Advertisement,
+    ) -> Result<AdvertisementId, BluetoothError> {
+        let object_path = advertising::new_advertisement_path();
+        let connection = self.connection();
+        let Token(token) = connection.start_receive(
+            advertising::advertisement_rule(object_path.clone()),
+            Box::new(move |message: Message, connection: &SyncConnection| {
+                if !message.get_no_reply() {
+                    let _ = connection.send(advertisement.handle_method_call(&message));
+                }
+                true
+            }),
+        );
+        if let Err(e) = self
+            .advertising_manager(adapter)
+            .register_advertisement(object_path.clone(), HashMap::new())
+            .await
+        {
+            connection.stop_receive(Token(token));
+            return Err(e.into());
+        }
+        tracing::trace!("Registered advertisement {} on {}", object_path, adapter);
+        Ok(AdvertisementId {
+            adapter: adapter.to_owned(),
+            object_path,
+            token,
+        })
+    }
+
+    /// Stop broadcasting the given advertisement.
+    pub async fn unregister_advertisement(
+        &self,
+        id: AdvertisementId,
+    ) -> Result<(), BluetoothError> {
+        let result = self
+            .advertising_manager(&id.adapter)
+            .unregister_advertisement(id.object_path)
+            .await;
+        self.connection().stop_receive(Token(id.token));
+        Ok(result?)
+    }
+
+    fn device(&self, id: &DeviceId) -> impl OrgBluezDevice1 {
+        Proxy::new(
+            "org.bluez",
+            id.object_path.to_owned(),
+            DBUS_METHOD_CALL_TIMEOUT,
+            self.connection(),
+        )
+    }
+
+    /// Connect to the Bluetooth device with the given D-Bus object path. If the session's limit of
+    /// concurrent connection attempts has been reached then this waits for one of them to finish
+    /// first.
+    #[tracing::instrument(skip(self, id), fields(device = %id))]
+    pub async fn connect(&self, id: &DeviceId) -> Result<(), BluetoothError> {
+        let _permit = self.connect_semaphore.acquire().await;
+        metrics::counter!(metric_names::CONNECT_ATTEMPTS, 1);
+        Ok(self.device(id).connect().await?)
+    }
+
+    /// Get whether the Bluetooth device with the given D-Bus object path is currently connected.
+    /// This may be because another process or an earlier session connected to it.
+    #[tracing::instrument(skip(self, id), fields(device = %id))]
+    pub async fn is_connected(&self, id: &DeviceId) -> Result<bool, BluetoothError> {
+        Ok(self.device(id).connected().await?)
+    }
+
+    /// Get whether BlueZ has finished discovering the GATT services of the connected Bluetooth
+    /// device with the given D-Bus object path, so that its characteristics can be used.
+    #[tracing::instrument(skip(self, id), fields(device = %id))]
+    pub async fn is_services_resolved(&self, id: &DeviceId) -> Result<bool, BluetoothError> {
+        Ok(self.device(id).services_resolved().await?)
+    }
+
+    /// Connect to the Bluetooth device with the given MAC address, via whichever adapter it was
+    /// discovered on currently has the fewest devices connected. If connecting via that adapter
+    /// fails, for example because it has as many connections as it can handle, then the other
+    /// adapters are tried in turn.
+    ///
+    /// Returns the ID of the device on the adapter which the connection succeeded on, or the error
+    /// from the last adapter tried.
+    #[tracing::instrument(skip(self))]
+    pub async fn connect_least_loaded(
+        &self,
+        mac_address: &MacAddress,
+    ) -> Result<DeviceId, BluetoothError> {
+        let devices = self.get_devices().await?;
+        let mut last_error = BluetoothError::DeviceNotFound(mac_address.to_owned());
+        for id in order_by_adapter_load(&devices, mac_address) {
+            match self.connect(&id).await {
+                Ok(()) => return Ok(id),
+                Err(e) => {
+                    tracing::warn!("Connecting via adapter {} failed: {:?}", id.adapter(), e);
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Disconnect from the Bluetooth device with the given D-Bus object path.
+    #[tracing::instrument(skip(self, id), fields(device = %id))]
+    pub async fn disconnect(&self, id: &DeviceId) -> Result<(), BluetoothError> {
+        Ok(self.device(id).disconnect().await?)
+    }
+
+    /// Remove the Bluetooth device with the given D-Bus object path from the adapter on which it was
+    /// discovered, disconnecting it first if necessary. This clears any cached state BlueZ has for
+    /// the device, such as its GATT services. It will be added again if it is discovered again.
+    #[tracing::instrument(skip(self, id), fields(device = %id))]
+    pub async fn remove_device(&self, id: &DeviceId) -> Result<(), BluetoothError> {
+        let adapter = Proxy::new(
+            "org.bluez",
+            id.adapter().object_path,
+            DBUS_METHOD_CALL_TIMEOUT,
+            self.connection(),
+        );
+        let device_path = Path::new(id.object_path.as_str())
+            .map_err(|e| BluetoothError::DbusError(dbus::Error::new_failed(&e)))?;
+        Ok(adapter.remove_device(device_path).await?)
+    }
+
+    // TODO: Change this to lookup the path from the UUIDs instead.
+    /// Read the value of the characteristic of the given device with the given path. The path
+    /// should be of the form "/service0001/char0002".
+    #[tracing::instrument(skip(self, id), fields(device = %id))]
+    pub async fn read_characteristic_value(
+        &self,
+        id: &DeviceId,
+        characteristic_path: &str,
+    ) -> Result<Vec<u8>, BluetoothError> {
+        let characteristic = self.get_characteristic_proxy(id, characteristic_path);
+        self.gatt_operation(id, || characteristic.read_value(HashMap::new()))
+            .await
+    }
+
+    /// Read the values of several characteristics of the given device with the given paths. The
+    /// paths should be of the form "/service0001/char0002".
+    ///
+    /// The reads are all queued at once, so they happen back to back rather than each waiting for
+    /// the caller to handle the previous result. The values are returned in the same order as the
+    /// paths, and if any read fails then an error is returned.
+    #[tracing::instrument(skip(self, id), fields(device = %id))]
+    pub async fn read_characteristic_values(
+        &self,
+        id: &DeviceId,
+        characteristic_paths: &[&str],
+    ) -> Result<Vec<Vec<u8>>, BluetoothError> {
+        future::try_join_all(
+            characteristic_paths
+                .iter()
+                .map(|characteristic_path| self.read_characteristic_value(id, characteristic_path)),
+        )
+        .await
+    }
+
+    // TODO: Change this to lookup the path from the UUIDs instead.
+    /// Write the given value to the characteristic of the given device with the given path. The
+    /// path should be of the form "/service0001/char0002".
+    #[tracing::instrument(skip(self, id, value), fields(device = %id))]
+    pub async fn write_characteristic_value(
+        &self,
+        id: &DeviceId,
+        characteristic_path: &str,
+        value: impl Into<Vec<u8>>,
+    ) -> Result<(), BluetoothError> {
+        self.write_characteristic_value_with_options(
+            id,
+            characteristic_path,
+            value,
+            WriteOptions::default(),
+        )
+        .await
+    }
+
+    /// Write the given value to the characteristic of the given device with the given path, with
+    /// the given options. The path should be of the form "/service0001/char0002".
+    #[tracing::instrument(skip(self, id, value), fields(device = %id))]
+    pub async fn write_characteristic_value_with_options(
+        &self,
+        id: &DeviceId,
+        characteristic_path: &str,
+        value: impl Into<Vec<u8>>,
+        options: WriteOptions,
+    ) -> Result<(), BluetoothError> {
+        let characteristic = self.get_characteristic_proxy(id, characteristic_path);
+        let value = value.into();
+        self.gatt_operation(id, || {
+            characteristic.write_value(value.clone(), options.to_bluez_options())
+        })
+        .await
+    }
+
+    /// Read the value of the characteristic with the given UUID, in the GATT service with the given
+    /// UUID, of the given device. The device must be connected and its services resolved.
+    #[tracing::instrument(skip(self, id), fields(device = %id))]
+    pub async fn read_service_characteristic(
+        &self,
+        id: &DeviceId,
+        service_uuid: &str,
+        characteristic_uuid: &str,
+    ) -> Result<Vec<u8>, BluetoothError> {
+        let characteristic_path = self
+            .get_characteristic_path(id, service_uuid, characteristic_uuid)
+            .await?;
+        self.read_characteristic_value(id, &characteristic_path)
+            .await
+    }
+
+    /// Write the given value to the characteristic with the given UUID, in the GATT service with
+    /// the given UUID, of the given device. The device must be connected and its services resolved.
+    #[tracing::instrument(skip(self, id, value), fields(device = %id))]
+    pub async fn write_service_characteristic(
+        &self,
+        id: &DeviceId,
+        service_uuid: &str,
+        characteristic_uuid: &str,
+        value: impl Into<Vec<u8>>,
+    ) -> Result<(), BluetoothError> {
+        let characteristic_path = self
+            .get_characteristic_path(id, service_uuid, characteristic_uuid)
+            .await?;
+        self.write_characteristic_value(id, &characteristic_path, value)
+            .await
+    }
+
+    /// Look up the path of the characteristic with the given UUID, in the GATT service with the
+    /// given UUID, of the given device. The path returned is relative to the device, of the form
+    /// "/service0001/char0002".
+    async fn get_characteristic_path(
+        &self,
+        id: &DeviceId,
+        service_uuid: &str,
+        characteristic_uuid: &str,
+    ) -> Result<String, BluetoothError> {
+        let bluez_root = Proxy::new(
+            "org.bluez",
+            "/",
+            DBUS_METHOD_CALL_TIMEOUT,
+            self.connection(),
+        );
+        let tree = bluez_root.get_managed_objects().await?;
+        find_characteristic_path(&tree, id, service_uuid, characteristic_uuid)
+    }
+
+    /// Start notifications on the characteristic of the given device with the given path. The path
+    /// should be of the form "/service0001/char0002".
+    #[tracing::instrument(skip(self, id), fields(device = %id))]
+    pub async fn start_notify(
+        &self,
+        id: &DeviceId,
+        characteristic_path: &str,
+    ) -> Result<(), BluetoothError> {
+        let characteristic = self.get_characteristic_proxy(id, characteristic_path);
+        self.gatt_operation(id, || characteristic.start_notify())
+            .await
+    }
+
+    /// Stop notifications on the characteristic of the given device with the given path. The path
+    /// should be of the form "/service0001/char0002".
+    #[tracing::instrument(skip(self, id), fields(device = %id))]
+    pub async fn stop_notify(
+        &self,
+        id: &DeviceId,
+        characteristic_path: &str,
+    ) -> Result<(), BluetoothError> {
+        let characteristic = self.get_characteristic_proxy(id, characteristic_path);
+        self.gatt_operation(id, || characteristic.stop_notify())
+            .await
+    }
+
+    /// Start notifications on the characteristic of the given device with the given path, and
+    /// return a stream of the values received. The path should be of the form
+    /// "/service0001/char0002".
+    ///
+    /// Notifications are stopped when the stream is dropped. With Tokio this must happen within a
+    /// runtime, as stopping them involves a D-Bus method call.
+    #[tracing::instrument(skip(self, id), fields(device = %id))]
+    pub async fn notify_stream(
+        &self,
+        id: &DeviceId,
+        characteristic_path: &str,
+    ) -> Result<impl Stream<Item = Vec<u8>>, BluetoothError> {
+        let full_path = id.object_path.to_string() + characteristic_path;
+        let mut rule = MatchRule::new();
+        rule.msg_type = Some(MessageType::Signal);
+        // These names are all constants that we know are valid, so validation should never fail.
+        rule.sender = Some(BusName::new("org.bluez").unwrap());
+        rule.interface = Some(Interface::new("org.freedesktop.DBus.Properties").unwrap());
+        rule.member = Some(Member::new("PropertiesChanged").unwrap());
+        rule.path = Some(
+            Path::new(full_path.clone())
+                .map_err(|e| BluetoothError::DbusError(dbus::Error::new_failed(&e)))?,
+        );
+
+        let (msg_match, messages) = self.connection().add_match(rule).await?.msg_stream();
+        // Create the stream before starting notifications, so that if starting them fails the match
+        // is still cleaned up when it is dropped.
+        let values = messages.filter_map(|message| {
+            future::ready(match BluetoothEvent::from(message) {
+                Some(BluetoothEvent::Value { value, .. }) => Some(value.into_vec()),
+                _ => None,
+            })
+        });
+        let stream = NotificationStream {
+            connection: self.connection(),
+            characteristic_path: full_path,
+            msg_match,
+            values: Box::pin(values),
+        };
+        self.start_notify(id, characteristic_path).await?;
+        Ok(stream)
+    }
+
+    /// Run the given GATT operation on the given device, after any others which are already queued
+    /// for the same device have finished. If BlueZ reports that another operation is in progress
+    /// (e.g. from another process) then it is retried a few times before giving up.
+    async fn gatt_operation<T, F, Fut>(
+        &self,
+        id: &DeviceId,
+        operation: F,
+    ) -> Result<T, BluetoothError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, dbus::Error>>,
+    {
+        let lock = self
+            .device_locks
+            .lock()
+            .unwrap()
+            .entry(id.to_owned())
+            .or_default()
+            .clone();
+        let result = {
+            let _guard = lock.lock().await;
+            let mut retries = 0;
+            loop {
+                match operation().await {
+                    Err(e) if is_in_progress(&e) && retries < GATT_IN_PROGRESS_RETRIES => {
+                        retries += 1;
+                        tracing::trace!("GATT operation in progress, retry {}", retries);
+                        runtime::sleep(GATT_IN_PROGRESS_RETRY_DELAY).await;
+                    }
+                    result => break result,
+                }
+            }
+        };
+
+        // Remove the lock from the map if nothing else is waiting for it, so that it doesn't grow
+        // forever. New waiters can only get a reference to the lock while the map is locked.
+        let mut device_locks = self.device_locks.lock().unwrap();
+        if Arc::strong_count(&lock) == 2 {
+            device_locks.remove(id);
+        }
+
+        Ok(result?)
+    }
+
+    fn get_characteristic_proxy(
+        &self,
+        id: &DeviceId,
+        characteristic_path: &str,
+    ) -> Proxy<Arc<SyncConnection>> {
+        let full_path = id.object_path.to_string() + characteristic_path;
+        Proxy::new(
+            "org.bluez",
+            full_path,
+            DBUS_METHOD_CALL_TIMEOUT,
+            self.connection(),
+        )
+    }
+}
+
+/// Keep the given D-Bus connection running, reconnecting if it is lost, and notify `bus_resets`
+/// whenever it is re-established or the BlueZ daemon restarts. This never finishes unless the task
+/// running the connection panics.
+async fn maintain_connection(
+    dbus_resource: DbusResource,
+    connection: Arc<RwLock<Arc<SyncConnection>>>,
+    bus_resets: broadcast::Sender<()>,
+    capabilities: Arc<Mutex<Option<BluezCapabilities>>>,
+) -> Result<(), SpawnError> {
+    let mut resource = runtime::spawn(dbus_resource);
+    loop {
+        let current_connection = connection.read().unwrap().clone();
+        let mut name_owner_changes = match watch_name_owner_changes(&current_connection).await {
+            // The match must be kept alive for messages to keep arriving.
+            Ok(watch) => Some(watch),
+            Err(e) => {
+                tracing::warn!("Failed to watch for BlueZ restarts: {:?}", e);
+                None
+            }
+        };
+
+        // Wait until the connection is lost, noticing any BlueZ restarts in the meantime.
+        let error = loop {
+            let message = match &mut name_owner_changes {
+                Some((_msg_match, messages)) => {
+                    match future::select(&mut resource, messages.next()).await {
+                        Either::Left((error, _)) => break error?,
+                        Either::Right((message, _)) => message,
+                    }
+                }
+                None => break (&mut resource).await?,
+            };
+            match message {
+                Some(message) => {
+                    if is_bluez_started(&message) {
+                        tracing::warn!("BlueZ daemon restarted");
+                        // It may have been upgraded.
+                        *capabilities.lock().unwrap() = None;
+                        // Sending only fails if nothing is subscribed, which is fine.
+                        let _ = bus_resets.send(());
+                    }
+                }
+                None => name_owner_changes = None,
+            }
+        };
+        tracing::error!("Lost D-Bus connection: {}", error);
+
+        let (new_resource, new_connection) = loop {
+            match runtime::new_system_connection() {
+                Ok(result) => break result,
+                Err(e) => {
+                    tracing::warn!("Failed to reconnect to D-Bus: {}", e);
+                    runtime::sleep(DBUS_RECONNECT_INTERVAL).await;
+                }
+            }
+        };
+        tracing::info!("Reconnected to D-Bus");
+        resource = runtime::spawn(new_resource);
+        *connection.write().unwrap() = new_connection;
+        *capabilities.lock().unwrap() = None;
+        let _ = bus_resets.send(());
+    }
+}
+
+/// Add a match rule for changes to the owners of names on the bus, which is how BlueZ restarting
+/// can be noticed.
+async fn watch_name_owner_changes(
+    connection: &SyncConnection,
+) -> Result<(MsgMatch, impl Stream<Item = Message> + Unpin), BluetoothError> {
+    let mut rule = MatchRule::new();
+    rule.msg_type = Some(MessageType::Signal);
+    // These names are all constants that we know are valid, so validation should never fail.
+    rule.sender = Some(BusName::new("org.freedesktop.DBus").unwrap());
+    rule.interface = Some(Interface::new("org.freedesktop.DBus").unwrap());
+    rule.member = Some(Member::new("NameOwnerChanged").unwrap());
+    Ok(connection.add_match(rule).await?.msg_stream())
+}
+
+/// Returns whether the given `NameOwnerChanged` signal is for BlueZ getting a new owner, which means
+/// it has started.
+fn is_bluez_started(message: &Message) -> bool {
+    match message.read3::<&str, &str, &str>() {
+        Ok((name, _old_owner, new_owner)) => name == "org.bluez" && !new_owner.is_empty(),
+        Err(_) => false,
+    }
+}
+
+/// Returns whether the given error is BlueZ reporting that another operation is already in progress.
+fn is_in_progress(error: &dbus::Error) -> bool {
+    error.name() == Some(IN_PROGRESS_ERROR_NAME)
+}
+
+/// A stream of values received in notifications from a GATT characteristic, which stops the
+/// notifications and removes its D-Bus match rule when dropped.
+struct NotificationStream {
+    connection: Arc<SyncConnection>,
+    characteristic_path: String,
+    msg_match: MsgMatch,
+    values: Pin<Box<dyn Stream<Item = Vec<u8>> + Send>>,
+}
+
+impl Stream for NotificationStream {
+    type Item = Vec<u8>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.values.as_mut().poll_next(cx)
+    }
+}
+
+impl Drop for NotificationStream {
+    fn drop(&mut self) {
+        let connection = self.connection.clone();
+        let characteristic_path = self.characteristic_path.clone();
+        let token = self.msg_match.token();
+        if !runtime::in_runtime() {
+            tracing::warn!(
+                "Notification stream for {} dropped outside of a runtime, not stopping notifications.",
+                characteristic_path
+            );
+            return;
+        }
+        let _ = runtime::spawn(async move {
+            if let Err(e) = connection.remove_match(token).await {
+                tracing::warn!("Removing match for {} failed: {:?}", characteristic_path, e);
+            }
+            let characteristic: Proxy<Arc<SyncConnection>> = Proxy::new(
+                "org.bluez",
+                characteristic_path.as_str(),
+                DBUS_METHOD_CALL_TIMEOUT,
+                connection,
+            );
+            if let Err(e) = characteristic.stop_notify().await {
+                metrics::counter!(metric_names::DBUS_ERRORS, 1);
+                tracing::warn!(
+                    "Stopping notifications on {} failed: {:?}",
+                    characteristic.path,
+                    e
+                );
+            }
+        });
+    }
+}
+
+/// Find the path relative to the given device of the characteristic with the given UUID, in the GATT
+/// service with the given UUID, in the given tree of BlueZ objects.
+fn find_characteristic_path(
+    tree: &ManagedObjects,
+    id: &DeviceId,
+    service_uuid: &str,
+    characteristic_uuid: &str,
+) -> Result<String, BluetoothError> {
+    let service_path =
+        find_child_with_uuid(tree, &id.object_path, GATT_SERVICE_INTERFACE, service_uuid)
+            .ok_or_else(|| BluetoothError::ServiceNotFound(service_uuid.to_owned()))?;
+    let characteristic_path = find_child_with_uuid(
+        tree,
+        service_path,
+        GATT_CHARACTERISTIC_INTERFACE,
+        characteristic_uuid,
+    )
+    .ok_or_else(|| BluetoothError::CharacteristicNotFound(characteristic_uuid.to_owned()))?;
+    Ok(characteristic_path[id.object_path.len()..].to_owned())
+}
+
+/// Find the object directly under the given parent path which implements the given interface with
+/// the given UUID. UUIDs are compared case-insensitively.
+fn find_child_with_uuid<'a>(
+    tree: &'a ManagedObjects,
+    parent_path: &str,
+    interface: &str,
+    uuid: &str,
+) -> Option<&'a str> {
+    tree.iter()
+        .find(|(path, interfaces)| {
+            let is_child = path
+                .strip_prefix(parent_path)
+                .and_then(|rest| rest.strip_prefix('/'))
+                .map(|rest| !rest.is_empty() && !rest.contains('/'))
+                .unwrap_or(false);
+            is_child
+                && interfaces
+                    .get(interface)
+                    .and_then(|properties| properties.get("UUID"))
+                    .and_then(|object_uuid| object_uuid.0.as_str())
+                    .map(|object_uuid| object_uuid.eq_ignore_ascii_case(uuid))
+                    .unwrap_or(false)
+        })
+        .map(|(path, _)| &**path)
+}
+
+/// Get the IDs of the device with the given MAC address on each adapter on which it has been
+/// discovered, ordered by how many devices are connected to each adapter, least first.
+fn order_by_adapter_load(devices: &[DeviceInfo], mac_address: &MacAddress) -> Vec<DeviceId> {
+    let mut connections_per_adapter: HashMap<AdapterId, usize> = HashMap::new();
+    for device in devices.iter().filter(|device| device.connected) {
+        *connections_per_adapter
+            .entry(device.id.adapter())
+            .or_default() += 1;
+    }
+    devices
+        .iter()
+        .filter(|device| &device.mac_address == mac_address)
+        .map(|device| device.id.to_owned())
+        .sorted_by_key(|id| {
+            connections_per_adapter
+                .get(&id.adapter())
+                .copied()
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+/// Get the service data from the given device properties, if there is any. This includes both the
+/// `ServiceData` property and any service data in the `AdvertisingData` property, which is where
+/// BlueZ puts AD types it doesn't otherwise parse, such as service data with 32-bit or 128-bit
+/// UUIDs on older versions. If a UUID is in both then the `ServiceData` property takes precedence.
+pub(crate) fn get_service_data(
+    device_properties: &HashMap<String, Variant<Box<dyn RefArg>>>,
+) -> Option<HashMap<String, Vec<u8>>> {
+    // UUIDs don't get populated until we connect. Use:
+    //     "ServiceData": Variant(InternalDict { data: [
+    //         ("0000fe95-0000-1000-8000-00805f9b34fb", Variant([48, 88, 91, 5, 1, 23, 33, 215, 56, 193, 164, 40, 1, 0])
+    //     )], outer_sig: Signature("a{sv}") })
+    // instead.
+    let service_data = get_byte_array_dict(device_properties, "ServiceData", |key| {
+        Some(key.as_str()?.to_owned())
+    });
+    let advertised_service_data = get_advertising_data(device_properties)
+        .map(|advertising_data| service_data_from_advertising_data(&advertising_data))
+        .filter(|service_data| !service_data.is_empty());
+    match (service_data, advertised_service_data) {
+        (None, None) => None,
+        (service_data, advertised_service_data) => {
+            let mut merged = advertised_service_data.unwrap_or_default();
+            merged.extend(service_data.unwrap_or_default());
+            Some(merged)
+        }
+    }
+}
+
+/// Get the manufacturer-specific data from the given device properties, if there is any.
+pub(crate) fn get_manufacturer_data(
+    device_properties: &HashMap<String, Variant<Box<dyn RefArg>>>,
+) -> Option<HashMap<u16, Vec<u8>>> {
+    get_byte_array_dict(device_properties, "ManufacturerData", |key| {
+        Some(key.as_u64()? as u16)
+    })
+}
+
+/// Get the raw advertising data from the given device properties, as a map from AD type to data.
+fn get_advertising_data(
+    device_properties: &HashMap<String, Variant<Box<dyn RefArg>>>,
+) -> Option<HashMap<u8, Vec<u8>>> {
+    get_byte_array_dict(device_properties, "AdvertisingData", |key| {
+        Some(key.as_u64()? as u8)
+    })
+}
+
+/// Get a property which is a dictionary of byte arrays in variants, such as `ServiceData`, with its
+/// keys converted by the given function. Entries whose key or value can't be converted are skipped.
+fn get_byte_array_dict<K: Eq + Hash>(
+    properties: &HashMap<String, Variant<Box<dyn RefArg>>>,
+    name: &str,
+    convert_key: impl Fn(&dyn RefArg) -> Option<K>,
+) -> Option<HashMap<K, Vec<u8>>> {
+    Some(
+        properties
+            .get(name)?
+            // Variant(...)
+            .as_iter()?
+            .next()?
+            // InternalDict(...)
+            .as_iter()?
+            .tuples::<(_, _)>()
+            .filter_map(|(k, v)| {
+                let k = convert_key(k)?;
+                let v: Option<Vec<u8>> = v
+                    .box_clone()
+                    .as_static_inner(0)?
+                    .as_iter()?
+                    .map(|el| Some(el.as_u64()? as u8))
+                    .collect();
+                let v = v?;
+                Some((k, v))
+            })
+            .collect(),
+    )
+}
+
+/// The AD types of service data with 16-bit, 32-bit and 128-bit UUIDs.
+const AD_TYPE_SERVICE_DATA_16: u8 = 0x16;
+const AD_TYPE_SERVICE_DATA_32: u8 = 0x20;
+const AD_TYPE_SERVICE_DATA_128: u8 = 0x21;
+/// The suffix of a full UUID expanded from a 16-bit or 32-bit Bluetooth UUID.
+const BLUETOOTH_BASE_UUID_SUFFIX: &str = "-0000-1000-8000-00805f9b34fb";
+
+/// Extract the service data from raw advertising data, as a map from the full service UUID to the
+/// data which follows it. The UUIDs are sent least significant byte first.
+fn service_data_from_advertising_data(
+    advertising_data: &HashMap<u8, Vec<u8>>,
+) -> HashMap<String, Vec<u8>> {
+    advertising_data
+        .iter()
+        .filter_map(|(&ad_type, data)| {
+            let uuid_length = match ad_type {
+                AD_TYPE_SERVICE_DATA_16 => 2,
+                AD_TYPE_SERVICE_DATA_32 => 4,
+                AD_TYPE_SERVICE_DATA_128 => 16,
+                _ => return None,
+            };
+            if data.len() < uuid_length {
+                return None;
+            }
+            let (uuid, data) = data.split_at(uuid_length);
+            let uuid = match uuid_length {
+                2 => format!(
+                    "0000{:04x}{}",
+                    u16::from_le_bytes([uuid[0], uuid[1]]),
+                    BLUETOOTH_BASE_UUID_SUFFIX
+                ),
+                4 => format!(
+                    "{:08x}{}",
+                    u32::from_le_bytes([uuid[0], uuid[1], uuid[2], uuid[3]]),
+                    BLUETOOTH_BASE_UUID_SUFFIX
+                ),
+                _ => {
+                    let hex: String = uuid.iter().rev().map(|b| format!("{:02x}", b)).collect();
+                    format!(
+                        "{}-{}-{}-{}-{}",
+                        &hex[0..8],
+                        &hex[8..12],
+                        &hex[12..16],
+                        &hex[16..20],
+                        &hex[20..32]
+                    )
+                }
+            };
+            Some((uuid, data.to_owned()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_mac_address() {
+        let mac_address = MacAddress::new([0xa4, 0xc1, 0x38, 0xd7, 0x21, 0x17]);
+        assert_eq!("A4:C1:38:D7:21:17".parse(), Ok(mac_address));
+        assert_eq!("a4:c1:38:d7:21:17".parse(), Ok(mac_address));
+        assert_eq!("A4-C1-38-D7-21-17".parse(), Ok(mac_address));
+        assert_eq!("a4c138d72117".parse(), Ok(mac_address));
+        assert_eq!(mac_address.to_string(), "A4:C1:38:D7:21:17");
+
+        for invalid in &[
+            "",
+            "A4:C1:38:D7:21",
+            "A4:C1:38:D7:21:17:00",
+            "A4:C1-38:D7:21:17",
+            "A4:C1:38:D7:21:1G",
+            "A4C138D7211",
+            "+4:C1:38:D7:21:17",
+            "A4:C1:38:D7:2:117",
+        ] {
+            assert_eq!(
+                invalid.parse::<MacAddress>(),
+                Err(ParseMacAddressError()),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn mac_address_octets() {
+        let octets = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+        let mac_address = MacAddress::from(octets);
+        assert_eq!(mac_address.octets(), octets);
+        assert_eq!(<[u8; 6]>::from(mac_address), octets);
+    }
+
+    #[test]
+    fn mac_address_order_matches_string() {
+        let mut mac_addresses: Vec<MacAddress> = [
+            "A4:C1:38:D7:21:17",
+            "0A:00:00:00:00:00",
+            "A4:C1:38:00:FF:FF",
+        ]
+        .iter()
+        .map(|s| s.parse().unwrap())
+        .collect();
+        mac_addresses.sort();
+        let strings: Vec<String> = mac_addresses.iter().map(ToString::to_string).collect();
+        let mut sorted_strings = strings.clone();
+        sorted_strings.sort();
+        assert_eq!(strings, sorted_strings);
+    }
+
+    #[test]
+    fn device_adapter() {
+        let device_id = DeviceId::new("/org/bluez/hci0/dev_11_22_33_44_55_66");
+        assert_eq!(device_id.adapter(), AdapterId::new("/org/bluez/hci0"));
+    }
+
+    #[test]
+    fn device_id_from_mac_address() {
+        let adapter = AdapterId::new("/org/bluez/hci1");
+        let mac_address: MacAddress = "11:22:33:44:55:66".parse().unwrap();
+        let device_id = DeviceId::from_mac_address(&adapter, &mac_address);
+        assert_eq!(
+            device_id,
+            DeviceId::new("/org/bluez/hci1/dev_11_22_33_44_55_66")
+        );
+        assert_eq!(device_id.adapter(), adapter);
+        assert_eq!(device_id.mac_address(), Some(mac_address));
+    }
+
+    #[test]
+    fn parse_ids() {
+        let device_id = DeviceId::new("/org/bluez/hci0/dev_11_22_33_44_55_66");
+        assert_eq!(device_id.to_string().parse(), Ok(device_id.clone()));
+        assert_eq!(
+            "/org/bluez/hci0/dev_11_22_33_44_55_66".parse(),
+            Ok(device_id.clone())
+        );
+        assert_eq!(String::from(device_id.clone()).parse(), Ok(device_id));
+        assert!("hci0".parse::<DeviceId>().is_err());
+        assert!("dev_11_22_33_44_55_66".parse::<DeviceId>().is_err());
+        assert!("hci0/dev_11_22_33".parse::<DeviceId>().is_err());
+
+        let adapter_id = AdapterId::new("/org/bluez/hci0");
+        assert_eq!("hci0".parse(), Ok(adapter_id.clone()));
+        assert_eq!("/org/bluez/hci0".parse(), Ok(adapter_id));
+        assert!("".parse::<AdapterId>().is_err());
+        assert!("hci0/dev_11_22_33_44_55_66".parse::<AdapterId>().is_err());
+    }
+
+    #[test]
+    fn discovery_filter_options() {
+        assert!(DiscoveryFilter::default().to_bluez_filter().is_empty());
+        let filter = DiscoveryFilter {
+            rssi: Some(-80),
+            transport: Some(Transport::Le),
+            ..Default::default()
+        };
+        let options = filter.to_bluez_filter();
+        assert_eq!(options.len(), 2);
+        assert_eq!(options["RSSI"].0.as_i64(), Some(-80));
+        assert_eq!(options["Transport"].0.as_str(), Some("le"));
+    }
+
+    #[test]
+    fn discoverable_filter_needs_bluez_5_50() {
+        let filter = DiscoveryFilter {
+            discoverable: Some(true),
+            ..Default::default()
+        };
+        let old = BluezCapabilities {
+            version: Some(BluezVersion::new(5, 48)),
+            ..Default::default()
+        };
+        let new = BluezCapabilities {
+            version: Some(BluezVersion::new(5, 50)),
+            ..Default::default()
+        };
+        assert!(matches!(
+            filter.check_supported(&old),
+            Err(BluetoothError::UnsupportedBluezVersion { .. })
+        ));
+        assert!(filter.check_supported(&new).is_ok());
+    }
+
+    #[test]
+    fn access_denied_is_permission_denied() {
+        let error: BluetoothError = dbus::Error::new_custom(
+            ACCESS_DENIED_ERROR_NAME,
+            "Rejected send message, 1 matched rules",
+        )
+        .into();
+        assert!(matches!(error, BluetoothError::PermissionDenied(_)));
+        assert!(error.to_string().contains("bluetooth` group"));
+
+        let error: BluetoothError =
+            dbus::Error::new_custom("org.bluez.Error.Failed", "Failed").into();
+        assert!(matches!(error, BluetoothError::DbusError(_)));
+    }
+
+    #[test]
+    fn adapter_display() {
+        assert_eq!(AdapterId::new("/org/bluez/hci1").to_string(), "hci1");
+    }
+
+    fn make_tree(objects: &[(&str, &str, &str)]) -> ManagedObjects {
+        objects
+            .iter()
+            .map(|&(path, interface, uuid)| {
+                let mut properties: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+                properties.insert("UUID".to_owned(), Variant(Box::new(uuid.to_owned())));
+                let mut interfaces = HashMap::new();
+                interfaces.insert(interface.to_owned(), properties);
+                (Path::new(path).unwrap(), interfaces)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn find_characteristic() {
+        let device = "/org/bluez/hci0/dev_11_22_33_44_55_66";
+        let tree = make_tree(&[
+            (
+                "/org/bluez/hci0/dev_11_22_33_44_55_66/service0021",
+                GATT_SERVICE_INTERFACE,
+                "ebe0ccb0-7a0a-4b0c-8a1a-6ff2997da3a6",
+            ),
+            (
+                "/org/bluez/hci0/dev_11_22_33_44_55_66/service0021/char0022",
+                GATT_CHARACTERISTIC_INTERFACE,
+                "ebe0ccb7-7a0a-4b0c-8a1a-6ff2997da3a6",
+            ),
+            (
+                "/org/bluez/hci0/dev_11_22_33_44_55_66/service0021/char0035",
+                GATT_CHARACTERISTIC_INTERFACE,
+                "ebe0ccc1-7a0a-4b0c-8a1a-6ff2997da3a6",
+            ),
+            (
+                "/org/bluez/hci0/dev_11_22_33_44_55_77/service0021/char0036",
+                GATT_CHARACTERISTIC_INTERFACE,
+                "ebe0ccc1-7a0a-4b0c-8a1a-6ff2997da3a6",
+            ),
+        ]);
+        let id = DeviceId::new(device);
+
+        assert_eq!(
+            find_characteristic_path(
+                &tree,
+                &id,
+                "EBE0CCB0-7A0A-4B0C-8A1A-6FF2997DA3A6",
+                "ebe0ccc1-7a0a-4b0c-8a1a-6ff2997da3a6"
+            )
+            .unwrap(),
+            "/service0021/char0035"
+        );
+        assert!(matches!(
+            find_characteristic_path(
+                &tree,
+                &id,
+                "ebe0ccb0-7a0a-4b0c-8a1a-6ff2997da3a6",
+                "00000000-0000-0000-0000-000000000000"
+            ),
+            Err(BluetoothError::CharacteristicNotFound(_))
+        ));
+        assert!(matches!(
+            find_characteristic_path(
+                &tree,
+                &DeviceId::new("/org/bluez/hci0/dev_11_22_33_44_55_77"),
+                "ebe0ccb0-7a0a-4b0c-8a1a-6ff2997da3a6",
+                "ebe0ccc1-7a0a-4b0c-8a1a-6ff2997da3a6"
+            ),
+            Err(BluetoothError::ServiceNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn write_options() {
+        assert!(WriteOptions::default().to_bluez_options().is_empty());
+
+        let options = WriteOptions {
+            offset: 5,
+            write_type: Some(WriteType::WithoutResponse),
+        }
+        .to_bluez_options();
+        assert_eq!(options.len(), 2);
+        assert_eq!(options["offset"].0.as_u64(), Some(5));
+        assert_eq!(options["type"].0.as_str(), Some("command"));
+    }
+
+    #[test]
+    fn in_progress_error() {
+        assert!(is_in_progress(&dbus::Error::new_custom(
+            IN_PROGRESS_ERROR_NAME,
+            "Operation already in progress"
+        )));
+        assert!(!is_in_progress(&dbus::Error::new_custom(
+            "org.bluez.Error.Failed",
+            "Operation failed"
+        )));
+    }
+
+    fn make_device(object_path: &str, mac_address: &str, connected: bool) -> DeviceInfo {
+        DeviceInfo {
+            id: DeviceId::new(object_path),
+            mac_address: mac_address.parse().unwrap(),
+            name: None,
+            alias: None,
+            paired: false,
+            trusted: false,
+            rssi: None,
+            connected,
+            service_data: HashMap::new(),
+            manufacturer_data: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn least_loaded_adapter_first() {
+        let devices = vec![
+            make_device(
+                "/org/bluez/hci0/dev_11_22_33_44_55_66",
+                "11:22:33:44:55:66",
+                false,
+            ),
+            make_device(
+                "/org/bluez/hci0/dev_11_22_33_44_55_77",
+                "11:22:33:44:55:77",
+                true,
+            ),
+            make_device(
+                "/org/bluez/hci0/dev_11_22_33_44_55_88",
+                "11:22:33:44:55:88",
+                true,
+            ),
+            make_device(
+                "/org/bluez/hci1/dev_11_22_33_44_55_66",
+                "11:22:33:44:55:66",
+                false,
+            ),
+            make_device(
+                "/org/bluez/hci1/dev_11_22_33_44_55_88",
+                "11:22:33:44:55:88",
+                false,
+            ),
+            make_device(
+                "/org/bluez/hci2/dev_11_22_33_44_55_99",
+                "11:22:33:44:55:99",
+                true,
+            ),
+        ];
+        assert_eq!(
+            order_by_adapter_load(&devices, &"11:22:33:44:55:66".parse().unwrap()),
+            vec![
+                DeviceId::new("/org/bluez/hci1/dev_11_22_33_44_55_66"),
+                DeviceId::new("/org/bluez/hci0/dev_11_22_33_44_55_66"),
+            ]
+        );
+        assert_eq!(
+            order_by_adapter_load(&devices, &"11:22:33:44:55:00".parse().unwrap()),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn device_info_from_properties() {
+        let mut properties: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+        properties.insert(
+            "Address".to_owned(),
+            Variant(Box::new("11:22:33:44:55:66".to_owned())),
+        );
+        properties.insert("Alias".to_owned(), Variant(Box::new("Kitchen".to_owned())));
+        properties.insert("Paired".to_owned(), Variant(Box::new(true)));
+        properties.insert("RSSI".to_owned(), Variant(Box::new(-60i16)));
+        let id = DeviceId::new("/org/bluez/hci0/dev_11_22_33_44_55_66");
+
+        let device = DeviceInfo::from_properties(id.clone(), &properties).unwrap();
+        assert_eq!(device.id, id);
+        assert_eq!(device.mac_address, "11:22:33:44:55:66".parse().unwrap());
+        assert_eq!(device.name, None);
+        assert_eq!(device.alias, Some("Kitchen".to_owned()));
+        assert!(device.paired);
+        assert!(!device.trusted);
+        assert!(!device.connected);
+        assert_eq!(device.rssi, Some(-60));
+
+        properties.remove("Address");
+        assert!(DeviceInfo::from_properties(id, &properties).is_none());
+    }
+
+    #[test]
+    fn device_display() {
+        assert_eq!(
+            DeviceId::new("/org/bluez/hci0/dev_11_22_33_44_55_66").to_string(),
+            "hci0/dev_11_22_33_44_55_66"
+        );
+    }
+}
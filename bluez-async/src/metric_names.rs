@@ -0,0 +1,7 @@
+//! Names of the metrics which this crate records via the [`metrics`](https://docs.rs/metrics)
+//! facade. They will be ignored unless the application installs a metrics recorder.
+
+/// Counter of attempts to connect to a Bluetooth device.
+pub const CONNECT_ATTEMPTS: &str = "bluez_connect_attempts_total";
+/// Counter of errors returned by D-Bus method calls to the Bluetooth daemon.
+pub const DBUS_ERRORS: &str = "bluez_dbus_errors_total";
@@ -0,0 +1,215 @@
+//! The parts of the library which depend on the async runtime: spawning tasks, waiting, and driving
+//! the D-Bus connection. Tokio is used by default; enable the `runtime-async-std` or `runtime-smol`
+//! feature instead of the default `runtime-tokio` to use async-std or smol.
+//!
+//! Tokio's synchronisation primitives are used whichever runtime is chosen, as they don't need the
+//! Tokio runtime to be running.
+
+use crate::SpawnError;
+use futures::future::{self, Either};
+use futures::pin_mut;
+use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+use thiserror::Error;
+
+#[cfg(not(any(
+    feature = "runtime-tokio",
+    feature = "runtime-async-std",
+    feature = "runtime-smol"
+)))]
+compile_error!(
+    "One of the `runtime-tokio`, `runtime-async-std` or `runtime-smol` features must be enabled."
+);
+
+#[cfg(feature = "runtime-tokio")]
+use tokio_runtime as imp;
+
+#[cfg(all(
+    not(feature = "runtime-tokio"),
+    any(feature = "runtime-async-std", feature = "runtime-smol")
+))]
+use async_io_runtime as imp;
+
+pub(crate) use imp::new_system_connection;
+pub use imp::{in_runtime, sleep, spawn};
+
+/// A future which runs the D-Bus connection, and completes with an error if it is lost.
+pub(crate) type DbusResource = Pin<Box<dyn Future<Output = Box<dyn Error + Send + Sync>> + Send>>;
+
+/// A handle to a task started by `spawn`, which may be awaited for the task's result. Dropping it
+/// leaves the task running.
+pub type JoinHandle<T> = Pin<Box<dyn Future<Output = Result<T, SpawnError>> + Send>>;
+
+/// The error returned by `timeout` if the future doesn't complete in time.
+#[derive(Clone, Copy, Debug, Error, Eq, PartialEq)]
+#[error("Timed out")]
+pub struct Elapsed;
+
+/// Wait for the given future to complete, or for the given duration to pass, whichever is first.
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Result<F::Output, Elapsed> {
+    let delay = sleep(duration);
+    pin_mut!(future, delay);
+    match future::select(future, delay).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(((), _)) => Err(Elapsed),
+    }
+}
+
+#[cfg(feature = "runtime-tokio")]
+mod tokio_runtime {
+    use super::{DbusResource, JoinHandle};
+    use crate::SpawnError;
+    use dbus::nonblock::SyncConnection;
+    use futures::FutureExt;
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Connect to the D-Bus system bus (this is blocking, unfortunately).
+    pub(crate) fn new_system_connection() -> Result<(DbusResource, Arc<SyncConnection>), dbus::Error>
+    {
+        let (resource, connection) = dbus_tokio::connection::new_system_sync()?;
+        Ok((Box::pin(resource), connection))
+    }
+
+    /// Spawn the given future as a new task.
+    pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        Box::pin(
+            tokio::spawn(future).map(|result| result.map_err(|e| SpawnError::Join(Box::new(e)))),
+        )
+    }
+
+    /// Wait until the given duration has passed.
+    pub fn sleep(duration: Duration) -> impl Future<Output = ()> + Send {
+        tokio::time::delay_for(duration)
+    }
+
+    /// Whether tasks can be spawned from the current thread, i.e. it is within a Tokio runtime.
+    pub fn in_runtime() -> bool {
+        tokio::runtime::Handle::try_current().is_ok()
+    }
+}
+
+#[cfg(all(
+    not(feature = "runtime-tokio"),
+    any(feature = "runtime-async-std", feature = "runtime-smol")
+))]
+mod async_io_runtime {
+    use super::{DbusResource, JoinHandle};
+    use crate::SpawnError;
+    use async_io::{Async, Timer};
+    use dbus::channel::{BusType, Channel};
+    use dbus::nonblock::{NonblockReply, Process, SyncConnection};
+    use futures::channel::{mpsc, oneshot};
+    use futures::future::{self, Either};
+    use futures::{FutureExt, StreamExt};
+    use std::error::Error;
+    use std::future::Future;
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    /// The file descriptor of a D-Bus connection. It is owned by libdbus, so isn't closed when this
+    /// is dropped.
+    struct WatchFd(RawFd);
+
+    impl AsRawFd for WatchFd {
+        fn as_raw_fd(&self) -> RawFd {
+            self.0
+        }
+    }
+
+    /// Connect to the D-Bus system bus (this is blocking, unfortunately).
+    pub(crate) fn new_system_connection() -> Result<(DbusResource, Arc<SyncConnection>), dbus::Error>
+    {
+        let mut channel = Channel::get_private(BusType::System)?;
+        channel.set_watch_enabled(true);
+        let watch = Async::new(WatchFd(channel.watch().fd))
+            .map_err(|e| dbus::Error::new_failed(&e.to_string()))?;
+        let mut connection = SyncConnection::from(channel);
+        connection.set_timeout_maker(Some(make_timeout));
+        // Messages sent from other tasks are only written out when the connection is next driven,
+        // so it needs waking up for them.
+        let (wakeup_sender, wakeups) = mpsc::unbounded();
+        connection.set_waker(Some(Box::new(move || {
+            wakeup_sender.unbounded_send(()).map_err(|_| ())
+        })));
+        let connection = Arc::new(connection);
+        let resource = drive_connection(connection.clone(), watch, wakeups);
+        Ok((Box::pin(resource), connection))
+    }
+
+    fn make_timeout(deadline: Instant) -> Pin<Box<dyn Future<Output = ()> + Send + Sync>> {
+        // `Timer` is also a `Stream`, so `FutureExt` must be named explicitly.
+        Box::pin(FutureExt::map(Timer::at(deadline), |_| ()))
+    }
+
+    /// Read and write messages on the D-Bus connection whenever its socket is ready or a message is
+    /// sent, until the connection fails.
+    async fn drive_connection(
+        connection: Arc<SyncConnection>,
+        watch: Async<WatchFd>,
+        mut wakeups: mpsc::UnboundedReceiver<()>,
+    ) -> Box<dyn Error + Send + Sync> {
+        loop {
+            let has_messages_to_send = {
+                let channel: &Channel = (*connection).as_ref();
+                if let Err(()) = channel.read_write(Some(Duration::default())) {
+                    return Box::new(dbus::Error::new_failed("D-Bus connection closed"));
+                }
+                connection.process_all();
+                channel.has_messages_to_send()
+            };
+            let ready = if has_messages_to_send {
+                watch.writable().boxed()
+            } else {
+                watch.readable().boxed()
+            };
+            match future::select(ready, wakeups.next()).await {
+                Either::Left((Err(e), _)) => return Box::new(e),
+                Either::Left((Ok(()), _)) | Either::Right((Some(()), _)) => {}
+                Either::Right((None, _)) => {
+                    return Box::new(dbus::Error::new_failed("D-Bus connection dropped"));
+                }
+            }
+        }
+    }
+
+    /// Spawn the given future as a new task.
+    pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        // smol cancels tasks when their handle is dropped, so send the result back separately
+        // instead to behave the same as the other runtimes. If the task panics then the sender is
+        // dropped, so the handle still completes.
+        let (sender, receiver) = oneshot::channel();
+        let task = async move {
+            let _ = sender.send(future.await);
+        };
+        #[cfg(feature = "runtime-async-std")]
+        async_std::task::spawn(task);
+        #[cfg(not(feature = "runtime-async-std"))]
+        smol::spawn(task).detach();
+        Box::pin(receiver.map(|result| result.map_err(|e| SpawnError::Join(Box::new(e)))))
+    }
+
+    /// Wait until the given duration has passed.
+    pub fn sleep(duration: Duration) -> impl Future<Output = ()> + Send {
+        FutureExt::map(Timer::after(duration), |_| ())
+    }
+
+    /// Whether tasks can be spawned from the current thread. async-std and smol both start their
+    /// global executors on demand, so this is always true.
+    pub fn in_runtime() -> bool {
+        true
+    }
+}
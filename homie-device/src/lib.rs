@@ -3,6 +3,7 @@
 //!
 //! See the examples directory for examples of how to use it.
 
+use async_channel::{Receiver, Sender};
 use futures::future::try_join;
 use futures::FutureExt;
 
@@ -11,24 +12,26 @@ use rumqttc::{
     self, AsyncClient, ClientError, ConnectionError, Event, EventLoop, Incoming, LastWill,
     MqttOptions, QoS,
 };
+use std::error::Error;
 use std::fmt::{self, Debug, Display, Formatter};
 use std::future::Future;
 use std::pin::Pin;
-use std::str;
+use std::str::{self, FromStr};
 use std::time::{Duration, Instant};
 use thiserror::Error;
 use tokio::task::{self, JoinError, JoinHandle};
-use tokio::time::delay_for;
+use tokio::time::{delay_for, timeout};
 
 mod types;
 pub use crate::types::{Datatype, Node, Property};
 mod values;
 pub use crate::values::{Color, ColorFormat, ColorHSV, ColorRGB};
 
-const HOMIE_VERSION: &str = "4.0";
 const HOMIE_IMPLEMENTATION: &str = "homie-rs";
 const STATS_INTERVAL: Duration = Duration::from_secs(60);
 const REQUESTS_CAP: usize = 10;
+/// How long to wait for the broker to send the retained `$nodes` topic from a previous run.
+const RETAINED_NODES_TIMEOUT: Duration = Duration::from_secs(2);
 
 /// Error type for futures representing tasks spawned by this crate.
 #[derive(Error, Debug)]
@@ -86,12 +89,69 @@ impl Into<Vec<u8>> for State {
     }
 }
 
+/// A version of the [Homie convention](https://homieiot.github.io/) which a device can announce.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HomieVersion {
+    /// Version 3.0.1, which has the firmware, network and stats attributes as part of the core
+    /// convention rather than as extensions.
+    V3,
+    /// Version 4.0, which moves the firmware, network and stats attributes into the legacy
+    /// extensions.
+    V4,
+}
+
+impl HomieVersion {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::V3 => "3.0.1",
+            Self::V4 => "4.0",
+        }
+    }
+}
+
+impl Display for HomieVersion {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// An error parsing a `HomieVersion` from a string.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParseHomieVersionError(String);
+
+impl Display for ParseHomieVersionError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Unsupported Homie version '{}', expected '3.0.1' or '4.0'",
+            self.0
+        )
+    }
+}
+
+impl Error for ParseHomieVersionError {}
+
+impl FromStr for HomieVersion {
+    type Err = ParseHomieVersionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "3" | "3.0" | "3.0.1" => Ok(Self::V3),
+            "4" | "4.0" | "4.0.0" => Ok(Self::V4),
+            _ => Err(ParseHomieVersionError(s.to_owned())),
+        }
+    }
+}
+
 type UpdateCallback = Box<
     dyn FnMut(String, String, String) -> Pin<Box<dyn Future<Output = Option<String>> + Send>>
         + Send
         + Sync,
 >;
 
+type BroadcastCallback =
+    Box<dyn FnMut(String, String) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
 /// Builder for `HomieDevice` and associated objects.
 pub struct HomieDeviceBuilder {
     device_base: String,
@@ -100,6 +160,9 @@ pub struct HomieDeviceBuilder {
     firmware_version: Option<String>,
     mqtt_options: MqttOptions,
     update_callback: Option<UpdateCallback>,
+    broadcast_callback: Option<BroadcastCallback>,
+    read_previous_nodes: bool,
+    homie_version: HomieVersion,
 }
 
 impl Debug for HomieDeviceBuilder {
@@ -114,6 +177,12 @@ impl Debug for HomieDeviceBuilder {
                 "update_callback",
                 &self.update_callback.as_ref().map(|_| "..."),
             )
+            .field(
+                "broadcast_callback",
+                &self.broadcast_callback.as_ref().map(|_| "..."),
+            )
+            .field("read_previous_nodes", &self.read_previous_nodes)
+            .field("homie_version", &self.homie_version)
             .finish()
     }
 }
@@ -139,6 +208,37 @@ impl HomieDeviceBuilder {
         ));
     }
 
+    /// Set a callback to be called for messages sent to the Homie
+    /// [broadcast channel](https://homieiot.github.io/specification/#broadcast-channel). It will be
+    /// called with the broadcast level (the subtopic after `$broadcast/`, e.g. `alert`) and the
+    /// message. The device will only subscribe to broadcasts if this is set.
+    pub fn set_broadcast_callback<F, Fut>(&mut self, mut broadcast_callback: F)
+    where
+        F: (FnMut(String, String) -> Fut) + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.broadcast_callback = Some(Box::new(move |level: String, message: String| {
+            broadcast_callback(level, message).boxed()
+        }));
+    }
+
+    /// Set whether to read the list of nodes which a previous run of the device left retained on the
+    /// MQTT broker when the device is spawned. If this is set, they will be available from
+    /// `HomieDevice::previous_node_ids()`, so the caller can re-advertise or clean them up.
+    ///
+    /// This is disabled by default, as it delays startup while waiting for the broker.
+    pub fn set_read_previous_nodes(&mut self, read_previous_nodes: bool) {
+        self.read_previous_nodes = read_previous_nodes;
+    }
+
+    /// Set which version of the Homie convention the device announces. This is 4.0 by default.
+    ///
+    /// For version 3.0.1 the firmware and network attributes are required, so if the firmware name
+    /// and version haven't been set then those of this crate are used.
+    pub fn set_homie_version(&mut self, homie_version: HomieVersion) {
+        self.homie_version = homie_version;
+    }
+
     /// Create a new Homie device, connect to the MQTT broker, and start a task to handle the MQTT
     /// connection.
     ///
@@ -148,15 +248,27 @@ impl HomieDeviceBuilder {
     pub async fn spawn(
         self,
     ) -> Result<(HomieDevice, impl Future<Output = Result<(), SpawnError>>), ClientError> {
-        let (event_loop, mut homie, stats, firmware, update_callback) = self.build();
+        let read_previous_nodes = self.read_previous_nodes;
+        let mut builder = self;
+        let broadcast_callback = builder.broadcast_callback.take();
+        let (event_loop, mut homie, stats, firmware, update_callback) = builder.build();
+        let (nodes_tx, nodes_rx) = async_channel::bounded(1);
+        let subscribe_broadcasts = broadcast_callback.is_some();
 
         // This needs to be spawned before we wait for anything to be sent, as the start() calls below do.
-        let event_task = homie.spawn(event_loop, update_callback);
+        let event_task = homie.spawn(event_loop, update_callback, broadcast_callback, nodes_tx);
+
+        if subscribe_broadcasts {
+            homie.subscribe_broadcasts().await?;
+        }
 
         stats.start().await?;
         if let Some(firmware) = firmware {
             firmware.start().await?;
         }
+        if read_previous_nodes {
+            homie.previous_node_ids = homie.read_previous_node_ids(nodes_rx).await?;
+        }
         homie.start().await?;
 
         let stats_task = stats.spawn();
@@ -187,21 +299,33 @@ impl HomieDeviceBuilder {
         let publisher = DevicePublisher::new(client, self.device_base);
 
         let mut extension_ids = vec![HomieStats::EXTENSION_ID];
-        let stats = HomieStats::new(publisher.clone());
-        let firmware = if let (Some(firmware_name), Some(firmware_version)) =
-            (self.firmware_name, self.firmware_version)
-        {
+        let stats = HomieStats::new(publisher.clone(), self.homie_version);
+        let firmware = match (
+            self.firmware_name,
+            self.firmware_version,
+            self.homie_version,
+        ) {
+            (Some(firmware_name), Some(firmware_version), _) => {
+                Some((firmware_name, firmware_version))
+            }
+            // The firmware attributes are required by Homie 3.
+            (_, _, HomieVersion::V3) => Some((
+                env!("CARGO_PKG_NAME").to_owned(),
+                env!("CARGO_PKG_VERSION").to_owned(),
+            )),
+            _ => None,
+        }
+        .map(|(firmware_name, firmware_version)| {
             extension_ids.push(HomieFirmware::EXTENSION_ID);
-            Some(HomieFirmware::new(
-                publisher.clone(),
-                firmware_name,
-                firmware_version,
-            ))
-        } else {
-            None
-        };
+            HomieFirmware::new(publisher.clone(), firmware_name, firmware_version)
+        });
 
-        let homie = HomieDevice::new(publisher, self.device_name, &extension_ids);
+        let homie = HomieDevice::new(
+            publisher,
+            self.device_name,
+            &extension_ids,
+            self.homie_version,
+        );
 
         (event_loop, homie, stats, firmware, self.update_callback)
     }
@@ -216,6 +340,8 @@ pub struct HomieDevice {
     nodes: Vec<Node>,
     state: State,
     extension_ids: String,
+    previous_node_ids: Vec<String>,
+    homie_version: HomieVersion,
 }
 
 impl HomieDevice {
@@ -240,27 +366,55 @@ impl HomieDevice {
             firmware_version: None,
             mqtt_options,
             update_callback: None,
+            broadcast_callback: None,
+            read_previous_nodes: false,
+            homie_version: HomieVersion::V4,
         }
     }
 
-    fn new(publisher: DevicePublisher, device_name: String, extension_ids: &[&str]) -> HomieDevice {
+    fn new(
+        publisher: DevicePublisher,
+        device_name: String,
+        extension_ids: &[&str],
+        homie_version: HomieVersion,
+    ) -> HomieDevice {
         HomieDevice {
             publisher,
             device_name,
             nodes: vec![],
             state: State::Disconnected,
             extension_ids: extension_ids.join(","),
+            previous_node_ids: vec![],
+            homie_version,
         }
     }
 
+    /// Subscribe to the device's `$nodes` topic and wait a short time for the broker to send the
+    /// value retained from a previous run, then unsubscribe again.
+    async fn read_previous_node_ids(
+        &self,
+        nodes_rx: Receiver<String>,
+    ) -> Result<Vec<String>, ClientError> {
+        self.publisher.subscribe("$nodes").await?;
+        let node_ids = match timeout(RETAINED_NODES_TIMEOUT, nodes_rx.recv()).await {
+            Ok(Ok(nodes)) => split_node_ids(&nodes),
+            _ => vec![],
+        };
+        self.publisher.unsubscribe("$nodes").await?;
+        Ok(node_ids)
+    }
+
     async fn start(&mut self) -> Result<(), ClientError> {
         assert_eq!(self.state, State::Disconnected);
         self.publisher
-            .publish_retained("$homie", HOMIE_VERSION)
-            .await?;
-        self.publisher
-            .publish_retained("$extensions", self.extension_ids.as_str())
+            .publish_retained("$homie", self.homie_version.as_str())
             .await?;
+        // Extensions were introduced in Homie 4.
+        if self.homie_version == HomieVersion::V4 {
+            self.publisher
+                .publish_retained("$extensions", self.extension_ids.as_str())
+                .await?;
+        }
         self.publisher
             .publish_retained("$implementation", HOMIE_IMPLEMENTATION)
             .await?;
@@ -276,8 +430,11 @@ impl HomieDevice {
         &self,
         mut event_loop: EventLoop,
         mut update_callback: Option<UpdateCallback>,
+        mut broadcast_callback: Option<BroadcastCallback>,
+        nodes_tx: Sender<String>,
     ) -> impl Future<Output = Result<(), SpawnError>> {
         let device_base = format!("{}/", self.publisher.device_base);
+        let broadcast_base = broadcast_base(&self.publisher.device_base);
         let (incoming_tx, incoming_rx) = async_channel::unbounded();
 
         let mqtt_task = task::spawn(async move {
@@ -301,7 +458,12 @@ impl HomieDevice {
                         SpawnError::Internal("Incoming event channel sender closed.")
                     })? {
                         if let Some(rest) = publish.topic.strip_prefix(&device_base) {
-                            if let ([node_id, property_id, "set"], Ok(payload)) = (
+                            if rest == "$nodes" {
+                                if let Ok(payload) = str::from_utf8(&publish.payload) {
+                                    // Nobody may be waiting for this any more, so ignore errors.
+                                    let _ = nodes_tx.try_send(payload.to_owned());
+                                }
+                            } else if let ([node_id, property_id, "set"], Ok(payload)) = (
                                 rest.split('/').collect::<Vec<&str>>().as_slice(),
                                 str::from_utf8(&publish.payload),
                             ) {
@@ -328,6 +490,15 @@ impl HomieDevice {
                                     }
                                 }
                             }
+                        } else if let (Some(level), Some(callback)) = (
+                            broadcast_base
+                                .as_ref()
+                                .and_then(|base| publish.topic.strip_prefix(base)),
+                            broadcast_callback.as_mut(),
+                        ) {
+                            let message = String::from_utf8_lossy(&publish.payload);
+                            log::trace!("broadcast {:?}: {:?}", level, message);
+                            callback(level.to_string(), message.into_owned()).await;
                         } else {
                             log::warn!("Unexpected publish: {:?}", publish);
                         }
@@ -355,6 +526,65 @@ impl HomieDevice {
         self.publish_nodes().await
     }
 
+    /// Subscribe to all messages on the broadcast channel for the Homie base topic which the device
+    /// is under.
+    async fn subscribe_broadcasts(&self) -> Result<(), ClientError> {
+        if let Some(broadcast_base) = broadcast_base(&self.publisher.device_base) {
+            self.publisher
+                .client
+                .subscribe(format!("{}#", broadcast_base), QoS::AtLeastOnce)
+                .await?;
+        } else {
+            log::warn!(
+                "Can't subscribe to broadcasts as device base {:?} has no base topic.",
+                self.publisher.device_base
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns whether a node with the given ID has been added.
+    pub fn has_node(&self, node_id: &str) -> bool {
+        self.nodes.iter().any(|n| n.id == node_id)
+    }
+
+    /// Get the IDs of the nodes which a previous run of the device left retained on the MQTT
+    /// broker. This will be empty unless `HomieDeviceBuilder::set_read_previous_nodes` was set.
+    pub fn previous_node_ids(&self) -> &[String] {
+        &self.previous_node_ids
+    }
+
+    /// Clear all retained topics for the given node, such as one left behind by a previous run of
+    /// the device which is no longer needed. The node must not currently be added.
+    pub async fn clear_retained_node(&self, node: &Node) -> Result<(), ClientError> {
+        assert!(
+            !self.has_node(&node.id),
+            "Tried to clear retained topics for node which is still added: {:?}",
+            node
+        );
+        let mut subtopics = vec![
+            format!("{}/$name", node.id),
+            format!("{}/$type", node.id),
+            format!("{}/$properties", node.id),
+        ];
+        for property in &node.properties {
+            for attribute in &[
+                "",
+                "/$name",
+                "/$datatype",
+                "/$settable",
+                "/$unit",
+                "/$format",
+            ] {
+                subtopics.push(format!("{}/{}{}", node.id, property.id, attribute));
+            }
+        }
+        for subtopic in subtopics {
+            self.publisher.publish_retained(&subtopic, "").await?;
+        }
+        Ok(())
+    }
+
     /// Remove the node with the given ID.
     pub async fn remove_node(&mut self, node_id: &str) -> Result<(), ClientError> {
         // Panic on attempt to remove a node which was never added.
@@ -528,21 +758,27 @@ impl DevicePublisher {
 struct HomieStats {
     publisher: DevicePublisher,
     start_time: Instant,
+    homie_version: HomieVersion,
 }
 
 impl HomieStats {
     const EXTENSION_ID: &'static str = "org.homie.legacy-stats:0.1.1:[4.x]";
 
-    fn new(publisher: DevicePublisher) -> Self {
+    fn new(publisher: DevicePublisher, homie_version: HomieVersion) -> Self {
         let now = Instant::now();
         Self {
             publisher,
             start_time: now,
+            homie_version,
         }
     }
 
     /// Send initial topics.
     async fn start(&self) -> Result<(), ClientError> {
+        // Homie 3 lists the stats which are available.
+        if self.homie_version == HomieVersion::V3 {
+            self.publisher.publish_retained("$stats", "uptime").await?;
+        }
         self.publisher
             .publish_retained("$stats/interval", STATS_INTERVAL.as_secs().to_string())
             .await
@@ -600,6 +836,23 @@ impl HomieFirmware {
     }
 }
 
+/// Get the prefix of broadcast topics for the Homie base topic which the given device is under, if
+/// any. For example, for `homie/device-id` this will be `homie/$broadcast/`.
+fn broadcast_base(device_base: &str) -> Option<String> {
+    device_base
+        .rfind('/')
+        .map(|index| format!("{}/$broadcast/", &device_base[..index]))
+}
+
+/// Split a comma-separated list of node IDs as published to the `$nodes` topic.
+fn split_node_ids(nodes: &str) -> Vec<String> {
+    nodes
+        .split(',')
+        .filter(|node_id| !node_id.is_empty())
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
 fn try_join_handles<A, B, E>(
     a: JoinHandle<Result<A, E>>,
     b: JoinHandle<Result<B, E>>,
@@ -628,18 +881,37 @@ fn simplify_unit_pair<E>(m: Result<((), ()), E>) -> Result<(), E> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use async_channel::Receiver;
     use rumqttc::Request;
 
     fn make_test_device() -> (HomieDevice, Receiver<Request>) {
+        make_test_device_with_version(HomieVersion::V4)
+    }
+
+    fn make_test_device_with_version(
+        homie_version: HomieVersion,
+    ) -> (HomieDevice, Receiver<Request>) {
         let (requests_tx, requests_rx) = async_channel::unbounded();
         let (cancel_tx, _cancel_rx) = async_channel::unbounded();
         let client = AsyncClient::from_senders(requests_tx, cancel_tx);
         let publisher = DevicePublisher::new(client, "homie/test-device".to_string());
-        let device = HomieDevice::new(publisher, "Test device".to_string(), &[]);
+        let device = HomieDevice::new(publisher, "Test device".to_string(), &[], homie_version);
         (device, requests_rx)
     }
 
+    /// Get the topics and payloads of all retained messages published so far.
+    fn published_values(rx: &Receiver<Request>) -> Vec<(String, String)> {
+        let mut published = vec![];
+        while let Ok(request) = rx.try_recv() {
+            if let Request::Publish(publish) = request {
+                published.push((
+                    publish.topic,
+                    String::from_utf8(publish.payload.to_vec()).unwrap(),
+                ));
+            }
+        }
+        published
+    }
+
     #[tokio::test]
     #[should_panic(expected = "Tried to add node with duplicate ID")]
     async fn add_node_fails_given_duplicate_id() {
@@ -734,6 +1006,54 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn start_announces_homie_version() -> Result<(), ClientError> {
+        let (mut device, rx) = make_test_device_with_version(HomieVersion::V4);
+        device.start().await?;
+        let published = published_values(&rx);
+        assert!(published.contains(&("homie/test-device/$homie".to_string(), "4.0".to_string())));
+        assert!(published
+            .iter()
+            .any(|(topic, _)| topic == "homie/test-device/$extensions"));
+
+        let (mut device, rx) = make_test_device_with_version(HomieVersion::V3);
+        device.start().await?;
+        let published = published_values(&rx);
+        assert!(published.contains(&("homie/test-device/$homie".to_string(), "3.0.1".to_string())));
+        assert!(!published
+            .iter()
+            .any(|(topic, _)| topic == "homie/test-device/$extensions"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn homie_3_build_includes_firmware() -> Result<(), ClientError> {
+        let mut builder = HomieDevice::builder(
+            "homie/test-device",
+            "Test device",
+            MqttOptions::new("client_id", "hostname", 1234),
+        );
+        builder.set_homie_version(HomieVersion::V3);
+
+        let (_event_loop, homie, _stats, firmware, _callback) = builder.build();
+
+        assert_eq!(homie.homie_version, HomieVersion::V3);
+        assert_eq!(firmware.unwrap().firmware_name, "homie-device");
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_homie_version() {
+        assert_eq!("3.0.1".parse(), Ok(HomieVersion::V3));
+        assert_eq!("4.0".parse(), Ok(HomieVersion::V4));
+        assert_eq!(
+            "2.0".parse::<HomieVersion>(),
+            Err(ParseHomieVersionError("2.0".to_owned()))
+        );
+    }
+
     #[tokio::test]
     async fn minimal_build_succeeds() -> Result<(), ClientError> {
         let builder = HomieDevice::builder(
@@ -793,6 +1113,73 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn has_node_after_add_and_remove() -> Result<(), ClientError> {
+        let (mut device, rx) = make_test_device();
+
+        assert!(!device.has_node("id"));
+        device
+            .add_node(Node::new("id", "Name", "type", vec![]))
+            .await?;
+        assert!(device.has_node("id"));
+        device.remove_node("id").await?;
+        assert!(!device.has_node("id"));
+
+        // Need to keep rx alive until here so that the channel isn't closed.
+        drop(rx);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn clear_retained_node_publishes_empty_values() -> Result<(), ClientError> {
+        let (device, rx) = make_test_device();
+
+        let node = Node::new(
+            "id",
+            "Name",
+            "type",
+            vec![Property::integer("prop", "Property", false, None, None)],
+        );
+        device.clear_retained_node(&node).await?;
+
+        let mut topics = vec![];
+        while let Ok(request) = rx.try_recv() {
+            if let Request::Publish(publish) = request {
+                assert!(publish.retain);
+                assert!(publish.payload.is_empty());
+                topics.push(publish.topic);
+            }
+        }
+        assert!(topics.contains(&"homie/test-device/id/$name".to_string()));
+        assert!(topics.contains(&"homie/test-device/id/prop".to_string()));
+        assert!(topics.contains(&"homie/test-device/id/prop/$datatype".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn broadcast_base_for_device() {
+        assert_eq!(
+            broadcast_base("homie/test-device"),
+            Some("homie/$broadcast/".to_string())
+        );
+        assert_eq!(
+            broadcast_base("some/prefix/test-device"),
+            Some("some/prefix/$broadcast/".to_string())
+        );
+        assert_eq!(broadcast_base("test-device"), None);
+    }
+
+    #[test]
+    fn split_node_ids_empty() {
+        assert_eq!(split_node_ids(""), Vec::<String>::new());
+    }
+
+    #[test]
+    fn split_node_ids_several() {
+        assert_eq!(split_node_ids("a,b,c"), vec!["a", "b", "c"]);
+    }
+
     /// Add a node, remove it, and add it back again.
     #[tokio::test]
     async fn add_node_succeeds_after_remove() -> Result<(), ClientError> {
@@ -0,0 +1,1375 @@
+//! Command-line tool for talking to Xiaomi Mijia 2 Bluetooth temperature/humidity sensors.
+
+use chrono::{DateTime, Utc};
+use indicatif::{ProgressBar, ProgressStyle};
+use mijia::{
+    ClockOffset, ComfortLevel, HistoryRecord, MacAddress, MijiaEvent, MijiaSession, Readings,
+    SensorProps, TemperatureUnit,
+};
+use serde_json::json;
+use stable_eyre::eyre::{eyre, Report};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
+use structopt::clap::AppSettings;
+use structopt::StructOpt;
+use tokio::stream::{Stream, StreamExt};
+use tokio::time;
+
+/// How long to keep scanning for a sensor before giving up.
+const SCAN_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often to check whether the sensor has been discovered while scanning.
+const SCAN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long to wait for the sensor to send readings after subscribing to them.
+const READINGS_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to scan for sensors to watch, if none are given.
+const WATCH_SCAN_DURATION: Duration = Duration::from_secs(10);
+/// How often to redraw the table of readings while watching sensors.
+const WATCH_REDRAW_INTERVAL: Duration = Duration::from_secs(1);
+/// How long to wait for BlueZ to discover a sensor's services after connecting to it.
+const SERVICES_RESOLVED_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often to check whether a sensor's services have been discovered.
+const SERVICES_RESOLVED_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Signal strength below which a sensor is likely to be too far away for a reliable connection.
+const WEAK_RSSI: i16 = -90;
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Talk to Xiaomi Mijia 2 Bluetooth temperature/humidity sensors.")]
+struct Options {
+    /// Print output as JSON rather than in a human-readable format.
+    #[structopt(long, global = true)]
+    json: bool,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Connect to a sensor and print one set of readings.
+    Read {
+        /// The MAC address of the sensor.
+        mac_address: MacAddress,
+    },
+    /// Download the historical records stored on a sensor and print them, or delete them.
+    #[structopt(setting = AppSettings::ArgsNegateSubcommands)]
+    History {
+        #[structopt(subcommand)]
+        command: Option<HistoryCommand>,
+        /// The MAC address of the sensor.
+        mac_address: Option<MacAddress>,
+        /// Only print records from this time onwards, in RFC 3339 format.
+        #[structopt(long)]
+        since: Option<DateTime<Utc>>,
+        /// The format in which to print records. `--json` implies JSON.
+        #[structopt(long, default_value = "csv", possible_values = &["csv", "json"])]
+        format: OutputFormat,
+        /// Download records in chunks, saving those received so far to this file after each chunk
+        /// so that an interrupted download can be resumed by running the same command again.
+        #[structopt(long)]
+        checkpoint: Option<PathBuf>,
+        /// How many records to download in each chunk when using a checkpoint file.
+        #[structopt(long, default_value = "100")]
+        chunk_size: u32,
+        /// Correct the times of records for how far the sensor's clock is ahead of or behind the
+        /// host's clock. The times according to the sensor's clock are included as `raw_time` in
+        /// JSON output.
+        #[structopt(long)]
+        correct_time: bool,
+    },
+    /// Get or set the comfort level thresholds which determine when a sensor shows a happy face.
+    Comfort(ComfortCommand),
+    /// Get the time of a sensor's clock, or set it to the current time.
+    Clock(ClockCommand),
+    /// Get or set the temperature unit which sensors use for their displays.
+    Unit(UnitCommand),
+    /// Scan for sensors and print a list of those found.
+    Scan {
+        /// How long to scan for, in seconds.
+        #[structopt(long, default_value = "10")]
+        duration: u64,
+        /// A file mapping sensor MAC addresses to names, in the same format as mijia-homie's
+        /// `sensor_names.conf`.
+        #[structopt(long)]
+        names: Option<PathBuf>,
+    },
+    /// Connect to sensors and print a continuously updating table of their readings, until
+    /// interrupted.
+    Watch {
+        /// The MAC addresses of the sensors to watch. If none are given then all sensors found
+        /// within 10 seconds are watched.
+        mac_addresses: Vec<MacAddress>,
+        /// A file mapping sensor MAC addresses to names, in the same format as mijia-homie's
+        /// `sensor_names.conf`.
+        #[structopt(long)]
+        names: Option<PathBuf>,
+    },
+    /// Connect to a sensor step by step, timing each step, to help work out why connections to it
+    /// are failing.
+    Diagnose {
+        /// The MAC address of the sensor.
+        mac_address: MacAddress,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum HistoryCommand {
+    /// Delete all historical records stored on a sensor, to free up its memory.
+    Clear {
+        /// The MAC address of the sensor.
+        mac_address: MacAddress,
+        /// Delete the records without asking for confirmation first.
+        #[structopt(long)]
+        force: bool,
+        /// Download all records and save them to this file as JSON before deleting them. If the
+        /// sensor stores a new record in the meantime then nothing is deleted.
+        #[structopt(long)]
+        export: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum ClockCommand {
+    /// Print the time of a sensor's clock, and how far it has drifted from the host's clock.
+    Get {
+        /// The MAC address of the sensor.
+        mac_address: MacAddress,
+    },
+    /// Set a sensor's clock to the host's clock, printing how far it had drifted.
+    Set {
+        /// The MAC address of the sensor.
+        mac_address: MacAddress,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum ComfortCommand {
+    /// Print the comfort level thresholds of a sensor.
+    Get {
+        /// The MAC address of the sensor.
+        mac_address: MacAddress,
+    },
+    /// Set the comfort level thresholds of a sensor. Any thresholds not given are left unchanged.
+    Set {
+        /// The MAC address of the sensor.
+        mac_address: MacAddress,
+        /// The comfortable temperature range in ºC, e.g. "19..24".
+        #[structopt(long, parse(try_from_str = parse_range))]
+        temp: Option<(f32, f32)>,
+        /// The comfortable percent humidity range, e.g. "40..60".
+        #[structopt(long, parse(try_from_str = parse_range))]
+        humidity: Option<(u8, u8)>,
+    },
+}
+
+/// A format in which to print records.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum OutputFormat {
+    Csv,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "json" => Ok(Self::Json),
+            _ => Err(eyre!("Invalid output format '{}'", s)),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+enum UnitCommand {
+    /// Print the temperature unit of one or more sensors.
+    Get {
+        /// The MAC addresses of the sensors.
+        #[structopt(required = true)]
+        mac_addresses: Vec<MacAddress>,
+    },
+    /// Set the temperature unit of one or more sensors.
+    Set {
+        /// The temperature unit to use, either C or F.
+        unit: TemperatureUnit,
+        /// The MAC addresses of the sensors.
+        #[structopt(required = true)]
+        mac_addresses: Vec<MacAddress>,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Report> {
+    stable_eyre::install()?;
+    tracing_subscriber::fmt::init();
+
+    let Options { json, command } = Options::from_args();
+
+    let (_, session) = MijiaSession::new().await?;
+
+    match command {
+        Command::Read { mac_address } => read(&session, &mac_address, json).await,
+        Command::History {
+            command:
+                Some(HistoryCommand::Clear {
+                    mac_address,
+                    force,
+                    export,
+                }),
+            ..
+        } => history_clear(&session, &mac_address, force, export.as_deref(), json).await,
+        Command::History {
+            command: None,
+            mac_address: None,
+            ..
+        } => Err(eyre!("No sensor MAC address given.")),
+        Command::History {
+            command: None,
+            mac_address: Some(mac_address),
+            since,
+            format,
+            checkpoint,
+            chunk_size,
+            correct_time,
+        } => {
+            let format = if json { OutputFormat::Json } else { format };
+            match checkpoint {
+                Some(checkpoint) => {
+                    history_chunked(
+                        &session,
+                        &mac_address,
+                        since,
+                        format,
+                        correct_time,
+                        &checkpoint,
+                        chunk_size,
+                    )
+                    .await
+                }
+                None => history(&session, &mac_address, since, format, correct_time).await,
+            }
+        }
+        Command::Comfort(ComfortCommand::Get { mac_address }) => {
+            comfort_get(&session, &mac_address, json).await
+        }
+        Command::Comfort(ComfortCommand::Set {
+            mac_address,
+            temp,
+            humidity,
+        }) => comfort_set(&session, &mac_address, temp, humidity, json).await,
+        Command::Clock(ClockCommand::Get { mac_address }) => {
+            clock_get(&session, &mac_address, json).await
+        }
+        Command::Clock(ClockCommand::Set { mac_address }) => {
+            clock_set(&session, &mac_address, json).await
+        }
+        Command::Unit(UnitCommand::Get { mac_addresses }) => {
+            unit_get(&session, &mac_addresses, json).await
+        }
+        Command::Unit(UnitCommand::Set {
+            unit,
+            mac_addresses,
+        }) => unit_set(&session, unit, &mac_addresses, json).await,
+        Command::Scan { duration, names } => {
+            let names = match names {
+                Some(filename) => read_sensor_names(&filename)?,
+                None => HashMap::new(),
+            };
+            scan(&session, Duration::from_secs(duration), &names, json).await
+        }
+        Command::Watch {
+            mac_addresses,
+            names,
+        } => {
+            let names = match names {
+                Some(filename) => read_sensor_names(&filename)?,
+                None => HashMap::new(),
+            };
+            watch(&session, &mac_addresses, &names, json).await
+        }
+        Command::Diagnose { mac_address } => diagnose(&session, &mac_address, json).await,
+    }
+}
+
+/// Parse a range of the form "min..max".
+fn parse_range<T>(s: &str) -> Result<(T, T), Report>
+where
+    T: FromStr + PartialOrd + Display,
+    T::Err: Display,
+{
+    let parts: Vec<&str> = s.splitn(2, "..").collect();
+    if parts.len() != 2 {
+        return Err(eyre!("Invalid range '{}', expected 'min..max'", s));
+    }
+    let min: T = parts[0]
+        .trim()
+        .parse()
+        .map_err(|e| eyre!("Invalid range minimum '{}': {}", parts[0], e))?;
+    let max: T = parts[1]
+        .trim()
+        .parse()
+        .map_err(|e| eyre!("Invalid range maximum '{}': {}", parts[1], e))?;
+    if min > max {
+        return Err(eyre!(
+            "Range minimum {} is greater than maximum {}",
+            min,
+            max
+        ));
+    }
+    Ok((min, max))
+}
+
+/// Scan for the sensor with the given MAC address, and connect to it.
+async fn connect_sensor(
+    session: &MijiaSession,
+    mac_address: &MacAddress,
+) -> Result<SensorProps, Report> {
+    let sensor = find_sensor(session, mac_address).await?;
+    session.bt_session.connect(&sensor.id).await?;
+    Ok(sensor)
+}
+
+/// Scan for the sensor with the given MAC address until it is found or the scan times out.
+async fn find_sensor(
+    session: &MijiaSession,
+    mac_address: &MacAddress,
+) -> Result<SensorProps, Report> {
+    session.bt_session.start_discovery().await?;
+
+    let deadline = Instant::now() + SCAN_TIMEOUT;
+    let sensor = loop {
+        let sensors = session.get_sensors().await?;
+        if let Some(sensor) = sensors
+            .into_iter()
+            .find(|sensor| sensor.mac_address == *mac_address)
+        {
+            break sensor;
+        }
+        if Instant::now() > deadline {
+            return Err(eyre!("Sensor {} not found.", mac_address));
+        }
+        time::delay_for(SCAN_POLL_INTERVAL).await;
+    };
+    Ok(sensor)
+}
+
+/// Wait for the next set of readings from the given sensor on the given event stream.
+async fn wait_for_readings(
+    events: &mut (impl Stream<Item = MijiaEvent> + Unpin),
+    sensor: &SensorProps,
+) -> Result<Readings, Report> {
+    time::timeout(READINGS_TIMEOUT, async {
+        while let Some(event) = events.next().await {
+            if let MijiaEvent::Readings { id, readings, .. } = event {
+                if id == sensor.id {
+                    return Some(readings);
+                }
+            }
+        }
+        None
+    })
+    .await
+    .map_err(|_| {
+        eyre!(
+            "Timed out waiting for readings from {}.",
+            sensor.mac_address
+        )
+    })?
+    .ok_or_else(|| eyre!("Event stream ended unexpectedly."))
+}
+
+/// Connect to the given sensor, wait for a set of readings and print them.
+async fn read(session: &MijiaSession, mac_address: &MacAddress, json: bool) -> Result<(), Report> {
+    let sensor = connect_sensor(session, mac_address).await?;
+    let mut readings_stream = session.subscribe_readings(&sensor.id).await?;
+    let readings = time::timeout(READINGS_TIMEOUT, readings_stream.next())
+        .await
+        .map_err(|_| eyre!("Timed out waiting for readings from {}.", mac_address))?
+        .ok_or_else(|| eyre!("Readings stream ended unexpectedly."))?;
+    // Dropping the stream stops notifications.
+    drop(readings_stream);
+
+    if json {
+        println!("{}", readings_to_json(mac_address, &readings));
+    } else {
+        println!("Temperature: {:.2}ºC", readings.temperature);
+        println!("Humidity: {}%", readings.humidity);
+        println!(
+            "Battery: {} mV ({}%)",
+            readings.battery_voltage, readings.battery_percent
+        );
+    }
+
+    session.bt_session.disconnect(&sensor.id).await?;
+    Ok(())
+}
+
+fn readings_to_json(mac_address: &MacAddress, readings: &Readings) -> serde_json::Value {
+    json!({
+        "mac_address": mac_address.to_string(),
+        "temperature": readings.temperature,
+        "humidity": readings.humidity,
+        "battery_voltage": readings.battery_voltage,
+        "battery_percent": readings.battery_percent,
+    })
+}
+
+/// Connect to the given sensor, download all its historical records and print those since the given
+/// time in the given format, optionally correcting their times for the sensor's clock offset.
+async fn history(
+    session: &MijiaSession,
+    mac_address: &MacAddress,
+    since: Option<DateTime<Utc>>,
+    format: OutputFormat,
+    correct_time: bool,
+) -> Result<(), Report> {
+    let sensor = connect_sensor(session, mac_address).await?;
+    let clock_offset = get_clock_offset(session, &sensor, correct_time).await?;
+
+    let history = download_history(session, &sensor).await?;
+    print_history(history.into_iter().flatten(), clock_offset, since, format)?;
+
+    session.bt_session.disconnect(&sensor.id).await?;
+    Ok(())
+}
+
+/// Download all historical records from the given connected sensor, showing a progress bar.
+async fn download_history(
+    session: &MijiaSession,
+    sensor: &SensorProps,
+) -> Result<Vec<Option<HistoryRecord>>, Report> {
+    let progress = ProgressBar::new(0);
+    progress.set_style(
+        ProgressStyle::default_bar().template("{wide_bar} {pos}/{len} records, {eta} remaining"),
+    );
+    let history = session
+        .get_all_history_with_progress(&sensor.id, |history_progress| {
+            progress.set_length(history_progress.expected() as u64);
+            progress.set_position(history_progress.received as u64);
+        })
+        .await?;
+    progress.finish_and_clear();
+    Ok(history)
+}
+
+/// Connect to the given sensor and delete all its historical records, after asking for confirmation
+/// unless `force` is set. If an export file is given then the records are first downloaded and saved
+/// to it, and only deleted if no newer record has been stored since.
+async fn history_clear(
+    session: &MijiaSession,
+    mac_address: &MacAddress,
+    force: bool,
+    export: Option<&Path>,
+    json: bool,
+) -> Result<(), Report> {
+    let sensor = connect_sensor(session, mac_address).await?;
+    let history_range = session.get_history_range(&sensor.id).await?;
+    let question = format!(
+        "Delete {} records stored on {}?",
+        history_range.len(),
+        mac_address
+    );
+    let deleted = if history_range.is_empty() {
+        eprintln!("No records stored on {}.", mac_address);
+        0
+    } else if !force && !confirm(&question)? {
+        eprintln!("Not deleting records.");
+        0
+    } else {
+        let deleted = match export {
+            Some(path) => {
+                let records: Vec<HistoryRecord> = download_history(session, &sensor)
+                    .await?
+                    .into_iter()
+                    .flatten()
+                    .collect();
+                let records_json: Vec<_> = records.iter().map(record_to_json).collect();
+                std::fs::write(path, serde_json::to_vec_pretty(&records_json)?)?;
+                eprintln!("Saved {} records to {}.", records.len(), path.display());
+                match records.last() {
+                    Some(last) => session.delete_history_up_to(&sensor.id, last.index).await?,
+                    None => false,
+                }
+            }
+            None => {
+                session.delete_history(&sensor.id).await?;
+                true
+            }
+        };
+        if !deleted {
+            return Err(eyre!(
+                "Records changed on {} while exporting them, so none were deleted.",
+                mac_address
+            ));
+        }
+        history_range.len()
+    };
+
+    if json {
+        println!(
+            "{}",
+            json!({ "mac_address": mac_address.to_string(), "deleted": deleted })
+        );
+    } else if deleted > 0 {
+        println!("Deleted {} records from {}.", deleted, mac_address);
+    }
+
+    session.bt_session.disconnect(&sensor.id).await?;
+    Ok(())
+}
+
+/// Ask a yes/no question on the terminal, treating anything but yes as no.
+fn confirm(question: &str) -> Result<bool, Report> {
+    eprint!("{} [y/N] ", question);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Connect to the given sensor and download its history in chunks, saving a checkpoint to the given
+/// file after each chunk. If the file already exists then the download resumes from where it left
+/// off. Once all records have been downloaded they are printed and the checkpoint file is removed.
+async fn history_chunked(
+    session: &MijiaSession,
+    mac_address: &MacAddress,
+    since: Option<DateTime<Utc>>,
+    format: OutputFormat,
+    correct_time: bool,
+    checkpoint_path: &Path,
+    chunk_size: u32,
+) -> Result<(), Report> {
+    let mut checkpoint = if checkpoint_path.exists() {
+        let checkpoint = Checkpoint::load(checkpoint_path)?;
+        if checkpoint.mac_address != *mac_address {
+            return Err(eyre!(
+                "Checkpoint {} is for sensor {}, not {}.",
+                checkpoint_path.display(),
+                checkpoint.mac_address,
+                mac_address
+            ));
+        }
+        eprintln!(
+            "Resuming from record {} with {} records already downloaded.",
+            checkpoint.next_index,
+            checkpoint.records.len()
+        );
+        checkpoint
+    } else {
+        Checkpoint {
+            mac_address: *mac_address,
+            next_index: 0,
+            records: vec![],
+        }
+    };
+
+    let sensor = connect_sensor(session, mac_address).await?;
+    let clock_offset = get_clock_offset(session, &sensor, correct_time).await?;
+
+    let progress = ProgressBar::new_spinner();
+    progress.set_style(ProgressStyle::default_spinner().template("{spinner} {pos} records"));
+    progress.set_position(checkpoint.records.len() as u64);
+    let mut save_result = Ok(());
+    let result = session
+        .get_history_in_chunks(
+            &sensor.id,
+            checkpoint.next_index,
+            chunk_size,
+            |records, next_index| {
+                checkpoint.records.extend(records);
+                checkpoint.next_index = next_index;
+                progress.set_position(checkpoint.records.len() as u64);
+                if save_result.is_ok() {
+                    save_result = checkpoint.save(checkpoint_path);
+                }
+            },
+        )
+        .await;
+    progress.finish_and_clear();
+    save_result?;
+    result?;
+
+    print_history(checkpoint.records.into_iter(), clock_offset, since, format)?;
+    std::fs::remove_file(checkpoint_path)?;
+
+    session.bt_session.disconnect(&sensor.id).await?;
+    Ok(())
+}
+
+/// Measure the offset of the given sensor's clock if the times of records are to be corrected.
+async fn get_clock_offset(
+    session: &MijiaSession,
+    sensor: &SensorProps,
+    correct_time: bool,
+) -> Result<Option<ClockOffset>, Report> {
+    if !correct_time {
+        return Ok(None);
+    }
+    let clock_offset = session.get_clock_offset(&sensor.id).await?;
+    eprintln!("Sensor clock offset: {:?}", clock_offset);
+    Ok(Some(clock_offset))
+}
+
+/// Print the given history records from the given time onwards in the given format, correcting
+/// their times for the given clock offset if any.
+fn print_history(
+    records: impl Iterator<Item = HistoryRecord>,
+    clock_offset: Option<ClockOffset>,
+    since: Option<DateTime<Utc>>,
+    format: OutputFormat,
+) -> Result<(), Report> {
+    let since = since.map_or(SystemTime::UNIX_EPOCH, SystemTime::from);
+    let records = records
+        .map(|mut record| {
+            if let Some(clock_offset) = clock_offset {
+                record.correct_time(clock_offset);
+            }
+            record
+        })
+        .filter(|record| record.time >= since);
+    match format {
+        OutputFormat::Csv => {
+            println!("index,time,temperature_min,temperature_max,humidity_min,humidity_max");
+            for record in records {
+                println!("{}", record_to_csv(&record));
+            }
+        }
+        OutputFormat::Json => {
+            let records: Vec<_> = records.map(|record| record_to_json(&record)).collect();
+            println!("{}", serde_json::to_string_pretty(&records)?);
+        }
+    }
+    Ok(())
+}
+
+/// The progress of a chunked history download, saved after each chunk so it can be resumed.
+#[derive(Clone, Debug, PartialEq)]
+struct Checkpoint {
+    mac_address: MacAddress,
+    /// The index of the first record which hasn't been downloaded yet.
+    next_index: u32,
+    /// The records downloaded so far.
+    records: Vec<HistoryRecord>,
+}
+
+impl Checkpoint {
+    fn load(path: &Path) -> Result<Self, Report> {
+        let value: serde_json::Value = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        let invalid = || eyre!("Invalid checkpoint file {}", path.display());
+        let mac_address = value["mac_address"].as_str().ok_or_else(invalid)?.parse()?;
+        let next_index = value["next_index"].as_u64().ok_or_else(invalid)? as u32;
+        let records = value["records"]
+            .as_array()
+            .ok_or_else(invalid)?
+            .iter()
+            .map(|record| record_from_json(record).ok_or_else(invalid))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            mac_address,
+            next_index,
+            records,
+        })
+    }
+
+    /// Write the checkpoint to a temporary file and then move it into place, so that the previous
+    /// checkpoint is kept if writing is interrupted.
+    fn save(&self, path: &Path) -> Result<(), Report> {
+        let records: Vec<_> = self.records.iter().map(record_to_json).collect();
+        let value = json!({
+            "mac_address": self.mac_address.to_string(),
+            "next_index": self.next_index,
+            "records": records,
+        });
+        let temporary_path = path.with_extension("tmp");
+        std::fs::write(&temporary_path, serde_json::to_vec(&value)?)?;
+        std::fs::rename(temporary_path, path)?;
+        Ok(())
+    }
+}
+
+fn record_to_csv(record: &HistoryRecord) -> String {
+    format!(
+        "{},{},{:.1},{:.1},{},{}",
+        record.index,
+        DateTime::<Utc>::from(record.time).to_rfc3339(),
+        record.temperature_min,
+        record.temperature_max,
+        record.humidity_min,
+        record.humidity_max
+    )
+}
+
+fn record_to_json(record: &HistoryRecord) -> serde_json::Value {
+    json!({
+        "index": record.index,
+        "time": DateTime::<Utc>::from(record.time).to_rfc3339(),
+        "raw_time": DateTime::<Utc>::from(record.raw_time).to_rfc3339(),
+        "temperature_min": record.temperature_min,
+        "temperature_max": record.temperature_max,
+        "humidity_min": record.humidity_min,
+        "humidity_max": record.humidity_max,
+    })
+}
+
+/// Parse a record in the format produced by `record_to_json`.
+fn record_from_json(value: &serde_json::Value) -> Option<HistoryRecord> {
+    let time = DateTime::parse_from_rfc3339(value["time"].as_str()?)
+        .ok()?
+        .into();
+    Some(HistoryRecord {
+        index: value["index"].as_u64()? as u32,
+        time,
+        raw_time: match value["raw_time"].as_str() {
+            Some(raw_time) => DateTime::parse_from_rfc3339(raw_time).ok()?.into(),
+            None => time,
+        },
+        temperature_min: value["temperature_min"].as_f64()? as f32,
+        temperature_max: value["temperature_max"].as_f64()? as f32,
+        humidity_min: value["humidity_min"].as_u64()? as u8,
+        humidity_max: value["humidity_max"].as_u64()? as u8,
+    })
+}
+
+/// Connect to the given sensor and print its comfort level thresholds.
+async fn comfort_get(
+    session: &MijiaSession,
+    mac_address: &MacAddress,
+    json: bool,
+) -> Result<(), Report> {
+    let sensor = connect_sensor(session, mac_address).await?;
+    let comfort_level = session.get_comfort_level(&sensor.id).await?;
+    print_comfort_level(mac_address, &comfort_level, json);
+    session.bt_session.disconnect(&sensor.id).await?;
+    Ok(())
+}
+
+/// Connect to the given sensor and update whichever of its comfort level thresholds are given.
+async fn comfort_set(
+    session: &MijiaSession,
+    mac_address: &MacAddress,
+    temperature: Option<(f32, f32)>,
+    humidity: Option<(u8, u8)>,
+    json: bool,
+) -> Result<(), Report> {
+    let sensor = connect_sensor(session, mac_address).await?;
+    let old_comfort_level = session.get_comfort_level(&sensor.id).await?;
+    let (temperature_min, temperature_max) = temperature.unwrap_or((
+        old_comfort_level.temperature_min,
+        old_comfort_level.temperature_max,
+    ));
+    let (humidity_min, humidity_max) = humidity.unwrap_or((
+        old_comfort_level.humidity_min,
+        old_comfort_level.humidity_max,
+    ));
+    let comfort_level =
+        ComfortLevel::new(temperature_min, temperature_max, humidity_min, humidity_max)?;
+    session
+        .set_comfort_level(&sensor.id, &comfort_level)
+        .await?;
+    print_comfort_level(mac_address, &comfort_level, json);
+    session.bt_session.disconnect(&sensor.id).await?;
+    Ok(())
+}
+
+fn print_comfort_level(mac_address: &MacAddress, comfort_level: &ComfortLevel, json: bool) {
+    if json {
+        println!(
+            "{}",
+            json!({
+                "mac_address": mac_address.to_string(),
+                "temperature_min": comfort_level.temperature_min,
+                "temperature_max": comfort_level.temperature_max,
+                "humidity_min": comfort_level.humidity_min,
+                "humidity_max": comfort_level.humidity_max,
+            })
+        );
+    } else {
+        println!("{}", comfort_level);
+    }
+}
+
+/// Connect to the given sensor and print its clock time and drift.
+async fn clock_get(
+    session: &MijiaSession,
+    mac_address: &MacAddress,
+    json: bool,
+) -> Result<(), Report> {
+    let sensor = connect_sensor(session, mac_address).await?;
+    let (sensor_time, drift) = get_clock(session, &sensor).await?;
+    if json {
+        println!(
+            "{}",
+            json!({
+                "mac_address": mac_address.to_string(),
+                "sensor_time": sensor_time.to_rfc3339(),
+                "drift": drift.num_seconds(),
+            })
+        );
+    } else {
+        print_clock(sensor_time, drift);
+    }
+    session.bt_session.disconnect(&sensor.id).await?;
+    Ok(())
+}
+
+/// Connect to the given sensor, print its clock time and drift, then set it to the host's time.
+async fn clock_set(
+    session: &MijiaSession,
+    mac_address: &MacAddress,
+    json: bool,
+) -> Result<(), Report> {
+    let sensor = connect_sensor(session, mac_address).await?;
+    let (sensor_time, drift) = get_clock(session, &sensor).await?;
+    if !json {
+        print_clock(sensor_time, drift);
+    }
+    let now = SystemTime::now();
+    session.set_time(&sensor.id, now).await?;
+    if json {
+        println!(
+            "{}",
+            json!({
+                "mac_address": mac_address.to_string(),
+                "sensor_time": sensor_time.to_rfc3339(),
+                "drift": drift.num_seconds(),
+                "set_time": DateTime::<Utc>::from(now).to_rfc3339(),
+            })
+        );
+    } else {
+        println!("Set time to {}", DateTime::<Utc>::from(now));
+    }
+    session.bt_session.disconnect(&sensor.id).await?;
+    Ok(())
+}
+
+/// Get the time of the given sensor's clock, and how far ahead of the host's clock it is.
+async fn get_clock(
+    session: &MijiaSession,
+    sensor: &SensorProps,
+) -> Result<(DateTime<Utc>, chrono::Duration), Report> {
+    let sensor_time: DateTime<Utc> = session.get_time(&sensor.id).await?.into();
+    let drift = sensor_time - Utc::now();
+    Ok((sensor_time, drift))
+}
+
+fn print_clock(sensor_time: DateTime<Utc>, drift: chrono::Duration) {
+    println!(
+        "Sensor time: {} (drift {:+}s)",
+        sensor_time,
+        drift.num_seconds()
+    );
+}
+
+/// Connect to each of the given sensors in turn and print its temperature unit.
+async fn unit_get(
+    session: &MijiaSession,
+    mac_addresses: &[MacAddress],
+    json: bool,
+) -> Result<(), Report> {
+    let mut units = vec![];
+    for mac_address in mac_addresses {
+        let sensor = connect_sensor(session, mac_address).await?;
+        let unit = session.get_temperature_unit(&sensor.id).await?;
+        if !json {
+            println!("{}: {}", mac_address, unit);
+        }
+        units.push(unit_to_json(mac_address, unit));
+        session.bt_session.disconnect(&sensor.id).await?;
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&units)?);
+    }
+    Ok(())
+}
+
+/// Connect to each of the given sensors in turn and set its temperature unit.
+async fn unit_set(
+    session: &MijiaSession,
+    unit: TemperatureUnit,
+    mac_addresses: &[MacAddress],
+    json: bool,
+) -> Result<(), Report> {
+    let mut units = vec![];
+    for mac_address in mac_addresses {
+        let sensor = connect_sensor(session, mac_address).await?;
+        session.set_temperature_unit(&sensor.id, unit).await?;
+        if !json {
+            println!("{}: {}", mac_address, unit);
+        }
+        units.push(unit_to_json(mac_address, unit));
+        session.bt_session.disconnect(&sensor.id).await?;
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&units)?);
+    }
+    Ok(())
+}
+
+fn unit_to_json(mac_address: &MacAddress, unit: TemperatureUnit) -> serde_json::Value {
+    json!({
+        "mac_address": mac_address.to_string(),
+        "unit": unit.to_string(),
+    })
+}
+
+/// Scan for sensors for the given duration, then print a table of all those found.
+async fn scan(
+    session: &MijiaSession,
+    duration: Duration,
+    names: &HashMap<MacAddress, String>,
+    json: bool,
+) -> Result<(), Report> {
+    // Sensors which BlueZ already knew about before we started scanning, e.g. because they have
+    // been connected before.
+    let known: HashSet<MacAddress> = session
+        .get_sensors()
+        .await?
+        .into_iter()
+        .map(|sensor| sensor.mac_address)
+        .collect();
+
+    session.bt_session.start_discovery().await?;
+    time::delay_for(duration).await;
+
+    let mut sensors = session.get_sensors().await?;
+    sensors.sort_by_key(|sensor| sensor.mac_address);
+    if json {
+        let sensors: Vec<_> = sensors
+            .iter()
+            .map(|sensor| {
+                json!({
+                    "mac_address": sensor.mac_address.to_string(),
+                    "name": names.get(&sensor.mac_address),
+                    "rssi": sensor.rssi,
+                    "known": known.contains(&sensor.mac_address),
+                    "connected": sensor.connected,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&sensors)?);
+        return Ok(());
+    }
+    println!(
+        "{:<17}  {:<20}  {:>9}  {:<5}  {:<9}",
+        "MAC address", "Name", "RSSI", "Known", "Connected"
+    );
+    for sensor in sensors {
+        let rssi = sensor
+            .rssi
+            .map_or_else(|| "-".to_string(), |rssi| format!("{} dBm", rssi));
+        println!(
+            "{:<17}  {:<20}  {:>9}  {:<5}  {:<9}",
+            sensor.mac_address,
+            names.get(&sensor.mac_address).map_or("-", String::as_str),
+            rssi,
+            yes_no(known.contains(&sensor.mac_address)),
+            yes_no(sensor.connected)
+        );
+    }
+    Ok(())
+}
+
+/// Connect to the given sensors, or to all sensors found by scanning if none are given, and keep
+/// printing a table of their latest readings, signal strength and how long ago the readings were
+/// received. In JSON mode each set of readings is instead printed as a line of JSON as it arrives.
+async fn watch(
+    session: &MijiaSession,
+    mac_addresses: &[MacAddress],
+    names: &HashMap<MacAddress, String>,
+    json: bool,
+) -> Result<(), Report> {
+    let (_msg_match, mut events) = session.event_stream().await?;
+
+    let mac_addresses = if mac_addresses.is_empty() {
+        session.bt_session.start_discovery().await?;
+        time::delay_for(WATCH_SCAN_DURATION).await;
+        let mut sensors = session.get_sensors().await?;
+        sensors.sort_by_key(|sensor| sensor.mac_address);
+        sensors
+            .into_iter()
+            .map(|sensor| sensor.mac_address)
+            .collect()
+    } else {
+        mac_addresses.to_vec()
+    };
+    if mac_addresses.is_empty() {
+        return Err(eyre!("No sensors found."));
+    }
+
+    // Sensors which can't be connected to are still watched, as their readings may be decoded from
+    // their advertisements while discovery is running.
+    for mac_address in &mac_addresses {
+        let result = match connect_sensor(session, mac_address).await {
+            Ok(sensor) => session
+                .start_notify_sensor(&sensor.id)
+                .await
+                .map_err(Report::from),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            eprintln!("Failed to connect to {}: {}", mac_address, e);
+        }
+    }
+
+    let mut latest: HashMap<MacAddress, (Readings, Instant)> = HashMap::new();
+    let mut next_redraw = Instant::now();
+    loop {
+        if Instant::now() >= next_redraw {
+            if !json {
+                let sensors: HashMap<MacAddress, SensorProps> = session
+                    .get_sensors()
+                    .await?
+                    .into_iter()
+                    .map(|sensor| (sensor.mac_address, sensor))
+                    .collect();
+                print_watch_table(&mac_addresses, &sensors, &latest, names);
+            }
+            next_redraw = Instant::now() + WATCH_REDRAW_INTERVAL;
+        }
+
+        match time::timeout(
+            next_redraw.saturating_duration_since(Instant::now()),
+            events.next(),
+        )
+        .await
+        {
+            Ok(Some(MijiaEvent::Readings {
+                mac_address,
+                readings,
+                time: received,
+                ..
+            })) => {
+                if mac_addresses.contains(&mac_address) {
+                    if json {
+                        let mut value = readings_to_json(&mac_address, &readings);
+                        value["time"] = json!(DateTime::<Utc>::from(received).to_rfc3339());
+                        println!("{}", value);
+                    }
+                    latest.insert(mac_address, (readings, Instant::now()));
+                }
+            }
+            Ok(Some(_)) | Err(_) => {}
+            Ok(None) => return Err(eyre!("Event stream ended unexpectedly.")),
+        }
+    }
+}
+
+/// Clear the terminal and print a table of the latest readings of the given sensors.
+fn print_watch_table(
+    mac_addresses: &[MacAddress],
+    sensors: &HashMap<MacAddress, SensorProps>,
+    latest: &HashMap<MacAddress, (Readings, Instant)>,
+    names: &HashMap<MacAddress, String>,
+) {
+    // Clear the screen and move the cursor to the top left.
+    print!("\x1B[2J\x1B[H");
+    println!(
+        "{:<17}  {:<20}  {:>11}  {:>8}  {:>14}  {:>9}  {:<9}  {:>7}",
+        "MAC address", "Name", "Temperature", "Humidity", "Battery", "RSSI", "Connected", "Updated"
+    );
+    for mac_address in mac_addresses {
+        let sensor = sensors.get(mac_address);
+        let rssi = sensor
+            .and_then(|sensor| sensor.rssi)
+            .map_or_else(|| "-".to_string(), |rssi| format!("{} dBm", rssi));
+        let (temperature, humidity, battery, updated) = match latest.get(mac_address) {
+            Some((readings, time)) => (
+                format!("{:.2}ºC", readings.temperature),
+                format!("{}%", readings.humidity),
+                format!(
+                    "{} mV ({}%)",
+                    readings.battery_voltage, readings.battery_percent
+                ),
+                format!("{}s ago", time.elapsed().as_secs()),
+            ),
+            None => (
+                "-".to_string(),
+                "-".to_string(),
+                "-".to_string(),
+                "never".to_string(),
+            ),
+        };
+        println!(
+            "{:<17}  {:<20}  {:>11}  {:>8}  {:>14}  {:>9}  {:<9}  {:>7}",
+            mac_address,
+            names.get(mac_address).map_or("-", String::as_str),
+            temperature,
+            humidity,
+            battery,
+            rssi,
+            yes_no(sensor.is_some_and(|sensor| sensor.connected)),
+            updated
+        );
+    }
+}
+
+/// The outcome of one step of connecting to a sensor while diagnosing it.
+struct DiagnosticStep {
+    name: &'static str,
+    duration: Duration,
+    error: Option<String>,
+    /// What a failure of this step most likely means.
+    hint: &'static str,
+}
+
+/// The steps carried out so far while diagnosing a sensor.
+#[derive(Default)]
+struct Diagnostics {
+    steps: Vec<DiagnosticStep>,
+}
+
+impl Diagnostics {
+    /// Run the given step, recording how long it took and whether it failed. Returns its result if
+    /// it succeeded.
+    async fn run<T>(
+        &mut self,
+        name: &'static str,
+        hint: &'static str,
+        step: impl Future<Output = Result<T, Report>>,
+    ) -> Option<T> {
+        let start = Instant::now();
+        let result = step.await;
+        self.steps.push(DiagnosticStep {
+            name,
+            duration: start.elapsed(),
+            error: result.as_ref().err().map(ToString::to_string),
+            hint,
+        });
+        result.ok()
+    }
+}
+
+/// Scan for, connect to, discover the services of, read from and subscribe to notifications from the
+/// given sensor, stopping at the first step which fails, and print how long each step took along
+/// with the sensor's signal strength and adapter.
+async fn diagnose(
+    session: &MijiaSession,
+    mac_address: &MacAddress,
+    json: bool,
+) -> Result<(), Report> {
+    let (msg_match, mut events) = session.event_stream().await?;
+    let mut diagnostics = Diagnostics::default();
+
+    let sensor = diagnostics
+        .run(
+            "discover",
+            "The sensor may be out of range, its battery may be flat, or the adapter may be off.",
+            find_sensor(session, mac_address),
+        )
+        .await;
+    if let Some(sensor) = &sensor {
+        diagnose_connection(session, sensor, &mut events, &mut diagnostics).await;
+        // The sensor may not be connected if an earlier step failed.
+        let _ = session.bt_session.disconnect(&sensor.id).await;
+    }
+    session
+        .bt_session
+        .connection()
+        .remove_match(msg_match.token())
+        .await?;
+
+    print_diagnostics(mac_address, sensor.as_ref(), &diagnostics, json);
+    Ok(())
+}
+
+/// Carry out the steps of diagnosing the given sensor after it has been found, until one fails.
+async fn diagnose_connection(
+    session: &MijiaSession,
+    sensor: &SensorProps,
+    events: &mut (impl Stream<Item = MijiaEvent> + Unpin),
+    diagnostics: &mut Diagnostics,
+) {
+    let id = &sensor.id;
+    if diagnostics
+        .run(
+            "connect",
+            "The signal may be too weak, or the adapter may have too many connections.",
+            async { session.bt_session.connect(id).await.map_err(Report::from) },
+        )
+        .await
+        .is_none()
+    {
+        return;
+    }
+    if diagnostics
+        .run(
+            "resolve services",
+            "BlueZ may be in a bad state; try restarting it or removing the device.",
+            wait_for_services(session, sensor),
+        )
+        .await
+        .is_none()
+    {
+        return;
+    }
+    if diagnostics
+        .run(
+            "read characteristic",
+            "The sensor may be running unsupported firmware.",
+            async { session.get_temperature_unit(id).await.map_err(Report::from) },
+        )
+        .await
+        .is_none()
+    {
+        return;
+    }
+    diagnostics
+        .run(
+            "notify",
+            "The sensor may be running unsupported firmware, or its battery may be low.",
+            async {
+                session.start_notify_sensor(id).await?;
+                wait_for_readings(events, sensor).await
+            },
+        )
+        .await;
+}
+
+/// Wait until BlueZ has discovered the services of the given connected sensor.
+async fn wait_for_services(session: &MijiaSession, sensor: &SensorProps) -> Result<(), Report> {
+    let deadline = Instant::now() + SERVICES_RESOLVED_TIMEOUT;
+    while !session.bt_session.is_services_resolved(&sensor.id).await? {
+        if Instant::now() > deadline {
+            return Err(eyre!("Timed out waiting for services to be resolved."));
+        }
+        time::delay_for(SERVICES_RESOLVED_POLL_INTERVAL).await;
+    }
+    Ok(())
+}
+
+/// Print the outcome of each diagnostic step, along with a hint about the likely cause of the first
+/// failure if any.
+fn print_diagnostics(
+    mac_address: &MacAddress,
+    sensor: Option<&SensorProps>,
+    diagnostics: &Diagnostics,
+    json: bool,
+) {
+    let rssi = sensor.and_then(|sensor| sensor.rssi);
+    let failed = diagnostics.steps.iter().find(|step| step.error.is_some());
+    let hint = match (failed, rssi) {
+        (Some(step), _) => Some(step.hint),
+        (None, Some(rssi)) if rssi < WEAK_RSSI => {
+            Some("The signal is weak, so the connection may be unreliable.")
+        }
+        (None, _) => None,
+    };
+
+    if json {
+        let steps: Vec<_> = diagnostics
+            .steps
+            .iter()
+            .map(|step| {
+                json!({
+                    "name": step.name,
+                    "ok": step.error.is_none(),
+                    "seconds": step.duration.as_secs_f64(),
+                    "error": step.error,
+                })
+            })
+            .collect();
+        println!(
+            "{}",
+            json!({
+                "mac_address": mac_address.to_string(),
+                "adapter": sensor.map(|sensor| sensor.id.adapter().to_string()),
+                "rssi": rssi,
+                "steps": steps,
+                "hint": hint,
+            })
+        );
+        return;
+    }
+
+    if let Some(sensor) = sensor {
+        println!("Adapter: {}", sensor.id.adapter());
+    }
+    println!(
+        "RSSI: {}",
+        rssi.map_or_else(|| "-".to_string(), |rssi| format!("{} dBm", rssi))
+    );
+    for step in &diagnostics.steps {
+        println!(
+            "{:<19}  {:<6}  {:>6.2}s  {}",
+            step.name,
+            if step.error.is_some() { "failed" } else { "ok" },
+            step.duration.as_secs_f64(),
+            step.error.as_deref().unwrap_or("")
+        );
+    }
+    if let Some(hint) = hint {
+        println!("{}", hint);
+    }
+}
+
+/// Read a file of lines of the form "MAC=name" into a map, ignoring lines starting with '#'.
+fn read_sensor_names(filename: &Path) -> Result<HashMap<MacAddress, String>, Report> {
+    let mut names = HashMap::new();
+    let file =
+        File::open(filename).map_err(|e| eyre!("Failed to open {}: {}", filename.display(), e))?;
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if !line.is_empty() && !line.starts_with('#') {
+            let parts: Vec<&str> = line.splitn(2, '=').collect();
+            if parts.len() != 2 {
+                return Err(eyre!("Invalid line '{}'", line));
+            }
+            names.insert(parts[0].parse()?, parts[1].to_string());
+        }
+    }
+    Ok(names)
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_range_valid() {
+        assert_eq!(parse_range::<f32>("19..24.5").unwrap(), (19.0, 24.5));
+        assert_eq!(parse_range::<u8>("40..60").unwrap(), (40, 60));
+        assert_eq!(parse_range::<f32>("-5..-1").unwrap(), (-5.0, -1.0));
+    }
+
+    #[test]
+    fn parse_range_invalid() {
+        assert!(parse_range::<u8>("40").is_err());
+        assert!(parse_range::<u8>("40..").is_err());
+        assert!(parse_range::<u8>("60..40").is_err());
+        assert!(parse_range::<u8>("40..300").is_err());
+    }
+
+    #[test]
+    fn checkpoint_round_trip() {
+        let checkpoint = Checkpoint {
+            mac_address: "A4:C1:38:D7:21:17".parse().unwrap(),
+            next_index: 200,
+            records: vec![HistoryRecord {
+                index: 150,
+                time: SystemTime::UNIX_EPOCH + Duration::from_secs(1607000000),
+                raw_time: SystemTime::UNIX_EPOCH + Duration::from_secs(1607000030),
+                temperature_min: 19.5,
+                temperature_max: 21.25,
+                humidity_min: 40,
+                humidity_max: 55,
+            }],
+        };
+        let path = std::env::temp_dir().join("mijia-cli-checkpoint-test.json");
+        checkpoint.save(&path).unwrap();
+        assert_eq!(Checkpoint::load(&path).unwrap(), checkpoint);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,128 @@
+//! Choosing which Bluetooth adapter to connect to each sensor through, when more than one of them
+//! can hear it, or restricting the bridge to a single adapter.
+
+use mijia::bluetooth::AdapterInfo;
+use mijia::{MacAddress, SensorProps};
+use std::collections::HashMap;
+
+/// Given the sensors found by a scan, which may include the same sensor several times if it was
+/// discovered on several adapters, pick the one for each sensor with the strongest signal. Sensors
+/// with no RSSI are treated as having the weakest signal.
+pub fn strongest_signal(sensors: Vec<SensorProps>) -> Vec<SensorProps> {
+    let mut best: HashMap<MacAddress, SensorProps> = HashMap::new();
+    for props in sensors {
+        match best.get(&props.mac_address) {
+            Some(existing) if existing.rssi >= props.rssi => {}
+            _ => {
+                best.insert(props.mac_address, props);
+            }
+        }
+    }
+    best.values().cloned().collect()
+}
+
+/// Find the adapter with the given name (such as `hci0`) or MAC address.
+pub fn find_adapter<'a>(adapters: &'a [AdapterInfo], name: &str) -> Option<&'a AdapterInfo> {
+    let mac_address: Option<MacAddress> = name.parse().ok();
+    adapters.iter().find(|adapter| {
+        adapter.id.to_string() == name || Some(&adapter.mac_address) == mac_address.as_ref()
+    })
+}
+
+/// Derive a Homie device ID for a bridge which only uses the given adapter, by appending the
+/// adapter's MAC address to the given base ID. This stays the same even if the adapters are
+/// enumerated in a different order after a reboot.
+pub fn device_id_for_adapter(base: &str, adapter: &AdapterInfo) -> String {
+    format!(
+        "{}-{}",
+        base,
+        adapter
+            .mac_address
+            .to_string()
+            .replace(':', "")
+            .to_lowercase()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mijia::{AdapterId, DeviceId};
+
+    fn make_props(object_path: &str, mac_address: &str, rssi: Option<i16>) -> SensorProps {
+        SensorProps {
+            id: DeviceId::new(object_path),
+            mac_address: mac_address.parse().unwrap(),
+            rssi,
+            connected: false,
+        }
+    }
+
+    #[test]
+    fn picks_strongest_adapter() {
+        let mut sensors = strongest_signal(vec![
+            make_props(
+                "/org/bluez/hci0/dev_A4_C1_38_00_00_01",
+                "A4:C1:38:00:00:01",
+                Some(-80),
+            ),
+            make_props(
+                "/org/bluez/hci1/dev_A4_C1_38_00_00_01",
+                "A4:C1:38:00:00:01",
+                Some(-60),
+            ),
+            make_props(
+                "/org/bluez/hci2/dev_A4_C1_38_00_00_01",
+                "A4:C1:38:00:00:01",
+                None,
+            ),
+            make_props(
+                "/org/bluez/hci0/dev_A4_C1_38_00_00_02",
+                "A4:C1:38:00:00:02",
+                None,
+            ),
+        ]);
+        sensors.sort_by_key(|sensor| sensor.mac_address);
+
+        assert_eq!(sensors.len(), 2);
+        assert_eq!(
+            sensors[0].id,
+            DeviceId::new("/org/bluez/hci1/dev_A4_C1_38_00_00_01")
+        );
+        assert_eq!(
+            sensors[1].id,
+            DeviceId::new("/org/bluez/hci0/dev_A4_C1_38_00_00_02")
+        );
+    }
+    fn make_adapter(object_path: &str, mac_address: &str) -> AdapterInfo {
+        AdapterInfo {
+            id: AdapterId::new(object_path),
+            mac_address: mac_address.parse().unwrap(),
+            powered: true,
+            discovering: false,
+        }
+    }
+
+    #[test]
+    fn find_adapter_by_name_or_mac_address() {
+        let adapters = vec![
+            make_adapter("/org/bluez/hci0", "00:1A:7D:DA:71:01"),
+            make_adapter("/org/bluez/hci1", "00:1A:7D:DA:71:02"),
+        ];
+        assert_eq!(find_adapter(&adapters, "hci1"), Some(&adapters[1]));
+        assert_eq!(
+            find_adapter(&adapters, "00:1a:7d:da:71:01"),
+            Some(&adapters[0])
+        );
+        assert_eq!(find_adapter(&adapters, "hci2"), None);
+    }
+
+    #[test]
+    fn derive_device_id() {
+        let adapter = make_adapter("/org/bluez/hci1", "00:1A:7D:DA:71:02");
+        assert_eq!(
+            device_id_for_adapter("mijia-bridge", &adapter),
+            "mijia-bridge-001a7dda7102"
+        );
+    }
+}
@@ -0,0 +1,193 @@
+//! Aggregating the readings from each sensor over a window of time before publishing them, to
+//! smooth out the jitter between individual readings.
+
+use mijia::Readings;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// How to combine the readings within each window.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AggregationMethod {
+    /// The mean of each value.
+    Mean,
+    /// The median of each value. If there are an even number of readings the higher of the two
+    /// middle values is used.
+    Median,
+}
+
+/// An error parsing an `AggregationMethod` from a string.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParseAggregationMethodError(String);
+
+impl Display for ParseAggregationMethodError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Invalid aggregation method '{}', expected 'mean' or 'median'",
+            self.0
+        )
+    }
+}
+
+impl Error for ParseAggregationMethodError {}
+
+impl FromStr for AggregationMethod {
+    type Err = ParseAggregationMethodError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mean" | "average" => Ok(Self::Mean),
+            "median" => Ok(Self::Median),
+            _ => Err(ParseAggregationMethodError(s.to_owned())),
+        }
+    }
+}
+
+/// Configuration for aggregating readings.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Aggregation {
+    /// How long to accumulate readings from a sensor before publishing their aggregate.
+    pub window: Duration,
+    pub method: AggregationMethod,
+}
+
+/// The readings received from a single sensor within the current window.
+#[derive(Clone, Debug, Default)]
+pub struct ReadingsWindow {
+    start: Option<Instant>,
+    readings: Vec<Readings>,
+}
+
+impl ReadingsWindow {
+    /// Add the given readings to the window. If the window has been open for at least the configured
+    /// duration, returns the aggregate of all the readings in it and starts a new window.
+    pub fn add(
+        &mut self,
+        aggregation: &Aggregation,
+        readings: Readings,
+        now: Instant,
+    ) -> Option<Readings> {
+        let start = *self.start.get_or_insert(now);
+        self.readings.push(readings);
+        if now.duration_since(start) < aggregation.window {
+            return None;
+        }
+
+        let aggregate = match aggregation.method {
+            AggregationMethod::Mean => mean(&self.readings),
+            AggregationMethod::Median => median(&self.readings),
+        };
+        self.start = None;
+        self.readings.clear();
+        Some(aggregate)
+    }
+}
+
+fn mean(readings: &[Readings]) -> Readings {
+    let count = readings.len() as f32;
+    let mean_of = |value: fn(&Readings) -> f32| readings.iter().map(value).sum::<f32>() / count;
+    Readings {
+        temperature: mean_of(|r| r.temperature),
+        humidity: mean_of(|r| r.humidity.into()).round() as u8,
+        battery_voltage: mean_of(|r| r.battery_voltage.into()).round() as u16,
+        battery_percent: mean_of(|r| r.battery_percent.into()).round() as u16,
+    }
+}
+
+fn median(readings: &[Readings]) -> Readings {
+    let mut temperatures: Vec<f32> = readings.iter().map(|r| r.temperature).collect();
+    temperatures.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mut humidities: Vec<u8> = readings.iter().map(|r| r.humidity).collect();
+    humidities.sort_unstable();
+    let mut battery_voltages: Vec<u16> = readings.iter().map(|r| r.battery_voltage).collect();
+    battery_voltages.sort_unstable();
+    let mut battery_percents: Vec<u16> = readings.iter().map(|r| r.battery_percent).collect();
+    battery_percents.sort_unstable();
+
+    let middle = readings.len() / 2;
+    Readings {
+        temperature: temperatures[middle],
+        humidity: humidities[middle],
+        battery_voltage: battery_voltages[middle],
+        battery_percent: battery_percents[middle],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mijia::test_utils::readings;
+
+    #[test]
+    fn parse_method() {
+        assert_eq!("mean".parse(), Ok(AggregationMethod::Mean));
+        assert_eq!("Median".parse(), Ok(AggregationMethod::Median));
+        assert_eq!(
+            "mode".parse::<AggregationMethod>(),
+            Err(ParseAggregationMethodError("mode".to_owned()))
+        );
+    }
+
+    #[test]
+    fn mean_of_window() {
+        let aggregation = Aggregation {
+            window: Duration::from_secs(60),
+            method: AggregationMethod::Mean,
+        };
+        let mut window = ReadingsWindow::default();
+        let start = Instant::now();
+
+        assert_eq!(
+            window.add(&aggregation, readings(20.0, 50, 3000), start),
+            None
+        );
+        assert_eq!(
+            window.add(
+                &aggregation,
+                readings(21.0, 53, 3000),
+                start + Duration::from_secs(30)
+            ),
+            None
+        );
+        assert_eq!(
+            window.add(
+                &aggregation,
+                readings(22.0, 54, 3000),
+                start + Duration::from_secs(60)
+            ),
+            Some(readings(21.0, 52, 3000))
+        );
+        // A new window has started.
+        assert_eq!(
+            window.add(
+                &aggregation,
+                readings(22.0, 54, 3000),
+                start + Duration::from_secs(61)
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn median_of_window() {
+        let aggregation = Aggregation {
+            window: Duration::from_secs(60),
+            method: AggregationMethod::Median,
+        };
+        let mut window = ReadingsWindow::default();
+        let start = Instant::now();
+
+        window.add(&aggregation, readings(20.0, 50, 3000), start);
+        window.add(&aggregation, readings(30.0, 40, 3000), start);
+        assert_eq!(
+            window.add(
+                &aggregation,
+                readings(21.0, 45, 3000),
+                start + Duration::from_secs(60)
+            ),
+            Some(readings(21.0, 45, 3000))
+        );
+    }
+}
@@ -0,0 +1,335 @@
+//! Sending readings and history records to Azure IoT Hub as device-to-cloud messages, using the
+//! hub's MQTT dialect rather than going through an intermediate broker.
+
+use crate::messages::{compact_mac_address, sensor_name, HistoryMessage, ReadingsMessage};
+use crate::SensorState;
+use hmac::{Hmac, Mac, NewMac};
+use mijia::{MacAddress, MijiaEvent};
+use rumqttc::{
+    certs, pkcs8_private_keys, rsa_private_keys, AsyncClient, ClientConfig, MqttOptions, QoS,
+};
+use serde::Serialize;
+use sha2::Sha256;
+use stable_eyre::eyre::{self, bail};
+use std::collections::HashMap;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::RecvError;
+use tokio::sync::Mutex;
+use tokio::time::delay_for;
+
+/// The port for MQTT over TLS, which is the only one IoT Hub accepts for MQTT.
+const PORT: u16 = 8883;
+/// The version of the IoT Hub API which the username requests.
+const API_VERSION: &str = "2018-06-30";
+/// How long SAS tokens are valid for, if `AZURE_IOT_SAS_TTL` isn't set.
+pub const DEFAULT_SAS_TTL: Duration = Duration::from_secs(60 * 60);
+/// How long to wait before reconnecting after the connection fails.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+/// How many requests to queue for the MQTT client before publishing blocks.
+const REQUESTS_CAP: usize = 100;
+
+/// How the bridge authenticates to IoT Hub as its device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Authentication {
+    /// Generate shared access signature tokens from the device's base64-encoded symmetric key.
+    SharedAccessKey(String),
+    /// Use an X.509 client certificate and its private key, both PEM-encoded.
+    X509 {
+        certificate: Vec<u8>,
+        private_key: Vec<u8>,
+    },
+}
+
+/// Configuration for sending messages to Azure IoT Hub.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AzureIot {
+    /// The hostname of the hub, such as `my-hub.azure-devices.net`.
+    pub hostname: String,
+    /// The ID of the device which the bridge is registered as.
+    pub device_id: String,
+    pub authentication: Authentication,
+    /// How long each SAS token is valid for. A new one is generated whenever the bridge reconnects,
+    /// including when IoT Hub disconnects it because the previous one has expired.
+    pub sas_ttl: Duration,
+}
+
+/// A message tagged with its type, so readings and history records can be told apart when routing
+/// messages.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+struct TaggedMessage<T> {
+    #[serde(rename = "type")]
+    message_type: &'static str,
+    #[serde(flatten)]
+    message: T,
+}
+
+impl AzureIot {
+    /// Parse a device connection string as shown in the Azure portal, such as
+    /// `HostName=my-hub.azure-devices.net;DeviceId=mijia-bridge;SharedAccessKey=...`. If it has no
+    /// shared access key then the given X.509 certificate and private key are used instead.
+    pub fn from_connection_string(
+        connection_string: &str,
+        x509: Option<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<Self, eyre::Report> {
+        let fields: HashMap<&str, &str> = connection_string
+            .split(';')
+            .filter_map(|field| {
+                let mut parts = field.splitn(2, '=');
+                Some((parts.next()?.trim(), parts.next()?.trim()))
+            })
+            .collect();
+        let hostname = match fields.get("HostName") {
+            Some(hostname) => hostname.to_string(),
+            None => bail!("Azure IoT Hub connection string has no HostName"),
+        };
+        let device_id = match fields.get("DeviceId") {
+            Some(device_id) => device_id.to_string(),
+            None => bail!("Azure IoT Hub connection string has no DeviceId"),
+        };
+        let authentication = match (fields.get("SharedAccessKey"), x509) {
+            (Some(key), _) => Authentication::SharedAccessKey(key.to_string()),
+            (None, Some((certificate, private_key))) => Authentication::X509 {
+                certificate,
+                private_key,
+            },
+            (None, None) => bail!(
+                "Azure IoT Hub connection string has no SharedAccessKey, and no certificate is set"
+            ),
+        };
+        Ok(Self {
+            hostname,
+            device_id,
+            authentication,
+            sas_ttl: DEFAULT_SAS_TTL,
+        })
+    }
+
+    /// Send every readings and history record event from the bridge to IoT Hub as device-to-cloud
+    /// messages, until the bridge stops.
+    pub async fn run(&self, state: Arc<Mutex<SensorState>>) -> Result<(), eyre::Report> {
+        tracing::info!(
+            "Sending messages to Azure IoT Hub {} as {}",
+            self.hostname,
+            self.device_id
+        );
+        let mut events = state.lock().await.events.subscribe();
+        let (client, mut event_loop) = AsyncClient::new(self.mqtt_options()?, REQUESTS_CAP);
+        let azure_iot = self.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = event_loop.poll().await {
+                    tracing::warn!("Azure IoT Hub connection failed: {}", e);
+                    delay_for(RECONNECT_INTERVAL).await;
+                    // The SAS token may have expired, so generate a new one before reconnecting.
+                    if let Some(password) = azure_iot.password(SystemTime::now()) {
+                        event_loop
+                            .options
+                            .set_credentials(azure_iot.username(), password);
+                    }
+                }
+            }
+        });
+
+        loop {
+            let (mac_address, event) = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Azure IoT Hub missed {} events.", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            };
+            let name = sensor_name(&*state.lock().await, mac_address);
+            let (message_type, payload) = match event {
+                MijiaEvent::Readings { readings, time, .. } => {
+                    let message = TaggedMessage {
+                        message_type: "readings",
+                        message: ReadingsMessage::new(&name, mac_address, &readings, time),
+                    };
+                    ("readings", serde_json::to_vec(&message)?)
+                }
+                MijiaEvent::HistoryRecord { record, .. } => {
+                    let message = TaggedMessage {
+                        message_type: "history",
+                        message: HistoryMessage::new(&name, mac_address, &record),
+                    };
+                    ("history", serde_json::to_vec(&message)?)
+                }
+                _ => continue,
+            };
+            client
+                .publish(
+                    self.events_topic(mac_address, message_type),
+                    QoS::AtLeastOnce,
+                    false,
+                    payload,
+                )
+                .await?;
+        }
+    }
+
+    /// Construct the options for connecting to IoT Hub, with a fresh SAS token if using one.
+    pub fn mqtt_options(&self) -> Result<MqttOptions, eyre::Report> {
+        let mut client_config = ClientConfig::new();
+        client_config.root_store = match rustls_native_certs::load_native_certs() {
+            Ok(root_store) => root_store,
+            Err((_, e)) => return Err(e.into()),
+        };
+        if let Authentication::X509 {
+            certificate,
+            private_key,
+        } = &self.authentication
+        {
+            let certificate = certs(&mut BufReader::new(certificate.as_slice()))
+                .map_err(|()| eyre::eyre!("Invalid Azure IoT Hub certificate"))?;
+            let mut private_keys = rsa_private_keys(&mut BufReader::new(private_key.as_slice()))
+                .map_err(|()| eyre::eyre!("Invalid Azure IoT Hub private key"))?;
+            if private_keys.is_empty() {
+                private_keys = pkcs8_private_keys(&mut BufReader::new(private_key.as_slice()))
+                    .map_err(|()| eyre::eyre!("Invalid Azure IoT Hub private key"))?;
+            }
+            if private_keys.is_empty() {
+                bail!("No private key found for Azure IoT Hub certificate");
+            }
+            client_config.set_single_client_cert(certificate, private_keys.remove(0))?;
+        }
+
+        let mut mqtt_options =
+            MqttOptions::new(self.device_id.clone(), self.hostname.clone(), PORT);
+        mqtt_options
+            .set_keep_alive(30)
+            .set_tls_client_config(Arc::new(client_config))
+            .set_credentials(
+                self.username(),
+                self.password(SystemTime::now()).unwrap_or_default(),
+            );
+        Ok(mqtt_options)
+    }
+
+    /// Get the MQTT username which IoT Hub expects for the device.
+    fn username(&self) -> String {
+        format!(
+            "{}/{}/?api-version={}",
+            self.hostname, self.device_id, API_VERSION
+        )
+    }
+
+    /// Get the MQTT password for the device, which is a SAS token valid from the given time if
+    /// using a shared access key, or `None` for X.509 authentication.
+    fn password(&self, now: SystemTime) -> Option<String> {
+        match &self.authentication {
+            Authentication::SharedAccessKey(key) => {
+                let expiry = (now + self.sas_ttl)
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                match sas_token(&self.hostname, &self.device_id, key, expiry) {
+                    Ok(token) => Some(token),
+                    Err(e) => {
+                        tracing::error!("Failed to generate SAS token: {:?}", e);
+                        None
+                    }
+                }
+            }
+            Authentication::X509 { .. } => None,
+        }
+    }
+
+    /// Get the device-to-cloud topic for the given kind of message from the given sensor. The
+    /// message type and sensor are added as application properties, so IoT Hub can route on them
+    /// without parsing the body.
+    fn events_topic(&self, mac_address: MacAddress, message_type: &str) -> String {
+        format!(
+            "devices/{}/messages/events/$.ct=application%2Fjson&$.ce=utf-8&type={}&sensor={}",
+            self.device_id,
+            message_type,
+            compact_mac_address(mac_address)
+        )
+    }
+}
+
+/// Generate a SAS token for the given device, signed with its base64-encoded shared access key and
+/// expiring at the given number of seconds since the Unix epoch.
+fn sas_token(
+    hostname: &str,
+    device_id: &str,
+    key: &str,
+    expiry: u64,
+) -> Result<String, eyre::Report> {
+    let resource_uri = url_encode(&format!("{}/devices/{}", hostname, device_id));
+    let key = base64::decode(key)?;
+    let mut mac = Hmac::<Sha256>::new_varkey(&key)
+        .map_err(|_| eyre::eyre!("Invalid Azure IoT Hub shared access key"))?;
+    mac.update(format!("{}\n{}", resource_uri, expiry).as_bytes());
+    let signature = base64::encode(mac.finalize().into_bytes());
+    Ok(format!(
+        "SharedAccessSignature sr={}&sig={}&se={}",
+        resource_uri,
+        url_encode(&signature),
+        expiry
+    ))
+}
+
+/// Percent-encode everything except unreserved characters, as SAS tokens require.
+fn url_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"-_.~".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{:02X}", b)
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_connection_string() {
+        let azure_iot = AzureIot::from_connection_string(
+            "HostName=my-hub.azure-devices.net;DeviceId=mijia-bridge;SharedAccessKey=c2VjcmV0",
+            None,
+        )
+        .unwrap();
+        assert_eq!(azure_iot.hostname, "my-hub.azure-devices.net");
+        assert_eq!(azure_iot.device_id, "mijia-bridge");
+        assert_eq!(
+            azure_iot.authentication,
+            Authentication::SharedAccessKey("c2VjcmV0".to_owned())
+        );
+        assert_eq!(
+            azure_iot.username(),
+            "my-hub.azure-devices.net/mijia-bridge/?api-version=2018-06-30"
+        );
+        assert_eq!(
+            azure_iot.events_topic("A4:C1:38:D7:21:17".parse().unwrap(), "readings"),
+            "devices/mijia-bridge/messages/events/$.ct=application%2Fjson&$.ce=utf-8&type=readings&sensor=A4C138D72117"
+        );
+
+        assert!(AzureIot::from_connection_string(
+            "HostName=my-hub.azure-devices.net;DeviceId=mijia-bridge;x509=true",
+            None
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn generate_sas_token() {
+        assert_eq!(
+            sas_token(
+                "my-hub.azure-devices.net",
+                "mijia-bridge",
+                "c2VjcmV0",
+                1_600_000_000
+            )
+            .unwrap(),
+            "SharedAccessSignature sr=my-hub.azure-devices.net%2Fdevices%2Fmijia-bridge\
+             &sig=s2cmrL2n9YwiXgC6YDhU1Ey%2BTjDT0zbwBKZz0%2FWAKOI%3D&se=1600000000"
+        );
+    }
+}
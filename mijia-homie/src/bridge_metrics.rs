@@ -0,0 +1,183 @@
+//! A node on the bridge's Homie device with metrics about the bridge itself, so that its health can
+//! be watched from the same controller as the sensors' readings.
+
+use crate::brokers::HomieBrokers;
+use homie_device::{Node, Property};
+use std::time::{Duration, Instant};
+
+pub const METRICS_NODE_ID: &str = "metrics";
+const PROPERTY_ID_SENSORS_CONFIGURED: &str = "sensors-configured";
+const PROPERTY_ID_SENSORS_CONNECTED: &str = "sensors-connected";
+const PROPERTY_ID_EVENTS_PER_MINUTE: &str = "events-per-minute";
+const PROPERTY_ID_DECODE_ERRORS: &str = "decode-errors";
+const PROPERTY_ID_UPTIME: &str = "uptime";
+const PROPERTY_ID_DBUS_RECONNECTS: &str = "dbus-reconnects";
+const PROPERTY_ID_MQTT_RECONNECTS: &str = "mqtt-reconnects";
+
+/// Build the Homie node for the bridge's metrics.
+pub fn metrics_node() -> Node {
+    Node::new(
+        METRICS_NODE_ID,
+        "Bridge metrics",
+        "Metrics",
+        vec![
+            Property::integer(
+                PROPERTY_ID_SENSORS_CONFIGURED,
+                "Sensors configured",
+                false,
+                None,
+                None,
+            ),
+            Property::integer(
+                PROPERTY_ID_SENSORS_CONNECTED,
+                "Sensors connected",
+                false,
+                None,
+                None,
+            ),
+            Property::float(
+                PROPERTY_ID_EVENTS_PER_MINUTE,
+                "Events per minute",
+                false,
+                None,
+                None,
+            ),
+            Property::integer(
+                PROPERTY_ID_DECODE_ERRORS,
+                "Decode errors",
+                false,
+                None,
+                None,
+            ),
+            Property::integer(PROPERTY_ID_UPTIME, "Uptime", false, Some("s"), None),
+            Property::integer(
+                PROPERTY_ID_DBUS_RECONNECTS,
+                "D-Bus reconnects",
+                false,
+                None,
+                None,
+            ),
+            Property::integer(
+                PROPERTY_ID_MQTT_RECONNECTS,
+                "MQTT reconnects",
+                false,
+                None,
+                None,
+            ),
+        ],
+    )
+}
+
+/// A snapshot of the metrics about the bridge, to be published to the metrics node.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BridgeMetrics {
+    /// The number of sensors named in the sensor names file which the bridge may connect to.
+    pub sensors_configured: usize,
+    /// The number of sensors which are currently connected.
+    pub sensors_connected: usize,
+    /// The rate at which events have been received from sensors and adapters since the last report.
+    pub events_per_minute: f64,
+    /// The number of values from sensors which couldn't be decoded since the bridge started.
+    pub decode_errors: u64,
+    /// How long the bridge has been running.
+    pub uptime: Duration,
+    /// The number of times the D-Bus connection has been re-established or BlueZ has restarted
+    /// since the bridge started.
+    pub dbus_reconnects: u64,
+    /// The number of times the connection to an MQTT broker has been re-established since the
+    /// bridge started.
+    pub mqtt_reconnects: u64,
+}
+
+impl BridgeMetrics {
+    /// Publish the metrics to the properties of the metrics node.
+    pub fn publish(&self, homie: &HomieBrokers) {
+        let publish = |property_id: &str, value: String| {
+            homie.publish_value(METRICS_NODE_ID, property_id, value);
+        };
+        publish(
+            PROPERTY_ID_SENSORS_CONFIGURED,
+            self.sensors_configured.to_string(),
+        );
+        publish(
+            PROPERTY_ID_SENSORS_CONNECTED,
+            self.sensors_connected.to_string(),
+        );
+        publish(
+            PROPERTY_ID_EVENTS_PER_MINUTE,
+            format!("{:.1}", self.events_per_minute),
+        );
+        publish(PROPERTY_ID_DECODE_ERRORS, self.decode_errors.to_string());
+        publish(PROPERTY_ID_UPTIME, self.uptime.as_secs().to_string());
+        publish(
+            PROPERTY_ID_DBUS_RECONNECTS,
+            self.dbus_reconnects.to_string(),
+        );
+        publish(
+            PROPERTY_ID_MQTT_RECONNECTS,
+            self.mqtt_reconnects.to_string(),
+        );
+    }
+}
+
+/// Keeps track of when the bridge started and how many events had been received as of the last
+/// report, to work out the uptime and event rate for each report.
+#[derive(Clone, Debug)]
+pub struct MetricsClock {
+    started: Instant,
+    last_report: Instant,
+    last_events: u64,
+}
+
+impl MetricsClock {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            started: now,
+            last_report: now,
+            last_events: 0,
+        }
+    }
+
+    /// Get how long it has been since the bridge started.
+    pub fn uptime(&self, now: Instant) -> Duration {
+        now.duration_since(self.started)
+    }
+
+    /// Work out the rate of events per minute since the last call, or since the bridge started for
+    /// the first call, given the total number of events received so far.
+    pub fn events_per_minute(&mut self, now: Instant, events: u64) -> f64 {
+        let elapsed = now.duration_since(self.last_report);
+        let new_events = events.saturating_sub(self.last_events);
+        self.last_report = now;
+        self.last_events = events;
+        if elapsed == Duration::default() {
+            0.0
+        } else {
+            new_events as f64 * 60.0 / elapsed.as_secs_f64()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_rate() {
+        let started = Instant::now();
+        let mut clock = MetricsClock::new(started);
+        assert_eq!(clock.events_per_minute(started, 0), 0.0);
+        assert_eq!(
+            clock.events_per_minute(started + Duration::from_secs(30), 10),
+            20.0
+        );
+        assert_eq!(
+            clock.events_per_minute(started + Duration::from_secs(150), 40),
+            15.0
+        );
+        assert_eq!(
+            clock.uptime(started + Duration::from_secs(150)),
+            Duration::from_secs(150)
+        );
+    }
+}
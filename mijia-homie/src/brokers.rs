@@ -0,0 +1,587 @@
+use crate::offline_queue::{OfflineQueue, QueuedValue};
+use backoff::backoff::Backoff;
+use backoff::ExponentialBackoff;
+use futures::future;
+use homie_device::{HomieDevice, HomieVersion, Node};
+use rumqttc::MqttOptions;
+use stable_eyre::eyre;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{delay_until, Instant};
+
+/// The longest to wait between attempts to reconnect to a broker.
+const MAX_RECONNECT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// The maximum number of values to buffer for each broker while it is unreachable. Beyond this the
+/// oldest are dropped.
+const MAX_BUFFERED_VALUES: usize = 10_000;
+
+/// An update to the Homie device, to be published to every broker.
+#[derive(Clone, Debug)]
+enum Update {
+    AddNode(Node),
+    RemoveNode(String),
+    PublishValue {
+        node_id: String,
+        property_id: String,
+        value: String,
+    },
+}
+
+/// A message received from any broker for the bridge to act on.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Incoming {
+    /// A message on the Homie broadcast channel.
+    Broadcast {
+        /// The broadcast level, e.g. `alert`.
+        level: String,
+        message: String,
+    },
+    /// A request from a controller to set the value of a settable property.
+    Set {
+        node_id: String,
+        property_id: String,
+        value: String,
+    },
+}
+
+/// How to handle nodes which a previous run of the bridge left retained on a broker.
+#[derive(Debug)]
+pub struct PreviousNodes {
+    /// Nodes to re-advertise if a previous run left them behind, keyed by node ID.
+    pub known: HashMap<String, Node>,
+    /// Constructs a node with the given ID, to clear the retained topics of any other nodes.
+    pub stale_node: fn(&str) -> Node,
+}
+
+/// The bridge's Homie device, published to one or more MQTT brokers. Each broker has its own
+/// connection, which is re-established independently if it fails, so one broker being unreachable
+/// doesn't stop updates being published to the others.
+#[derive(Debug)]
+pub struct HomieBrokers {
+    update_senders: Vec<mpsc::UnboundedSender<Update>>,
+    incoming: mpsc::UnboundedSender<Incoming>,
+    /// The number of times the connection to any broker has been re-established.
+    reconnects: Arc<AtomicU64>,
+}
+
+impl HomieBrokers {
+    /// Spawn a task for each of the given brokers to connect to it and publish the Homie device.
+    /// Returns the `HomieBrokers` along with a channel on which broadcasts and requests to set
+    /// properties from any of the brokers will be received.
+    ///
+    /// If an offline queue directory is given, values which can't be published because a broker is
+    /// unreachable will be persisted there until they can be.
+    pub fn spawn(
+        device_base: &str,
+        device_name: &str,
+        homie_version: HomieVersion,
+        brokers: Vec<MqttOptions>,
+        previous_nodes: PreviousNodes,
+        offline_queue_directory: Option<&Path>,
+    ) -> (Self, mpsc::UnboundedReceiver<Incoming>) {
+        let previous_nodes = Arc::new(previous_nodes);
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        let reconnects = Arc::new(AtomicU64::new(0));
+        let update_senders = brokers
+            .into_iter()
+            .map(|mqtt_options| {
+                let (update_tx, update_rx) = mpsc::unbounded_channel();
+                let offline_queue = offline_queue_directory.map(|directory| {
+                    let (host, port) = mqtt_options.broker_address();
+                    OfflineQueue::new(directory, &host, port)
+                });
+                let broker = Broker {
+                    device_base: device_base.to_owned(),
+                    device_name: device_name.to_owned(),
+                    homie_version,
+                    mqtt_options,
+                    previous_nodes: previous_nodes.clone(),
+                    updates: update_rx,
+                    incoming: incoming_tx.clone(),
+                    nodes: vec![],
+                    values: HashMap::new(),
+                    buffered_values: VecDeque::new(),
+                    offline_queue,
+                    connected_before: false,
+                    reconnects: reconnects.clone(),
+                };
+                tokio::spawn(broker.run());
+                update_tx
+            })
+            .collect();
+        (
+            Self {
+                update_senders,
+                incoming: incoming_tx,
+                reconnects,
+            },
+            incoming_rx,
+        )
+    }
+
+    /// Get a sender for the channel on which incoming messages from the brokers are received, so
+    /// that other sources can send the bridge commands in the same way.
+    pub fn incoming_sender(&self) -> mpsc::UnboundedSender<Incoming> {
+        self.incoming.clone()
+    }
+
+    /// Get the number of times the connection to any of the brokers has been re-established since
+    /// the bridge started.
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects.load(Ordering::Relaxed)
+    }
+
+    /// Add a node to the Homie device on all brokers.
+    pub fn add_node(&self, node: Node) {
+        self.send(Update::AddNode(node));
+    }
+
+    /// Remove the node with the given ID from the Homie device on all brokers.
+    pub fn remove_node(&self, node_id: &str) {
+        self.send(Update::RemoveNode(node_id.to_owned()));
+    }
+
+    /// Publish a new value for the given property of the given node to all brokers.
+    pub fn publish_value(&self, node_id: &str, property_id: &str, value: impl ToString) {
+        self.send(Update::PublishValue {
+            node_id: node_id.to_owned(),
+            property_id: property_id.to_owned(),
+            value: value.to_string(),
+        });
+    }
+
+    fn send(&self, update: Update) {
+        for update_tx in &self.update_senders {
+            // The broker tasks only stop once the sender is dropped, so this can't fail.
+            update_tx.send(update.clone()).unwrap();
+        }
+    }
+}
+
+/// The connection to a single MQTT broker, along with the state of the Homie device which should be
+/// published to it so it can be republished after reconnecting.
+struct Broker {
+    device_base: String,
+    device_name: String,
+    /// The version of the Homie convention to announce.
+    homie_version: HomieVersion,
+    mqtt_options: MqttOptions,
+    previous_nodes: Arc<PreviousNodes>,
+    updates: mpsc::UnboundedReceiver<Update>,
+    incoming: mpsc::UnboundedSender<Incoming>,
+    nodes: Vec<Node>,
+    /// The latest value of each property, keyed by node ID and property ID.
+    values: HashMap<(String, String), String>,
+    /// Values published while the broker was unreachable, in order, to be published once it is
+    /// reconnected.
+    buffered_values: VecDeque<QueuedValue>,
+    /// Where to persist buffered values so they survive restarts, if anywhere.
+    offline_queue: Option<OfflineQueue>,
+    /// Whether we have successfully connected to the broker before, since this process started.
+    connected_before: bool,
+    /// Shared with the `HomieBrokers`, and incremented whenever the connection is re-established.
+    reconnects: Arc<AtomicU64>,
+}
+
+impl Broker {
+    /// Keep connecting to the broker and publishing updates, until the `HomieBrokers` is dropped.
+    ///
+    /// Every time the connection is (re-)established the whole device is published again, so that
+    /// it is complete even if the broker lost its retained messages in the meantime.
+    async fn run(mut self) {
+        let (host, port) = self.mqtt_options.broker_address();
+        let mut backoff = ExponentialBackoff {
+            max_interval: MAX_RECONNECT_INTERVAL,
+            max_elapsed_time: None,
+            ..Default::default()
+        };
+        self.load_offline_queue();
+        loop {
+            match self.connect().await {
+                Ok((homie, homie_handle)) => {
+                    tracing::info!(%host, port, "Connected to MQTT broker");
+                    backoff.reset();
+                    match self.publish_updates(homie, homie_handle).await {
+                        Ok(true) => tracing::warn!(%host, port, "MQTT connection closed"),
+                        Ok(false) => return,
+                        Err(e) => tracing::error!(%host, port, "MQTT connection failed: {:?}", e),
+                    }
+                }
+                Err(e) => tracing::error!(%host, port, "Failed to connect to MQTT broker: {:?}", e),
+            }
+
+            // Keep track of updates while waiting to reconnect, so they can be published afterwards.
+            let delay = backoff.next_backoff().unwrap_or(MAX_RECONNECT_INTERVAL);
+            tracing::info!(%host, port, "Reconnecting to MQTT broker in {:?}", delay);
+            let reconnect_at = Instant::now() + delay;
+            loop {
+                tokio::select! {
+                    _ = delay_until(reconnect_at) => break,
+                    update = self.updates.recv() => match update {
+                        Some(update) => {
+                            if self.apply(&update) {
+                                self.buffer(&update);
+                            }
+                        }
+                        None => return,
+                    },
+                }
+            }
+        }
+    }
+
+    /// Connect to the broker and publish the current state of the device.
+    async fn connect(
+        &mut self,
+    ) -> Result<
+        (
+            HomieDevice,
+            impl std::future::Future<Output = Result<(), homie_device::SpawnError>>,
+        ),
+        eyre::Report,
+    > {
+        let mut homie_builder = HomieDevice::builder(
+            &self.device_base,
+            &self.device_name,
+            self.mqtt_options.clone(),
+        );
+        homie_builder.set_firmware(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        homie_builder.set_read_previous_nodes(true);
+        homie_builder.set_homie_version(self.homie_version);
+        // The receiver is only dropped when the bridge is shutting down, so ignore errors.
+        let incoming = self.incoming.clone();
+        homie_builder.set_broadcast_callback(move |level, message| {
+            let _ = incoming.send(Incoming::Broadcast { level, message });
+            future::ready(())
+        });
+        let incoming = self.incoming.clone();
+        homie_builder.set_update_callback(move |node_id, property_id, value| {
+            let _ = incoming.send(Incoming::Set {
+                node_id,
+                property_id,
+                value,
+            });
+            // Any new value is published by the bridge to all brokers once it has been handled.
+            future::ready(None)
+        });
+        let (mut homie, homie_handle) = homie_builder.spawn().await?;
+
+        self.reconcile_previous_nodes(&homie).await?;
+        for node in &self.nodes {
+            homie.add_node(node.clone()).await?;
+        }
+        // Publish the values buffered during the outage in order after any others, so that the
+        // latest value of each property is the one left retained.
+        let buffered_keys: HashSet<_> = self.buffered_values.iter().map(|(key, _)| key).collect();
+        for (key, value) in &self.values {
+            if !buffered_keys.contains(key) {
+                homie.publish_value(&key.0, &key.1, value).await?;
+            }
+        }
+        if !self.buffered_values.is_empty() {
+            tracing::info!("Publishing {} buffered values", self.buffered_values.len());
+        }
+        for ((node_id, property_id), value) in &self.buffered_values {
+            // Values persisted by a previous run may be for sensors which we haven't reconnected to.
+            if self.has_node(node_id) {
+                homie.publish_value(node_id, property_id, value).await?;
+            }
+        }
+        homie.ready().await?;
+        self.buffered_values.clear();
+        if let Some(offline_queue) = &self.offline_queue {
+            if let Err(e) = offline_queue.clear() {
+                tracing::error!("Failed to clear offline queue: {:?}", e);
+            }
+        }
+        if self.connected_before {
+            self.reconnects.fetch_add(1, Ordering::Relaxed);
+        }
+        self.connected_before = true;
+
+        Ok((homie, homie_handle))
+    }
+
+    /// Re-advertise the nodes left retained by a previous run for sensors which we still know about,
+    /// and clear the retained topics for any others so they don't linger on the broker.
+    ///
+    /// After reconnecting, nodes are only re-advertised if they are still current.
+    async fn reconcile_previous_nodes(&mut self, homie: &HomieDevice) -> Result<(), eyre::Report> {
+        for node_id in homie.previous_node_ids() {
+            if self.has_node(node_id) {
+                continue;
+            }
+            match self.previous_nodes.known.get(node_id) {
+                Some(node) if !self.connected_before => {
+                    tracing::debug!(node = %node_id, "Re-advertising node from previous run");
+                    self.nodes.push(node.clone());
+                }
+                _ => {
+                    tracing::info!(node = %node_id, "Removing stale node from previous run");
+                    homie
+                        .clear_retained_node(&(self.previous_nodes.stale_node)(node_id))
+                        .await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Publish updates until either the connection fails or the `HomieBrokers` is dropped. Returns
+    /// true if the connection was closed, or false if there will be no more updates.
+    async fn publish_updates(
+        &mut self,
+        mut homie: HomieDevice,
+        homie_handle: impl std::future::Future<Output = Result<(), homie_device::SpawnError>>,
+    ) -> Result<bool, eyre::Report> {
+        tokio::pin!(homie_handle);
+        loop {
+            tokio::select! {
+                result = &mut homie_handle => {
+                    result?;
+                    return Ok(true);
+                }
+                update = self.updates.recv() => match update {
+                    Some(update) => {
+                        if self.apply(&update) {
+                            if let Err(e) = publish_update(&mut homie, &update).await {
+                                self.buffer(&update);
+                                return Err(e);
+                            }
+                        }
+                    }
+                    None => {
+                        homie.disconnect().await?;
+                        return Ok(false);
+                    }
+                },
+            }
+        }
+    }
+
+    fn has_node(&self, node_id: &str) -> bool {
+        self.nodes.iter().any(|n| n.id == node_id)
+    }
+
+    /// Apply the given update to the stored state of the device. Returns false if it made no
+    /// difference, such as adding a node which was already re-advertised, and so doesn't need to be
+    /// published.
+    fn apply(&mut self, update: &Update) -> bool {
+        match update {
+            Update::AddNode(node) => {
+                if self.has_node(&node.id) {
+                    return false;
+                }
+                self.nodes.push(node.clone());
+            }
+            Update::RemoveNode(node_id) => {
+                if !self.has_node(node_id) {
+                    return false;
+                }
+                self.nodes.retain(|n| &n.id != node_id);
+                self.values
+                    .retain(|(value_node_id, _), _| value_node_id != node_id);
+                self.buffered_values
+                    .retain(|((value_node_id, _), _)| value_node_id != node_id);
+            }
+            Update::PublishValue {
+                node_id,
+                property_id,
+                value,
+            } => {
+                self.values.insert(
+                    (node_id.to_owned(), property_id.to_owned()),
+                    value.to_owned(),
+                );
+            }
+        }
+        true
+    }
+
+    /// Buffer the given update to be published once the broker is reconnected, if it is a value.
+    /// Nodes don't need to be buffered, as they will all be published anyway.
+    fn buffer(&mut self, update: &Update) {
+        if let Update::PublishValue {
+            node_id,
+            property_id,
+            value,
+        } = update
+        {
+            if let Some(offline_queue) = &self.offline_queue {
+                if let Err(e) = offline_queue.append(node_id, property_id, value) {
+                    tracing::error!("Failed to persist value to offline queue: {:?}", e);
+                }
+            }
+            self.push_buffered_value((
+                (node_id.to_owned(), property_id.to_owned()),
+                value.to_owned(),
+            ));
+        }
+    }
+
+    fn push_buffered_value(&mut self, value: QueuedValue) {
+        if self.buffered_values.len() >= MAX_BUFFERED_VALUES {
+            self.buffered_values.pop_front();
+        }
+        self.buffered_values.push_back(value);
+    }
+
+    /// Buffer any values which were persisted by a previous run but never published.
+    fn load_offline_queue(&mut self) {
+        let values = match self.offline_queue.as_ref().map(OfflineQueue::load) {
+            Some(Ok(values)) => values,
+            Some(Err(e)) => {
+                tracing::error!("Failed to load offline queue: {:?}", e);
+                return;
+            }
+            None => return,
+        };
+        if !values.is_empty() {
+            tracing::info!("Loaded {} values from offline queue", values.len());
+        }
+        for value in values {
+            self.push_buffered_value(value);
+        }
+    }
+}
+
+async fn publish_update(homie: &mut HomieDevice, update: &Update) -> Result<(), eyre::Report> {
+    match update {
+        Update::AddNode(node) => homie.add_node(node.clone()).await?,
+        Update::RemoveNode(node_id) => homie.remove_node(node_id).await?,
+        Update::PublishValue {
+            node_id,
+            property_id,
+            value,
+        } => homie.publish_value(node_id, property_id, value).await?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use homie_device::Property;
+
+    fn make_test_broker() -> (Broker, mpsc::UnboundedSender<Update>) {
+        let (update_tx, update_rx) = mpsc::unbounded_channel();
+        let broker = Broker {
+            device_base: "homie/test-device".to_owned(),
+            device_name: "Test device".to_owned(),
+            homie_version: HomieVersion::V4,
+            mqtt_options: MqttOptions::new("client_id", "hostname", 1234),
+            previous_nodes: Arc::new(PreviousNodes {
+                known: HashMap::new(),
+                stale_node: |node_id| Node::new(node_id, node_id, "type", vec![]),
+            }),
+            updates: update_rx,
+            incoming: mpsc::unbounded_channel().0,
+            nodes: vec![],
+            values: HashMap::new(),
+            buffered_values: VecDeque::new(),
+            offline_queue: None,
+            connected_before: false,
+            reconnects: Arc::default(),
+        };
+        (broker, update_tx)
+    }
+
+    fn make_test_node(node_id: &str) -> Node {
+        Node::new(
+            node_id,
+            "Name",
+            "type",
+            vec![Property::integer("prop", "Property", false, None, None)],
+        )
+    }
+
+    #[test]
+    fn add_node_twice_is_ignored() {
+        let (mut broker, _update_tx) = make_test_broker();
+
+        assert!(broker.apply(&Update::AddNode(make_test_node("node"))));
+        assert!(!broker.apply(&Update::AddNode(make_test_node("node"))));
+        assert_eq!(broker.nodes, vec![make_test_node("node")]);
+    }
+
+    #[test]
+    fn remove_unknown_node_is_ignored() {
+        let (mut broker, _update_tx) = make_test_broker();
+
+        assert!(!broker.apply(&Update::RemoveNode("node".to_owned())));
+    }
+
+    #[test]
+    fn remove_node_forgets_values() {
+        let (mut broker, _update_tx) = make_test_broker();
+
+        broker.apply(&Update::AddNode(make_test_node("node")));
+        broker.apply(&Update::AddNode(make_test_node("other")));
+        for node_id in &["node", "other"] {
+            assert!(broker.apply(&Update::PublishValue {
+                node_id: node_id.to_string(),
+                property_id: "prop".to_owned(),
+                value: "42".to_owned(),
+            }));
+        }
+        assert!(broker.apply(&Update::RemoveNode("node".to_owned())));
+
+        assert_eq!(broker.nodes, vec![make_test_node("other")]);
+        assert_eq!(
+            broker.values.keys().collect::<Vec<_>>(),
+            vec![&("other".to_owned(), "prop".to_owned())]
+        );
+    }
+
+    fn make_test_value(node_id: &str, value: &str) -> Update {
+        Update::PublishValue {
+            node_id: node_id.to_owned(),
+            property_id: "prop".to_owned(),
+            value: value.to_owned(),
+        }
+    }
+
+    #[test]
+    fn buffer_keeps_values_in_order() {
+        let (mut broker, _update_tx) = make_test_broker();
+
+        broker.buffer(&Update::AddNode(make_test_node("node")));
+        broker.buffer(&make_test_value("node", "1"));
+        broker.buffer(&make_test_value("node", "2"));
+
+        assert_eq!(
+            broker.buffered_values,
+            vec![
+                (("node".to_owned(), "prop".to_owned()), "1".to_owned()),
+                (("node".to_owned(), "prop".to_owned()), "2".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn buffer_drops_oldest_when_full() {
+        let (mut broker, _update_tx) = make_test_broker();
+
+        for i in 0..MAX_BUFFERED_VALUES + 1 {
+            broker.buffer(&make_test_value("node", &i.to_string()));
+        }
+
+        assert_eq!(broker.buffered_values.len(), MAX_BUFFERED_VALUES);
+        assert_eq!(broker.buffered_values.front().unwrap().1, "1");
+    }
+
+    #[test]
+    fn remove_node_forgets_buffered_values() {
+        let (mut broker, _update_tx) = make_test_broker();
+
+        broker.apply(&Update::AddNode(make_test_node("node")));
+        broker.buffer(&make_test_value("node", "1"));
+        broker.apply(&Update::RemoveNode("node".to_owned()));
+
+        assert!(broker.buffered_values.is_empty());
+    }
+}
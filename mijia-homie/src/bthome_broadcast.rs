@@ -0,0 +1,96 @@
+//! Re-broadcasting a sensor's readings from the bridge's Bluetooth adapter as BTHome
+//! advertisements, so that Home Assistant's native BLE integration can pick them up through an
+//! adapter or proxy near the bridge.
+
+use crate::SensorState;
+use mijia::bluetooth::{Advertisement, AdvertisementId};
+use mijia::decode::bthome::{encode_readings, BTHOME_SERVICE_UUID};
+use mijia::{AdapterId, MacAddress, MijiaEvent, MijiaSession, Readings};
+use stable_eyre::eyre;
+use std::sync::Arc;
+use tokio::sync::broadcast::RecvError;
+use tokio::sync::Mutex;
+
+/// Configuration for re-broadcasting readings as BTHome advertisements.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BtHomeBroadcast {
+    /// The sensor whose readings to broadcast. The advertisements come from the bridge's adapter,
+    /// so Home Assistant treats them as a single device and only one sensor can be broadcast.
+    pub sensor: MacAddress,
+}
+
+impl BtHomeBroadcast {
+    /// Broadcast the readings of the configured sensor whenever they arrive, until the bridge
+    /// stops. Each new set of readings replaces the previous advertisement.
+    pub async fn run(
+        &self,
+        state: Arc<Mutex<SensorState>>,
+        session: &MijiaSession,
+    ) -> Result<(), eyre::Report> {
+        let (mut events, adapter) = {
+            let state = state.lock().await;
+            (state.events.subscribe(), state.adapter.clone())
+        };
+        let adapter = match adapter {
+            Some(adapter) => adapter,
+            None => first_adapter(session).await?,
+        };
+        tracing::info!(
+            "Broadcasting readings of {} as BTHome advertisements from {}",
+            self.sensor,
+            adapter
+        );
+        let mut packet_id: u8 = 0;
+        let mut advertisement: Option<AdvertisementId> = None;
+        loop {
+            let readings = match events.recv().await {
+                Ok((mac_address, MijiaEvent::Readings { readings, .. }))
+                    if mac_address == self.sensor =>
+                {
+                    readings
+                }
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("BTHome broadcast missed {} events.", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            };
+            packet_id = packet_id.wrapping_add(1);
+            if let Some(previous) = advertisement.take() {
+                // This fails if BlueZ restarted since, in which case it is already gone.
+                if let Err(e) = session.bt_session.unregister_advertisement(previous).await {
+                    tracing::warn!("Failed to unregister BTHome advertisement: {}", e);
+                }
+            }
+            match session
+                .bt_session
+                .register_advertisement(&adapter, bthome_advertisement(&readings, packet_id))
+                .await
+            {
+                Ok(id) => advertisement = Some(id),
+                Err(e) => tracing::error!("Failed to register BTHome advertisement: {}", e),
+            }
+        }
+    }
+}
+
+/// Build the advertisement for the given readings.
+fn bthome_advertisement(readings: &Readings, packet_id: u8) -> Advertisement {
+    let mut advertisement = Advertisement::default();
+    advertisement.service_data.insert(
+        BTHOME_SERVICE_UUID.to_owned(),
+        encode_readings(readings, packet_id),
+    );
+    advertisement
+}
+
+/// Pick the first Bluetooth adapter on the system, for when `ADAPTER` isn't set.
+async fn first_adapter(session: &MijiaSession) -> Result<AdapterId, eyre::Report> {
+    let adapters = session.bt_session.get_adapters().await?;
+    adapters
+        .into_iter()
+        .next()
+        .map(|adapter| adapter.id)
+        .ok_or_else(|| eyre::eyre!("No Bluetooth adapter to broadcast BTHome advertisements from"))
+}
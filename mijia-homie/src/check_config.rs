@@ -0,0 +1,324 @@
+//! Validating all of the bridge's configuration without connecting to any sensors, so deployments
+//! can check it before restarting the real service.
+
+use crate::saved_state::StateFile;
+use crate::store::Store;
+use crate::telemetry::LogFormat;
+use crate::{
+    get_aggregation, get_aws_iot, get_azure_iot, get_brokers, get_bthome_broadcast, get_graphite,
+    get_history_sync, get_max_concurrent_connects, get_plausibility, get_rate_limit,
+    get_sensor_filter, get_smoothing, get_status_options, hashmap_from_file, parse_env_var,
+    read_sensor_bind_keys, read_sensor_names, read_sensor_thresholds, read_sensor_timeouts,
+    DEFAULT_DEVICE_ID, SENSOR_BIND_KEYS_FILENAME, SENSOR_LOCATIONS_FILENAME, SENSOR_NAMES_FILENAME,
+    SENSOR_THRESHOLDS_FILENAME, SENSOR_TIMEOUTS_FILENAME,
+};
+use homie_device::HomieVersion;
+use rumqttc::{Event, EventLoop, Incoming, MqttOptions};
+use stable_eyre::eyre::{self, WrapErr};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// How long to wait for each broker to accept a connection when checking connectivity.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The results of checking each part of the configuration.
+#[derive(Debug, Default)]
+struct Report {
+    /// The name of each check, and either a summary of what was found or the error.
+    checks: Vec<(String, Result<String, eyre::Report>)>,
+}
+
+impl Report {
+    fn check(&mut self, name: &str, result: Result<String, eyre::Report>) {
+        self.checks.push((name.to_owned(), result));
+    }
+
+    fn failures(&self) -> usize {
+        self.checks
+            .iter()
+            .filter(|(_, result)| result.is_err())
+            .count()
+    }
+
+    fn print(&self) {
+        for (name, result) in &self.checks {
+            match result {
+                Ok(summary) => println!("ok    {}: {}", name, summary),
+                Err(e) => println!("FAIL  {}: {:#}", name, e),
+            }
+        }
+        match self.failures() {
+            0 => println!("Configuration is valid."),
+            failures => println!("{} of {} checks failed.", failures, self.checks.len()),
+        }
+    }
+}
+
+/// Load and validate all configuration options and files, print a report of what was found and any
+/// problems, and return whether everything was valid. If `check_connectivity` is set then also try
+/// connecting to each MQTT broker.
+pub async fn check_config(dotenv: Result<(), eyre::Report>, check_connectivity: bool) -> bool {
+    let mut report = Report::default();
+    report.check(".env", dotenv.map(|()| "loaded".to_owned()));
+
+    let device_id = std::env::var("DEVICE_ID").unwrap_or_else(|_| DEFAULT_DEVICE_ID.to_string());
+    report.check(
+        "device",
+        check_device().map(|()| format!("device ID {}", device_id)),
+    );
+    report.check(
+        "logging",
+        parse_env_var::<LogFormat>("LOG_FORMAT")
+            .map(|format| format!("{:?} format", format.unwrap_or(LogFormat::Text)).to_lowercase()),
+    );
+
+    // Loading the platform certificates panics if it fails, so check it first.
+    let brokers = match check_native_certs() {
+        Ok(summary) => {
+            report.check("TLS root certificates", Ok(summary));
+            get_brokers(&device_id)
+        }
+        Err(e) => {
+            report.check("TLS root certificates", Err(e));
+            vec![]
+        }
+    };
+    for (i, broker) in brokers.iter().enumerate() {
+        let name = format!("broker {}", i + 1);
+        let (host, port) = broker.broker_address();
+        let summary = format!(
+            "{}:{} as {}{}",
+            host,
+            port,
+            broker.client_id(),
+            if broker.tls_client_config().is_some() {
+                " with TLS"
+            } else {
+                ""
+            }
+        );
+        let suffix = if i == 0 {
+            String::new()
+        } else {
+            format!("_{}", i + 1)
+        };
+        let result = match parse_env_var::<u16>(&format!("PORT{}", suffix)) {
+            Err(e) => Err(e),
+            Ok(_) if check_connectivity => check_broker(broker.clone())
+                .await
+                .map(|()| format!("connected to {}", summary)),
+            Ok(_) => Ok(summary),
+        };
+        report.check(&name, result);
+    }
+
+    let sensor_names = read_sensor_names(SENSOR_NAMES_FILENAME)
+        .wrap_err(format!("reading {}", SENSOR_NAMES_FILENAME));
+    match sensor_names {
+        Ok((sensor_names, disabled_sensors)) => {
+            report.check(
+                SENSOR_NAMES_FILENAME,
+                Ok(format!(
+                    "{} sensors, {} disabled",
+                    sensor_names.len(),
+                    disabled_sensors.len()
+                )),
+            );
+            report.check(
+                "sensor filter",
+                get_sensor_filter(&sensor_names, disabled_sensors).map(|filter| {
+                    match filter.allowlist {
+                        Some(allowlist) => format!("{} sensors allowed", allowlist.len()),
+                        None => format!("{} sensors blocked", filter.blocklist.len()),
+                    }
+                }),
+            );
+        }
+        Err(e) => report.check(SENSOR_NAMES_FILENAME, Err(e)),
+    }
+    report.check(
+        SENSOR_THRESHOLDS_FILENAME,
+        read_sensor_thresholds(SENSOR_THRESHOLDS_FILENAME)
+            .map(|thresholds| format!("{} sensors", thresholds.len())),
+    );
+    report.check(
+        SENSOR_LOCATIONS_FILENAME,
+        hashmap_from_file(SENSOR_LOCATIONS_FILENAME)
+            .map(|locations| format!("{} sensors", locations.len())),
+    );
+    report.check(
+        SENSOR_TIMEOUTS_FILENAME,
+        read_sensor_timeouts(SENSOR_TIMEOUTS_FILENAME)
+            .map(|timeouts| format!("{} sensors", timeouts.len())),
+    );
+    report.check(
+        SENSOR_BIND_KEYS_FILENAME,
+        read_sensor_bind_keys(SENSOR_BIND_KEYS_FILENAME)
+            .map(|bind_keys| format!("{} sensors", bind_keys.len())),
+    );
+
+    report.check("smoothing", get_smoothing().map(describe));
+    report.check("aggregation", get_aggregation().map(describe));
+    report.check(
+        "plausibility",
+        get_plausibility().map(|plausibility| format!("{:?}", plausibility)),
+    );
+    report.check(
+        "rate limit",
+        get_rate_limit().map(|rate_limit| format!("{:?}", rate_limit)),
+    );
+
+    report.check(
+        "status",
+        get_status_options().map(|status_options| format!("{:?}", status_options)),
+    );
+    report.check("history sync", get_history_sync().map(describe));
+
+    report.check("store", check_store());
+    report.check("state file", check_state_file());
+    report.check("outputs", check_outputs());
+    report.check(
+        "AWS IoT Core",
+        get_aws_iot().and_then(|aws_iot| match aws_iot {
+            Some(aws_iot) => aws_iot.check_tls_material().map(|()| {
+                format!(
+                    "{}:{} as {}",
+                    aws_iot.endpoint, aws_iot.port, aws_iot.thing_name
+                )
+            }),
+            None => Ok("disabled".to_owned()),
+        }),
+    );
+    report.check(
+        "Azure IoT Hub",
+        get_azure_iot().and_then(|azure_iot| match azure_iot {
+            Some(azure_iot) => azure_iot
+                .mqtt_options()
+                .map(|_| format!("{} as {}", azure_iot.hostname, azure_iot.device_id)),
+            None => Ok("disabled".to_owned()),
+        }),
+    );
+
+    report.print();
+    report.failures() == 0
+}
+
+/// Describe an optional configuration, or say that it is disabled.
+fn describe<T: std::fmt::Debug>(config: Option<T>) -> String {
+    match config {
+        Some(config) => format!("{:?}", config),
+        None => "disabled".to_owned(),
+    }
+}
+
+fn check_device() -> Result<(), eyre::Report> {
+    if std::env::var("DEVICE_ID_PER_ADAPTER").is_ok() && std::env::var("ADAPTER").is_err() {
+        eyre::bail!("DEVICE_ID_PER_ADAPTER is set but ADAPTER isn't.");
+    }
+    get_max_concurrent_connects()?;
+    parse_env_var::<HomieVersion>("HOMIE_VERSION")?;
+    Ok(())
+}
+
+/// Check that the platform's root certificates can be loaded, if any broker uses TLS.
+fn check_native_certs() -> Result<String, eyre::Report> {
+    let uses_tls =
+        std::env::vars().any(|(name, _)| name == "USE_TLS" || name.starts_with("USE_TLS_"));
+    if !uses_tls {
+        return Ok("not needed".to_owned());
+    }
+    match rustls_native_certs::load_native_certs() {
+        Ok(root_store) => Ok(format!("{} loaded", root_store.len())),
+        Err((_, e)) => Err(e).wrap_err("loading platform certificates"),
+    }
+}
+
+/// Try connecting to the broker with the given options, and wait for it to accept the connection.
+async fn check_broker(mqtt_options: MqttOptions) -> Result<(), eyre::Report> {
+    let mut event_loop = EventLoop::new(mqtt_options, 10);
+    timeout(CONNECT_TIMEOUT, async {
+        loop {
+            if let Event::Incoming(Incoming::ConnAck(_)) = event_loop.poll().await? {
+                return Ok(());
+            }
+        }
+    })
+    .await
+    .wrap_err("timed out connecting")?
+}
+
+fn check_store() -> Result<String, eyre::Report> {
+    match std::env::var("SQLITE_FILENAME") {
+        Ok(filename) => {
+            Store::open(&filename).wrap_err_with(|| format!("opening {}", filename))?;
+            Ok(filename)
+        }
+        Err(_) => Ok("disabled".to_owned()),
+    }
+}
+
+fn check_state_file() -> Result<String, eyre::Report> {
+    match std::env::var("STATE_FILENAME") {
+        Ok(filename) => {
+            let saved_sensors = StateFile::new(filename.clone())
+                .load()
+                .wrap_err("loading saved sensor state")?;
+            Ok(format!("{} with {} sensors", filename, saved_sensors.len()))
+        }
+        Err(_) => Ok("disabled".to_owned()),
+    }
+}
+
+/// Check the options for the web dashboard, gRPC, Graphite, PostgreSQL, Zigbee2MQTT-style and
+/// BTHome outputs, and list those which are enabled.
+fn check_outputs() -> Result<String, eyre::Report> {
+    let mut outputs = vec![];
+    if let Some(address) = parse_env_var::<SocketAddr>("WEB_ADDRESS")? {
+        outputs.push(format!("web on {}", address));
+    }
+    if let Some(address) = parse_env_var::<SocketAddr>("GRPC_ADDRESS")? {
+        if cfg!(not(feature = "grpc")) {
+            eyre::bail!("GRPC_ADDRESS is set but mijia-homie was built without the grpc feature.");
+        }
+        outputs.push(format!("gRPC on {}", address));
+    }
+    if let Some(graphite) = get_graphite() {
+        outputs.push(format!("Graphite at {}", graphite.address));
+    }
+    if std::env::var("POSTGRES_URL").is_ok() {
+        if cfg!(not(feature = "postgres")) {
+            eyre::bail!(
+                "POSTGRES_URL is set but mijia-homie was built without the postgres feature."
+            );
+        }
+        parse_env_var::<usize>("POSTGRES_BATCH_SIZE")?;
+        outputs.push("PostgreSQL".to_owned());
+    }
+    if let Ok(base_topic) = std::env::var("ZIGBEE2MQTT_BASE_TOPIC") {
+        outputs.push(format!("Zigbee2MQTT-style under {}", base_topic));
+    }
+    if let Some(bthome_broadcast) = get_bthome_broadcast()? {
+        outputs.push(format!("BTHome broadcast of {}", bthome_broadcast.sensor));
+    }
+    if outputs.is_empty() {
+        Ok("Homie only".to_owned())
+    } else {
+        Ok(outputs.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn count_failures() {
+        let mut report = Report::default();
+        report.check("good", Ok("fine".to_owned()));
+        report.check("bad", Err(eyre::eyre!("broken")));
+        assert_eq!(report.failures(), 1);
+        assert_eq!(describe::<u32>(None), "disabled");
+        assert_eq!(describe(Some(42)), "42");
+    }
+}
@@ -0,0 +1,129 @@
+//! Commands which can be sent to the bridge over MQTT to trigger actions at runtime.
+
+use mijia::MacAddress;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// A command for the bridge, sent by setting the `command` property of its `bridge` node.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BridgeCommand {
+    /// Scan for any sensors which haven't been found yet.
+    Rescan,
+    /// Disconnect from the sensor with the given name or MAC address, so that it will be
+    /// reconnected.
+    Reconnect(String),
+    /// Download all history records stored on the sensor with the given name or MAC address.
+    DownloadHistory(String),
+    /// Publish a summary of the state of all sensors.
+    DumpState,
+    /// Change the name of the sensor with the given MAC address.
+    Rename {
+        mac_address: MacAddress,
+        name: String,
+    },
+}
+
+/// An error parsing a `BridgeCommand` from a string.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParseBridgeCommandError(String);
+
+impl Display for ParseBridgeCommandError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Invalid bridge command '{}'", self.0)
+    }
+}
+
+impl Error for ParseBridgeCommandError {}
+
+impl FromStr for BridgeCommand {
+    type Err = ParseBridgeCommandError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (command, argument) = split_first_word(s);
+        match (command, argument) {
+            ("rescan", "") => Ok(Self::Rescan),
+            ("reconnect", sensor) if !sensor.is_empty() => Ok(Self::Reconnect(sensor.to_owned())),
+            ("download-history", sensor) if !sensor.is_empty() => {
+                Ok(Self::DownloadHistory(sensor.to_owned()))
+            }
+            ("dump-state", "") => Ok(Self::DumpState),
+            ("rename", argument) => {
+                let (mac_address, name) = split_first_word(argument);
+                match mac_address.parse() {
+                    Ok(mac_address) if !name.is_empty() && !name.contains('\n') => {
+                        Ok(Self::Rename {
+                            mac_address,
+                            name: name.to_owned(),
+                        })
+                    }
+                    _ => Err(ParseBridgeCommandError(s.to_owned())),
+                }
+            }
+            _ => Err(ParseBridgeCommandError(s.to_owned())),
+        }
+    }
+}
+
+/// Split the given string into its first word and the rest, with surrounding whitespace trimmed.
+fn split_first_word(s: &str) -> (&str, &str) {
+    match s.find(char::is_whitespace) {
+        Some(index) => (&s[..index], s[index..].trim()),
+        None => (s, ""),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!("rescan".parse(), Ok(BridgeCommand::Rescan));
+        assert_eq!(
+            "reconnect Living room".parse(),
+            Ok(BridgeCommand::Reconnect("Living room".to_owned()))
+        );
+        assert_eq!(
+            " download-history A4:C1:38:D7:21:17 ".parse(),
+            Ok(BridgeCommand::DownloadHistory(
+                "A4:C1:38:D7:21:17".to_owned()
+            ))
+        );
+        assert_eq!("dump-state".parse(), Ok(BridgeCommand::DumpState));
+        assert_eq!(
+            "rename a4:c1:38:d7:21:17 Living room".parse(),
+            Ok(BridgeCommand::Rename {
+                mac_address: "A4:C1:38:D7:21:17".parse().unwrap(),
+                name: "Living room".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn parse_invalid_commands() {
+        assert_eq!(
+            "reconnect".parse::<BridgeCommand>(),
+            Err(ParseBridgeCommandError("reconnect".to_owned()))
+        );
+        assert_eq!(
+            "rescan now".parse::<BridgeCommand>(),
+            Err(ParseBridgeCommandError("rescan now".to_owned()))
+        );
+        assert_eq!(
+            "rename Living room".parse::<BridgeCommand>(),
+            Err(ParseBridgeCommandError("rename Living room".to_owned()))
+        );
+        assert_eq!(
+            "rename A4:C1:38:D7:21:17".parse::<BridgeCommand>(),
+            Err(ParseBridgeCommandError(
+                "rename A4:C1:38:D7:21:17".to_owned()
+            ))
+        );
+        assert_eq!(
+            "explode".parse::<BridgeCommand>(),
+            Err(ParseBridgeCommandError("explode".to_owned()))
+        );
+    }
+}
@@ -0,0 +1,112 @@
+//! Tracking the minimum, maximum and mean of each sensor's readings over the current day, so that
+//! simple dashboards can show them without needing a time-series database.
+
+use chrono::NaiveDate;
+use mijia::Readings;
+
+/// The minimum, maximum and mean of a single value.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Statistic {
+    pub min: f32,
+    pub max: f32,
+    sum: f32,
+    count: u32,
+}
+
+impl Statistic {
+    fn new(value: f32) -> Self {
+        Self {
+            min: value,
+            max: value,
+            sum: value,
+            count: 1,
+        }
+    }
+
+    fn add(&mut self, value: f32) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value;
+        self.count += 1;
+    }
+
+    pub fn mean(&self) -> f32 {
+        self.sum / self.count as f32
+    }
+}
+
+/// Statistics of the temperature and humidity readings from a sensor on a single day.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DailyStats {
+    pub date: NaiveDate,
+    pub temperature: Statistic,
+    pub humidity: Statistic,
+}
+
+impl DailyStats {
+    fn new(date: NaiveDate, readings: &Readings) -> Self {
+        Self {
+            date,
+            temperature: Statistic::new(readings.temperature),
+            humidity: Statistic::new(readings.humidity.into()),
+        }
+    }
+
+    /// Update the given statistics with readings received on the given date, resetting them if it
+    /// is a different day to the existing statistics.
+    pub fn update(stats: &mut Option<DailyStats>, date: NaiveDate, readings: &Readings) {
+        match stats {
+            Some(stats) if stats.date == date => {
+                stats.temperature.add(readings.temperature);
+                stats.humidity.add(readings.humidity.into());
+            }
+            _ => *stats = Some(DailyStats::new(date, readings)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mijia::test_utils::readings;
+
+    #[test]
+    fn update_same_day() {
+        let date = NaiveDate::from_ymd(2020, 11, 1);
+        let mut stats = None;
+        DailyStats::update(&mut stats, date, &readings(20.0, 40, 3000));
+        DailyStats::update(&mut stats, date, &readings(24.0, 50, 3000));
+        DailyStats::update(&mut stats, date, &readings(22.0, 60, 3000));
+
+        let stats = stats.unwrap();
+        assert_eq!(stats.temperature.min, 20.0);
+        assert_eq!(stats.temperature.max, 24.0);
+        assert_eq!(stats.temperature.mean(), 22.0);
+        assert_eq!(stats.humidity.min, 40.0);
+        assert_eq!(stats.humidity.max, 60.0);
+        assert_eq!(stats.humidity.mean(), 50.0);
+    }
+
+    #[test]
+    fn update_resets_on_new_day() {
+        let mut stats = None;
+        DailyStats::update(
+            &mut stats,
+            NaiveDate::from_ymd(2020, 11, 1),
+            &readings(20.0, 40, 3000),
+        );
+        DailyStats::update(
+            &mut stats,
+            NaiveDate::from_ymd(2020, 11, 2),
+            &readings(24.0, 50, 3000),
+        );
+
+        assert_eq!(
+            stats,
+            Some(DailyStats::new(
+                NaiveDate::from_ymd(2020, 11, 2),
+                &readings(24.0, 50, 3000)
+            ))
+        );
+    }
+}
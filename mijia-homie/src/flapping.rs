@@ -0,0 +1,127 @@
+//! Detecting sensors which keep connecting and then dropping straight away, such as those with a
+//! failing battery, so that they can be left alone for a while rather than tying up the connection
+//! loop indefinitely.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// A connection which drops within this long counts towards the sensor flapping.
+const SHORT_CONNECTION: Duration = Duration::from_secs(60);
+/// How far back to count short connections.
+const FLAP_WINDOW: Duration = Duration::from_secs(15 * 60);
+/// How many short connections within `FLAP_WINDOW` mean that the sensor is flapping.
+const FLAP_THRESHOLD: usize = 3;
+/// How long to wait before trying to connect to a flapping sensor again.
+const QUARANTINE_DURATION: Duration = Duration::from_secs(30 * 60);
+
+/// Tracks the recent connections of a single sensor to decide whether it should be quarantined.
+#[derive(Clone, Debug, Default)]
+pub struct FlapDetector {
+    /// When the sensor last connected, if it is connected.
+    connected_at: Option<Instant>,
+    /// When each recent short connection dropped.
+    short_connections: VecDeque<Instant>,
+    /// When the sensor may next be connected to, if it has been quarantined.
+    quarantined_until: Option<Instant>,
+}
+
+impl FlapDetector {
+    /// Record that the sensor connected at the given time.
+    pub fn connected(&mut self, now: Instant) {
+        self.connected_at = Some(now);
+    }
+
+    /// Record that the sensor disconnected at the given time. Returns true if this has caused it to
+    /// be quarantined.
+    pub fn disconnected(&mut self, now: Instant) -> bool {
+        let connected_at = match self.connected_at.take() {
+            Some(connected_at) => connected_at,
+            None => return false,
+        };
+        if now.duration_since(connected_at) >= SHORT_CONNECTION {
+            return false;
+        }
+        self.short_connections.push_back(now);
+        while let Some(&oldest) = self.short_connections.front() {
+            if now.duration_since(oldest) > FLAP_WINDOW {
+                self.short_connections.pop_front();
+            } else {
+                break;
+            }
+        }
+        if self.short_connections.len() >= FLAP_THRESHOLD {
+            self.short_connections.clear();
+            self.quarantined_until = Some(now + QUARANTINE_DURATION);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns whether the sensor is quarantined at the given time, so shouldn't be connected to.
+    pub fn is_quarantined(&self, now: Instant) -> bool {
+        match self.quarantined_until {
+            Some(quarantined_until) => now < quarantined_until,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Connect and disconnect the detector at the given offsets in seconds from `start`, returning
+    /// whether the last disconnection caused it to be quarantined.
+    fn connections(detector: &mut FlapDetector, start: Instant, times: &[(u64, u64)]) -> bool {
+        let mut quarantined = false;
+        for &(connected, disconnected) in times {
+            detector.connected(start + Duration::from_secs(connected));
+            quarantined = detector.disconnected(start + Duration::from_secs(disconnected));
+        }
+        quarantined
+    }
+
+    #[test]
+    fn long_connections_not_quarantined() {
+        let mut detector = FlapDetector::default();
+        let start = Instant::now();
+        assert!(!connections(
+            &mut detector,
+            start,
+            &[(0, 100), (110, 300), (310, 400)]
+        ));
+        assert!(!detector.is_quarantined(start + Duration::from_secs(400)));
+    }
+
+    #[test]
+    fn repeated_short_connections_quarantined() {
+        let mut detector = FlapDetector::default();
+        let start = Instant::now();
+        assert!(!connections(&mut detector, start, &[(0, 5), (10, 15)]));
+        assert!(connections(&mut detector, start, &[(20, 25)]));
+        assert!(detector.is_quarantined(start + Duration::from_secs(25)));
+        assert!(!detector.is_quarantined(start + Duration::from_secs(25) + QUARANTINE_DURATION));
+    }
+
+    #[test]
+    fn old_short_connections_forgotten() {
+        let mut detector = FlapDetector::default();
+        let start = Instant::now();
+        assert!(!connections(
+            &mut detector,
+            start,
+            &[(0, 5), (10, 15), (1000, 1005)]
+        ));
+        assert!(!detector.is_quarantined(start + Duration::from_secs(1005)));
+    }
+
+    #[test]
+    fn disconnection_without_connection_ignored() {
+        let mut detector = FlapDetector::default();
+        let start = Instant::now();
+        for seconds in 0..5 {
+            assert!(!detector.disconnected(start + Duration::from_secs(seconds)));
+        }
+    }
+}
@@ -0,0 +1,245 @@
+//! Sending readings and history records to Graphite over the Carbon plaintext protocol, for users
+//! with an existing Graphite and Grafana stack who don't want MQTT in the middle.
+
+use crate::SensorState;
+use mijia::{HistoryRecord, MacAddress, MijiaEvent, Readings};
+use stable_eyre::eyre;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::RecvError;
+use tokio::sync::Mutex;
+
+/// The port of the Carbon plaintext protocol, if `GRAPHITE_ADDRESS` doesn't include one.
+pub const DEFAULT_PORT: u16 = 2003;
+/// The template for metric paths, if `GRAPHITE_PATH_TEMPLATE` isn't set.
+pub const DEFAULT_PATH_TEMPLATE: &str = "mijia.{name}.{property}";
+
+/// Configuration for sending metrics to Graphite.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Graphite {
+    /// The host and port of the Carbon daemon.
+    pub address: String,
+    /// The template for the path of each metric. `{name}`, `{mac}`, `{location}` and `{property}`
+    /// are replaced with the sensor's name, MAC address and location and the name of the value.
+    pub path_template: String,
+}
+
+/// The sensor which a set of values came from, for filling in a path template.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct SensorLabels {
+    name: String,
+    mac_address: MacAddress,
+    location: Option<String>,
+}
+
+impl Graphite {
+    /// Send every readings and history record event from the bridge to Graphite, until the bridge
+    /// stops. If Graphite can't be reached then values are dropped, and it is reconnected to when
+    /// the next values arrive.
+    pub async fn run(&self, state: Arc<Mutex<SensorState>>) -> Result<(), eyre::Report> {
+        tracing::info!("Sending metrics to Graphite at {}", self.address);
+        let mut events = state.lock().await.events.subscribe();
+        let mut stream: Option<TcpStream> = None;
+        loop {
+            let (mac_address, event) = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Graphite missed {} events.", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            };
+            let labels = sensor_labels(&*state.lock().await, mac_address);
+            let lines = match event {
+                MijiaEvent::Readings { readings, time, .. } => {
+                    self.readings_lines(&labels, &readings, time)
+                }
+                MijiaEvent::HistoryRecord { record, .. } => {
+                    self.history_record_lines(&labels, &record)
+                }
+                _ => continue,
+            };
+
+            if stream.is_none() {
+                match TcpStream::connect(&self.address).await {
+                    Ok(new_stream) => stream = Some(new_stream),
+                    Err(e) => {
+                        tracing::warn!("Failed to connect to Graphite at {}: {}", self.address, e);
+                        continue;
+                    }
+                }
+            }
+            if let Some(connected) = &mut stream {
+                if let Err(e) = connected.write_all(lines.as_bytes()).await {
+                    tracing::warn!("Failed to send metrics to Graphite: {}", e);
+                    stream = None;
+                }
+            }
+        }
+    }
+
+    /// Format the given readings as lines of the Carbon plaintext protocol.
+    fn readings_lines(
+        &self,
+        labels: &SensorLabels,
+        readings: &Readings,
+        time: SystemTime,
+    ) -> String {
+        let timestamp = unix_timestamp(time);
+        [
+            ("temperature", format!("{:.2}", readings.temperature)),
+            ("humidity", readings.humidity.to_string()),
+            ("battery", readings.battery_percent.to_string()),
+            ("battery_voltage", readings.battery_voltage.to_string()),
+        ]
+        .iter()
+        .map(|(property, value)| {
+            format!(
+                "{} {} {}\n",
+                self.metric_path(labels, property),
+                value,
+                timestamp
+            )
+        })
+        .collect()
+    }
+
+    /// Format the given history record as lines of the Carbon plaintext protocol, with the time at
+    /// which the sensor recorded it.
+    fn history_record_lines(&self, labels: &SensorLabels, record: &HistoryRecord) -> String {
+        let timestamp = unix_timestamp(record.time);
+        [
+            ("temperature_min", format!("{:.1}", record.temperature_min)),
+            ("temperature_max", format!("{:.1}", record.temperature_max)),
+            ("humidity_min", record.humidity_min.to_string()),
+            ("humidity_max", record.humidity_max.to_string()),
+        ]
+        .iter()
+        .map(|(property, value)| {
+            format!(
+                "{} {} {}\n",
+                self.metric_path(labels, property),
+                value,
+                timestamp
+            )
+        })
+        .collect()
+    }
+
+    /// Fill in the path template for the given property of the given sensor.
+    fn metric_path(&self, labels: &SensorLabels, property: &str) -> String {
+        self.path_template
+            .replace("{name}", &path_component(&labels.name))
+            .replace("{mac}", &path_component(&labels.mac_address.to_string()))
+            .replace(
+                "{location}",
+                &path_component(labels.location.as_deref().unwrap_or("unknown")),
+            )
+            .replace("{property}", property)
+    }
+}
+
+/// Get the name and location of the sensor with the given MAC address.
+fn sensor_labels(state: &SensorState, mac_address: MacAddress) -> SensorLabels {
+    SensorLabels {
+        name: state
+            .sensor_names
+            .get(&mac_address)
+            .cloned()
+            .unwrap_or_else(|| mac_address.to_string()),
+        mac_address,
+        location: state
+            .publish_options
+            .sensor_locations
+            .get(&mac_address)
+            .cloned(),
+    }
+}
+
+/// Replace any characters which would break up a Graphite metric path, such as dots and spaces,
+/// with underscores.
+fn path_component(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Convert the given time to a number of seconds since the Unix epoch.
+fn unix_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn labels() -> SensorLabels {
+        SensorLabels {
+            name: "Living room.1".to_owned(),
+            mac_address: "A4:C1:38:D7:21:17".parse().unwrap(),
+            location: Some("Down stairs".to_owned()),
+        }
+    }
+
+    #[test]
+    fn path_template() {
+        let graphite = Graphite {
+            address: "localhost:2003".to_owned(),
+            path_template: "home.{location}.{name}.{mac}.{property}".to_owned(),
+        };
+        assert_eq!(
+            graphite.metric_path(&labels(), "temperature"),
+            "home.Down_stairs.Living_room_1.A4_C1_38_D7_21_17.temperature"
+        );
+    }
+
+    #[test]
+    fn readings_and_history() {
+        let graphite = Graphite {
+            address: "localhost:2003".to_owned(),
+            path_template: DEFAULT_PATH_TEMPLATE.to_owned(),
+        };
+        let time = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let readings = Readings {
+            temperature: 21.5,
+            humidity: 45,
+            battery_voltage: 3000,
+            battery_percent: 90,
+        };
+        assert_eq!(
+            graphite.readings_lines(&labels(), &readings, time),
+            "mijia.Living_room_1.temperature 21.50 1600000000\n\
+             mijia.Living_room_1.humidity 45 1600000000\n\
+             mijia.Living_room_1.battery 90 1600000000\n\
+             mijia.Living_room_1.battery_voltage 3000 1600000000\n"
+        );
+
+        let record = HistoryRecord {
+            index: 42,
+            time,
+            raw_time: time,
+            temperature_min: 19.0,
+            temperature_max: 22.5,
+            humidity_min: 40,
+            humidity_max: 55,
+        };
+        assert_eq!(
+            graphite.history_record_lines(&labels(), &record),
+            "mijia.Living_room_1.temperature_min 19.0 1600000000\n\
+             mijia.Living_room_1.temperature_max 22.5 1600000000\n\
+             mijia.Living_room_1.humidity_min 40 1600000000\n\
+             mijia.Living_room_1.humidity_max 55 1600000000\n"
+        );
+    }
+}
@@ -0,0 +1,319 @@
+//! An optional gRPC server which streams events from sensors and allows their settings to be read
+//! and changed, for other services on the network to integrate with without going through MQTT.
+
+use crate::{ConnectionStatus, SensorState};
+use futures::Stream;
+use hyper::server::conn::Http;
+use mijia::{ComfortLevel, DeviceId, MacAddress, MijiaEvent, MijiaSession, TemperatureUnit};
+use stable_eyre::eyre;
+use std::convert::TryInto;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::RecvError;
+use tokio::sync::Mutex;
+use tonic::{Request, Response, Status};
+
+mod proto {
+    tonic::include_proto!("mijia_homie");
+}
+
+use proto::bridge_server::{Bridge, BridgeServer};
+use proto::sensor_event::Event;
+
+/// Serve the gRPC API on the given address.
+pub async fn serve(
+    address: SocketAddr,
+    state: Arc<Mutex<SensorState>>,
+    session: MijiaSession,
+) -> Result<(), eyre::Report> {
+    let mut listener = TcpListener::bind(address).await?;
+    tracing::info!("Serving gRPC on {}", address);
+    let server = BridgeServer::new(BridgeService { state, session });
+    loop {
+        let (stream, peer_address) = listener.accept().await?;
+        let server = server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = Http::new()
+                .with_executor(TokioExecutor)
+                .http2_only(true)
+                .serve_connection(stream, server)
+                .await
+            {
+                tracing::warn!("Error serving gRPC to {}: {:?}", peer_address, e);
+            }
+        });
+    }
+}
+
+/// Runs the futures for HTTP/2 streams on the Tokio runtime.
+#[derive(Clone, Copy, Debug)]
+struct TokioExecutor;
+
+impl<F> hyper::rt::Executor<F> for TokioExecutor
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    fn execute(&self, future: F) {
+        tokio::spawn(future);
+    }
+}
+
+struct BridgeService {
+    state: Arc<Mutex<SensorState>>,
+    session: MijiaSession,
+}
+
+impl BridgeService {
+    /// Find the ID of the connected sensor with the given MAC address.
+    async fn connected_sensor(&self, mac_address: &str) -> Result<DeviceId, Status> {
+        let mac_address: MacAddress = mac_address
+            .parse()
+            .map_err(|_| Status::invalid_argument("Invalid MAC address"))?;
+        let state = self.state.lock().await;
+        let sensor = state
+            .sensors
+            .values()
+            .find(|sensor| sensor.mac_address == mac_address)
+            .ok_or_else(|| Status::not_found("Unknown sensor"))?;
+        if sensor.connection_status != ConnectionStatus::Connected {
+            return Err(Status::failed_precondition("Sensor is not connected"));
+        }
+        Ok(sensor.id.clone())
+    }
+}
+
+#[tonic::async_trait]
+impl Bridge for BridgeService {
+    type StreamEventsStream =
+        Pin<Box<dyn Stream<Item = Result<proto::SensorEvent, Status>> + Send + Sync>>;
+
+    async fn stream_events(
+        &self,
+        _request: Request<proto::StreamEventsRequest>,
+    ) -> Result<Response<Self::StreamEventsStream>, Status> {
+        let events = self.state.lock().await.events.subscribe();
+        let stream = futures::stream::unfold(events, |mut events| async move {
+            loop {
+                match events.recv().await {
+                    Ok((mac_address, event)) => {
+                        if let Some(event) = sensor_event(&mac_address, event) {
+                            return Some((Ok(event), events));
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!("gRPC event stream missed {} events.", skipped);
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn get_settings(
+        &self,
+        request: Request<proto::GetSettingsRequest>,
+    ) -> Result<Response<proto::Settings>, Status> {
+        let id = self
+            .connected_sensor(&request.get_ref().mac_address)
+            .await?;
+        let settings = self
+            .session
+            .get_settings(&id)
+            .await
+            .map_err(|e| Status::unavailable(e.to_string()))?;
+        let temperature_unit = match settings.temperature_unit {
+            TemperatureUnit::Celcius => proto::TemperatureUnit::Celsius,
+            TemperatureUnit::Fahrenheit => proto::TemperatureUnit::Fahrenheit,
+        };
+        Ok(Response::new(proto::Settings {
+            time: unix_timestamp(settings.time),
+            temperature_unit: temperature_unit as i32,
+            comfort_level: Some(proto::ComfortLevel {
+                temperature_min: settings.comfort_level.temperature_min,
+                temperature_max: settings.comfort_level.temperature_max,
+                humidity_min: settings.comfort_level.humidity_min.into(),
+                humidity_max: settings.comfort_level.humidity_max.into(),
+            }),
+        }))
+    }
+
+    async fn set_time(
+        &self,
+        request: Request<proto::SetTimeRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let request = request.into_inner();
+        let id = self.connected_sensor(&request.mac_address).await?;
+        let time = if request.time == 0 {
+            SystemTime::now()
+        } else if request.time > 0 {
+            UNIX_EPOCH + Duration::from_secs(request.time as u64)
+        } else {
+            return Err(Status::invalid_argument("Time must not be before 1970"));
+        };
+        self.session
+            .set_time(&id, time)
+            .await
+            .map_err(|e| Status::unavailable(e.to_string()))?;
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn set_temperature_unit(
+        &self,
+        request: Request<proto::SetTemperatureUnitRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let request = request.into_inner();
+        let id = self.connected_sensor(&request.mac_address).await?;
+        let unit = match proto::TemperatureUnit::from_i32(request.temperature_unit) {
+            Some(proto::TemperatureUnit::Celsius) => TemperatureUnit::Celcius,
+            Some(proto::TemperatureUnit::Fahrenheit) => TemperatureUnit::Fahrenheit,
+            None => return Err(Status::invalid_argument("Invalid temperature unit")),
+        };
+        self.session
+            .set_temperature_unit(&id, unit)
+            .await
+            .map_err(|e| Status::unavailable(e.to_string()))?;
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn set_comfort_level(
+        &self,
+        request: Request<proto::SetComfortLevelRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let request = request.into_inner();
+        let id = self.connected_sensor(&request.mac_address).await?;
+        let comfort_level = request
+            .comfort_level
+            .and_then(comfort_level_from_proto)
+            .ok_or_else(|| Status::invalid_argument("Invalid comfort level"))?;
+        self.session
+            .set_comfort_level(&id, &comfort_level)
+            .await
+            .map_err(|e| Status::unavailable(e.to_string()))?;
+        Ok(Response::new(proto::Empty {}))
+    }
+}
+
+/// Convert the given event to a protobuf message, if it is one which is streamed.
+fn sensor_event(mac_address: &MacAddress, event: MijiaEvent) -> Option<proto::SensorEvent> {
+    let event = match event {
+        MijiaEvent::Readings { readings, time, .. } => Event::Readings(proto::Readings {
+            temperature: readings.temperature,
+            humidity: readings.humidity.into(),
+            battery_voltage: readings.battery_voltage.into(),
+            battery_percent: readings.battery_percent.into(),
+            time: unix_timestamp(time),
+        }),
+        MijiaEvent::HistoryRecord { record, .. } => Event::HistoryRecord(proto::HistoryRecord {
+            index: record.index,
+            time: unix_timestamp(record.time),
+            temperature_min: record.temperature_min,
+            temperature_max: record.temperature_max,
+            humidity_min: record.humidity_min.into(),
+            humidity_max: record.humidity_max.into(),
+        }),
+        MijiaEvent::Disconnected { .. } => Event::Disconnected(proto::Disconnected {}),
+        _ => return None,
+    };
+    Some(proto::SensorEvent {
+        mac_address: mac_address.to_string(),
+        event: Some(event),
+    })
+}
+
+/// Convert a comfort level from a protobuf message, if it is valid.
+fn comfort_level_from_proto(comfort_level: proto::ComfortLevel) -> Option<ComfortLevel> {
+    ComfortLevel::new(
+        comfort_level.temperature_min,
+        comfort_level.temperature_max,
+        comfort_level.humidity_min.try_into().ok()?,
+        comfort_level.humidity_max.try_into().ok()?,
+    )
+    .ok()
+}
+
+/// Convert the given time to a number of seconds since the Unix epoch.
+fn unix_timestamp(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mijia::{HistoryRecord, Readings, ReadingsSource};
+
+    #[test]
+    fn readings_event() {
+        let mac_address: MacAddress = "A4:C1:38:D7:21:17".parse().unwrap();
+        let event = MijiaEvent::Readings {
+            id: DeviceId::new("/org/bluez/hci0/dev_A4_C1_38_D7_21_17"),
+            mac_address,
+            readings: Readings {
+                temperature: 21.5,
+                humidity: 45,
+                battery_voltage: 3000,
+                battery_percent: 90,
+            },
+            time: UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+            source: ReadingsSource::Notification,
+        };
+        assert_eq!(
+            sensor_event(&mac_address, event),
+            Some(proto::SensorEvent {
+                mac_address: "A4:C1:38:D7:21:17".to_owned(),
+                event: Some(Event::Readings(proto::Readings {
+                    temperature: 21.5,
+                    humidity: 45,
+                    battery_voltage: 3000,
+                    battery_percent: 90,
+                    time: 1_600_000_000,
+                })),
+            })
+        );
+    }
+
+    #[test]
+    fn history_record_event() {
+        let mac_address: MacAddress = "A4:C1:38:D7:21:17".parse().unwrap();
+        let event = MijiaEvent::HistoryRecord {
+            id: DeviceId::new("/org/bluez/hci0/dev_A4_C1_38_D7_21_17"),
+            mac_address,
+            record: HistoryRecord {
+                index: 42,
+                time: UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+                raw_time: UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+                temperature_min: 19.0,
+                temperature_max: 22.5,
+                humidity_min: 40,
+                humidity_max: 55,
+            },
+        };
+        match sensor_event(&mac_address, event).unwrap().event {
+            Some(Event::HistoryRecord(record)) => {
+                assert_eq!(record.index, 42);
+                assert_eq!(record.time, 1_600_000_000);
+                assert_eq!(record.humidity_max, 55);
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn invalid_comfort_level() {
+        let comfort_level = proto::ComfortLevel {
+            temperature_min: 19.0,
+            temperature_max: 24.0,
+            humidity_min: 40,
+            humidity_max: 300,
+        };
+        assert_eq!(comfort_level_from_proto(comfort_level), None);
+    }
+}
@@ -0,0 +1,116 @@
+//! Reports of the health of the bridge, so that external monitoring can notice if it has silently
+//! stopped working even though its Homie device still looks fine.
+
+use mijia::bluetooth::AdapterInfo;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+/// Counters and timestamps tracked to report on the health of the bridge.
+#[derive(Clone, Debug, Default)]
+pub struct Health {
+    /// When the last event was received from any sensor, if ever.
+    pub last_event: Option<Instant>,
+    /// The number of events which have been received.
+    pub events: u64,
+    /// The number of times the D-Bus connection has been re-established or BlueZ has restarted.
+    pub bus_resets: u64,
+    /// The number of failed attempts to connect to a sensor.
+    pub connect_failures: u64,
+    /// The number of times a connected sensor has disconnected.
+    pub disconnections: u64,
+    /// The number of times a sensor has been disconnected because it stopped sending updates.
+    pub stale_timeouts: u64,
+    /// The number of times discovery has been restarted after an adapter stopped it unexpectedly.
+    pub discovery_restarts: u64,
+    /// The number of readings which have been dropped because they were implausible.
+    pub implausible_readings: u64,
+}
+
+/// A snapshot of the health of the bridge, to be published as JSON.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct HealthReport {
+    /// The number of sensors which the bridge knows about.
+    pub sensors_known: usize,
+    /// The number of sensors which are currently connected.
+    pub sensors_connected: usize,
+    /// How many seconds ago the last event was received from any sensor, if ever.
+    pub last_event_age_seconds: Option<u64>,
+    /// Whether each Bluetooth adapter is powered on, by adapter name.
+    pub adapters_powered: BTreeMap<String, bool>,
+    /// The number of failed attempts to connect to a sensor since the bridge started.
+    pub connect_failures: u64,
+    /// The number of times a connected sensor has disconnected since the bridge started.
+    pub disconnections: u64,
+    /// The number of times a sensor has been disconnected because it stopped sending updates since
+    /// the bridge started.
+    pub stale_timeouts: u64,
+    /// The number of times discovery has been restarted after an adapter stopped it unexpectedly
+    /// since the bridge started.
+    pub discovery_restarts: u64,
+    /// The number of readings which have been dropped because they were implausible since the
+    /// bridge started.
+    pub implausible_readings: u64,
+}
+
+impl Health {
+    /// Construct a report of the current health of the bridge.
+    pub fn report(
+        &self,
+        now: Instant,
+        sensors_known: usize,
+        sensors_connected: usize,
+        adapters: &[AdapterInfo],
+    ) -> HealthReport {
+        HealthReport {
+            sensors_known,
+            sensors_connected,
+            last_event_age_seconds: self
+                .last_event
+                .map(|last_event| now.duration_since(last_event).as_secs()),
+            adapters_powered: adapters
+                .iter()
+                .map(|adapter| (adapter.id.to_string(), adapter.powered))
+                .collect(),
+            connect_failures: self.connect_failures,
+            disconnections: self.disconnections,
+            stale_timeouts: self.stale_timeouts,
+            discovery_restarts: self.discovery_restarts,
+            implausible_readings: self.implausible_readings,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mijia::AdapterId;
+    use std::time::Duration;
+
+    #[test]
+    fn report_json() {
+        let now = Instant::now();
+        let health = Health {
+            last_event: Some(now - Duration::from_secs(5)),
+            events: 42,
+            bus_resets: 0,
+            connect_failures: 2,
+            disconnections: 1,
+            stale_timeouts: 0,
+            discovery_restarts: 1,
+            implausible_readings: 4,
+        };
+        let adapters = vec![AdapterInfo {
+            id: AdapterId::new("/org/bluez/hci0"),
+            mac_address: "00:11:22:33:44:55".parse().unwrap(),
+            powered: true,
+            discovering: false,
+        }];
+
+        let report = health.report(now, 3, 2, &adapters);
+        assert_eq!(
+            serde_json::to_string(&report).unwrap(),
+            r#"{"sensors_known":3,"sensors_connected":2,"last_event_age_seconds":5,"adapters_powered":{"hci0":true},"connect_failures":2,"disconnections":1,"stale_timeouts":0,"discovery_restarts":1,"implausible_readings":4}"#
+        );
+    }
+}
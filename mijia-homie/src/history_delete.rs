@@ -0,0 +1,82 @@
+//! Deleting the history stored on a sensor on demand, when a controller sets the sensor node's
+//! `delete-history` property. This can't be undone, so the value set must be the sensor's MAC
+//! address to confirm that it is the right sensor, rather than anything a careless or retained
+//! message might contain.
+
+use crate::{ConnectionStatus, Sensor, SensorState};
+use mijia::{DeviceId, MacAddress, MijiaSession};
+use tokio::sync::Mutex;
+
+/// Published to the `delete-history` property once the sensor's history has been deleted.
+const STATUS_DELETED: &str = "deleted";
+/// Published to the `delete-history` property if the value set wasn't the sensor's MAC address.
+const STATUS_UNCONFIRMED: &str = "unconfirmed";
+/// Published to the `delete-history` property if deleting the sensor's history failed.
+const STATUS_FAILED: &str = "failed";
+
+/// Delete all history records stored on the sensor with the given ID, if `confirmation` is its MAC
+/// address, and publish the outcome to its `delete-history` property.
+#[tracing::instrument(skip(state, session, id), fields(device = %id))]
+pub async fn delete_history(
+    state: &Mutex<SensorState>,
+    session: &MijiaSession,
+    id: &DeviceId,
+    confirmation: &str,
+) {
+    let node_id = {
+        let state = state.lock().await;
+        let sensor = match state.sensors.get(id) {
+            Some(sensor) => sensor,
+            None => return,
+        };
+        if !is_confirmed(confirmation, &sensor.mac_address) {
+            tracing::warn!(sensor = %sensor.name, mac = %sensor.mac_address, "Not deleting history, as '{}' isn't the sensor's MAC address", confirmation);
+            publish_status(&state, &sensor.node_id(), STATUS_UNCONFIRMED);
+            return;
+        }
+        if sensor.connection_status != ConnectionStatus::Connected {
+            tracing::warn!(sensor = %sensor.name, mac = %sensor.mac_address, "Can't delete history while not connected");
+            publish_status(&state, &sensor.node_id(), STATUS_FAILED);
+            return;
+        }
+        sensor.node_id()
+    };
+
+    let status = match session.delete_history(id).await {
+        Ok(()) => {
+            tracing::info!("Deleted history from {:?}", id);
+            STATUS_DELETED
+        }
+        Err(e) => {
+            tracing::error!("Failed to delete history from {:?}: {:?}", id, e);
+            STATUS_FAILED
+        }
+    };
+    publish_status(&*state.lock().await, &node_id, status);
+}
+
+/// Whether the given value confirms deleting the history of the sensor with the given MAC address.
+fn is_confirmed(confirmation: &str, mac_address: &MacAddress) -> bool {
+    confirmation.trim().parse::<MacAddress>().ok().as_ref() == Some(mac_address)
+}
+
+fn publish_status(state: &SensorState, node_id: &str, status: &str) {
+    state
+        .homie
+        .publish_value(node_id, Sensor::PROPERTY_ID_DELETE_HISTORY, status);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn confirmation() {
+        let mac_address: MacAddress = "A4:C1:38:D7:21:17".parse().unwrap();
+        assert!(is_confirmed("A4:C1:38:D7:21:17", &mac_address));
+        assert!(is_confirmed(" a4c138d72117\n", &mac_address));
+        assert!(!is_confirmed("A4:C1:38:D7:21:18", &mac_address));
+        assert!(!is_confirmed("true", &mac_address));
+        assert!(!is_confirmed("", &mac_address));
+    }
+}
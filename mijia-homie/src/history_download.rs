@@ -0,0 +1,136 @@
+//! Downloading all history stored on a sensor on demand, when a controller sets the sensor node's
+//! `download-history` property or sends the bridge a `download-history` command.
+
+use crate::history_sync::wait_for_history;
+use crate::{ConnectionStatus, Sensor, SensorState};
+use mijia::{DeviceId, MijiaSession};
+use stable_eyre::eyre;
+use std::fmt::{self, Display, Formatter};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::Instrument;
+
+/// The value which a controller sets the `download-history` property to in order to start a
+/// download.
+pub const DOWNLOAD_HISTORY_REQUEST: &str = "download";
+
+/// The state of a requested history download, which is published as the value of the sensor's
+/// `download-history` property.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DownloadStatus {
+    /// The records have been requested, and are being received.
+    Downloading,
+    /// All the records stored on the sensor have been received.
+    Complete,
+    /// Records stopped arriving before the last one was received.
+    Incomplete,
+    /// The download couldn't be started.
+    Failed,
+}
+
+impl DownloadStatus {
+    /// All possible values, including the one to request a download, for the format of a Homie enum
+    /// property.
+    pub const VALUES: &'static [&'static str] = &[
+        DOWNLOAD_HISTORY_REQUEST,
+        "downloading",
+        "complete",
+        "incomplete",
+        "failed",
+    ];
+}
+
+impl Display for DownloadStatus {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Downloading => "downloading",
+            Self::Complete => "complete",
+            Self::Incomplete => "incomplete",
+            Self::Failed => "failed",
+        })
+    }
+}
+
+/// Start downloading all history records stored on the sensor with the given ID in a new task, so
+/// that other requests from controllers can be handled in the meantime.
+pub fn spawn_history_download(
+    state: Arc<Mutex<SensorState>>,
+    session: &MijiaSession,
+    id: DeviceId,
+) {
+    let session = session.clone();
+    let span = tracing::info_span!("download_history", device = %id);
+    tokio::spawn(async move { download_history(&state, &session, &id).await }.instrument(span));
+}
+
+/// Download all history records stored on the sensor with the given ID, publishing the status of the
+/// download to its `download-history` property. The records are stored and published by the event
+/// loop as they arrive, so this only waits until the last of them has been received.
+async fn download_history(state: &Mutex<SensorState>, session: &MijiaSession, id: &DeviceId) {
+    let node_id = {
+        let state = state.lock().await;
+        let sensor = match state.sensors.get(id) {
+            Some(sensor) => sensor,
+            None => return,
+        };
+        if state.store.is_none() && !state.publish_options.history {
+            tracing::warn!("Can't download history without SQLITE_FILENAME or PUBLISH_HISTORY set");
+            publish_status(&state, &sensor.node_id(), DownloadStatus::Failed);
+            return;
+        }
+        if sensor.connection_status != ConnectionStatus::Connected {
+            tracing::warn!(sensor = %sensor.name, mac = %sensor.mac_address, "Can't download history while not connected");
+            publish_status(&state, &sensor.node_id(), DownloadStatus::Failed);
+            return;
+        }
+        let node_id = sensor.node_id();
+        publish_status(&state, &node_id, DownloadStatus::Downloading);
+        node_id
+    };
+
+    let status = match request_history(session, id).await {
+        Ok(last_index) => {
+            if wait_for_history(state, id, last_index).await {
+                DownloadStatus::Complete
+            } else {
+                DownloadStatus::Incomplete
+            }
+        }
+        Err(e) => {
+            tracing::error!("Failed to request history from {:?}: {:?}", id, e);
+            DownloadStatus::Failed
+        }
+    };
+    tracing::info!("History download from {:?} {}", id, status);
+    publish_status(&*state.lock().await, &node_id, status);
+}
+
+/// Request all history records stored on the sensor, returning the index of the last one.
+async fn request_history(session: &MijiaSession, id: &DeviceId) -> Result<u32, eyre::Report> {
+    let last_record = session.get_last_history_record(id).await?;
+    session.start_notify_history(id, None).await?;
+    Ok(last_record.index)
+}
+
+fn publish_status(state: &SensorState, node_id: &str, status: DownloadStatus) {
+    state
+        .homie
+        .publish_value(node_id, Sensor::PROPERTY_ID_DOWNLOAD_HISTORY, status);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statuses_are_enum_values() {
+        for status in &[
+            DownloadStatus::Downloading,
+            DownloadStatus::Complete,
+            DownloadStatus::Incomplete,
+            DownloadStatus::Failed,
+        ] {
+            assert!(DownloadStatus::VALUES.contains(&status.to_string().as_str()));
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! Periodically downloading new history records from every connected sensor, so that the configured
+//! outputs get a complete record even of readings which were missed while the bridge was running.
+
+use crate::{ConnectionStatus, SensorState};
+use mijia::{DeviceId, HistoryRecord, MijiaSession};
+use stable_eyre::eyre;
+use std::cmp::max;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::{delay_for, interval};
+
+/// How often to check whether the requested history records have all been received, before deleting
+/// them from the sensor.
+const RECEIVED_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long to wait for the next history record before giving up on receiving the rest.
+const RECEIVED_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Configuration for syncing history from sensors.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HistorySync {
+    /// How often to download new history records from each sensor.
+    pub interval: Duration,
+    /// Whether to delete the history stored on each sensor once all its records have been received
+    /// and stored, to stop its flash from filling up.
+    pub delete_after_sync: bool,
+}
+
+impl HistorySync {
+    /// Request new history records from every connected sensor once per interval, until the bridge
+    /// stops. The records are delivered as events, which are deduplicated and forwarded to the
+    /// outputs by the event loop.
+    pub async fn run(
+        &self,
+        state: Arc<Mutex<SensorState>>,
+        session: &MijiaSession,
+    ) -> Result<(), eyre::Report> {
+        tracing::info!("Syncing history from sensors every {:?}", self.interval);
+        let mut interval = interval(self.interval);
+        // The first tick completes immediately, but sensors will only just have been connected and
+        // backfilled then.
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            self.sync_all(&state, session).await;
+        }
+    }
+
+    /// Request history records which haven't yet been received from each connected sensor.
+    async fn sync_all(&self, state: &Mutex<SensorState>, session: &MijiaSession) {
+        let sensors: Vec<_> = state
+            .lock()
+            .await
+            .sensors
+            .values()
+            .filter(|sensor| sensor.connection_status == ConnectionStatus::Connected)
+            .map(|sensor| {
+                (
+                    sensor.id.clone(),
+                    sensor.name.clone(),
+                    sensor.mac_address,
+                    sensor.last_history_index,
+                )
+            })
+            .collect();
+        for (id, name, mac_address, last_history_index) in sensors {
+            if let Err(e) = self
+                .sync_sensor(state, session, &id, last_history_index)
+                .await
+            {
+                tracing::warn!(sensor = %name, mac = %mac_address, "Failed to sync history: {:?}", e);
+            }
+        }
+    }
+
+    #[tracing::instrument(name = "sync_history", skip(self, state, session, id), fields(device = %id))]
+    async fn sync_sensor(
+        &self,
+        state: &Mutex<SensorState>,
+        session: &MijiaSession,
+        id: &DeviceId,
+        last_history_index: Option<u32>,
+    ) -> Result<(), eyre::Report> {
+        let history_range = session.get_history_range(id).await?;
+        let last_record = session.get_last_history_record(id).await?;
+        if let Some(start_index) =
+            sync_start_index(&history_range, &last_record, last_history_index)
+        {
+            tracing::info!(
+                "Requesting history from record {} to {}",
+                start_index,
+                last_record.index
+            );
+            session.start_notify_history(id, Some(start_index)).await?;
+        }
+        if !self.delete_after_sync {
+            return Ok(());
+        }
+
+        if !wait_for_history(state, id, last_record.index).await {
+            tracing::warn!(
+                "Not all history records were received, so not deleting them from the sensor"
+            );
+            return Ok(());
+        }
+        let unstored_history = state
+            .lock()
+            .await
+            .sensors
+            .get(id)
+            .map(|sensor| sensor.unstored_history);
+        if unstored_history != Some(false) {
+            tracing::warn!(
+                "Some history records failed to be stored, so not deleting them from the sensor"
+            );
+            return Ok(());
+        }
+        if session.delete_history_up_to(id, last_record.index).await? {
+            tracing::info!("Deleted history up to record {}", last_record.index);
+        }
+        Ok(())
+    }
+}
+
+/// Wait until the history record with the given index has been received from the sensor, as long
+/// as records keep arriving. Returns whether it was received.
+pub async fn wait_for_history(state: &Mutex<SensorState>, id: &DeviceId, last_index: u32) -> bool {
+    let mut previous_index = None;
+    let mut waited = Duration::default();
+    loop {
+        let received_index = match state.lock().await.sensors.get(id) {
+            Some(sensor) => sensor.last_history_index,
+            None => return false,
+        };
+        if received_index == Some(last_index) {
+            return true;
+        }
+        if received_index == previous_index {
+            if waited >= RECEIVED_TIMEOUT {
+                return false;
+            }
+            waited += RECEIVED_POLL_INTERVAL;
+        } else {
+            previous_index = received_index;
+            waited = Duration::default();
+        }
+        delay_for(RECEIVED_POLL_INTERVAL).await;
+    }
+}
+
+/// Work out the index of the first history record to request from a sensor with the given range of
+/// records, or `None` if all its records have already been received.
+fn sync_start_index(
+    history_range: &Range<u32>,
+    last_record: &HistoryRecord,
+    last_history_index: Option<u32>,
+) -> Option<u32> {
+    match last_history_index {
+        Some(index) if index == last_record.index => None,
+        Some(index) if index < last_record.index => Some(max(history_range.start, index + 1)),
+        // If nothing has been received yet, or the sensor's history has been cleared since, request
+        // everything and let the event loop drop any records which were already received.
+        _ => Some(history_range.start),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    #[test]
+    fn start_index() {
+        let last_record = HistoryRecord {
+            index: 99,
+            time: SystemTime::now(),
+            raw_time: SystemTime::now(),
+            temperature_min: 20.0,
+            temperature_max: 21.0,
+            humidity_min: 40,
+            humidity_max: 45,
+        };
+        assert_eq!(sync_start_index(&(10..100), &last_record, None), Some(10));
+        assert_eq!(
+            sync_start_index(&(10..100), &last_record, Some(50)),
+            Some(51)
+        );
+        assert_eq!(
+            sync_start_index(&(10..100), &last_record, Some(5)),
+            Some(10)
+        );
+        assert_eq!(sync_start_index(&(10..100), &last_record, Some(99)), None);
+        assert_eq!(
+            sync_start_index(&(10..100), &last_record, Some(150)),
+            Some(10)
+        );
+    }
+}
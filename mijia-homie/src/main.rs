@@ -1,22 +1,96 @@
 #![type_length_limit = "1138969"]
 
+mod adapter_selection;
+mod aggregation;
+mod aws_iot;
+mod azure_iot;
+mod bridge_metrics;
+mod brokers;
+mod bthome_broadcast;
+mod check_config;
+mod commands;
+mod daily_stats;
+mod flapping;
+mod graphite;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod health;
+mod history_delete;
+mod history_download;
+mod history_sync;
+mod messages;
+mod offline_queue;
+mod plausibility;
+#[cfg(feature = "postgres")]
+mod postgres;
+mod rate_limit;
+mod reconnection;
+mod saved_state;
+mod sensor_filter;
+mod sensor_names;
+mod smoothing;
+mod status;
+mod store;
+mod telemetry;
+mod thresholds;
+mod web;
+mod zigbee2mqtt;
+
+use crate::adapter_selection::{device_id_for_adapter, find_adapter, strongest_signal};
+use crate::aggregation::{Aggregation, AggregationMethod, ReadingsWindow};
+use crate::aws_iot::AwsIot;
+use crate::azure_iot::AzureIot;
+use crate::bridge_metrics::{metrics_node, BridgeMetrics, MetricsClock, METRICS_NODE_ID};
+use crate::brokers::{HomieBrokers, Incoming, PreviousNodes};
+use crate::bthome_broadcast::BtHomeBroadcast;
+use crate::commands::BridgeCommand;
+use crate::daily_stats::DailyStats;
+use crate::flapping::FlapDetector;
+use crate::graphite::Graphite;
+use crate::health::Health;
+use crate::history_delete::delete_history;
+use crate::history_download::{spawn_history_download, DownloadStatus, DOWNLOAD_HISTORY_REQUEST};
+use crate::history_sync::HistorySync;
+use crate::plausibility::Plausibility;
+use crate::rate_limit::RateLimit;
+use crate::reconnection::reconnection_order;
+use crate::saved_state::{unix_seconds, ConnectionStats, SavedReadings, SavedSensor, StateFile};
+use crate::sensor_filter::SensorFilter;
+use crate::sensor_names::set_sensor_name;
+use crate::smoothing::{ReadingsFilter, Smoothing, SmoothingMethod};
+use crate::status::{StatusOptions, StatusReport};
+use crate::store::Store;
+use crate::thresholds::{AlarmState, Thresholds, HUMIDITY_HYSTERESIS, TEMPERATURE_HYSTERESIS};
+use crate::web::HistoryRecordJson;
+use crate::zigbee2mqtt::Zigbee2Mqtt;
 use backoff::{future::FutureOperation, ExponentialBackoff};
-use futures::stream::StreamExt;
+use chrono::Local;
+use futures::stream::{StreamExt, TryStreamExt};
 use futures::TryFutureExt;
-use homie_device::{HomieDevice, Node, Property};
-use itertools::Itertools;
-use mijia::{DeviceId, MacAddress, MijiaEvent, MijiaSession, Readings, SensorProps};
+use homie_device::{HomieVersion, Node, Property};
+use itertools::{Either, Itertools};
+use mijia::bluetooth::{AdapterInfo, DEFAULT_MAX_CONCURRENT_CONNECTS};
+use mijia::{
+    AdapterId, BindKey, DeviceId, HistoryRecord, MacAddress, MijiaEvent, MijiaSession, Readings,
+    ReadingsSource, SensorProps,
+};
+use rand::Rng;
 use rumqttc::MqttOptions;
 use rustls::ClientConfig;
 use stable_eyre::eyre;
 use stable_eyre::eyre::WrapErr;
-use std::collections::HashMap;
+use std::cmp::max;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::{BufRead, BufReader};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{broadcast, mpsc, Mutex};
 use tokio::{task, time, try_join};
+use tracing::Instrument;
 
 const DEFAULT_MQTT_PREFIX: &str = "homie";
 const DEFAULT_DEVICE_ID: &str = "mijia-bridge";
@@ -25,68 +99,159 @@ const DEFAULT_HOST: &str = "test.mosquitto.org";
 const DEFAULT_PORT: u16 = 1883;
 const SCAN_INTERVAL: Duration = Duration::from_secs(15);
 const CONNECT_INTERVAL: Duration = Duration::from_secs(1);
+/// How many sensors which haven't been tried yet, such as those found at startup, to connect to at
+/// once.
+const INITIAL_CONNECT_CONCURRENCY: usize = 2;
+/// The maximum random delay before each initial connection attempt, so that they are staggered
+/// rather than all starting in the order the sensors were found.
+const INITIAL_CONNECT_JITTER: Duration = Duration::from_secs(5);
 const UPDATE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often to publish a report of the health of the bridge.
+const HEALTH_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+/// How often to save the state of sensors, if `STATE_FILENAME` is set.
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+/// How many sensor events may be buffered for each event stream before the slowest ones start
+/// missing events.
+const EVENT_STREAM_CAPACITY: usize = 100;
+/// The weight of each new value in the exponential moving average, if `SMOOTHING_ALPHA` isn't set.
+const DEFAULT_SMOOTHING_ALPHA: f32 = 0.3;
+/// How many recent values to take the median of, if `SMOOTHING_SAMPLES` isn't set.
+const DEFAULT_SMOOTHING_SAMPLES: usize = 5;
 // SENSOR_CONNECT_RETRY_TIMEOUT must be smaller than
 // SENSOR_CONNECT_RESERVATION_TIMEOUT by at least a couple of dbus timeouts in
 // order to avoid races.
 const SENSOR_CONNECT_RESERVATION_TIMEOUT: Duration = Duration::from_secs(5 * 60);
 const SENSOR_CONNECT_RETRY_TIMEOUT: Duration = Duration::from_secs(60);
+/// The sensors store a history record once per hour.
+const HISTORY_RECORD_INTERVAL: Duration = Duration::from_secs(60 * 60);
 const SENSOR_NAMES_FILENAME: &str = "sensor_names.conf";
-
+const SENSOR_THRESHOLDS_FILENAME: &str = "sensor_thresholds.conf";
+const SENSOR_LOCATIONS_FILENAME: &str = "sensor_locations.conf";
+const SENSOR_TIMEOUTS_FILENAME: &str = "sensor_timeouts.conf";
+const SENSOR_BIND_KEYS_FILENAME: &str = "sensor_bind_keys.conf";
+/// The ID of the Homie node for controlling the bridge itself.
+const BRIDGE_NODE_ID: &str = "bridge";
+const PROPERTY_ID_COMMAND: &str = "command";
+const PROPERTY_ID_STATE: &str = "state";
+const PROPERTY_ID_HEALTH: &str = "health";
+const PROPERTY_ID_STATUS: &str = "status";
+const PROPERTY_ID_QUARANTINED: &str = "quarantined";
 #[tokio::main]
 async fn main() -> Result<(), eyre::Report> {
     stable_eyre::install()?;
-    dotenv::dotenv().wrap_err("reading .env")?;
-    pretty_env_logger::init();
+    let dotenv = dotenv::dotenv().map(|_| ()).wrap_err("reading .env");
+    let args: Vec<String> = std::env::args().collect();
+    if args.iter().any(|arg| arg == "--check-config") {
+        let check_connectivity = args.iter().any(|arg| arg == "--check-connectivity");
+        let valid = check_config::check_config(dotenv, check_connectivity).await;
+        std::process::exit(if valid { 0 } else { 1 });
+    }
+    dotenv?;
+    let _telemetry = telemetry::init()?;
     color_backtrace::install();
 
     let device_id = std::env::var("DEVICE_ID").unwrap_or_else(|_| DEFAULT_DEVICE_ID.to_string());
     let device_name =
         std::env::var("DEVICE_NAME").unwrap_or_else(|_| DEFAULT_DEVICE_NAME.to_string());
-
-    let mqtt_options = get_mqtt_options(&device_id);
     let mqtt_prefix =
         std::env::var("MQTT_PREFIX").unwrap_or_else(|_| DEFAULT_MQTT_PREFIX.to_string());
-    let device_base = format!("{}/{}", mqtt_prefix, device_id);
-    let mut homie_builder = HomieDevice::builder(&device_base, &device_name, mqtt_options);
-    homie_builder.set_firmware(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
-    let (homie, homie_handle) = homie_builder.spawn().await?;
+    let adapter_name = std::env::var("ADAPTER").ok();
+    let device_id_per_adapter = std::env::var("DEVICE_ID_PER_ADAPTER").is_ok();
+    if device_id_per_adapter && adapter_name.is_none() {
+        eyre::bail!("DEVICE_ID_PER_ADAPTER is set but ADAPTER isn't.");
+    }
 
     let local = task::LocalSet::new();
 
     // Connect a Bluetooth session.
-    let (dbus_handle, session) = MijiaSession::new().await?;
+    let max_concurrent_connects = get_max_concurrent_connects()?;
+    let (dbus_handle, session) =
+        MijiaSession::new_with_connect_limit(max_concurrent_connects).await?;
 
-    let sensor_handle = local.run_until(async move { run_sensor_system(homie, &session).await });
+    let sensor_handle = local.run_until(async move {
+        // Looking up the adapter needs the D-Bus connection to be running, so must happen here.
+        let adapter = match adapter_name {
+            Some(name) => Some(find_adapter_by_name(&session, &name).await?),
+            None => None,
+        };
+        let device_id = match &adapter {
+            Some(adapter) if device_id_per_adapter => device_id_for_adapter(&device_id, adapter),
+            _ => device_id,
+        };
+        let brokers = get_brokers(&device_id);
+        let zigbee2mqtt = get_zigbee2mqtt(&device_id);
+        let device_base = format!("{}/{}", mqtt_prefix, device_id);
+        run_sensor_system(
+            &device_base,
+            &device_name,
+            brokers,
+            zigbee2mqtt,
+            &session,
+            adapter.map(|adapter| adapter.id),
+        )
+        .await
+    });
 
     // Poll everything to completion, until the first one bombs out.
     let res: Result<_, eyre::Report> = try_join! {
-        // If this ever finishes, we lost connection to D-Bus.
+        // If this ever finishes, the task maintaining the D-Bus connection failed.
         dbus_handle.err_into(),
         // Bluetooth finished first. Convert error and get on with your life.
         sensor_handle.err_into(),
-        // MQTT event loop finished first.
-        homie_handle.err_into(),
     };
     res?;
     Ok(())
 }
 
-/// Construct the `MqttOptions` for connecting to the MQTT broker based on configuration options or
-/// defaults.
-fn get_mqtt_options(device_id: &str) -> MqttOptions {
-    let client_name = std::env::var("CLIENT_NAME").unwrap_or_else(|_| device_id.to_owned());
+/// Find the Bluetooth adapter with the given name or MAC address, which the bridge should restrict
+/// itself to.
+async fn find_adapter_by_name(
+    session: &MijiaSession,
+    name: &str,
+) -> Result<AdapterInfo, eyre::Report> {
+    let adapters = session.bt_session.get_adapters().await?;
+    let adapter = find_adapter(&adapters, name)
+        .ok_or_else(|| eyre::eyre!("Bluetooth adapter {} not found", name))?;
+    tracing::info!(
+        "Only using Bluetooth adapter {} ({})",
+        adapter.id,
+        adapter.mac_address
+    );
+    Ok(adapter.to_owned())
+}
+
+/// Construct the `MqttOptions` for each MQTT broker to publish to. The first is configured by the
+/// `HOST`, `PORT` etc. options; any others by the same options suffixed with `_2`, `_3` and so on.
+fn get_brokers(device_id: &str) -> Vec<MqttOptions> {
+    let mut brokers = vec![get_mqtt_options(device_id, "", "")];
+    for n in 2.. {
+        let suffix = format!("_{}", n);
+        if std::env::var(format!("HOST{}", suffix)).is_err() {
+            break;
+        }
+        brokers.push(get_mqtt_options(device_id, &suffix, ""));
+    }
+    brokers
+}
+
+/// Construct the `MqttOptions` for connecting to an MQTT broker based on configuration options with
+/// the given suffix, or defaults. The client name suffix is added to the client name, for a second
+/// connection to the same broker.
+fn get_mqtt_options(device_id: &str, suffix: &str, client_name_suffix: &str) -> MqttOptions {
+    let var = |name: &str| std::env::var(format!("{}{}", name, suffix));
+    let client_name =
+        var("CLIENT_NAME").unwrap_or_else(|_| device_id.to_owned()) + client_name_suffix;
 
-    let host = std::env::var("HOST").unwrap_or_else(|_| DEFAULT_HOST.to_string());
-    let port = std::env::var("PORT")
+    let host = var("HOST").unwrap_or_else(|_| DEFAULT_HOST.to_string());
+    let port = var("PORT")
         .ok()
         .and_then(|val| val.parse::<u16>().ok())
         .unwrap_or(DEFAULT_PORT);
 
     let mut mqtt_options = MqttOptions::new(client_name, host, port);
 
-    let username = std::env::var("USERNAME").ok();
-    let password = std::env::var("PASSWORD").ok();
+    let username = var("USERNAME").ok();
+    let password = var("PASSWORD").ok();
 
     mqtt_options.set_keep_alive(5);
     if let (Some(u), Some(p)) = (username, password) {
@@ -94,7 +259,7 @@ fn get_mqtt_options(device_id: &str) -> MqttOptions {
     }
 
     // Use `env -u USE_TLS` to unset this variable if you need to clear it.
-    if std::env::var("USE_TLS").is_ok() {
+    if var("USE_TLS").is_ok() {
         let mut client_config = ClientConfig::new();
         client_config.root_store =
             rustls_native_certs::load_native_certs().expect("could not load platform certs");
@@ -103,6 +268,298 @@ fn get_mqtt_options(device_id: &str) -> MqttOptions {
     mqtt_options
 }
 
+/// Construct the `Zigbee2Mqtt` configuration and the options for its connection to the first broker
+/// based on configuration options, or `None` if readings shouldn't be published in that layout.
+fn get_zigbee2mqtt(device_id: &str) -> Option<(Zigbee2Mqtt, MqttOptions)> {
+    let base_topic = std::env::var("ZIGBEE2MQTT_BASE_TOPIC").ok()?;
+    Some((
+        Zigbee2Mqtt { base_topic },
+        get_mqtt_options(device_id, "", "-zigbee2mqtt"),
+    ))
+}
+
+/// Make a property for a relative humidity, as either a float or an integer.
+fn humidity_property(id: &str, name: &str, float: bool) -> Property {
+    if float {
+        Property::float(id, name, false, Some("%"), None)
+    } else {
+        Property::integer(id, name, false, Some("%"), None)
+    }
+}
+
+/// Format a relative humidity value for a property made by `humidity_property`.
+fn format_humidity(humidity: f32, float: bool) -> String {
+    if float {
+        format!("{:.1}", humidity)
+    } else {
+        format!("{:.0}", humidity)
+    }
+}
+
+/// Build the Homie node for controlling the bridge, with a settable property to send it commands and
+/// a property for it to publish its state in response to `dump-state`.
+fn bridge_node() -> Node {
+    Node::new(
+        BRIDGE_NODE_ID,
+        "Bridge",
+        "Bridge",
+        vec![
+            Property::string(PROPERTY_ID_COMMAND, "Command", true, None),
+            Property::string(PROPERTY_ID_STATE, "State", false, None),
+            Property::string(PROPERTY_ID_HEALTH, "Health", false, None),
+            Property::string(PROPERTY_ID_STATUS, "Sensor status", false, None),
+            Property::string(PROPERTY_ID_QUARANTINED, "Quarantined sensors", false, None),
+        ],
+    )
+}
+
+/// Construct the `Aggregation` for readings based on configuration options, or `None` if they
+/// shouldn't be aggregated.
+fn get_aggregation() -> Result<Option<Aggregation>, eyre::Report> {
+    Ok(match parse_env_var("AGGREGATION_WINDOW")? {
+        Some(window) => Some(Aggregation {
+            window: Duration::from_secs(window),
+            method: parse_env_var("AGGREGATION_METHOD")?.unwrap_or(AggregationMethod::Mean),
+        }),
+        None => None,
+    })
+}
+
+/// Construct the `Smoothing` for readings based on configuration options, or `None` if they
+/// shouldn't be smoothed.
+fn get_smoothing() -> Result<Option<Smoothing>, eyre::Report> {
+    Ok(match parse_env_var("SMOOTHING_METHOD")? {
+        Some(SmoothingMethod::ExponentialMovingAverage) => {
+            Some(Smoothing::ExponentialMovingAverage {
+                alpha: parse_env_var("SMOOTHING_ALPHA")?.unwrap_or(DEFAULT_SMOOTHING_ALPHA),
+            })
+        }
+        Some(SmoothingMethod::Median) => Some(Smoothing::Median {
+            samples: parse_env_var("SMOOTHING_SAMPLES")?.unwrap_or(DEFAULT_SMOOTHING_SAMPLES),
+        }),
+        None => None,
+    })
+}
+
+/// Construct the `Graphite` configuration based on configuration options, or `None` if readings
+/// shouldn't be sent to Graphite.
+fn get_graphite() -> Option<Graphite> {
+    let address = std::env::var("GRAPHITE_ADDRESS").ok()?;
+    let address = if address.contains(':') {
+        address
+    } else {
+        format!("{}:{}", address, graphite::DEFAULT_PORT)
+    };
+    Some(Graphite {
+        address,
+        path_template: std::env::var("GRAPHITE_PATH_TEMPLATE")
+            .unwrap_or_else(|_| graphite::DEFAULT_PATH_TEMPLATE.to_owned()),
+    })
+}
+
+/// Construct the `BtHomeBroadcast` configuration based on configuration options, or `None` if
+/// readings shouldn't be re-broadcast as BTHome advertisements.
+fn get_bthome_broadcast() -> Result<Option<BtHomeBroadcast>, eyre::Report> {
+    Ok(parse_env_var("BTHOME_BROADCAST_SENSOR")?.map(|sensor| BtHomeBroadcast { sensor }))
+}
+
+/// Construct the `AwsIot` configuration based on configuration options, or `None` if readings
+/// shouldn't be published to AWS IoT Core.
+fn get_aws_iot() -> Result<Option<AwsIot>, eyre::Report> {
+    let endpoint = match std::env::var("AWS_IOT_ENDPOINT") {
+        Ok(endpoint) => endpoint,
+        Err(_) => return Ok(None),
+    };
+    let read_pem = |name: &str| -> Result<Vec<u8>, eyre::Report> {
+        let filename = std::env::var(name)
+            .wrap_err_with(|| format!("AWS_IOT_ENDPOINT is set but {} isn't", name))?;
+        std::fs::read(&filename).wrap_err_with(|| format!("reading {} {}", name, filename))
+    };
+    Ok(Some(AwsIot {
+        endpoint,
+        port: parse_env_var("AWS_IOT_PORT")?.unwrap_or(aws_iot::DEFAULT_PORT),
+        thing_name: std::env::var("AWS_IOT_THING_NAME")
+            .wrap_err("AWS_IOT_ENDPOINT is set but AWS_IOT_THING_NAME isn't")?,
+        topic_prefix: std::env::var("AWS_IOT_TOPIC_PREFIX")
+            .unwrap_or_else(|_| aws_iot::DEFAULT_TOPIC_PREFIX.to_owned()),
+        root_ca: read_pem("AWS_IOT_ROOT_CA")?,
+        certificate: read_pem("AWS_IOT_CERTIFICATE")?,
+        private_key: read_pem("AWS_IOT_PRIVATE_KEY")?,
+    }))
+}
+
+/// Construct the `AzureIot` configuration based on configuration options, or `None` if readings
+/// shouldn't be sent to Azure IoT Hub.
+fn get_azure_iot() -> Result<Option<AzureIot>, eyre::Report> {
+    let connection_string = match std::env::var("AZURE_IOT_CONNECTION_STRING") {
+        Ok(connection_string) => connection_string,
+        Err(_) => return Ok(None),
+    };
+    let x509 = match (
+        std::env::var("AZURE_IOT_CERTIFICATE"),
+        std::env::var("AZURE_IOT_PRIVATE_KEY"),
+    ) {
+        (Ok(certificate), Ok(private_key)) => Some((
+            std::fs::read(&certificate)
+                .wrap_err_with(|| format!("reading AZURE_IOT_CERTIFICATE {}", certificate))?,
+            std::fs::read(&private_key)
+                .wrap_err_with(|| format!("reading AZURE_IOT_PRIVATE_KEY {}", private_key))?,
+        )),
+        _ => None,
+    };
+    let mut azure_iot = AzureIot::from_connection_string(&connection_string, x509)?;
+    if let Some(sas_ttl) = parse_env_var("AZURE_IOT_SAS_TTL")? {
+        azure_iot.sas_ttl = Duration::from_secs(sas_ttl);
+    }
+    Ok(Some(azure_iot))
+}
+
+/// Construct the `HistorySync` configuration based on configuration options, or `None` if history
+/// shouldn't be synced periodically.
+fn get_history_sync() -> Result<Option<HistorySync>, eyre::Report> {
+    let delete_after_sync = std::env::var("DELETE_HISTORY_AFTER_SYNC").is_ok();
+    let interval = match parse_env_var("HISTORY_SYNC_INTERVAL")? {
+        Some(0) => eyre::bail!("HISTORY_SYNC_INTERVAL must be at least 1"),
+        Some(seconds) => Duration::from_secs(seconds),
+        None if delete_after_sync => {
+            eyre::bail!("DELETE_HISTORY_AFTER_SYNC is set but HISTORY_SYNC_INTERVAL isn't.")
+        }
+        None => return Ok(None),
+    };
+    // Records can only be confirmed as persisted once they are in the store.
+    if delete_after_sync && std::env::var("SQLITE_FILENAME").is_err() {
+        eyre::bail!("DELETE_HISTORY_AFTER_SYNC is set but SQLITE_FILENAME isn't.");
+    }
+    Ok(Some(HistorySync {
+        interval,
+        delete_after_sync,
+    }))
+}
+
+/// Construct the `StatusOptions` for reporting the status of sensors based on configuration options
+/// or defaults.
+fn get_status_options() -> Result<StatusOptions, eyre::Report> {
+    let mut status_options = StatusOptions::default();
+    if let Some(interval) = parse_env_var("STATUS_INTERVAL")? {
+        status_options.interval = match interval {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        };
+    }
+    if let Some(verbosity) = parse_env_var("STATUS_VERBOSITY")? {
+        status_options.verbosity = verbosity;
+    }
+    Ok(status_options)
+}
+
+/// Construct the `Plausibility` limits for readings based on configuration options or defaults.
+fn get_plausibility() -> Result<Plausibility, eyre::Report> {
+    let mut plausibility = Plausibility::default();
+    if let Some(temperature_min) = parse_env_var("PLAUSIBLE_TEMPERATURE_MIN")? {
+        plausibility.temperature_min = temperature_min;
+    }
+    if let Some(temperature_max) = parse_env_var("PLAUSIBLE_TEMPERATURE_MAX")? {
+        plausibility.temperature_max = temperature_max;
+    }
+    plausibility.temperature_delta = parse_env_var("PLAUSIBLE_TEMPERATURE_DELTA")?;
+    plausibility.humidity_delta = parse_env_var("PLAUSIBLE_HUMIDITY_DELTA")?;
+    if let Some(delta_interval) = parse_env_var("PLAUSIBLE_DELTA_INTERVAL")? {
+        plausibility.delta_interval = Duration::from_secs(delta_interval);
+    }
+    Ok(plausibility)
+}
+
+/// Construct the `RateLimit` for publishing readings based on configuration options or defaults.
+fn get_rate_limit() -> Result<RateLimit, eyre::Report> {
+    let mut rate_limit = RateLimit::default();
+    if let Some(min_interval) = parse_env_var("MIN_PUBLISH_INTERVAL")? {
+        rate_limit.min_interval = Duration::from_secs(min_interval);
+    }
+    if let Some(temperature_delta) = parse_env_var("PUBLISH_TEMPERATURE_DELTA")? {
+        rate_limit.temperature_delta = temperature_delta;
+    }
+    if let Some(humidity_delta) = parse_env_var("PUBLISH_HUMIDITY_DELTA")? {
+        rate_limit.humidity_delta = humidity_delta;
+    }
+    Ok(rate_limit)
+}
+
+/// Construct the `SensorFilter` for which sensors to connect to, based on configuration options.
+///
+/// If `SENSOR_ALLOWLIST` is set then only the sensors in it are connected to. Otherwise if
+/// `SENSOR_BLOCKLIST` is set then all sensors are connected to except those in it. If neither is set
+/// then only the sensors with names are, unless `AUTO_DISCOVER` is set or there is no sensor names
+/// file, in which case all sensors are. Disabled sensors are never connected to.
+fn get_sensor_filter(
+    sensor_names: &HashMap<MacAddress, String>,
+    disabled_sensors: HashSet<MacAddress>,
+) -> Result<SensorFilter, eyre::Report> {
+    let allowlist = parse_mac_address_list("SENSOR_ALLOWLIST")?;
+    let blocklist = parse_mac_address_list("SENSOR_BLOCKLIST")?;
+    let auto_discover = parse_env_var("AUTO_DISCOVER")?.unwrap_or(false)
+        || !Path::new(SENSOR_NAMES_FILENAME).exists();
+    let allowlist = match (allowlist, &blocklist) {
+        (Some(allowlist), _) => Some(allowlist),
+        (None, Some(_)) => None,
+        (None, None) if auto_discover => {
+            tracing::info!("Connecting to all sensors found");
+            None
+        }
+        (None, None) => Some(sensor_names.keys().cloned().collect()),
+    };
+    let mut blocklist = blocklist.unwrap_or_default();
+    blocklist.extend(disabled_sensors);
+    Ok(SensorFilter {
+        allowlist,
+        blocklist,
+    })
+}
+
+/// Parse the given environment variable as a comma-separated list of MAC addresses, or return
+/// `None` if it is not set.
+fn parse_mac_address_list(name: &str) -> Result<Option<HashSet<MacAddress>>, eyre::Report> {
+    std::env::var(name)
+        .ok()
+        .map(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|mac_address| !mac_address.is_empty())
+                .map(|mac_address| {
+                    mac_address
+                        .parse()
+                        .wrap_err_with(|| format!("parsing {} '{}'", name, mac_address))
+                })
+                .collect()
+        })
+        .transpose()
+}
+
+/// Get how many sensor connection attempts may be made at once from `MAX_CONCURRENT_CONNECTS`, or
+/// the default if it isn't set.
+fn get_max_concurrent_connects() -> Result<usize, eyre::Report> {
+    match parse_env_var::<usize>("MAX_CONCURRENT_CONNECTS")? {
+        Some(0) => eyre::bail!("MAX_CONCURRENT_CONNECTS must be at least 1"),
+        Some(max_concurrent_connects) => Ok(max_concurrent_connects),
+        None => Ok(DEFAULT_MAX_CONCURRENT_CONNECTS),
+    }
+}
+
+/// Parse the value of the given environment variable, or return `None` if it is not set.
+fn parse_env_var<T: FromStr>(name: &str) -> Result<Option<T>, eyre::Report>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    std::env::var(name)
+        .ok()
+        .map(|value| {
+            value
+                .parse()
+                .wrap_err_with(|| format!("parsing {} '{}'", name, value))
+        })
+        .transpose()
+}
+
 #[derive(Debug, Copy, Clone, Eq, Hash, Ord, PartialEq, PartialOrd)]
 enum ConnectionStatus {
     /// Not yet attempted to connect. Might already be connected from a previous
@@ -127,7 +584,40 @@ struct Sensor {
     id: DeviceId,
     mac_address: MacAddress,
     name: String,
+    /// The room or group which the sensor is in, if one is configured.
+    location: Option<String>,
     last_update_timestamp: Instant,
+    /// The wall-clock time at which we last received readings from the sensor, if ever.
+    last_readings_time: Option<SystemTime>,
+    /// The readings last received from the sensor, if any.
+    last_readings: Option<Readings>,
+    /// The received signal strength of the sensor's advertisements in dBm, as of the last scan.
+    rssi: Option<i16>,
+    /// The readings last published for the sensor and when, if any.
+    last_published: Option<(Instant, Readings)>,
+    /// Readings waiting to be aggregated, if aggregation is enabled.
+    readings_window: ReadingsWindow,
+    /// The state of smoothing the sensor's readings, if smoothing is enabled.
+    readings_filter: ReadingsFilter,
+    /// Statistics of the readings received today, if any.
+    daily_stats: Option<DailyStats>,
+    /// The thresholds for alarms on the sensor's readings, if any are configured.
+    thresholds: Option<Thresholds>,
+    /// The last published state of the temperature alarm, if any.
+    temperature_alarm: Option<AlarmState>,
+    /// The last published state of the humidity alarm, if any.
+    humidity_alarm: Option<AlarmState>,
+    /// The index of the last history record received from the sensor, if any.
+    last_history_index: Option<u32>,
+    /// The time of the latest history record received from the sensor, if any. Records from before
+    /// this have already been stored and published, so are dropped if they are received again.
+    last_history_time: Option<SystemTime>,
+    /// Whether storing any history record from the sensor has failed, in which case its history
+    /// mustn't be deleted from the sensor.
+    unstored_history: bool,
+    connection_stats: ConnectionStats,
+    /// Whether the sensor keeps dropping its connection straight away, so should be left alone.
+    flap_detector: FlapDetector,
     connection_status: ConnectionStatus,
 }
 
@@ -135,109 +625,713 @@ impl Sensor {
     const PROPERTY_ID_TEMPERATURE: &'static str = "temperature";
     const PROPERTY_ID_HUMIDITY: &'static str = "humidity";
     const PROPERTY_ID_BATTERY: &'static str = "battery";
+    const PROPERTY_ID_TEMPERATURE_RAW: &'static str = "temperature-raw";
+    const PROPERTY_ID_HUMIDITY_RAW: &'static str = "humidity-raw";
+    const PROPERTY_ID_TEMPERATURE_MIN: &'static str = "temperature-min";
+    const PROPERTY_ID_TEMPERATURE_MAX: &'static str = "temperature-max";
+    const PROPERTY_ID_TEMPERATURE_MEAN: &'static str = "temperature-mean";
+    const PROPERTY_ID_HUMIDITY_MIN: &'static str = "humidity-min";
+    const PROPERTY_ID_HUMIDITY_MAX: &'static str = "humidity-max";
+    const PROPERTY_ID_HUMIDITY_MEAN: &'static str = "humidity-mean";
+    const PROPERTY_ID_TEMPERATURE_ALARM: &'static str = "temperature-alarm";
+    const PROPERTY_ID_HUMIDITY_ALARM: &'static str = "humidity-alarm";
+    const PROPERTY_ID_LOCATION: &'static str = "location";
+    const PROPERTY_ID_HISTORY: &'static str = "history";
+    const PROPERTY_ID_DOWNLOAD_HISTORY: &'static str = "download-history";
+    const PROPERTY_ID_DELETE_HISTORY: &'static str = "delete-history";
 
-    pub fn new(props: SensorProps, sensor_names: &HashMap<MacAddress, String>) -> Self {
+    pub fn new(
+        props: SensorProps,
+        sensor_names: &HashMap<MacAddress, String>,
+        sensor_thresholds: &HashMap<MacAddress, Thresholds>,
+        sensor_locations: &HashMap<MacAddress, String>,
+        saved: Option<&SavedSensor>,
+    ) -> Self {
         let name = sensor_names
             .get(&props.mac_address)
             .cloned()
             .unwrap_or_else(|| props.mac_address.to_string());
+        let thresholds = sensor_thresholds.get(&props.mac_address).cloned();
+        let location = sensor_locations.get(&props.mac_address).cloned();
+        // Restored readings aren't published again, but are used to decide how much history to
+        // backfill once connected.
+        let last_readings = saved.and_then(|saved| saved.last_readings.as_ref());
         Self {
             id: props.id,
             mac_address: props.mac_address,
             name,
+            location,
             last_update_timestamp: Instant::now(),
+            last_readings_time: last_readings.map(SavedReadings::time),
+            last_readings: last_readings.map(SavedReadings::readings),
+            rssi: props.rssi,
+            last_published: None,
+            readings_window: ReadingsWindow::default(),
+            readings_filter: ReadingsFilter::default(),
+            daily_stats: None,
+            thresholds,
+            temperature_alarm: None,
+            humidity_alarm: None,
+            last_history_index: saved.and_then(|saved| saved.last_history_index),
+            last_history_time: saved.and_then(SavedSensor::last_history_time),
+            unstored_history: false,
+            connection_stats: saved.map(|saved| saved.stats).unwrap_or_default(),
+            flap_detector: FlapDetector::default(),
             connection_status: ConnectionStatus::Unknown,
         }
     }
 
+    /// Get the state of the sensor to save for the next time the bridge starts.
+    fn saved_state(&self) -> SavedSensor {
+        SavedSensor {
+            last_readings: match (self.last_readings_time, &self.last_readings) {
+                (Some(time), Some(readings)) => Some(SavedReadings::new(time, readings)),
+                _ => None,
+            },
+            last_history_index: self.last_history_index,
+            last_history_time: self.last_history_time.map(unix_seconds),
+            stats: self.connection_stats,
+        }
+    }
+
     pub fn node_id(&self) -> String {
-        self.mac_address.to_string().replace(":", "")
+        Self::node_id_for(&self.mac_address)
+    }
+
+    /// Get the Homie node ID to use for the sensor with the given MAC address.
+    fn node_id_for(mac_address: &MacAddress) -> String {
+        mac_address.to_string().replace(":", "")
     }
 
-    fn as_node(&self) -> Node {
-        Node::new(
+    fn as_node(&self, publish_options: &PublishOptions) -> Node {
+        Self::node(
             &self.node_id(),
             &self.name,
-            "Mijia sensor",
-            vec![
+            publish_options,
+            self.thresholds.is_some(),
+            self.location.is_some(),
+        )
+    }
+
+    /// Build the Homie node for a sensor with the given node ID and name, optionally including
+    /// properties for threshold alarms and the sensor's location.
+    fn node(
+        node_id: &str,
+        name: &str,
+        publish_options: &PublishOptions,
+        alarms: bool,
+        location: bool,
+    ) -> Node {
+        let humidity_float = publish_options.humidity_float;
+        let mut properties = vec![
+            Property::float(
+                Self::PROPERTY_ID_TEMPERATURE,
+                "Temperature",
+                false,
+                Some("ºC"),
+                None,
+            ),
+            humidity_property(Self::PROPERTY_ID_HUMIDITY, "Humidity", humidity_float),
+            Property::integer(
+                Self::PROPERTY_ID_BATTERY,
+                "Battery level",
+                false,
+                Some("%"),
+                None,
+            ),
+        ];
+        if publish_options.raw {
+            properties.extend(vec![
                 Property::float(
-                    Self::PROPERTY_ID_TEMPERATURE,
-                    "Temperature",
+                    Self::PROPERTY_ID_TEMPERATURE_RAW,
+                    "Unsmoothed temperature",
                     false,
                     Some("ºC"),
                     None,
                 ),
-                Property::integer(
-                    Self::PROPERTY_ID_HUMIDITY,
-                    "Humidity",
+                humidity_property(
+                    Self::PROPERTY_ID_HUMIDITY_RAW,
+                    "Unsmoothed humidity",
+                    humidity_float,
+                ),
+            ]);
+        }
+        if publish_options.daily_stats {
+            properties.extend(vec![
+                Property::float(
+                    Self::PROPERTY_ID_TEMPERATURE_MIN,
+                    "Minimum temperature today",
                     false,
-                    Some("%"),
+                    Some("ºC"),
                     None,
                 ),
-                Property::integer(
-                    Self::PROPERTY_ID_BATTERY,
-                    "Battery level",
+                Property::float(
+                    Self::PROPERTY_ID_TEMPERATURE_MAX,
+                    "Maximum temperature today",
+                    false,
+                    Some("ºC"),
+                    None,
+                ),
+                Property::float(
+                    Self::PROPERTY_ID_TEMPERATURE_MEAN,
+                    "Mean temperature today",
+                    false,
+                    Some("ºC"),
+                    None,
+                ),
+                humidity_property(
+                    Self::PROPERTY_ID_HUMIDITY_MIN,
+                    "Minimum humidity today",
+                    humidity_float,
+                ),
+                humidity_property(
+                    Self::PROPERTY_ID_HUMIDITY_MAX,
+                    "Maximum humidity today",
+                    humidity_float,
+                ),
+                Property::float(
+                    Self::PROPERTY_ID_HUMIDITY_MEAN,
+                    "Mean humidity today",
                     false,
                     Some("%"),
                     None,
                 ),
-            ],
-        )
+            ]);
+        }
+        if alarms {
+            properties.extend(vec![
+                Property::enumeration(
+                    Self::PROPERTY_ID_TEMPERATURE_ALARM,
+                    "Temperature alarm",
+                    false,
+                    None,
+                    AlarmState::VALUES,
+                ),
+                Property::enumeration(
+                    Self::PROPERTY_ID_HUMIDITY_ALARM,
+                    "Humidity alarm",
+                    false,
+                    None,
+                    AlarmState::VALUES,
+                ),
+            ]);
+        }
+        if location {
+            properties.push(Property::string(
+                Self::PROPERTY_ID_LOCATION,
+                "Location",
+                false,
+                None,
+            ));
+        }
+        if publish_options.history {
+            properties.push(Property::string(
+                Self::PROPERTY_ID_HISTORY,
+                "History record",
+                false,
+                None,
+            ));
+        }
+        properties.push(Property::enumeration(
+            Self::PROPERTY_ID_DOWNLOAD_HISTORY,
+            "Download history",
+            true,
+            None,
+            DownloadStatus::VALUES,
+        ));
+        properties.push(Property::string(
+            Self::PROPERTY_ID_DELETE_HISTORY,
+            "Delete history",
+            true,
+            None,
+        ));
+        Node::new(node_id, name, "Mijia sensor", properties)
     }
 
-    async fn publish_readings(
+    /// Handle a new set of readings from the sensor. A notification means that the sensor is
+    /// connected, even if it wasn't known to be, but an advertisement doesn't, so readings decoded
+    /// from advertisements are published without changing the connection status.
+    fn got_readings(
         &mut self,
-        homie: &HomieDevice,
+        homie: &HomieBrokers,
         readings: &Readings,
-    ) -> Result<(), eyre::Report> {
-        println!("{} {} ({})", self.mac_address, readings, self.name);
+        time: SystemTime,
+        source: ReadingsSource,
+        publish_options: &PublishOptions,
+    ) {
+        match source {
+            ReadingsSource::Notification => {
+                self.last_update_timestamp = Instant::now();
+                match self.connection_status {
+                    ConnectionStatus::Connected | ConnectionStatus::Connecting { .. } => {}
+                    _ => {
+                        tracing::info!(
+                            "Got update from disconnected device {:?}. Connecting.",
+                            self.id
+                        );
+                        self.mark_connected(homie, publish_options);
+                        // TODO: Make sure the connection interval is set.
+                    }
+                }
+            }
+            ReadingsSource::Advertisement => {
+                // The node is only added when the sensor connects, so make sure it is there to
+                // publish to. Adding it again is ignored.
+                if self.connection_status != ConnectionStatus::Connected {
+                    self.add_node(homie, publish_options);
+                }
+            }
+        }
+        self.publish_readings(homie, readings, time, publish_options);
+    }
+
+    fn publish_readings(
+        &mut self,
+        homie: &HomieBrokers,
+        readings: &Readings,
+        time: SystemTime,
+        publish_options: &PublishOptions,
+    ) {
+        tracing::info!(
+            sensor = %self.name,
+            mac = %self.mac_address,
+            event = "readings",
+            temperature = readings.temperature,
+            humidity = readings.humidity,
+            battery_percent = readings.battery_percent,
+            "{}",
+            readings
+        );
 
         let node_id = self.node_id();
-        self.last_update_timestamp = Instant::now();
-        homie
-            .publish_value(
+        let now = Instant::now();
+        self.last_readings_time = Some(time);
+        self.last_readings = Some(readings.clone());
+        DailyStats::update(
+            &mut self.daily_stats,
+            Local::today().naive_local(),
+            readings,
+        );
+        self.check_alarms(homie, readings);
+        if publish_options.raw {
+            homie.publish_value(
                 &node_id,
-                Self::PROPERTY_ID_TEMPERATURE,
+                Self::PROPERTY_ID_TEMPERATURE_RAW,
                 format!("{:.2}", readings.temperature),
-            )
-            .await?;
-        homie
-            .publish_value(&node_id, Self::PROPERTY_ID_HUMIDITY, readings.humidity)
-            .await?;
-        homie
-            .publish_value(
+            );
+            homie.publish_value(
                 &node_id,
-                Self::PROPERTY_ID_BATTERY,
-                readings.battery_percent,
-            )
-            .await?;
+                Self::PROPERTY_ID_HUMIDITY_RAW,
+                format_humidity(readings.humidity.into(), publish_options.humidity_float),
+            );
+        }
+        let readings = match &publish_options.smoothing {
+            Some(smoothing) => self.readings_filter.add(smoothing, readings),
+            None => readings.clone(),
+        };
+        let readings = if let Some(aggregation) = &publish_options.aggregation {
+            match self.readings_window.add(aggregation, readings, now) {
+                Some(aggregate) => aggregate,
+                None => return,
+            }
+        } else {
+            readings
+        };
+        let readings = &readings;
+        if !publish_options
+            .rate_limit
+            .should_publish(self.last_published.as_ref(), readings, now)
+        {
+            tracing::debug!(sensor = %self.name, mac = %self.mac_address, "Not publishing readings due to rate limit");
+            return;
+        }
+        self.last_published = Some((now, readings.clone()));
+        homie.publish_value(
+            &node_id,
+            Self::PROPERTY_ID_TEMPERATURE,
+            format!("{:.2}", readings.temperature),
+        );
+        homie.publish_value(
+            &node_id,
+            Self::PROPERTY_ID_HUMIDITY,
+            format_humidity(readings.humidity.into(), publish_options.humidity_float),
+        );
+        homie.publish_value(
+            &node_id,
+            Self::PROPERTY_ID_BATTERY,
+            readings.battery_percent,
+        );
+        if publish_options.daily_stats {
+            self.publish_daily_stats(homie, publish_options.humidity_float);
+        }
+    }
+
+    /// Check the given readings against the sensor's thresholds, if any, and publish the state of
+    /// each alarm if it has changed.
+    fn check_alarms(&mut self, homie: &HomieBrokers, readings: &Readings) {
+        let thresholds = match &self.thresholds {
+            Some(thresholds) => thresholds,
+            None => return,
+        };
+        let node_id = self.node_id();
+
+        let temperature_alarm = thresholds.temperature.check(
+            self.temperature_alarm.unwrap_or(AlarmState::Ok),
+            readings.temperature,
+            TEMPERATURE_HYSTERESIS,
+        );
+        if self.temperature_alarm != Some(temperature_alarm) {
+            if temperature_alarm != AlarmState::Ok {
+                tracing::warn!(sensor = %self.name, mac = %self.mac_address, event = "temperature_alarm", "Temperature {}: {:.2}ºC", temperature_alarm, readings.temperature);
+            }
+            homie.publish_value(
+                &node_id,
+                Self::PROPERTY_ID_TEMPERATURE_ALARM,
+                temperature_alarm,
+            );
+            self.temperature_alarm = Some(temperature_alarm);
+        }
+
+        let humidity_alarm = thresholds.humidity.check(
+            self.humidity_alarm.unwrap_or(AlarmState::Ok),
+            readings.humidity.into(),
+            HUMIDITY_HYSTERESIS,
+        );
+        if self.humidity_alarm != Some(humidity_alarm) {
+            if humidity_alarm != AlarmState::Ok {
+                tracing::warn!(sensor = %self.name, mac = %self.mac_address, event = "humidity_alarm", "Humidity {}: {}%", humidity_alarm, readings.humidity);
+            }
+            homie.publish_value(&node_id, Self::PROPERTY_ID_HUMIDITY_ALARM, humidity_alarm);
+            self.humidity_alarm = Some(humidity_alarm);
+        }
+    }
+
+    fn publish_daily_stats(&self, homie: &HomieBrokers, humidity_float: bool) {
+        if let Some(stats) = &self.daily_stats {
+            let node_id = self.node_id();
+            let temperature = &stats.temperature;
+            let humidity = &stats.humidity;
+            homie.publish_value(
+                &node_id,
+                Self::PROPERTY_ID_TEMPERATURE_MIN,
+                format!("{:.2}", temperature.min),
+            );
+            homie.publish_value(
+                &node_id,
+                Self::PROPERTY_ID_TEMPERATURE_MAX,
+                format!("{:.2}", temperature.max),
+            );
+            homie.publish_value(
+                &node_id,
+                Self::PROPERTY_ID_TEMPERATURE_MEAN,
+                format!("{:.2}", temperature.mean()),
+            );
+            homie.publish_value(
+                &node_id,
+                Self::PROPERTY_ID_HUMIDITY_MIN,
+                format_humidity(humidity.min, humidity_float),
+            );
+            homie.publish_value(
+                &node_id,
+                Self::PROPERTY_ID_HUMIDITY_MAX,
+                format_humidity(humidity.max, humidity_float),
+            );
+            homie.publish_value(
+                &node_id,
+                Self::PROPERTY_ID_HUMIDITY_MEAN,
+                format!("{:.1}", humidity.mean()),
+            );
+        }
+    }
+
+    /// Publish a history record downloaded from the sensor as JSON, including the time at which the
+    /// sensor recorded it.
+    fn publish_history_record(
+        &self,
+        homie: &HomieBrokers,
+        record: &HistoryRecord,
+    ) -> Result<(), eyre::Report> {
+        homie.publish_value(
+            &self.node_id(),
+            Self::PROPERTY_ID_HISTORY,
+            serde_json::to_string(&HistoryRecordJson::from(record))?,
+        );
         Ok(())
     }
 
-    async fn mark_connected(&mut self, homie: &mut HomieDevice) -> Result<(), eyre::Report> {
-        homie.add_node(self.as_node()).await?;
+    /// Add the sensor's node to the Homie device, along with its location.
+    fn add_node(&self, homie: &HomieBrokers, publish_options: &PublishOptions) {
+        homie.add_node(self.as_node(publish_options));
+        if let Some(location) = &self.location {
+            homie.publish_value(&self.node_id(), Self::PROPERTY_ID_LOCATION, location);
+        }
+    }
+
+    fn mark_connected(&mut self, homie: &HomieBrokers, publish_options: &PublishOptions) {
+        self.add_node(homie, publish_options);
+        // The node's values were cleared when it was removed, so make sure the next readings are
+        // published regardless of the rate limit.
+        self.last_published = None;
+        self.readings_window = ReadingsWindow::default();
+        self.readings_filter = ReadingsFilter::default();
+        self.temperature_alarm = None;
+        self.humidity_alarm = None;
+        if self.connection_status != ConnectionStatus::Connected {
+            self.flap_detector.connected(Instant::now());
+        }
         self.connection_status = ConnectionStatus::Connected;
-        Ok(())
     }
 }
 
 async fn run_sensor_system(
-    mut homie: HomieDevice,
+    device_base: &str,
+    device_name: &str,
+    brokers: Vec<MqttOptions>,
+    zigbee2mqtt: Option<(Zigbee2Mqtt, MqttOptions)>,
     session: &MijiaSession,
+    adapter: Option<AdapterId>,
 ) -> Result<(), eyre::Report> {
-    let sensor_names = hashmap_from_file(SENSOR_NAMES_FILENAME)
+    let (sensor_names, disabled_sensors) = read_sensor_names(SENSOR_NAMES_FILENAME)
         .wrap_err(format!("reading {}", SENSOR_NAMES_FILENAME))?;
+    for mac_address in &disabled_sensors {
+        tracing::info!(sensor = %sensor_names[mac_address], mac = %mac_address, "Sensor disabled");
+    }
+    let sensor_filter = get_sensor_filter(&sensor_names, disabled_sensors)?;
+    let sensor_thresholds = read_sensor_thresholds(SENSOR_THRESHOLDS_FILENAME)
+        .wrap_err(format!("reading {}", SENSOR_THRESHOLDS_FILENAME))?;
+    let sensor_locations = hashmap_from_file(SENSOR_LOCATIONS_FILENAME)
+        .wrap_err(format!("reading {}", SENSOR_LOCATIONS_FILENAME))?;
+    let sensor_timeouts = read_sensor_timeouts(SENSOR_TIMEOUTS_FILENAME)
+        .wrap_err(format!("reading {}", SENSOR_TIMEOUTS_FILENAME))?;
+    let sensor_bind_keys = read_sensor_bind_keys(SENSOR_BIND_KEYS_FILENAME)
+        .wrap_err(format!("reading {}", SENSOR_BIND_KEYS_FILENAME))?;
+    for (mac_address, bind_key) in sensor_bind_keys {
+        session.set_bind_key(mac_address, bind_key);
+    }
 
-    homie.ready().await?;
+    let store = match std::env::var("SQLITE_FILENAME") {
+        Ok(filename) => {
+            Some(Store::open(&filename).wrap_err_with(|| format!("opening {}", filename))?)
+        }
+        Err(_) => None,
+    };
+    let smoothing = get_smoothing()?;
+    let publish_options = PublishOptions {
+        aggregation: get_aggregation()?,
+        raw: smoothing.is_some() && std::env::var("PUBLISH_RAW_READINGS").is_ok(),
+        smoothing,
+        plausibility: get_plausibility()?,
+        rate_limit: get_rate_limit()?,
+        daily_stats: std::env::var("DAILY_STATISTICS").is_ok(),
+        history: std::env::var("PUBLISH_HISTORY").is_ok(),
+        humidity_float: std::env::var("HUMIDITY_AS_FLOAT").is_ok(),
+        sensor_thresholds,
+        sensor_locations,
+    };
+
+    // Nodes left behind by a previous run are re-advertised if they are for sensors we still know
+    // about, and cleared otherwise. Disabled or blocked sensors are neither connected to nor
+    // published, so their nodes will be cleared.
+    let mut known_nodes: HashMap<String, Node> = sensor_names
+        .iter()
+        .filter(|(mac_address, _)| sensor_filter.allows(mac_address))
+        .map(|(mac_address, name)| {
+            let node_id = Sensor::node_id_for(mac_address);
+            let node = Sensor::node(
+                &node_id,
+                name,
+                &publish_options,
+                publish_options.sensor_thresholds.contains_key(mac_address),
+                publish_options.sensor_locations.contains_key(mac_address),
+            );
+            (node_id, node)
+        })
+        .collect();
+    known_nodes.insert(BRIDGE_NODE_ID.to_owned(), bridge_node());
+    known_nodes.insert(METRICS_NODE_ID.to_owned(), metrics_node());
+    let previous_nodes = PreviousNodes {
+        known: known_nodes,
+        stale_node: |node_id| {
+            let all_properties = PublishOptions {
+                daily_stats: true,
+                history: true,
+                raw: true,
+                ..Default::default()
+            };
+            Sensor::node(node_id, node_id, &all_properties, true, true)
+        },
+    };
+    let state_file = std::env::var("STATE_FILENAME").ok().map(StateFile::new);
+    let saved_sensors = match &state_file {
+        Some(state_file) => state_file.load().wrap_err("loading saved sensor state")?,
+        None => HashMap::new(),
+    };
+    let offline_queue_directory = std::env::var("OFFLINE_QUEUE_DIRECTORY").ok();
+    let web_address: Option<SocketAddr> = parse_env_var("WEB_ADDRESS")?;
+    let grpc_address: Option<SocketAddr> = parse_env_var("GRPC_ADDRESS")?;
+    let graphite = get_graphite();
+    let bthome_broadcast = get_bthome_broadcast()?;
+    let postgres_url = std::env::var("POSTGRES_URL").ok();
+    let aws_iot = get_aws_iot()?;
+    let azure_iot = get_azure_iot()?;
+    let status_options = get_status_options()?;
+    let history_sync = get_history_sync()?;
+    let homie_version = parse_env_var("HOMIE_VERSION")?.unwrap_or(HomieVersion::V4);
+    let (homie, incoming) = HomieBrokers::spawn(
+        device_base,
+        device_name,
+        homie_version,
+        brokers,
+        previous_nodes,
+        offline_queue_directory.as_ref().map(Path::new),
+    );
+    homie.add_node(bridge_node());
+    homie.add_node(metrics_node());
+    let web_commands = homie.incoming_sender();
 
     let state = Arc::new(Mutex::new(SensorState {
         sensors: HashMap::new(),
+        sensor_names,
+        sensor_timeouts,
+        remove_stale_devices: std::env::var("REMOVE_STALE_DEVICES").is_ok(),
         homie,
+        store,
+        publish_options,
+        scan_requested: false,
+        health: Health::default(),
+        adapter,
+        events: broadcast::channel(EVENT_STREAM_CAPACITY).0,
+        state_file,
+        saved_sensors,
+        quarantined: vec![],
+        discovery_stopped: HashSet::new(),
     }));
 
-    let connection_loop_handle = bluetooth_connection_loop(state.clone(), session, &sensor_names);
+    let connection_loop_handle =
+        bluetooth_connection_loop(state.clone(), session, &sensor_filter, status_options);
     let event_loop_handle = service_bluetooth_event_queue(state.clone(), session);
-    try_join!(connection_loop_handle, event_loop_handle).map(|((), ())| ())
+    let incoming_handle = handle_incoming(state.clone(), session, incoming);
+    let web_handle = async {
+        match web_address {
+            Some(address) => web::serve(address, state.clone(), web_commands).await,
+            None => Ok(()),
+        }
+    };
+    let grpc_handle = async {
+        match grpc_address {
+            Some(address) => serve_grpc(address, state.clone(), session.clone()).await,
+            None => Ok(()),
+        }
+    };
+    let graphite_handle = async {
+        match &graphite {
+            Some(graphite) => graphite.run(state.clone()).await,
+            None => Ok(()),
+        }
+    };
+    let bthome_broadcast_handle = async {
+        match &bthome_broadcast {
+            Some(bthome_broadcast) => bthome_broadcast.run(state.clone(), session).await,
+            None => Ok(()),
+        }
+    };
+    let postgres_handle = async {
+        match postgres_url {
+            Some(url) => write_postgres(url, state.clone()).await,
+            None => Ok(()),
+        }
+    };
+    let aws_iot_handle = async {
+        match &aws_iot {
+            Some(aws_iot) => aws_iot.run(state.clone()).await,
+            None => Ok(()),
+        }
+    };
+    let azure_iot_handle = async {
+        match &azure_iot {
+            Some(azure_iot) => azure_iot.run(state.clone()).await,
+            None => Ok(()),
+        }
+    };
+    let zigbee2mqtt_handle = async {
+        match zigbee2mqtt {
+            Some((zigbee2mqtt, mqtt_options)) => zigbee2mqtt.run(mqtt_options, state.clone()).await,
+            None => Ok(()),
+        }
+    };
+    let history_sync_handle = async {
+        match &history_sync {
+            Some(history_sync) => history_sync.run(state.clone(), session).await,
+            None => Ok(()),
+        }
+    };
+    try_join!(
+        connection_loop_handle,
+        event_loop_handle,
+        incoming_handle,
+        web_handle,
+        grpc_handle,
+        graphite_handle,
+        bthome_broadcast_handle,
+        postgres_handle,
+        aws_iot_handle,
+        azure_iot_handle,
+        zigbee2mqtt_handle,
+        history_sync_handle
+    )
+    .map(|((), (), (), (), (), (), (), (), (), (), (), ())| ())
+}
+
+#[cfg(feature = "grpc")]
+async fn serve_grpc(
+    address: SocketAddr,
+    state: Arc<Mutex<SensorState>>,
+    session: MijiaSession,
+) -> Result<(), eyre::Report> {
+    grpc::serve(address, state, session).await
+}
+
+#[cfg(not(feature = "grpc"))]
+async fn serve_grpc(
+    _address: SocketAddr,
+    _state: Arc<Mutex<SensorState>>,
+    _session: MijiaSession,
+) -> Result<(), eyre::Report> {
+    Err(eyre::eyre!(
+        "GRPC_ADDRESS is set but mijia-homie was built without the grpc feature."
+    ))
+}
+
+#[cfg(feature = "postgres")]
+async fn write_postgres(url: String, state: Arc<Mutex<SensorState>>) -> Result<(), eyre::Report> {
+    let mut postgres = postgres::Postgres {
+        url,
+        timescaledb: std::env::var("POSTGRES_TIMESCALEDB").is_ok(),
+        ..Default::default()
+    };
+    if let Ok(schema) = std::env::var("POSTGRES_SCHEMA") {
+        postgres.schema = schema;
+    }
+    if let Ok(readings_table) = std::env::var("POSTGRES_READINGS_TABLE") {
+        postgres.readings_table = readings_table;
+    }
+    if let Ok(history_table) = std::env::var("POSTGRES_HISTORY_TABLE") {
+        postgres.history_table = history_table;
+    }
+    if let Some(batch_size) = parse_env_var::<usize>("POSTGRES_BATCH_SIZE")? {
+        if batch_size == 0 {
+            eyre::bail!("POSTGRES_BATCH_SIZE must be at least 1");
+        }
+        postgres.batch_size = batch_size;
+    }
+    postgres.run(state).await
+}
+
+#[cfg(not(feature = "postgres"))]
+async fn write_postgres(_url: String, _state: Arc<Mutex<SensorState>>) -> Result<(), eyre::Report> {
+    Err(eyre::eyre!(
+        "POSTGRES_URL is set but mijia-homie was built without the postgres feature."
+    ))
 }
 
 /// Read the given file of key-value pairs into a hashmap.
@@ -248,56 +1342,168 @@ fn hashmap_from_file(filename: &str) -> Result<HashMap<MacAddress, String>, eyre
         for line in BufReader::new(file).lines() {
             let line = line?;
             if !line.starts_with('#') {
-                let parts: Vec<&str> = line.splitn(2, '=').collect();
-                if parts.len() != 2 {
-                    eyre::bail!("Invalid line '{}'", line);
-                }
-                map.insert(parts[0].parse()?, parts[1].to_string());
+                let (key, value) = parse_key_value(&line)?;
+                map.insert(key, value);
             }
         }
     }
     Ok(map)
 }
 
+/// Read the names of sensors from the given file. Lines starting with `!` are for sensors which
+/// are disabled, so keep their names but shouldn't be connected to. Returns the names of all
+/// sensors, along with the set of those which are disabled.
+fn read_sensor_names(
+    filename: &str,
+) -> Result<(HashMap<MacAddress, String>, HashSet<MacAddress>), eyre::Report> {
+    let mut names: HashMap<MacAddress, String> = HashMap::new();
+    let mut disabled = HashSet::new();
+    if let Ok(file) = File::open(filename) {
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if !line.starts_with('#') {
+                let (is_disabled, line) = match line.strip_prefix('!') {
+                    Some(line) => (true, line),
+                    None => (false, line.as_str()),
+                };
+                let (mac_address, name) = parse_key_value(line)?;
+                if is_disabled {
+                    disabled.insert(mac_address);
+                }
+                names.insert(mac_address, name);
+            }
+        }
+    }
+    Ok((names, disabled))
+}
+
+/// Parse a line of the form `MAC address=value`.
+fn parse_key_value(line: &str) -> Result<(MacAddress, String), eyre::Report> {
+    let parts: Vec<&str> = line.splitn(2, '=').collect();
+    if parts.len() != 2 {
+        eyre::bail!("Invalid line '{}'", line);
+    }
+    Ok((parts[0].parse()?, parts[1].to_string()))
+}
+
+/// Read the thresholds for alarms for each sensor from the given file. Returns an empty hashmap if
+/// the file doesn't exist, or an error if it is malformed.
+fn read_sensor_thresholds(filename: &str) -> Result<HashMap<MacAddress, Thresholds>, eyre::Report> {
+    hashmap_from_file(filename)?
+        .into_iter()
+        .map(|(mac_address, thresholds)| Ok((mac_address, thresholds.parse()?)))
+        .collect()
+}
+
+/// Read the update timeout in seconds for each sensor from the given file. Returns an empty hashmap
+/// if the file doesn't exist, or an error if it is malformed.
+fn read_sensor_timeouts(filename: &str) -> Result<HashMap<MacAddress, Duration>, eyre::Report> {
+    hashmap_from_file(filename)?
+        .into_iter()
+        .map(|(mac_address, timeout)| {
+            let seconds = timeout
+                .trim()
+                .parse()
+                .wrap_err_with(|| format!("parsing timeout '{}' for {}", timeout, mac_address))?;
+            Ok((mac_address, Duration::from_secs(seconds)))
+        })
+        .collect()
+}
+
+/// Read the bind key with which each sensor encrypts its advertisements from the given file.
+/// Returns an empty hashmap if the file doesn't exist, or an error if it is malformed.
+fn read_sensor_bind_keys(filename: &str) -> Result<HashMap<MacAddress, BindKey>, eyre::Report> {
+    hashmap_from_file(filename)?
+        .into_iter()
+        .map(|(mac_address, bind_key)| Ok((mac_address, bind_key.parse()?)))
+        .collect()
+}
+
 async fn bluetooth_connection_loop(
     state: Arc<Mutex<SensorState>>,
     session: &MijiaSession,
-    sensor_names: &HashMap<MacAddress, String>,
+    sensor_filter: &SensorFilter,
+    status_options: StatusOptions,
 ) -> Result<(), eyre::Report> {
     let mut next_scan_due = Instant::now();
+    let mut next_health_report_due = Instant::now();
+    let mut next_state_save_due = Instant::now() + STATE_SAVE_INTERVAL;
+    let mut next_status_report_due = Instant::now();
+    let mut next_metrics_report_due = Instant::now();
+    let mut metrics_clock = MetricsClock::new(Instant::now());
     loop {
-        // Print count and list of sensors in each state.
-        {
-            let counts = state
-                .lock()
-                .await
-                .sensors
-                .values()
-                .map(|sensor| (sensor.connection_status, sensor.name.clone()))
-                .into_group_map();
-            for (state, names) in counts.iter().sorted() {
-                println!("{:?}: {} {:?}", state, names.len(), names);
-            }
-        }
-
         // Look for more sensors if enough time has elapsed since last time we tried.
         let now = Instant::now();
-        if now > next_scan_due && state.lock().await.sensors.len() < sensor_names.len() {
+        let scan_requested = std::mem::replace(&mut state.lock().await.scan_requested, false);
+        let sensors_found = state.lock().await.sensors.len();
+        let more_sensors_expected = match sensor_filter.max_sensors() {
+            Some(max_sensors) => sensors_found < max_sensors,
+            None => true,
+        };
+        if (now > next_scan_due || scan_requested) && more_sensors_expected {
             next_scan_due = now + SCAN_INTERVAL;
-            check_for_sensors(state.clone(), session, &sensor_names).await?;
+            check_for_sensors(state.clone(), session, sensor_filter).await?;
         }
 
-        // Check the state of each sensor and act on it if appropriate.
+        restart_stopped_discovery(state.clone(), session).await;
+
+        if now > next_health_report_due {
+            next_health_report_due = now + HEALTH_REPORT_INTERVAL;
+            publish_health(&state, session).await;
+        }
+
+        if now > next_metrics_report_due {
+            next_metrics_report_due = now + HEALTH_REPORT_INTERVAL;
+            publish_metrics(
+                &*state.lock().await,
+                session,
+                sensor_filter,
+                &mut metrics_clock,
+                now,
+            );
+        }
+
+        if let Some(interval) = status_options.interval {
+            if now > next_status_report_due {
+                next_status_report_due = now + interval;
+                publish_status(&*state.lock().await, status_options)?;
+            }
+        }
+
+        publish_quarantined(&mut *state.lock().await, now);
+
+        if now > next_state_save_due {
+            next_state_save_due = now + STATE_SAVE_INTERVAL;
+            if let Err(e) = save_state(&mut *state.lock().await) {
+                tracing::error!("Failed to save sensor state: {:?}", e);
+            }
+        }
+
+        // Check the state of each sensor and act on it if appropriate. Those which most recently
+        // sent readings are reconnected first, so that one which is out of range doesn't hold up
+        // the rest.
         {
-            let ids: Vec<DeviceId> = state.lock().await.sensors.keys().cloned().collect();
-            for id in ids {
+            let (new_ids, ids): (Vec<DeviceId>, Vec<(DeviceId, Option<SystemTime>)>) =
+                state.lock().await.sensors.values().partition_map(|sensor| {
+                    if sensor.connection_status == ConnectionStatus::Unknown {
+                        Either::Left(sensor.id.clone())
+                    } else {
+                        Either::Right((sensor.id.clone(), sensor.last_readings_time))
+                    }
+                });
+            connect_new_sensors(state.clone(), session, new_ids).await?;
+            for id in reconnection_order(ids) {
                 let connection_status = state
                     .lock()
                     .await
                     .sensors
                     .get(&id)
                     .map(|sensor| {
-                        log::trace!("State of {} is {:?}", sensor.name, sensor.connection_status);
+                        tracing::trace!(
+                            "State of {} is {:?}",
+                            sensor.name,
+                            sensor.connection_status
+                        );
                         sensor.connection_status
                     })
                     .expect("sensors cannot be deleted");
@@ -308,10 +1514,82 @@ async fn bluetooth_connection_loop(
     }
 }
 
+/// Connect to the given sensors which haven't been tried yet, a few at a time and each after a
+/// random delay, so that a lot of sensors found at once don't all have to wait behind each other.
+async fn connect_new_sensors(
+    state: Arc<Mutex<SensorState>>,
+    session: &MijiaSession,
+    ids: Vec<DeviceId>,
+) -> Result<(), eyre::Report> {
+    futures::stream::iter(ids.into_iter().map(Ok))
+        .try_for_each_concurrent(INITIAL_CONNECT_CONCURRENCY, |id| {
+            let state = state.clone();
+            async move {
+                let jitter = rand::thread_rng().gen_range(0, INITIAL_CONNECT_JITTER.as_millis());
+                time::delay_for(Duration::from_millis(jitter as u64)).await;
+                connect_sensor_with_id(state, session, id).await
+            }
+        })
+        .await
+}
+
 #[derive(Debug)]
 struct SensorState {
     sensors: HashMap<DeviceId, Sensor>,
-    homie: HomieDevice,
+    /// The names of sensors, including any which have been renamed since the bridge started.
+    sensor_names: HashMap<MacAddress, String>,
+    /// How long to wait for an update from each sensor before reconnecting, for those sensors which
+    /// don't use the default of `UPDATE_TIMEOUT`.
+    sensor_timeouts: HashMap<MacAddress, Duration>,
+    /// Whether to remove sensors which stop sending updates from BlueZ's cache as well as
+    /// disconnecting them, so that they are rediscovered from scratch.
+    remove_stale_devices: bool,
+    homie: HomieBrokers,
+    /// The local database to store readings in, if one is configured.
+    store: Option<Store>,
+    publish_options: PublishOptions,
+    /// Whether a scan for sensors has been requested, regardless of when the last one was.
+    scan_requested: bool,
+    health: Health,
+    /// The only Bluetooth adapter to use, if the bridge is restricted to one.
+    adapter: Option<AdapterId>,
+    /// Events from known sensors, along with their MAC addresses, for anything which wants to
+    /// stream them.
+    events: broadcast::Sender<(MacAddress, MijiaEvent)>,
+    /// The file to save the state of sensors to, if one is configured.
+    state_file: Option<StateFile>,
+    /// The saved state of every sensor, including those which haven't been found since the bridge
+    /// started.
+    saved_sensors: HashMap<MacAddress, SavedSensor>,
+    /// The sensors last published as quarantined.
+    quarantined: Vec<MacAddress>,
+    /// Adapters which have stopped discovery unexpectedly, so it should be restarted on them.
+    discovery_stopped: HashSet<AdapterId>,
+}
+
+/// Options for how sensor readings are published.
+#[derive(Clone, Debug, Default)]
+struct PublishOptions {
+    /// How to aggregate readings before publishing them, if at all.
+    aggregation: Option<Aggregation>,
+    /// How to smooth readings before aggregating or publishing them, if at all.
+    smoothing: Option<Smoothing>,
+    /// Whether to also publish the unsmoothed temperature and humidity, if readings are smoothed.
+    raw: bool,
+    /// Limits on which readings are plausible, so that others are dropped rather than published.
+    plausibility: Plausibility,
+    rate_limit: RateLimit,
+    /// Whether to publish extra properties with statistics of each sensor's readings today.
+    daily_stats: bool,
+    /// Whether to publish history records downloaded from sensors to their `history` property.
+    history: bool,
+    /// Whether to declare and publish humidity as a float rather than an integer, for sensors with
+    /// custom firmware which report it with more precision.
+    humidity_float: bool,
+    /// The thresholds for alarms on each sensor's readings, for those sensors which have any.
+    sensor_thresholds: HashMap<MacAddress, Thresholds>,
+    /// The room or group which each sensor is in, for those sensors which have one.
+    sensor_locations: HashMap<MacAddress, String>,
 }
 
 async fn action_sensor(
@@ -328,6 +1606,12 @@ async fn action_sensor(
         | ConnectionStatus::Connecting { .. }
         | ConnectionStatus::Disconnected
         | ConnectionStatus::MarkedDisconnected => {
+            if state.lock().await.sensors[&id]
+                .flap_detector
+                .is_quarantined(Instant::now())
+            {
+                return Ok(());
+            }
             connect_sensor_with_id(state, session, id).await?;
             Ok(())
         }
@@ -341,21 +1625,66 @@ async fn action_sensor(
 async fn check_for_sensors(
     state: Arc<Mutex<SensorState>>,
     session: &MijiaSession,
-    sensor_names: &HashMap<MacAddress, String>,
+    sensor_filter: &SensorFilter,
 ) -> Result<(), eyre::Report> {
-    session.bt_session.start_discovery().await?;
+    let adapter = state.lock().await.adapter.clone();
+    match &adapter {
+        Some(adapter) => {
+            session
+                .bt_session
+                .start_discovery_on_adapter(adapter)
+                .await?
+        }
+        None => session.bt_session.start_discovery().await?,
+    }
+    let mut sensors = session.get_sensors().await?;
+    if let Some(adapter) = &adapter {
+        sensors.retain(|props| props.id.adapter() == *adapter);
+    }
 
-    let sensors = session.get_sensors().await?;
+    // If a sensor can be heard by several adapters, prefer the one which hears it best.
+    let sensors = strongest_signal(sensors);
     let state = &mut *state.lock().await;
     for props in sensors {
-        if sensor_names.contains_key(&props.mac_address)
-            && !state
-                .sensors
-                .values()
-                .any(|s| s.mac_address == props.mac_address)
-        {
-            let sensor = Sensor::new(props, &sensor_names);
-            state.sensors.insert(sensor.id.clone(), sensor);
+        if !sensor_filter.allows(&props.mac_address) {
+            continue;
+        }
+        let existing = state
+            .sensors
+            .values()
+            .find(|s| s.mac_address == props.mac_address)
+            .map(|s| (s.id.clone(), s.connection_status));
+        if let Some((id, _)) = &existing {
+            if let Some(rssi) = props.rssi {
+                state.sensors.get_mut(id).unwrap().rssi = Some(rssi);
+            }
+        }
+        match existing {
+            None => {
+                let saved = state.saved_sensors.get(&props.mac_address);
+                let sensor = Sensor::new(
+                    props,
+                    &state.sensor_names,
+                    &state.publish_options.sensor_thresholds,
+                    &state.publish_options.sensor_locations,
+                    saved,
+                );
+                state.sensors.insert(sensor.id.clone(), sensor);
+            }
+            // Only switch adapters while the sensor is definitely disconnected, so that it doesn't
+            // end up connected through both.
+            Some((id, ConnectionStatus::Disconnected)) if id != props.id => {
+                let mut sensor = state.sensors.remove(&id).unwrap();
+                tracing::info!(
+                    "Switching {} from adapter {} to {}, which has a stronger signal",
+                    sensor.name,
+                    id.adapter(),
+                    props.id.adapter()
+                );
+                sensor.id = props.id;
+                state.sensors.insert(sensor.id.clone(), sensor);
+            }
+            Some(_) => {}
         }
     }
     Ok(())
@@ -367,32 +1696,112 @@ async fn connect_sensor_with_id(
     id: DeviceId,
 ) -> Result<(), eyre::Report> {
     // Update the state of the sensor to `Connecting`.
-    {
+    let span = {
         let mut state = state.lock().await;
         let sensor = state.sensors.get_mut(&id).unwrap();
-        println!(
-            "Trying to connect to {} from status: {:?}",
-            sensor.name, sensor.connection_status
-        );
+        let span = tracing::info_span!("connect", sensor = %sensor.name, mac = %sensor.mac_address);
+        span.in_scope(|| {
+            tracing::info!(
+                "Trying to connect from status: {:?}",
+                sensor.connection_status
+            )
+        });
         sensor.connection_status = ConnectionStatus::Connecting {
             reserved_until: Instant::now() + SENSOR_CONNECT_RESERVATION_TIMEOUT,
         };
+        span
     };
-    let result = connect_and_subscribe_sensor_or_disconnect(session, &id).await;
 
-    let state = &mut *state.lock().await;
-    let sensor = state.sensors.get_mut(&id).unwrap();
-    match result {
-        Ok(()) => {
-            println!("Connected to {} and started notifications", sensor.name);
-            sensor.mark_connected(&mut state.homie).await?;
-            sensor.last_update_timestamp = Instant::now();
-        }
-        Err(e) => {
-            println!("Failed to connect to {}: {:?}", sensor.name, e);
-            sensor.connection_status = ConnectionStatus::Disconnected;
+    async {
+        let result = connect_and_subscribe_sensor_or_disconnect(session, &id).await;
+
+        let backfill = {
+            let state = &mut *state.lock().await;
+            let sensor = state.sensors.get_mut(&id).unwrap();
+            match result {
+                Ok(()) => {
+                    tracing::info!("Connected and started notifications");
+                    sensor.mark_connected(&state.homie, &state.publish_options);
+                    sensor.last_update_timestamp = Instant::now();
+                    sensor.connection_stats.connections += 1;
+                    // Only bother backfilling if there is somewhere to send the records.
+                    if state.store.is_some() || state.publish_options.history {
+                        sensor
+                            .last_readings_time
+                            .map(|since| (since, sensor.last_history_index))
+                    } else {
+                        None
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to connect: {:?}", e);
+                    sensor.connection_status = ConnectionStatus::Disconnected;
+                    state.health.connect_failures += 1;
+                    sensor.connection_stats.connect_failures += 1;
+                    None
+                }
+            }
+        };
+
+        if let Some((since, last_history_index)) = backfill {
+            if let Err(e) = request_history_since(session, &id, since, last_history_index).await {
+                tracing::warn!("Failed to request history for backfill: {:?}", e);
+            }
         }
+        Ok(())
     }
+    .instrument(span)
+    .await
+}
+
+/// If the given time is more than one history record interval ago, ask the sensor to send all
+/// history records it has stored since then, to fill the gap in readings while it was unreachable.
+/// If the index of the last history record received is known then the records after it are
+/// requested, otherwise the first missed record is estimated from the time.
+///
+/// The records will be delivered as `MijiaEvent::HistoryRecord` events, and stored by the event
+/// loop.
+#[tracing::instrument(name = "backfill_history", skip(session, id))]
+async fn request_history_since(
+    session: &MijiaSession,
+    id: &DeviceId,
+    since: SystemTime,
+    last_history_index: Option<u32>,
+) -> Result<(), eyre::Report> {
+    let gap = SystemTime::now().duration_since(since).unwrap_or_default();
+    if gap < HISTORY_RECORD_INTERVAL {
+        return Ok(());
+    }
+
+    let history_range = session.get_history_range(id).await?;
+    let last_record = session.get_last_history_record(id).await?;
+    let start_index = match last_history_index {
+        // The sensor's history may have been cleared since, in which case the index is no use.
+        Some(index) if index < last_record.index => max(history_range.start, index + 1),
+        Some(index) if index == last_record.index => return Ok(()),
+        _ => {
+            // Records are stored at regular intervals, so estimate how many we have missed based
+            // on the time of the last one.
+            let missed_records = last_record
+                .time
+                .duration_since(since)
+                .unwrap_or_default()
+                .as_secs()
+                / HISTORY_RECORD_INTERVAL.as_secs()
+                + 1;
+            max(
+                history_range.start,
+                last_record.index.saturating_sub(missed_records as u32),
+            )
+        }
+    };
+    tracing::info!(
+        "No readings for {:?}, requesting history from record {} to {}",
+        gap,
+        start_index,
+        last_record.index
+    );
+    session.start_notify_history(id, Some(start_index)).await?;
     Ok(())
 }
 
@@ -400,11 +1809,17 @@ async fn connect_and_subscribe_sensor_or_disconnect<'a>(
     session: &MijiaSession,
     id: &DeviceId,
 ) -> Result<(), eyre::Report> {
-    session
-        .bt_session
-        .connect(id)
-        .await
-        .wrap_err_with(|| format!("connecting to {:?}", id))?;
+    // The sensor may still be connected from a previous run, in which case there is no need to
+    // connect again.
+    if session.bt_session.is_connected(id).await.unwrap_or(false) {
+        tracing::info!("Already connected");
+    } else {
+        session
+            .bt_session
+            .connect(id)
+            .await
+            .wrap_err_with(|| format!("connecting to {:?}", id))?;
+    }
 
     let mut backoff = ExponentialBackoff::default();
     backoff.max_elapsed_time = Some(SENSOR_CONNECT_RETRY_TIMEOUT);
@@ -413,6 +1828,7 @@ async fn connect_and_subscribe_sensor_or_disconnect<'a>(
         || session.start_notify_sensor(id).map_err(Into::into),
         backoff,
     )
+    .instrument(tracing::info_span!("notify"))
     .or_else(|e| async {
         session
             .bt_session
@@ -424,6 +1840,120 @@ async fn connect_and_subscribe_sensor_or_disconnect<'a>(
     .await
 }
 
+/// Publish a report of the health of the bridge to the `health` property of the bridge node.
+async fn publish_health(state: &Mutex<SensorState>, session: &MijiaSession) {
+    // Get the adapters before locking the state, so that nothing else is held up waiting for BlueZ.
+    // This fails while BlueZ is restarting, which shouldn't stop the bridge.
+    let mut adapters = match session.bt_session.get_adapters().await {
+        Ok(adapters) => adapters,
+        Err(e) => {
+            tracing::error!("Failed to get adapters for health report: {:?}", e);
+            return;
+        }
+    };
+    let state = state.lock().await;
+    if let Some(adapter) = &state.adapter {
+        adapters.retain(|info| info.id == *adapter);
+    }
+    let sensors_connected = state
+        .sensors
+        .values()
+        .filter(|sensor| sensor.connection_status == ConnectionStatus::Connected)
+        .count();
+    let report = state.health.report(
+        Instant::now(),
+        state.sensors.len(),
+        sensors_connected,
+        &adapters,
+    );
+    match serde_json::to_string(&report) {
+        Ok(report) => state
+            .homie
+            .publish_value(BRIDGE_NODE_ID, PROPERTY_ID_HEALTH, report),
+        Err(e) => tracing::error!("Failed to serialise health report: {:?}", e),
+    }
+}
+
+/// Publish metrics about the bridge itself to the metrics node.
+fn publish_metrics(
+    state: &SensorState,
+    session: &MijiaSession,
+    sensor_filter: &SensorFilter,
+    metrics_clock: &mut MetricsClock,
+    now: Instant,
+) {
+    let metrics = BridgeMetrics {
+        sensors_configured: state
+            .sensor_names
+            .keys()
+            .filter(|mac_address| sensor_filter.allows(mac_address))
+            .count(),
+        sensors_connected: state
+            .sensors
+            .values()
+            .filter(|sensor| sensor.connection_status == ConnectionStatus::Connected)
+            .count(),
+        events_per_minute: metrics_clock.events_per_minute(now, state.health.events),
+        decode_errors: session.decode_failures(),
+        uptime: metrics_clock.uptime(now),
+        dbus_reconnects: state.health.bus_resets,
+        mqtt_reconnects: state.homie.reconnects(),
+    };
+    metrics.publish(&state.homie);
+}
+
+/// Log a summary of how many sensors are in each connection state, and publish it to the bridge
+/// node's `status` property.
+fn publish_status(state: &SensorState, status_options: StatusOptions) -> Result<(), eyre::Report> {
+    let report = StatusReport::new(state.sensors.values(), status_options.verbosity);
+    report.log();
+    state.homie.publish_value(
+        BRIDGE_NODE_ID,
+        PROPERTY_ID_STATUS,
+        serde_json::to_string(&report)?,
+    );
+    Ok(())
+}
+
+/// Restart discovery on any adapters which have stopped it unexpectedly, such as after the adapter
+/// was reset, so that new sensors can still be found.
+async fn restart_stopped_discovery(state: Arc<Mutex<SensorState>>, session: &MijiaSession) {
+    let adapters = std::mem::take(&mut state.lock().await.discovery_stopped);
+    for adapter in adapters {
+        match session
+            .bt_session
+            .start_discovery_on_adapter(&adapter)
+            .await
+        {
+            Ok(()) => {
+                tracing::info!("Restarted discovery on adapter {}", adapter);
+                state.lock().await.health.discovery_restarts += 1;
+            }
+            Err(e) => tracing::warn!("Failed to restart discovery on adapter {}: {}", adapter, e),
+        }
+    }
+}
+
+/// Publish the MAC addresses of the sensors which are currently quarantined for flapping to the
+/// `quarantined` property of the bridge node, as a comma-separated list, if they have changed.
+fn publish_quarantined(state: &mut SensorState, now: Instant) {
+    let quarantined: Vec<MacAddress> = state
+        .sensors
+        .values()
+        .filter(|sensor| sensor.flap_detector.is_quarantined(now))
+        .map(|sensor| sensor.mac_address)
+        .sorted()
+        .collect();
+    if quarantined != state.quarantined {
+        state.homie.publish_value(
+            BRIDGE_NODE_ID,
+            PROPERTY_ID_QUARANTINED,
+            quarantined.iter().join(","),
+        );
+        state.quarantined = quarantined;
+    }
+}
+
 /// If the sensor hasn't sent any updates in a while, disconnect it so we will try to reconnect.
 async fn check_for_stale_sensor(
     state: Arc<Mutex<SensorState>>,
@@ -432,34 +1962,267 @@ async fn check_for_stale_sensor(
 ) -> Result<(), eyre::Report> {
     let state = &mut *state.lock().await;
     let sensor = state.sensors.get_mut(&id).unwrap();
+    let update_timeout = state
+        .sensor_timeouts
+        .get(&sensor.mac_address)
+        .copied()
+        .unwrap_or(UPDATE_TIMEOUT);
     let now = Instant::now();
-    if now - sensor.last_update_timestamp > UPDATE_TIMEOUT {
-        println!(
-            "No update from {} for {:?}, reconnecting",
-            sensor.name,
+    if now - sensor.last_update_timestamp > update_timeout {
+        tracing::warn!(
+            sensor = %sensor.name, mac = %sensor.mac_address,
+            "No update for {:?}, reconnecting",
             now - sensor.last_update_timestamp
         );
         sensor.connection_status = ConnectionStatus::Disconnected;
-        state.homie.remove_node(&sensor.node_id()).await?;
+        state.homie.remove_node(&sensor.node_id());
+        state.health.stale_timeouts += 1;
         // We could drop our state lock at this point, if it ends up taking
         // too long. As it is, it's quite nice that we can't attempt to connect
         // while we're in the middle of disconnecting.
-        session
+        let disconnect_result = session
             .bt_session
             .disconnect(&id)
             .await
-            .wrap_err_with(|| format!("disconnecting from {:?}", id))?;
+            .wrap_err_with(|| format!("disconnecting from {:?}", id));
+        // Even if disconnecting failed, removing the device should tear down whatever is left of
+        // the connection.
+        if state.remove_stale_devices {
+            session
+                .bt_session
+                .remove_device(&id)
+                .await
+                .wrap_err_with(|| format!("removing {:?}", id))?;
+        }
+        disconnect_result?;
+    }
+    Ok(())
+}
+
+/// Handle messages to the Homie broadcast channel and commands sent to the bridge node, to let
+/// controllers trigger actions on the bridge.
+async fn handle_incoming(
+    state: Arc<Mutex<SensorState>>,
+    session: &MijiaSession,
+    mut incoming: mpsc::UnboundedReceiver<Incoming>,
+) -> Result<(), eyre::Report> {
+    while let Some(message) = incoming.recv().await {
+        match message {
+            Incoming::Broadcast { level, message } => match level.as_str() {
+                "alert" => tracing::warn!("Broadcast alert: {}", message),
+                "rescan" => {
+                    tracing::info!("Rescan requested by broadcast");
+                    state.lock().await.scan_requested = true;
+                }
+                "sync-clocks" => {
+                    tracing::info!("Clock sync requested by broadcast");
+                    sync_clocks(state.clone(), session).await;
+                }
+                _ => tracing::debug!("Ignoring broadcast {}: {}", level, message),
+            },
+            Incoming::Set {
+                node_id,
+                property_id,
+                value,
+            } if node_id == BRIDGE_NODE_ID && property_id == PROPERTY_ID_COMMAND => {
+                match value.parse() {
+                    Ok(command) => {
+                        handle_command(state.clone(), session, command).await;
+                        // Acknowledge the command, as the Homie convention is for the device to
+                        // publish the new value of a property once it has been set.
+                        state.lock().await.homie.publish_value(
+                            BRIDGE_NODE_ID,
+                            PROPERTY_ID_COMMAND,
+                            value,
+                        );
+                    }
+                    Err(e) => tracing::warn!("{}", e),
+                }
+            }
+            Incoming::Set {
+                node_id,
+                property_id,
+                value,
+            } if property_id == Sensor::PROPERTY_ID_DOWNLOAD_HISTORY
+                && value == DOWNLOAD_HISTORY_REQUEST =>
+            {
+                if let Some(id) = sensor_id_for_node(&state, &node_id).await {
+                    spawn_history_download(state.clone(), session, id);
+                }
+            }
+            Incoming::Set {
+                node_id,
+                property_id,
+                value,
+            } if property_id == Sensor::PROPERTY_ID_DELETE_HISTORY => {
+                if let Some(id) = sensor_id_for_node(&state, &node_id).await {
+                    delete_history(&state, session, &id, &value).await;
+                }
+            }
+            Incoming::Set {
+                node_id,
+                property_id,
+                value,
+            } => tracing::warn!(
+                "Ignoring request to set {}/{} to '{}'",
+                node_id,
+                property_id,
+                value
+            ),
+        }
     }
     Ok(())
 }
 
+/// Carry out the given command sent to the bridge node. Failures are logged rather than returned,
+/// as they shouldn't stop the bridge.
+async fn handle_command(
+    state: Arc<Mutex<SensorState>>,
+    session: &MijiaSession,
+    command: BridgeCommand,
+) {
+    tracing::info!("Received command {:?}", command);
+    match command {
+        BridgeCommand::Rescan => state.lock().await.scan_requested = true,
+        BridgeCommand::Reconnect(sensor) => {
+            let id = {
+                let state = &mut *state.lock().await;
+                let sensor = match find_sensor(&mut state.sensors, &sensor) {
+                    Some(sensor) => sensor,
+                    None => return,
+                };
+                if let ConnectionStatus::Connecting { .. } = sensor.connection_status {
+                    tracing::warn!(sensor = %sensor.name, mac = %sensor.mac_address, "Already connecting");
+                    return;
+                }
+                sensor.connection_status = ConnectionStatus::Disconnected;
+                state.homie.remove_node(&sensor.node_id());
+                sensor.id.clone()
+            };
+            // The connection loop will reconnect the sensor now it is marked as disconnected.
+            if let Err(e) = session.bt_session.disconnect(&id).await {
+                tracing::warn!("Failed to disconnect from {:?}: {:?}", id, e);
+            }
+        }
+        BridgeCommand::DownloadHistory(sensor) => {
+            let id = match find_sensor(&mut state.lock().await.sensors, &sensor) {
+                Some(sensor) => sensor.id.clone(),
+                None => return,
+            };
+            spawn_history_download(state, session, id);
+        }
+        BridgeCommand::DumpState => {
+            let state = state.lock().await;
+            let now = SystemTime::now();
+            let summary = state
+                .sensors
+                .values()
+                .sorted_by_key(|sensor| &sensor.name)
+                .map(|sensor| {
+                    let last_readings = match sensor.last_readings_time {
+                        Some(time) => format!(
+                            "last readings {}s ago",
+                            now.duration_since(time).unwrap_or_default().as_secs()
+                        ),
+                        None => "no readings".to_owned(),
+                    };
+                    format!(
+                        "{} ({}): {:?}, {}",
+                        sensor.name, sensor.mac_address, sensor.connection_status, last_readings
+                    )
+                })
+                .join("\n");
+            tracing::info!("State:\n{}", summary);
+            state
+                .homie
+                .publish_value(BRIDGE_NODE_ID, PROPERTY_ID_STATE, summary);
+        }
+        BridgeCommand::Rename { mac_address, name } => {
+            let state = &mut *state.lock().await;
+            state.sensor_names.insert(mac_address, name.clone());
+            if let Some(sensor) = state
+                .sensors
+                .values_mut()
+                .find(|sensor| sensor.mac_address == mac_address)
+            {
+                sensor.name = name.clone();
+                // The node can't be changed while it is published, so publish it again with the
+                // new name.
+                if sensor.connection_status == ConnectionStatus::Connected {
+                    state.homie.remove_node(&sensor.node_id());
+                    sensor.mark_connected(&state.homie, &state.publish_options);
+                }
+            }
+            if !Path::new(SENSOR_NAMES_FILENAME).exists() {
+                tracing::warn!(
+                    "Creating {}; set AUTO_DISCOVER=true to keep connecting to all sensors",
+                    SENSOR_NAMES_FILENAME
+                );
+            }
+            if let Err(e) = set_sensor_name(Path::new(SENSOR_NAMES_FILENAME), &mac_address, &name) {
+                tracing::error!("Failed to save sensor name: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Get the ID of the sensor with the given Homie node ID, logging a warning if there is none.
+async fn sensor_id_for_node(state: &Mutex<SensorState>, node_id: &str) -> Option<DeviceId> {
+    let id = state
+        .lock()
+        .await
+        .sensors
+        .values()
+        .find(|sensor| sensor.node_id() == node_id)
+        .map(|sensor| sensor.id.clone());
+    if id.is_none() {
+        tracing::warn!("No sensor found with node ID '{}'", node_id);
+    }
+    id
+}
+
+/// Find the sensor with the given name or MAC address, logging a warning if there is none.
+fn find_sensor<'a>(
+    sensors: &'a mut HashMap<DeviceId, Sensor>,
+    name_or_mac_address: &str,
+) -> Option<&'a mut Sensor> {
+    let mac_address = name_or_mac_address.parse::<MacAddress>().ok();
+    let sensor = sensors.values_mut().find(|sensor| {
+        sensor.name == name_or_mac_address || Some(&sensor.mac_address) == mac_address.as_ref()
+    });
+    if sensor.is_none() {
+        tracing::warn!(
+            "No sensor found with name or MAC address '{}'",
+            name_or_mac_address
+        );
+    }
+    sensor
+}
+
+/// Set the clock of every connected sensor to the current time.
+async fn sync_clocks(state: Arc<Mutex<SensorState>>, session: &MijiaSession) {
+    let sensors: Vec<(DeviceId, String, MacAddress)> = state
+        .lock()
+        .await
+        .sensors
+        .values()
+        .filter(|sensor| sensor.connection_status == ConnectionStatus::Connected)
+        .map(|sensor| (sensor.id.clone(), sensor.name.clone(), sensor.mac_address))
+        .collect();
+    for (id, name, mac_address) in sensors {
+        if let Err(e) = session.set_time(&id, SystemTime::now()).await {
+            tracing::error!(sensor = %name, mac = %mac_address, "Failed to set clock: {:?}", e);
+        }
+    }
+}
+
 async fn service_bluetooth_event_queue(
     state: Arc<Mutex<SensorState>>,
     session: &MijiaSession,
 ) -> Result<(), eyre::Report> {
-    println!("Subscribing to events");
+    tracing::info!("Subscribing to events");
     let (msg_match, mut events) = session.event_stream().await?;
-    println!("Processing events");
+    tracing::info!("Processing events");
 
     while let Some(event) = events.next().await {
         handle_bluetooth_event(state.clone(), event).await?
@@ -467,7 +2230,7 @@ async fn service_bluetooth_event_queue(
 
     session
         .bt_session
-        .connection
+        .connection()
         .remove_match(msg_match.token())
         .await?;
     // This should be unreachable, because the events Stream should never end,
@@ -475,44 +2238,275 @@ async fn service_bluetooth_event_queue(
     panic!("no more events");
 }
 
+/// Get the ID of the sensor which the given event is from, if it is from a sensor.
+fn event_device_id(event: &MijiaEvent) -> Option<&DeviceId> {
+    match event {
+        MijiaEvent::Readings { id, .. }
+        | MijiaEvent::HistoryRecord { id, .. }
+        | MijiaEvent::Disconnected { id, .. } => Some(id),
+        _ => None,
+    }
+}
+
 async fn handle_bluetooth_event(
     state: Arc<Mutex<SensorState>>,
     event: MijiaEvent,
 ) -> Result<(), eyre::Report> {
     let state = &mut *state.lock().await;
-    let homie = &mut state.homie;
+    state.health.last_event = Some(Instant::now());
+    state.health.events += 1;
+    // Drop implausible readings before anything else sees them.
+    if let MijiaEvent::Readings {
+        id, readings, time, ..
+    } = &event
+    {
+        if let Some(sensor) = state.sensors.get(id) {
+            let previous = sensor.last_readings_time.zip(sensor.last_readings.as_ref());
+            if let Err(implausibility) = state
+                .publish_options
+                .plausibility
+                .check(previous, readings, *time)
+            {
+                tracing::warn!(sensor = %sensor.name, mac = %sensor.mac_address, event = "implausible_readings", "Dropping implausible readings: {}", implausibility);
+                state.health.implausible_readings += 1;
+                return Ok(());
+            }
+        }
+    }
+    // Drop history records which have already been received, so they aren't stored or forwarded
+    // twice when history is synced or backfilled again.
+    if let MijiaEvent::HistoryRecord { id, record, .. } = &event {
+        if let Some(sensor) = state.sensors.get(id) {
+            if matches!(sensor.last_history_time, Some(time) if record.time <= time) {
+                tracing::trace!(sensor = %sensor.name, mac = %sensor.mac_address, "Dropping duplicate history record {}", record.index);
+                return Ok(());
+            }
+        }
+    }
+    if let Some(sensor) = event_device_id(&event).and_then(|id| state.sensors.get(id)) {
+        // Sending only fails if nothing is currently streaming events, which is fine.
+        let _ = state.events.send((sensor.mac_address, event.clone()));
+    }
+    let homie = &state.homie;
     let sensors = &mut state.sensors;
+    let store = &state.store;
+    let publish_options = &state.publish_options;
     match event {
-        MijiaEvent::Readings { id, readings } => {
+        MijiaEvent::Readings {
+            id,
+            mac_address,
+            readings,
+            time,
+            source,
+        } => {
             if let Some(sensor) = sensors.get_mut(&id) {
-                sensor.publish_readings(homie, &readings).await?;
-                match sensor.connection_status {
-                    ConnectionStatus::Connected | ConnectionStatus::Connecting { .. } => {}
-                    _ => {
-                        println!("Got update from disconnected device {:?}. Connecting.", id);
-                        sensor.mark_connected(homie).await?;
-                        // TODO: Make sure the connection interval is set.
+                if let Some(store) = store {
+                    if let Err(e) = store.insert_readings(&sensor.mac_address, time, &readings) {
+                        tracing::error!(sensor = %sensor.name, mac = %sensor.mac_address, "Failed to store readings: {:?}", e);
                     }
                 }
+                sensor.got_readings(homie, &readings, time, source, publish_options);
             } else {
-                println!("Got update from unknown device {:?}.", id);
+                tracing::warn!(mac = %mac_address, "Got update from unknown device {:?}.", id);
             }
         }
-        MijiaEvent::Disconnected { id } => {
+        MijiaEvent::Disconnected { id, mac_address } => {
             if let Some(sensor) = sensors.get_mut(&id) {
                 if sensor.connection_status == ConnectionStatus::Connected {
-                    println!("{} disconnected", sensor.name);
+                    tracing::info!(
+                        sensor = %sensor.name,
+                        mac = %sensor.mac_address,
+                        event = "disconnected",
+                        "Disconnected"
+                    );
                     sensor.connection_status = ConnectionStatus::MarkedDisconnected;
-                    homie.remove_node(&sensor.node_id()).await?;
+                    state.health.disconnections += 1;
+                    sensor.connection_stats.disconnections += 1;
+                    if sensor.flap_detector.disconnected(Instant::now()) {
+                        tracing::warn!(
+                            sensor = %sensor.name,
+                            mac = %sensor.mac_address,
+                            event = "quarantined",
+                            "Keeps disconnecting straight after connecting, not retrying for a while"
+                        );
+                    }
+                    homie.remove_node(&sensor.node_id());
                 } else {
-                    println!("{:?} disconnected but wasn't known to be connected.", id);
+                    tracing::info!("{:?} disconnected but wasn't known to be connected.", id);
                 }
             } else {
-                println!("Unknown device {:?} disconnected.", id);
+                tracing::info!(mac = %mac_address, "Unknown device {:?} disconnected.", id);
+            }
+        }
+        MijiaEvent::HistoryRecord { id, record, .. } => {
+            if let Some(sensor) = sensors.get_mut(&id) {
+                // Records are sent in order, so this will end up as the latest.
+                sensor.last_history_index = Some(record.index);
+                sensor.last_history_time = Some(record.time);
+                if let Some(store) = store {
+                    if let Err(e) = store.insert_history_record(&sensor.mac_address, &record) {
+                        sensor.unstored_history = true;
+                        tracing::error!(
+                            sensor = %sensor.name, mac = %sensor.mac_address,
+                            "Failed to store history record: {:?}",
+                            e
+                        );
+                    }
+                }
+                if publish_options.history {
+                    sensor.publish_history_record(homie, &record)?;
+                }
+            }
+        }
+        MijiaEvent::AdapterAdded { id } => {
+            tracing::info!("Bluetooth adapter {} added.", id);
+        }
+        MijiaEvent::AdapterPowered { id, powered: true } => {
+            tracing::info!("Bluetooth adapter {} powered on.", id);
+        }
+        MijiaEvent::AdapterRemoved { id } => {
+            tracing::warn!("Bluetooth adapter {} removed.", id);
+            mark_adapter_sensors_disconnected(sensors, homie, &id);
+        }
+        MijiaEvent::AdapterPowered { id, powered: false } => {
+            tracing::warn!("Bluetooth adapter {} powered off.", id);
+            mark_adapter_sensors_disconnected(sensors, homie, &id);
+        }
+        MijiaEvent::AdapterDiscovering {
+            id,
+            discovering: false,
+        } if state.adapter.is_none() || state.adapter.as_ref() == Some(&id) => {
+            // The bridge never stops discovery itself, so the adapter must have been reset.
+            tracing::warn!(
+                adapter = %id,
+                event = "discovery_stopped",
+                "Bluetooth adapter {} stopped discovery, restarting it.",
+                id
+            );
+            state.discovery_stopped.insert(id);
+        }
+        MijiaEvent::BusReset => {
+            tracing::warn!("D-Bus connection or BlueZ reset, reconnecting all sensors.");
+            state.health.bus_resets += 1;
+            for sensor in sensors.values_mut() {
+                if sensor.connection_status == ConnectionStatus::Connected {
+                    sensor.connection_status = ConnectionStatus::MarkedDisconnected;
+                    homie.remove_node(&sensor.node_id());
+                }
             }
+            // Discovery will have stopped too.
+            state.scan_requested = true;
         }
         _ => {}
     };
 
     Ok(())
 }
+
+/// Save the state of every sensor to the state file, if one is configured.
+fn save_state(state: &mut SensorState) -> Result<(), eyre::Report> {
+    let state_file = match &state.state_file {
+        Some(state_file) => state_file,
+        None => return Ok(()),
+    };
+    for sensor in state.sensors.values() {
+        state
+            .saved_sensors
+            .insert(sensor.mac_address, sensor.saved_state());
+    }
+    state_file.save(&state.saved_sensors)
+}
+
+/// Mark all connected sensors on the given adapter as disconnected, so that the connection loop
+/// will try to reconnect them once the adapter is available again.
+fn mark_adapter_sensors_disconnected(
+    sensors: &mut HashMap<DeviceId, Sensor>,
+    homie: &HomieBrokers,
+    adapter: &AdapterId,
+) {
+    for sensor in sensors.values_mut() {
+        if sensor.id.adapter() == *adapter
+            && sensor.connection_status == ConnectionStatus::Connected
+        {
+            tracing::warn!(sensor = %sensor.name, mac = %sensor.mac_address, "Lost Bluetooth adapter");
+            sensor.connection_status = ConnectionStatus::MarkedDisconnected;
+            homie.remove_node(&sensor.node_id());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mijia::test_utils::{sample_device_id, sample_mac_address, sample_readings};
+
+    fn make_test_homie() -> HomieBrokers {
+        let previous_nodes = PreviousNodes {
+            known: HashMap::new(),
+            stale_node: |node_id| Node::new(node_id, node_id, "type", vec![]),
+        };
+        let (homie, _incoming) = HomieBrokers::spawn(
+            "homie/test-device",
+            "Test device",
+            HomieVersion::V4,
+            vec![],
+            previous_nodes,
+            None,
+        );
+        homie
+    }
+
+    fn make_test_sensor(connection_status: ConnectionStatus) -> Sensor {
+        let props = SensorProps {
+            id: sample_device_id(),
+            mac_address: sample_mac_address(),
+            rssi: None,
+            connected: false,
+        };
+        let mut sensor = Sensor::new(
+            props,
+            &HashMap::new(),
+            &HashMap::new(),
+            &HashMap::new(),
+            None,
+        );
+        sensor.connection_status = connection_status;
+        sensor
+    }
+
+    #[test]
+    fn advertisement_readings_leave_sensor_disconnected() {
+        let homie = make_test_homie();
+        let publish_options = PublishOptions::default();
+        for &status in &[
+            ConnectionStatus::Unknown,
+            ConnectionStatus::Disconnected,
+            ConnectionStatus::MarkedDisconnected,
+        ] {
+            let mut sensor = make_test_sensor(status);
+            sensor.got_readings(
+                &homie,
+                &sample_readings(),
+                SystemTime::now(),
+                ReadingsSource::Advertisement,
+                &publish_options,
+            );
+            assert_eq!(sensor.connection_status, status);
+            assert_eq!(sensor.last_readings, Some(sample_readings()));
+        }
+    }
+
+    #[test]
+    fn notification_readings_mark_sensor_connected() {
+        let homie = make_test_homie();
+        let mut sensor = make_test_sensor(ConnectionStatus::MarkedDisconnected);
+        sensor.got_readings(
+            &homie,
+            &sample_readings(),
+            SystemTime::now(),
+            ReadingsSource::Notification,
+            &PublishOptions::default(),
+        );
+        assert_eq!(sensor.connection_status, ConnectionStatus::Connected);
+    }
+}
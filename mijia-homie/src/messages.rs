@@ -0,0 +1,117 @@
+//! JSON messages for readings and history records, shared by the outputs which publish to cloud IoT
+//! services rather than following the Homie convention.
+
+use crate::SensorState;
+use mijia::{HistoryRecord, MacAddress, Readings};
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The JSON payload published for each set of readings.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ReadingsMessage {
+    pub name: String,
+    pub mac_address: String,
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+    pub temperature: f32,
+    pub humidity: u8,
+    pub battery: u16,
+    pub battery_voltage: u16,
+}
+
+impl ReadingsMessage {
+    pub fn new(name: &str, mac_address: MacAddress, readings: &Readings, time: SystemTime) -> Self {
+        Self {
+            name: name.to_owned(),
+            mac_address: mac_address.to_string(),
+            time: unix_timestamp(time),
+            temperature: readings.temperature,
+            humidity: readings.humidity,
+            battery: readings.battery_percent,
+            battery_voltage: readings.battery_voltage,
+        }
+    }
+}
+
+/// The JSON payload published for each history record.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HistoryMessage {
+    pub name: String,
+    pub mac_address: String,
+    pub index: u32,
+    /// Seconds since the Unix epoch at which the sensor recorded the values.
+    pub time: u64,
+    pub temperature_min: f32,
+    pub temperature_max: f32,
+    pub humidity_min: u8,
+    pub humidity_max: u8,
+}
+
+impl HistoryMessage {
+    pub fn new(name: &str, mac_address: MacAddress, record: &HistoryRecord) -> Self {
+        Self {
+            name: name.to_owned(),
+            mac_address: mac_address.to_string(),
+            index: record.index,
+            time: unix_timestamp(record.time),
+            temperature_min: record.temperature_min,
+            temperature_max: record.temperature_max,
+            humidity_min: record.humidity_min,
+            humidity_max: record.humidity_max,
+        }
+    }
+}
+
+/// Get the name of the sensor with the given MAC address, or the MAC address if it has none.
+pub fn sensor_name(state: &SensorState, mac_address: MacAddress) -> String {
+    state
+        .sensor_names
+        .get(&mac_address)
+        .cloned()
+        .unwrap_or_else(|| mac_address.to_string())
+}
+
+/// Format the given MAC address without separators, for use in topics and keys which don't allow
+/// colons.
+pub fn compact_mac_address(mac_address: MacAddress) -> String {
+    mac_address
+        .octets()
+        .iter()
+        .map(|octet| format!("{:02X}", octet))
+        .collect()
+}
+
+/// Convert the given time to a number of seconds since the Unix epoch.
+fn unix_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn readings_json() {
+        let readings = Readings {
+            temperature: 21.5,
+            humidity: 45,
+            battery_voltage: 3000,
+            battery_percent: 90,
+        };
+        let mac_address: MacAddress = "A4:C1:38:D7:21:17".parse().unwrap();
+        let message = ReadingsMessage::new(
+            "Living room",
+            mac_address,
+            &readings,
+            UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+        );
+        assert_eq!(
+            serde_json::to_string(&message).unwrap(),
+            r#"{"name":"Living room","mac_address":"A4:C1:38:D7:21:17","time":1600000000,"temperature":21.5,"humidity":45,"battery":90,"battery_voltage":3000}"#
+        );
+        assert_eq!(compact_mac_address(mac_address), "A4C138D72117");
+    }
+}
@@ -0,0 +1,109 @@
+//! Persistence of property values which couldn't be published because an MQTT broker was
+//! unreachable, so that they survive restarts of the bridge until they can be published.
+
+use stable_eyre::eyre;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// The key and value of a single property value, in the order `((node_id, property_id), value)`.
+pub type QueuedValue = ((String, String), String);
+
+/// An append-only file of property values waiting to be published to a broker. Each line has the
+/// node ID, property ID and value separated by tabs.
+#[derive(Debug)]
+pub struct OfflineQueue {
+    path: PathBuf,
+}
+
+impl OfflineQueue {
+    /// Use the queue file for the broker with the given address in the given directory. The file
+    /// will be created when the first value is added.
+    pub fn new(directory: &Path, host: &str, port: u16) -> Self {
+        Self {
+            path: directory.join(format!("{}_{}.queue", host, port)),
+        }
+    }
+
+    /// Read all values from the queue, oldest first.
+    pub fn load(&self) -> Result<Vec<QueuedValue>, eyre::Report> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        let mut values = vec![];
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            let parts: Vec<&str> = line.splitn(3, '\t').collect();
+            if let [node_id, property_id, value] = parts.as_slice() {
+                values.push((
+                    (node_id.to_string(), property_id.to_string()),
+                    value.to_string(),
+                ));
+            } else {
+                eyre::bail!("Invalid line '{}' in {}", line, self.path.display());
+            }
+        }
+        Ok(values)
+    }
+
+    /// Add a value to the end of the queue.
+    pub fn append(
+        &self,
+        node_id: &str,
+        property_id: &str,
+        value: &str,
+    ) -> Result<(), eyre::Report> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}\t{}\t{}", node_id, property_id, value)?;
+        Ok(())
+    }
+
+    /// Remove all values from the queue, once they have been published.
+    pub fn clear(&self) -> Result<(), eyre::Report> {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_test_queue(name: &str) -> OfflineQueue {
+        let queue = OfflineQueue::new(&std::env::temp_dir(), name, std::process::id() as u16);
+        queue.clear().unwrap();
+        queue
+    }
+
+    #[test]
+    fn load_missing_file_is_empty() {
+        let queue = make_test_queue("offline-queue-missing");
+        assert_eq!(queue.load().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn append_load_clear() {
+        let queue = make_test_queue("offline-queue-append");
+        queue.append("node", "temperature", "21.50").unwrap();
+        queue.append("node", "humidity", "42").unwrap();
+        assert_eq!(
+            queue.load().unwrap(),
+            vec![
+                (
+                    ("node".to_owned(), "temperature".to_owned()),
+                    "21.50".to_owned()
+                ),
+                (("node".to_owned(), "humidity".to_owned()), "42".to_owned()),
+            ]
+        );
+        queue.clear().unwrap();
+        assert_eq!(queue.load().unwrap(), vec![]);
+    }
+}
@@ -0,0 +1,171 @@
+//! Rejecting readings which can't be right, such as those decoded from a corrupted notification,
+//! before they are stored or published and set off automations.
+
+use mijia::Readings;
+use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, SystemTime};
+
+/// Limits on what readings are plausible. By default only readings outside the range which the
+/// sensors can measure are rejected.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Plausibility {
+    /// The lowest plausible temperature in ºC.
+    pub temperature_min: f32,
+    /// The highest plausible temperature in ºC.
+    pub temperature_max: f32,
+    /// The highest plausible relative humidity in %.
+    pub humidity_max: u8,
+    /// The largest plausible change in temperature in ºC from the previous readings, if limited.
+    pub temperature_delta: Option<f32>,
+    /// The largest plausible change in relative humidity in % from the previous readings, if
+    /// limited.
+    pub humidity_delta: Option<u8>,
+    /// How recent the previous readings must be for the change from them to be checked. After this
+    /// long without plausible readings, a sudden change is assumed to be real.
+    pub delta_interval: Duration,
+}
+
+impl Default for Plausibility {
+    fn default() -> Self {
+        Self {
+            temperature_min: -40.0,
+            temperature_max: 85.0,
+            humidity_max: 100,
+            temperature_delta: None,
+            humidity_delta: None,
+            delta_interval: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// The reason why some readings were rejected as implausible.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Implausibility {
+    TemperatureOutOfRange(f32),
+    HumidityOutOfRange(u8),
+    TemperatureChange { from: f32, to: f32 },
+    HumidityChange { from: u8, to: u8 },
+}
+
+impl Display for Implausibility {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::TemperatureOutOfRange(temperature) => {
+                write!(f, "temperature {:.2}ºC out of range", temperature)
+            }
+            Self::HumidityOutOfRange(humidity) => write!(f, "humidity {}% out of range", humidity),
+            Self::TemperatureChange { from, to } => {
+                write!(f, "temperature jumped from {:.2}ºC to {:.2}ºC", from, to)
+            }
+            Self::HumidityChange { from, to } => {
+                write!(f, "humidity jumped from {}% to {}%", from, to)
+            }
+        }
+    }
+}
+
+impl Plausibility {
+    /// Check whether the given readings received at the given time are plausible, given the
+    /// previous plausible readings from the same sensor and when they were received, if any.
+    pub fn check(
+        &self,
+        previous: Option<(SystemTime, &Readings)>,
+        readings: &Readings,
+        now: SystemTime,
+    ) -> Result<(), Implausibility> {
+        if readings.temperature < self.temperature_min
+            || readings.temperature > self.temperature_max
+            || readings.temperature.is_nan()
+        {
+            return Err(Implausibility::TemperatureOutOfRange(readings.temperature));
+        }
+        if readings.humidity > self.humidity_max {
+            return Err(Implausibility::HumidityOutOfRange(readings.humidity));
+        }
+
+        let previous = match previous {
+            Some((time, previous))
+                if now.duration_since(time).unwrap_or_default() <= self.delta_interval =>
+            {
+                previous
+            }
+            _ => return Ok(()),
+        };
+        if let Some(temperature_delta) = self.temperature_delta {
+            if (readings.temperature - previous.temperature).abs() > temperature_delta {
+                return Err(Implausibility::TemperatureChange {
+                    from: previous.temperature,
+                    to: readings.temperature,
+                });
+            }
+        }
+        if let Some(humidity_delta) = self.humidity_delta {
+            let humidity_change =
+                (i16::from(readings.humidity) - i16::from(previous.humidity)).abs();
+            if humidity_change > i16::from(humidity_delta) {
+                return Err(Implausibility::HumidityChange {
+                    from: previous.humidity,
+                    to: readings.humidity,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mijia::test_utils::readings;
+
+    #[test]
+    fn out_of_range() {
+        let plausibility = Plausibility::default();
+        let now = SystemTime::now();
+        assert_eq!(
+            plausibility.check(None, &readings(21.0, 50, 3000), now),
+            Ok(())
+        );
+        assert_eq!(
+            plausibility.check(None, &readings(327.0, 50, 3000), now),
+            Err(Implausibility::TemperatureOutOfRange(327.0))
+        );
+        assert_eq!(
+            plausibility.check(None, &readings(21.0, 180, 3000), now),
+            Err(Implausibility::HumidityOutOfRange(180))
+        );
+    }
+
+    #[test]
+    fn large_changes() {
+        let plausibility = Plausibility {
+            temperature_delta: Some(5.0),
+            humidity_delta: Some(20),
+            ..Default::default()
+        };
+        let now = SystemTime::now();
+        let previous = readings(21.0, 50, 3000);
+        let recent = Some((now - Duration::from_secs(10), &previous));
+        assert_eq!(
+            plausibility.check(recent, &readings(24.0, 65, 3000), now),
+            Ok(())
+        );
+        assert_eq!(
+            plausibility.check(recent, &readings(40.0, 50, 3000), now),
+            Err(Implausibility::TemperatureChange {
+                from: 21.0,
+                to: 40.0
+            })
+        );
+        assert_eq!(
+            plausibility.check(recent, &readings(21.0, 10, 3000), now),
+            Err(Implausibility::HumidityChange { from: 50, to: 10 })
+        );
+        // Changes from readings which are too old aren't checked.
+        let old = Some((now - Duration::from_secs(10 * 60), &previous));
+        assert_eq!(
+            plausibility.check(old, &readings(40.0, 10, 3000), now),
+            Ok(())
+        );
+    }
+}
@@ -0,0 +1,362 @@
+//! An optional sink which writes readings and history records to PostgreSQL or TimescaleDB, for
+//! long-term storage without a separate bridge from MQTT to the database.
+
+use crate::SensorState;
+use mijia::{HistoryRecord, MacAddress, MijiaEvent, Readings};
+use stable_eyre::eyre;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::broadcast::RecvError;
+use tokio::sync::Mutex;
+use tokio::time;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::{Client, NoTls};
+
+/// How often to write buffered rows, even if there aren't enough for a full batch.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+/// The most rows of each kind to buffer while the database can't be reached, after which the
+/// oldest are dropped.
+const MAX_BUFFERED_ROWS: usize = 10_000;
+
+/// Configuration for writing to PostgreSQL.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Postgres {
+    /// The connection string, such as `host=localhost user=mijia dbname=sensors` or
+    /// `postgresql://mijia@localhost/sensors`.
+    pub url: String,
+    /// The schema containing the tables.
+    pub schema: String,
+    /// The table to insert readings into. It is created if it doesn't exist.
+    pub readings_table: String,
+    /// The table to insert history records into. It is created if it doesn't exist.
+    pub history_table: String,
+    /// How many rows to insert at once.
+    pub batch_size: usize,
+    /// Whether to make the tables TimescaleDB hypertables when creating them.
+    pub timescaledb: bool,
+}
+
+impl Default for Postgres {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            schema: "public".to_owned(),
+            readings_table: "mijia_readings".to_owned(),
+            history_table: "mijia_history".to_owned(),
+            batch_size: 100,
+            timescaledb: false,
+        }
+    }
+}
+
+/// A set of readings waiting to be inserted.
+#[derive(Clone, Debug, PartialEq)]
+struct ReadingsRow {
+    time: SystemTime,
+    mac_address: MacAddress,
+    readings: Readings,
+}
+
+/// A history record waiting to be inserted.
+#[derive(Clone, Debug, PartialEq)]
+struct HistoryRow {
+    mac_address: MacAddress,
+    record: HistoryRecord,
+}
+
+impl Postgres {
+    /// Write every readings and history record event from the bridge to the database, until the
+    /// bridge stops. Rows are buffered while the database can't be reached, and it is reconnected
+    /// to at the next flush.
+    pub async fn run(&self, state: Arc<Mutex<SensorState>>) -> Result<(), eyre::Report> {
+        tracing::info!(
+            "Writing readings to PostgreSQL tables {} and {}",
+            self.table(&self.readings_table),
+            self.table(&self.history_table)
+        );
+        let mut events = state.lock().await.events.subscribe();
+        let mut flush_interval = time::interval(FLUSH_INTERVAL);
+        let mut client: Option<Client> = None;
+        let mut readings_rows = vec![];
+        let mut history_rows = vec![];
+        loop {
+            let flush = tokio::select! {
+                event = events.recv() => {
+                    match event {
+                        Ok((mac_address, MijiaEvent::Readings { readings, time, .. })) => {
+                            readings_rows.push(ReadingsRow { time, mac_address, readings });
+                        }
+                        Ok((mac_address, MijiaEvent::HistoryRecord { record, .. })) => {
+                            history_rows.push(HistoryRow { mac_address, record });
+                        }
+                        Ok(_) => {}
+                        Err(RecvError::Lagged(skipped)) => {
+                            tracing::warn!("PostgreSQL writer missed {} events.", skipped);
+                        }
+                        Err(RecvError::Closed) => return Ok(()),
+                    }
+                    readings_rows.len() >= self.batch_size || history_rows.len() >= self.batch_size
+                }
+                _ = flush_interval.tick() => true,
+            };
+            if !flush || (readings_rows.is_empty() && history_rows.is_empty()) {
+                continue;
+            }
+
+            if client.is_none() {
+                match self.connect().await {
+                    Ok(new_client) => client = Some(new_client),
+                    Err(e) => tracing::warn!("Failed to connect to PostgreSQL: {:?}", e),
+                }
+            }
+            if let Some(connected) = &client {
+                match self.flush(connected, &readings_rows, &history_rows).await {
+                    Ok(()) => {
+                        readings_rows.clear();
+                        history_rows.clear();
+                    }
+                    Err(e) => {
+                        tracing::warn!("Failed to write to PostgreSQL: {:?}", e);
+                        client = None;
+                    }
+                }
+            }
+            drop_oldest(&mut readings_rows, "readings");
+            drop_oldest(&mut history_rows, "history records");
+        }
+    }
+
+    /// Connect to the database, and create the tables if they don't exist yet.
+    async fn connect(&self) -> Result<Client, eyre::Report> {
+        let (client, connection) = tokio_postgres::connect(&self.url, NoTls).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                tracing::warn!("PostgreSQL connection failed: {}", e);
+            }
+        });
+        client.batch_execute(&self.create_tables_sql()).await?;
+        tracing::info!("Connected to PostgreSQL");
+        Ok(client)
+    }
+
+    /// Insert the given rows in a single transaction.
+    async fn flush(
+        &self,
+        client: &Client,
+        readings_rows: &[ReadingsRow],
+        history_rows: &[HistoryRow],
+    ) -> Result<(), eyre::Report> {
+        client.batch_execute("BEGIN").await?;
+        for batch in readings_rows.chunks(self.batch_size) {
+            let mac_addresses: Vec<String> = batch
+                .iter()
+                .map(|row| row.mac_address.to_string())
+                .collect();
+            let humidities: Vec<i16> = batch
+                .iter()
+                .map(|row| row.readings.humidity.into())
+                .collect();
+            let battery_voltages: Vec<i32> = batch
+                .iter()
+                .map(|row| row.readings.battery_voltage.into())
+                .collect();
+            let battery_percents: Vec<i16> = batch
+                .iter()
+                .map(|row| row.readings.battery_percent as i16)
+                .collect();
+            let mut parameters: Vec<&(dyn ToSql + Sync)> = vec![];
+            for (i, row) in batch.iter().enumerate() {
+                parameters.push(&row.time);
+                parameters.push(&mac_addresses[i]);
+                parameters.push(&row.readings.temperature);
+                parameters.push(&humidities[i]);
+                parameters.push(&battery_voltages[i]);
+                parameters.push(&battery_percents[i]);
+            }
+            client
+                .execute(
+                    self.insert_sql(
+                        &self.readings_table,
+                        "time, mac_address, temperature, humidity, battery_voltage, battery_percent",
+                        6,
+                        batch.len(),
+                        "",
+                    )
+                    .as_str(),
+                    &parameters,
+                )
+                .await?;
+        }
+        for batch in history_rows.chunks(self.batch_size) {
+            let mac_addresses: Vec<String> = batch
+                .iter()
+                .map(|row| row.mac_address.to_string())
+                .collect();
+            let indices: Vec<i64> = batch.iter().map(|row| row.record.index.into()).collect();
+            let humidity_mins: Vec<i16> = batch
+                .iter()
+                .map(|row| row.record.humidity_min.into())
+                .collect();
+            let humidity_maxes: Vec<i16> = batch
+                .iter()
+                .map(|row| row.record.humidity_max.into())
+                .collect();
+            let mut parameters: Vec<&(dyn ToSql + Sync)> = vec![];
+            for (i, row) in batch.iter().enumerate() {
+                parameters.push(&row.record.time);
+                parameters.push(&mac_addresses[i]);
+                parameters.push(&indices[i]);
+                parameters.push(&row.record.temperature_min);
+                parameters.push(&row.record.temperature_max);
+                parameters.push(&humidity_mins[i]);
+                parameters.push(&humidity_maxes[i]);
+            }
+            client
+                .execute(
+                    self.insert_sql(
+                        &self.history_table,
+                        "time, mac_address, record_index, temperature_min, temperature_max, \
+                         humidity_min, humidity_max",
+                        7,
+                        batch.len(),
+                        " ON CONFLICT DO NOTHING",
+                    )
+                    .as_str(),
+                    &parameters,
+                )
+                .await?;
+        }
+        client.batch_execute("COMMIT").await?;
+        Ok(())
+    }
+
+    /// Get the SQL to create the tables if they don't exist.
+    fn create_tables_sql(&self) -> String {
+        let readings_table = self.table(&self.readings_table);
+        let history_table = self.table(&self.history_table);
+        let mut sql = format!(
+            "CREATE TABLE IF NOT EXISTS {} (\
+               time TIMESTAMPTZ NOT NULL, \
+               mac_address TEXT NOT NULL, \
+               temperature REAL NOT NULL, \
+               humidity SMALLINT NOT NULL, \
+               battery_voltage INTEGER NOT NULL, \
+               battery_percent SMALLINT NOT NULL); \
+             CREATE TABLE IF NOT EXISTS {} (\
+               time TIMESTAMPTZ NOT NULL, \
+               mac_address TEXT NOT NULL, \
+               record_index BIGINT NOT NULL, \
+               temperature_min REAL NOT NULL, \
+               temperature_max REAL NOT NULL, \
+               humidity_min SMALLINT NOT NULL, \
+               humidity_max SMALLINT NOT NULL, \
+               PRIMARY KEY (mac_address, time));",
+            readings_table, history_table
+        );
+        if self.timescaledb {
+            for table in &[readings_table, history_table] {
+                sql.push_str(&format!(
+                    " SELECT create_hypertable('{}', 'time', if_not_exists => TRUE);",
+                    table.replace('\'', "''")
+                ));
+            }
+        }
+        sql
+    }
+
+    /// Get the SQL to insert the given number of rows, each with the given number of columns, into
+    /// the given table.
+    fn insert_sql(
+        &self,
+        table: &str,
+        columns: &str,
+        column_count: usize,
+        row_count: usize,
+        suffix: &str,
+    ) -> String {
+        let rows: Vec<String> = (0..row_count)
+            .map(|row| {
+                let placeholders: Vec<String> = (1..=column_count)
+                    .map(|column| format!("${}", row * column_count + column))
+                    .collect();
+                format!("({})", placeholders.join(", "))
+            })
+            .collect();
+        format!(
+            "INSERT INTO {} ({}) VALUES {}{}",
+            self.table(table),
+            columns,
+            rows.join(", "),
+            suffix
+        )
+    }
+
+    /// Get the quoted name of the given table in the configured schema.
+    fn table(&self, table: &str) -> String {
+        format!(
+            "{}.{}",
+            quote_identifier(&self.schema),
+            quote_identifier(table)
+        )
+    }
+}
+
+/// Quote the given identifier for use in SQL.
+fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+/// Drop the oldest of the given rows if there are too many buffered.
+fn drop_oldest<T>(rows: &mut Vec<T>, kind: &str) {
+    if rows.len() > MAX_BUFFERED_ROWS {
+        let excess = rows.len() - MAX_BUFFERED_ROWS;
+        tracing::warn!(
+            "Dropping {} {} which couldn't be written to PostgreSQL",
+            excess,
+            kind
+        );
+        rows.drain(..excess);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_sql() {
+        let postgres = Postgres {
+            schema: "sensors".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(
+            postgres.insert_sql("readings", "a, b", 2, 2, " ON CONFLICT DO NOTHING"),
+            r#"INSERT INTO "sensors"."readings" (a, b) VALUES ($1, $2), ($3, $4) ON CONFLICT DO NOTHING"#
+        );
+    }
+
+    #[test]
+    fn quote_table() {
+        let postgres = Postgres::default();
+        assert_eq!(
+            postgres.table(r#"my "table""#),
+            r#""public"."my ""table""""#
+        );
+    }
+
+    #[test]
+    fn timescaledb_hypertables() {
+        let postgres = Postgres {
+            timescaledb: true,
+            ..Default::default()
+        };
+        let sql = postgres.create_tables_sql();
+        assert!(sql.contains(r#"CREATE TABLE IF NOT EXISTS "public"."mijia_readings""#));
+        assert!(sql.contains(
+            r#"SELECT create_hypertable('"public"."mijia_history"', 'time', if_not_exists => TRUE);"#
+        ));
+        assert!(!Postgres::default()
+            .create_tables_sql()
+            .contains("create_hypertable"));
+    }
+}
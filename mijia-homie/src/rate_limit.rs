@@ -0,0 +1,98 @@
+//! Limiting how often sensor readings are published, to keep down churn of retained topics and
+//! load on the MQTT broker from sensors which send notifications very frequently.
+
+use mijia::Readings;
+use std::time::{Duration, Instant};
+
+/// Limits on how often readings from each sensor are published. The default is not to limit them at
+/// all.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RateLimit {
+    /// The minimum time between publishing readings from the same sensor.
+    pub min_interval: Duration,
+    /// The minimum change in temperature in ºC since the last published readings for new readings
+    /// to be published.
+    pub temperature_delta: f32,
+    /// The minimum change in relative humidity in % since the last published readings for new
+    /// readings to be published.
+    pub humidity_delta: u8,
+}
+
+impl RateLimit {
+    /// Returns whether the given readings should be published at the given time, given the readings
+    /// which were last published for the same sensor and when, if any.
+    pub fn should_publish(
+        &self,
+        last_published: Option<&(Instant, Readings)>,
+        readings: &Readings,
+        now: Instant,
+    ) -> bool {
+        if let Some((last_time, last_readings)) = last_published {
+            let humidity_change =
+                (i16::from(readings.humidity) - i16::from(last_readings.humidity)).abs();
+            now.duration_since(*last_time) >= self.min_interval
+                && ((readings.temperature - last_readings.temperature).abs()
+                    >= self.temperature_delta
+                    || humidity_change >= i16::from(self.humidity_delta))
+        } else {
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mijia::test_utils::readings;
+
+    #[test]
+    fn default_always_publishes() {
+        let now = Instant::now();
+        let last = (now, readings(20.0, 50, 3000));
+        assert!(RateLimit::default().should_publish(Some(&last), &readings(20.0, 50, 3000), now));
+    }
+
+    #[test]
+    fn first_readings_published() {
+        let rate_limit = RateLimit {
+            min_interval: Duration::from_secs(60),
+            temperature_delta: 1.0,
+            humidity_delta: 5,
+        };
+        assert!(rate_limit.should_publish(None, &readings(20.0, 50, 3000), Instant::now()));
+    }
+
+    #[test]
+    fn min_interval() {
+        let rate_limit = RateLimit {
+            min_interval: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let now = Instant::now();
+        let last = (now, readings(20.0, 50, 3000));
+        assert!(!rate_limit.should_publish(
+            Some(&last),
+            &readings(25.0, 60, 3000),
+            now + Duration::from_secs(59)
+        ));
+        assert!(rate_limit.should_publish(
+            Some(&last),
+            &readings(25.0, 60, 3000),
+            now + Duration::from_secs(60)
+        ));
+    }
+
+    #[test]
+    fn deltas() {
+        let rate_limit = RateLimit {
+            temperature_delta: 0.5,
+            humidity_delta: 2,
+            ..Default::default()
+        };
+        let now = Instant::now();
+        let last = (now, readings(20.0, 50, 3000));
+        assert!(!rate_limit.should_publish(Some(&last), &readings(20.4, 49, 3000), now));
+        assert!(rate_limit.should_publish(Some(&last), &readings(19.5, 50, 3000), now));
+        assert!(rate_limit.should_publish(Some(&last), &readings(20.0, 48, 3000), now));
+    }
+}
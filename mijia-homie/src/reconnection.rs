@@ -0,0 +1,35 @@
+//! Deciding which order to reconnect sensors in, so that sensors which are likely to come back
+//! aren't kept waiting behind one which has been unreachable for a long time.
+
+use std::cmp::Reverse;
+use std::time::SystemTime;
+
+/// Given sensors along with the time at which each last sent readings, if ever, order them so that
+/// the most recently healthy come first and those which have never sent readings come last. Sensors
+/// with the same time keep their relative order.
+pub fn reconnection_order<T>(mut sensors: Vec<(T, Option<SystemTime>)>) -> Vec<T> {
+    sensors.sort_by_key(|(_, last_readings_time)| Reverse(*last_readings_time));
+    sensors.into_iter().map(|(sensor, _)| sensor).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn most_recently_healthy_first() {
+        let time = |seconds| Some(UNIX_EPOCH + Duration::from_secs(seconds));
+        let sensors = vec![
+            ("dead", None),
+            ("old", time(1_000)),
+            ("recent", time(3_000)),
+            ("never", None),
+            ("middle", time(2_000)),
+        ];
+        assert_eq!(
+            reconnection_order(sensors),
+            vec!["recent", "middle", "old", "dead", "never"]
+        );
+    }
+}
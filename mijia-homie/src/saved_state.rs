@@ -0,0 +1,181 @@
+//! Persistence of the state of each sensor, such as its latest readings and how far its history
+//! has been downloaded, so that it survives restarts of the bridge.
+
+use mijia::{MacAddress, Readings};
+use serde::{Deserialize, Serialize};
+use stable_eyre::eyre;
+use stable_eyre::eyre::WrapErr;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Counts of connection events for a single sensor.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ConnectionStats {
+    /// The number of times the bridge has successfully connected to the sensor.
+    pub connections: u64,
+    /// The number of failed attempts to connect to the sensor.
+    pub connect_failures: u64,
+    /// The number of times the sensor has disconnected after being connected.
+    pub disconnections: u64,
+}
+
+/// The state of a sensor to persist across restarts.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct SavedSensor {
+    /// The latest readings received from the sensor, if any.
+    #[serde(default)]
+    pub last_readings: Option<SavedReadings>,
+    /// The index of the latest history record received from the sensor, if any.
+    #[serde(default)]
+    pub last_history_index: Option<u32>,
+    /// The time of the latest history record received from the sensor, as a Unix timestamp in
+    /// seconds, if any.
+    #[serde(default)]
+    pub last_history_time: Option<u64>,
+    #[serde(default)]
+    pub stats: ConnectionStats,
+}
+
+impl SavedSensor {
+    /// Get the time of the latest history record received from the sensor, if any.
+    pub fn last_history_time(&self) -> Option<SystemTime> {
+        self.last_history_time
+            .map(|time| UNIX_EPOCH + Duration::from_secs(time))
+    }
+}
+
+/// A set of readings along with the time at which they were received.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SavedReadings {
+    /// The time at which the readings were received, as a Unix timestamp in seconds.
+    pub time: u64,
+    pub temperature: f32,
+    pub humidity: u8,
+    pub battery_voltage: u16,
+    pub battery_percent: u16,
+}
+
+impl SavedReadings {
+    pub fn new(time: SystemTime, readings: &Readings) -> Self {
+        Self {
+            time: unix_seconds(time),
+            temperature: readings.temperature,
+            humidity: readings.humidity,
+            battery_voltage: readings.battery_voltage,
+            battery_percent: readings.battery_percent,
+        }
+    }
+
+    /// Get the time at which the readings were received.
+    pub fn time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.time)
+    }
+
+    pub fn readings(&self) -> Readings {
+        Readings {
+            temperature: self.temperature,
+            humidity: self.humidity,
+            battery_voltage: self.battery_voltage,
+            battery_percent: self.battery_percent,
+        }
+    }
+}
+
+/// Convert the given time to a Unix timestamp in seconds, for saving.
+pub fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// A JSON file containing the saved state of every known sensor, keyed by MAC address.
+#[derive(Debug)]
+pub struct StateFile {
+    path: PathBuf,
+}
+
+impl StateFile {
+    /// Use the state file at the given path. It will be created when the state is first saved.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Read the saved state of every sensor, or nothing if the file doesn't exist yet.
+    pub fn load(&self) -> Result<HashMap<MacAddress, SavedSensor>, eyre::Report> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let sensors: BTreeMap<String, SavedSensor> = serde_json::from_str(&contents)
+            .wrap_err_with(|| format!("parsing {}", self.path.display()))?;
+        sensors
+            .into_iter()
+            .map(|(mac_address, sensor)| Ok((mac_address.parse()?, sensor)))
+            .collect()
+    }
+
+    /// Save the state of the given sensors, replacing whatever was saved before. The new state is
+    /// written to a temporary file first, so the previous state isn't lost if writing fails.
+    pub fn save(&self, sensors: &HashMap<MacAddress, SavedSensor>) -> Result<(), eyre::Report> {
+        // Sort by MAC address so that the file is easier to read and compare.
+        let sensors: BTreeMap<String, &SavedSensor> = sensors
+            .iter()
+            .map(|(mac_address, sensor)| (mac_address.to_string(), sensor))
+            .collect();
+        let temporary_path = self.path.with_extension("tmp");
+        fs::write(&temporary_path, serde_json::to_string_pretty(&sensors)?)?;
+        fs::rename(&temporary_path, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("sensor_state_{}.json", std::process::id()));
+        let state_file = StateFile::new(&path);
+        assert_eq!(state_file.load().unwrap(), HashMap::new());
+
+        let readings = Readings {
+            temperature: 21.5,
+            humidity: 45,
+            battery_voltage: 3000,
+            battery_percent: 90,
+        };
+        let time = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let mut sensors = HashMap::new();
+        sensors.insert(
+            "A4:C1:38:D7:21:17".parse().unwrap(),
+            SavedSensor {
+                last_readings: Some(SavedReadings::new(time, &readings)),
+                last_history_index: Some(42),
+                last_history_time: Some(1_599_999_000),
+                stats: ConnectionStats {
+                    connections: 3,
+                    connect_failures: 1,
+                    disconnections: 2,
+                },
+            },
+        );
+        sensors.insert("A4:C1:38:D7:21:18".parse().unwrap(), SavedSensor::default());
+        state_file.save(&sensors).unwrap();
+
+        let loaded = state_file.load().unwrap();
+        assert_eq!(loaded, sensors);
+        let last_readings = loaded[&"A4:C1:38:D7:21:17".parse().unwrap()]
+            .last_readings
+            .clone()
+            .unwrap();
+        assert_eq!(last_readings.time(), time);
+        assert_eq!(last_readings.readings(), readings);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
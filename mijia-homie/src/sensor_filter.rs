@@ -0,0 +1,70 @@
+//! Deciding which of the sensors discovered by a scan the bridge should connect to.
+
+use mijia::MacAddress;
+use std::collections::HashSet;
+
+/// Which sensors the bridge should connect to.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SensorFilter {
+    /// If set, only sensors with these MAC addresses are connected to. Otherwise any sensor which
+    /// isn't blocked is.
+    pub allowlist: Option<HashSet<MacAddress>>,
+    /// Sensors which are never connected to, even if they are in the allowlist.
+    pub blocklist: HashSet<MacAddress>,
+}
+
+impl SensorFilter {
+    /// Returns whether the bridge should connect to the sensor with the given MAC address.
+    pub fn allows(&self, mac_address: &MacAddress) -> bool {
+        if self.blocklist.contains(mac_address) {
+            return false;
+        }
+        match &self.allowlist {
+            Some(allowlist) => allowlist.contains(mac_address),
+            None => true,
+        }
+    }
+
+    /// The number of sensors which the filter allows, or `None` if there is no limit, in which case
+    /// scanning should continue indefinitely.
+    pub fn max_sensors(&self) -> Option<usize> {
+        self.allowlist
+            .as_ref()
+            .map(|allowlist| allowlist.difference(&self.blocklist).count())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mac_addresses(addresses: &[&str]) -> HashSet<MacAddress> {
+        addresses
+            .iter()
+            .map(|address| address.parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn allowlist_and_blocklist() {
+        let filter = SensorFilter {
+            allowlist: Some(mac_addresses(&["A4:C1:38:00:00:01", "A4:C1:38:00:00:02"])),
+            blocklist: mac_addresses(&["A4:C1:38:00:00:02"]),
+        };
+        assert!(filter.allows(&"A4:C1:38:00:00:01".parse().unwrap()));
+        assert!(!filter.allows(&"A4:C1:38:00:00:02".parse().unwrap()));
+        assert!(!filter.allows(&"A4:C1:38:00:00:03".parse().unwrap()));
+        assert_eq!(filter.max_sensors(), Some(1));
+    }
+
+    #[test]
+    fn blocklist_only() {
+        let filter = SensorFilter {
+            allowlist: None,
+            blocklist: mac_addresses(&["A4:C1:38:00:00:02"]),
+        };
+        assert!(filter.allows(&"A4:C1:38:00:00:01".parse().unwrap()));
+        assert!(!filter.allows(&"A4:C1:38:00:00:02".parse().unwrap()));
+        assert_eq!(filter.max_sensors(), None);
+    }
+}
@@ -0,0 +1,98 @@
+//! Persisting changes to the names of sensors back to the sensor names file, so that sensors
+//! renamed at runtime keep their new names after the bridge is restarted.
+
+use mijia::MacAddress;
+use stable_eyre::eyre;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Set the name of the sensor with the given MAC address in the sensor names file at the given path.
+///
+/// Comments, the order of the other sensors and whether the sensor is disabled are all kept. If the
+/// sensor isn't already in the file it is added to the end, and if the file doesn't exist it is
+/// created.
+pub fn set_sensor_name(
+    path: &Path,
+    mac_address: &MacAddress,
+    name: &str,
+) -> Result<(), eyre::Report> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut found = false;
+    let mut lines: Vec<String> = contents
+        .lines()
+        .map(|line| {
+            if !line.starts_with('#') {
+                let (prefix, entry) = match line.strip_prefix('!') {
+                    Some(entry) => ("!", entry),
+                    None => ("", line),
+                };
+                let key = entry.split_once('=').map_or(entry, |(key, _)| key);
+                let line_mac_address = key.parse::<MacAddress>().ok();
+                if line_mac_address.as_ref() == Some(mac_address) {
+                    found = true;
+                    return format!("{}{}={}", prefix, mac_address, name);
+                }
+            }
+            line.to_owned()
+        })
+        .collect();
+    if !found {
+        lines.push(format!("{}={}", mac_address, name));
+    }
+
+    let mut contents = lines.join("\n");
+    contents.push('\n');
+    fs::write(path, contents)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn make_test_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}_{}.conf", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn rename_existing_sensor() {
+        let path = make_test_path("sensor-names-rename");
+        fs::write(
+            &path,
+            "# Comment\nA4:C1:38:00:00:01=Kitchen\n!A4:C1:38:00:00:02=Bedroom\n",
+        )
+        .unwrap();
+
+        set_sensor_name(&path, &"A4:C1:38:00:00:01".parse().unwrap(), "Pantry").unwrap();
+        set_sensor_name(&path, &"a4:c1:38:00:00:02".parse().unwrap(), "Attic").unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "# Comment\nA4:C1:38:00:00:01=Pantry\n!A4:C1:38:00:00:02=Attic\n"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn add_new_sensor() {
+        let path = make_test_path("sensor-names-add");
+
+        set_sensor_name(&path, &"A4:C1:38:00:00:01".parse().unwrap(), "Kitchen").unwrap();
+        set_sensor_name(&path, &"A4:C1:38:00:00:02".parse().unwrap(), "Bedroom").unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "A4:C1:38:00:00:01=Kitchen\nA4:C1:38:00:00:02=Bedroom\n"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,163 @@
+//! Smoothing each sensor's readings before publishing them, to tame the jitter of a few tenths of a
+//! degree between consecutive readings without waiting for a whole aggregation window.
+
+use mijia::Readings;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// How to smooth the temperature and humidity from each sensor.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Smoothing {
+    /// An exponential moving average, where each new value has the given weight between 0 and 1.
+    /// Smaller weights give smoother but slower-moving values.
+    ExponentialMovingAverage { alpha: f32 },
+    /// The median of the given number of most recent values.
+    Median { samples: usize },
+}
+
+/// An error parsing a `SmoothingMethod` from a string.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParseSmoothingMethodError(String);
+
+impl Display for ParseSmoothingMethodError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Invalid smoothing method '{}', expected 'ema' or 'median'",
+            self.0
+        )
+    }
+}
+
+impl Error for ParseSmoothingMethodError {}
+
+/// The kind of smoothing to use, without its parameters.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SmoothingMethod {
+    ExponentialMovingAverage,
+    Median,
+}
+
+impl FromStr for SmoothingMethod {
+    type Err = ParseSmoothingMethodError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ema" | "exponential" => Ok(Self::ExponentialMovingAverage),
+            "median" => Ok(Self::Median),
+            _ => Err(ParseSmoothingMethodError(s.to_owned())),
+        }
+    }
+}
+
+/// The smoothing state of a single value, such as the temperature of one sensor.
+#[derive(Clone, Debug, Default)]
+struct ValueFilter {
+    average: Option<f32>,
+    recent: VecDeque<f32>,
+}
+
+impl ValueFilter {
+    fn add(&mut self, smoothing: &Smoothing, value: f32) -> f32 {
+        match *smoothing {
+            Smoothing::ExponentialMovingAverage { alpha } => {
+                let average = match self.average {
+                    Some(average) => average + alpha * (value - average),
+                    None => value,
+                };
+                self.average = Some(average);
+                average
+            }
+            Smoothing::Median { samples } => {
+                self.recent.push_back(value);
+                while self.recent.len() > samples.max(1) {
+                    self.recent.pop_front();
+                }
+                let mut sorted: Vec<f32> = self.recent.iter().copied().collect();
+                sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                sorted[sorted.len() / 2]
+            }
+        }
+    }
+}
+
+/// The smoothing state of the readings from a single sensor. Each property is smoothed separately;
+/// the battery level isn't smoothed at all, as it doesn't jitter in the same way.
+#[derive(Clone, Debug, Default)]
+pub struct ReadingsFilter {
+    temperature: ValueFilter,
+    humidity: ValueFilter,
+}
+
+impl ReadingsFilter {
+    /// Add the given raw readings to the filter, and return the smoothed readings.
+    pub fn add(&mut self, smoothing: &Smoothing, readings: &Readings) -> Readings {
+        Readings {
+            temperature: self.temperature.add(smoothing, readings.temperature),
+            humidity: self
+                .humidity
+                .add(smoothing, readings.humidity.into())
+                .round() as u8,
+            ..readings.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mijia::test_utils::readings;
+
+    #[test]
+    fn parse_method() {
+        assert_eq!("EMA".parse(), Ok(SmoothingMethod::ExponentialMovingAverage));
+        assert_eq!("median".parse(), Ok(SmoothingMethod::Median));
+        assert_eq!(
+            "mode".parse::<SmoothingMethod>(),
+            Err(ParseSmoothingMethodError("mode".to_owned()))
+        );
+    }
+
+    #[test]
+    fn exponential_moving_average() {
+        let smoothing = Smoothing::ExponentialMovingAverage { alpha: 0.5 };
+        let mut filter = ReadingsFilter::default();
+        assert_eq!(
+            filter.add(&smoothing, &readings(20.0, 50, 3000)),
+            readings(20.0, 50, 3000)
+        );
+        assert_eq!(
+            filter.add(&smoothing, &readings(21.0, 54, 3000)),
+            readings(20.5, 52, 3000)
+        );
+        assert_eq!(
+            filter.add(&smoothing, &readings(20.5, 52, 3000)),
+            readings(20.5, 52, 3000)
+        );
+    }
+
+    #[test]
+    fn median_of_recent() {
+        let smoothing = Smoothing::Median { samples: 3 };
+        let mut filter = ReadingsFilter::default();
+        assert_eq!(
+            filter.add(&smoothing, &readings(20.0, 50, 3000)),
+            readings(20.0, 50, 3000)
+        );
+        assert_eq!(
+            filter.add(&smoothing, &readings(20.6, 52, 3000)),
+            readings(20.6, 52, 3000)
+        );
+        assert_eq!(
+            filter.add(&smoothing, &readings(20.3, 51, 3000)),
+            readings(20.3, 51, 3000)
+        );
+        // The 20.0 has dropped out of the window.
+        assert_eq!(
+            filter.add(&smoothing, &readings(20.9, 49, 3000)),
+            readings(20.6, 51, 3000)
+        );
+    }
+}
@@ -0,0 +1,201 @@
+//! A periodic summary of how many sensors are in each connection state, logged and published on the
+//! bridge node in place of listing every sensor on every pass of the connection loop.
+
+use crate::{ConnectionStatus, Sensor};
+use serde::Serialize;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How often to report the status, if `STATUS_INTERVAL` isn't set.
+pub const DEFAULT_STATUS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How much detail to include in the status report, set by `STATUS_VERBOSITY`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StatusVerbosity {
+    /// Only the number of sensors in each state.
+    Counts,
+    /// The names of the sensors in each state as well.
+    Sensors,
+}
+
+/// An error parsing a `StatusVerbosity` from a string.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParseStatusVerbosityError(String);
+
+impl Display for ParseStatusVerbosityError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Invalid status verbosity '{}', expected 'counts' or 'sensors'",
+            self.0
+        )
+    }
+}
+
+impl Error for ParseStatusVerbosityError {}
+
+impl FromStr for StatusVerbosity {
+    type Err = ParseStatusVerbosityError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "counts" => Ok(Self::Counts),
+            "sensors" => Ok(Self::Sensors),
+            _ => Err(ParseStatusVerbosityError(s.to_owned())),
+        }
+    }
+}
+
+/// How often and in how much detail to report the status.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct StatusOptions {
+    /// How often to report the status, or `None` to never log it.
+    pub interval: Option<Duration>,
+    pub verbosity: StatusVerbosity,
+}
+
+impl Default for StatusOptions {
+    fn default() -> Self {
+        Self {
+            interval: Some(DEFAULT_STATUS_INTERVAL),
+            verbosity: StatusVerbosity::Counts,
+        }
+    }
+}
+
+/// The number of sensors in each connection state, and optionally their names.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct StatusReport {
+    pub unknown: usize,
+    pub connecting: usize,
+    pub connected: usize,
+    pub disconnected: usize,
+    /// The names of the sensors in each state which has any, if requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sensors: Option<SensorsByStatus>,
+}
+
+/// The names of the sensors in each connection state, sorted.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct SensorsByStatus {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unknown: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub connecting: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub connected: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub disconnected: Vec<String>,
+}
+
+impl StatusReport {
+    /// Summarise the connection states of the given sensors.
+    pub fn new<'a>(
+        sensors: impl IntoIterator<Item = &'a Sensor>,
+        verbosity: StatusVerbosity,
+    ) -> Self {
+        let mut by_status = SensorsByStatus::default();
+        for sensor in sensors {
+            let names = match sensor.connection_status {
+                ConnectionStatus::Unknown => &mut by_status.unknown,
+                ConnectionStatus::Connecting { .. } => &mut by_status.connecting,
+                ConnectionStatus::Connected => &mut by_status.connected,
+                ConnectionStatus::Disconnected | ConnectionStatus::MarkedDisconnected => {
+                    &mut by_status.disconnected
+                }
+            };
+            names.push(sensor.name.clone());
+        }
+        for names in &mut [
+            &mut by_status.unknown,
+            &mut by_status.connecting,
+            &mut by_status.connected,
+            &mut by_status.disconnected,
+        ] {
+            names.sort();
+        }
+        Self {
+            unknown: by_status.unknown.len(),
+            connecting: by_status.connecting.len(),
+            connected: by_status.connected.len(),
+            disconnected: by_status.disconnected.len(),
+            sensors: match verbosity {
+                StatusVerbosity::Counts => None,
+                StatusVerbosity::Sensors => Some(by_status),
+            },
+        }
+    }
+
+    /// Log the report, with each count as a separate field.
+    pub fn log(&self) {
+        match &self.sensors {
+            Some(sensors) => tracing::info!(
+                event = "status",
+                unknown = self.unknown,
+                connecting = self.connecting,
+                connected = self.connected,
+                disconnected = self.disconnected,
+                "{} connected {:?}, {} connecting {:?}, {} disconnected {:?}, {} unknown {:?}",
+                self.connected,
+                sensors.connected,
+                self.connecting,
+                sensors.connecting,
+                self.disconnected,
+                sensors.disconnected,
+                self.unknown,
+                sensors.unknown,
+            ),
+            None => tracing::info!(
+                event = "status",
+                unknown = self.unknown,
+                connecting = self.connecting,
+                connected = self.connected,
+                disconnected = self.disconnected,
+                "{} connected, {} connecting, {} disconnected, {} unknown",
+                self.connected,
+                self.connecting,
+                self.disconnected,
+                self.unknown,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_verbosity() {
+        assert_eq!("counts".parse(), Ok(StatusVerbosity::Counts));
+        assert_eq!(" Sensors".parse(), Ok(StatusVerbosity::Sensors));
+        assert_eq!(
+            "all".parse::<StatusVerbosity>(),
+            Err(ParseStatusVerbosityError("all".to_owned()))
+        );
+    }
+
+    #[test]
+    fn report_json() {
+        let report = StatusReport {
+            connected: 2,
+            disconnected: 1,
+            sensors: Some(SensorsByStatus {
+                connected: vec!["Bedroom".to_owned(), "Kitchen".to_owned()],
+                disconnected: vec!["Garden".to_owned()],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            serde_json::to_string(&report).unwrap(),
+            r#"{"unknown":0,"connecting":0,"connected":2,"disconnected":1,"sensors":{"connected":["Bedroom","Kitchen"],"disconnected":["Garden"]}}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&StatusReport::default()).unwrap(),
+            r#"{"unknown":0,"connecting":0,"connected":0,"disconnected":0}"#
+        );
+    }
+}
@@ -0,0 +1,218 @@
+//! Local storage of sensor readings and history records in an SQLite database, so that data
+//! survives MQTT broker outages and can be queried later.
+
+use mijia::{HistoryRecord, MacAddress, Readings};
+use rusqlite::{params, Connection};
+use stable_eyre::eyre;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// An SQLite database of sensor readings and history records.
+#[derive(Debug)]
+pub struct Store {
+    connection: Connection,
+}
+
+impl Store {
+    /// Open the SQLite database at the given path, creating it and its tables if necessary.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, eyre::Report> {
+        Self::new(Connection::open(path)?)
+    }
+
+    fn new(connection: Connection) -> Result<Self, eyre::Report> {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS readings (
+                mac_address TEXT NOT NULL,
+                time INTEGER NOT NULL,
+                temperature REAL NOT NULL,
+                humidity INTEGER NOT NULL,
+                battery_voltage INTEGER NOT NULL,
+                battery_percent INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS readings_mac_address_time ON readings (mac_address, time);
+            CREATE TABLE IF NOT EXISTS history (
+                mac_address TEXT NOT NULL,
+                time INTEGER NOT NULL,
+                record_index INTEGER NOT NULL,
+                temperature_min REAL NOT NULL,
+                temperature_max REAL NOT NULL,
+                humidity_min INTEGER NOT NULL,
+                humidity_max INTEGER NOT NULL,
+                PRIMARY KEY (mac_address, time)
+            );",
+        )?;
+        Ok(Self { connection })
+    }
+
+    /// Store a set of readings received from the given sensor at the given time.
+    pub fn insert_readings(
+        &self,
+        mac_address: &MacAddress,
+        time: SystemTime,
+        readings: &Readings,
+    ) -> Result<(), eyre::Report> {
+        self.connection.execute(
+            "INSERT INTO readings
+                (mac_address, time, temperature, humidity, battery_voltage, battery_percent)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                mac_address.to_string(),
+                unix_timestamp(time),
+                readings.temperature as f64,
+                readings.humidity,
+                readings.battery_voltage,
+                readings.battery_percent,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Store a history record downloaded from the given sensor. If there is already a record for
+    /// the same sensor and time it will be replaced.
+    pub fn insert_history_record(
+        &self,
+        mac_address: &MacAddress,
+        record: &HistoryRecord,
+    ) -> Result<(), eyre::Report> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO history
+                (mac_address, time, record_index, temperature_min, temperature_max, humidity_min,
+                 humidity_max)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                mac_address.to_string(),
+                unix_timestamp(record.time),
+                record.index,
+                record.temperature_min as f64,
+                record.temperature_max as f64,
+                record.humidity_min,
+                record.humidity_max,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Get the stored history records for the given sensor from the given time onwards, in order of
+    /// time.
+    pub fn get_history(
+        &self,
+        mac_address: &MacAddress,
+        since: SystemTime,
+    ) -> Result<Vec<HistoryRecord>, eyre::Report> {
+        let mut statement = self.connection.prepare(
+            "SELECT record_index, time, temperature_min, temperature_max, humidity_min, humidity_max
+                FROM history WHERE mac_address = ?1 AND time >= ?2 ORDER BY time",
+        )?;
+        let records = statement
+            .query_map(
+                params![mac_address.to_string(), unix_timestamp(since)],
+                |row| {
+                    let time =
+                        SystemTime::UNIX_EPOCH + Duration::from_secs(row.get::<_, i64>(1)? as u64);
+                    Ok(HistoryRecord {
+                        index: row.get(0)?,
+                        time,
+                        raw_time: time,
+                        temperature_min: row.get::<_, f64>(2)? as f32,
+                        temperature_max: row.get::<_, f64>(3)? as f32,
+                        humidity_min: row.get(4)?,
+                        humidity_max: row.get(5)?,
+                    })
+                },
+            )?
+            .collect::<Result<_, _>>()?;
+        Ok(records)
+    }
+}
+
+/// Convert the given time to a number of seconds since the Unix epoch, clamping times before the
+/// epoch to 0.
+fn unix_timestamp(time: SystemTime) -> i64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(store: &Store, table: &str) -> i64 {
+        store
+            .connection
+            .query_row(
+                &format!("SELECT COUNT(*) FROM {}", table),
+                params![],
+                |row| row.get(0),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn insert_readings() {
+        let store = Store::new(Connection::open_in_memory().unwrap()).unwrap();
+        let mac_address: MacAddress = "A4:C1:38:D7:21:17".parse().unwrap();
+        let readings = Readings {
+            temperature: 21.5,
+            humidity: 42,
+            battery_voltage: 3000,
+            battery_percent: 90,
+        };
+        store
+            .insert_readings(&mac_address, SystemTime::now(), &readings)
+            .unwrap();
+        store
+            .insert_readings(&mac_address, SystemTime::now(), &readings)
+            .unwrap();
+        assert_eq!(count(&store, "readings"), 2);
+    }
+
+    #[test]
+    fn insert_history_record_replaces_duplicates() {
+        let store = Store::new(Connection::open_in_memory().unwrap()).unwrap();
+        let mac_address: MacAddress = "A4:C1:38:D7:21:17".parse().unwrap();
+        let record = HistoryRecord {
+            index: 42,
+            time: SystemTime::UNIX_EPOCH + Duration::from_secs(1582632000),
+            raw_time: SystemTime::UNIX_EPOCH + Duration::from_secs(1582632000),
+            temperature_min: 21.3,
+            temperature_max: 22.1,
+            humidity_min: 60,
+            humidity_max: 67,
+        };
+        store.insert_history_record(&mac_address, &record).unwrap();
+        store.insert_history_record(&mac_address, &record).unwrap();
+        assert_eq!(count(&store, "history"), 1);
+    }
+
+    #[test]
+    fn get_history_since() {
+        let store = Store::new(Connection::open_in_memory().unwrap()).unwrap();
+        let mac_address: MacAddress = "A4:C1:38:D7:21:17".parse().unwrap();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1582632000);
+        let records: Vec<HistoryRecord> = (0..3)
+            .map(|index| HistoryRecord {
+                index,
+                time: start + Duration::from_secs(3600 * index as u64),
+                raw_time: start + Duration::from_secs(3600 * index as u64),
+                temperature_min: 21.5,
+                temperature_max: 22.0,
+                humidity_min: 60,
+                humidity_max: 67,
+            })
+            .collect();
+        for record in records.iter().rev() {
+            store.insert_history_record(&mac_address, record).unwrap();
+        }
+        store
+            .insert_history_record(&"A4:C1:38:D7:21:18".parse().unwrap(), &records[2])
+            .unwrap();
+
+        assert_eq!(
+            store
+                .get_history(&mac_address, start + Duration::from_secs(1))
+                .unwrap(),
+            records[1..]
+        );
+    }
+}
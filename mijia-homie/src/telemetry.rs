@@ -0,0 +1,160 @@
+//! Setting up log output, and optionally exporting traces to an OpenTelemetry collector over OTLP.
+
+use crate::parse_env_var;
+use stable_eyre::eyre;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+/// The filter to use for log output if `RUST_LOG` is not set.
+const DEFAULT_LOG_FILTER: &str = "info";
+
+/// The format in which to write log output, set by `LOG_FORMAT`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LogFormat {
+    /// Human-readable lines of text.
+    Text,
+    /// One JSON object per line, with the fields of the event and the span it is in, for log
+    /// ingestion systems such as Loki or Elasticsearch.
+    Json,
+}
+
+/// An error parsing a `LogFormat` from a string.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParseLogFormatError(String);
+
+impl Display for ParseLogFormatError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Invalid log format '{}', expected 'text' or 'json'",
+            self.0
+        )
+    }
+}
+
+impl Error for ParseLogFormatError {}
+
+impl FromStr for LogFormat {
+    type Err = ParseLogFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(ParseLogFormatError(s.to_owned())),
+        }
+    }
+}
+
+/// Keeps the OTLP exporter running. Spans which haven't been exported yet are flushed when this is
+/// dropped, so it should be kept until the bridge exits.
+#[must_use]
+#[derive(Debug)]
+pub struct TelemetryGuard {
+    #[cfg(feature = "otlp")]
+    _uninstall: Option<opentelemetry_otlp::Uninstall>,
+}
+
+/// Install the global tracing subscriber, logging to stdout in the format given by `LOG_FORMAT` and
+/// exporting traces to the collector at `OTLP_ENDPOINT` if it is set.
+pub fn init() -> Result<TelemetryGuard, eyre::Report> {
+    let log_format: LogFormat = parse_env_var("LOG_FORMAT")?.unwrap_or(LogFormat::Text);
+    let otlp_endpoint = std::env::var("OTLP_ENDPOINT").ok();
+    let (text_layer, json_layer) = match log_format {
+        LogFormat::Text => (Some(tracing_subscriber::fmt::layer()), None),
+        LogFormat::Json => (
+            None,
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_current_span(true)
+                    .with_span_list(false),
+            ),
+        ),
+    };
+    let registry = tracing_subscriber::registry()
+        .with(
+            EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_FILTER)),
+        )
+        .with(text_layer)
+        .with(json_layer);
+
+    #[cfg(feature = "otlp")]
+    {
+        let (otlp_layer, uninstall) = match otlp_endpoint {
+            Some(endpoint) => {
+                let (layer, uninstall) = otlp::layer(endpoint)?;
+                (Some(layer), Some(uninstall))
+            }
+            None => (None, None),
+        };
+        registry.with(otlp_layer).init();
+        Ok(TelemetryGuard {
+            _uninstall: uninstall,
+        })
+    }
+
+    #[cfg(not(feature = "otlp"))]
+    {
+        registry.init();
+        if otlp_endpoint.is_some() {
+            eyre::bail!("OTLP_ENDPOINT is set but mijia-homie was built without the otlp feature.");
+        }
+        Ok(TelemetryGuard {})
+    }
+}
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use opentelemetry::sdk::{trace, Resource};
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::Uninstall;
+    use stable_eyre::eyre;
+    use tracing::Subscriber;
+    use tracing_opentelemetry::OpenTelemetryLayer;
+    use tracing_subscriber::registry::LookupSpan;
+
+    /// The service name with which exported spans are labelled.
+    const SERVICE_NAME: &str = "mijia-homie";
+
+    /// Start exporting spans to the OTLP collector at the given endpoint, returning a layer to add
+    /// to the subscriber.
+    pub fn layer<S>(
+        endpoint: String,
+    ) -> Result<(OpenTelemetryLayer<S, trace::Tracer>, Uninstall), eyre::Report>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let (tracer, uninstall) =
+            opentelemetry_otlp::new_pipeline()
+                .with_endpoint(endpoint)
+                .with_trace_config(trace::config().with_resource(Resource::new(vec![
+                    KeyValue::new("service.name", SERVICE_NAME),
+                ])))
+                .install()?;
+        Ok((
+            tracing_opentelemetry::layer().with_tracer(tracer),
+            uninstall,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_log_format() {
+        assert_eq!("text".parse(), Ok(LogFormat::Text));
+        assert_eq!(" JSON ".parse(), Ok(LogFormat::Json));
+        assert_eq!(
+            "yaml".parse::<LogFormat>(),
+            Err(ParseLogFormatError("yaml".to_owned()))
+        );
+    }
+}
@@ -0,0 +1,178 @@
+//! Alarms for when a sensor's readings go outside configured thresholds, so that brokers and
+//! controllers can send notifications without extra tooling.
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// How far the temperature in ºC must move back within its threshold for an alarm to be cleared.
+pub const TEMPERATURE_HYSTERESIS: f32 = 0.5;
+/// How far the humidity in % must move back within its threshold for an alarm to be cleared.
+pub const HUMIDITY_HYSTERESIS: f32 = 2.0;
+
+/// The state of the alarm for a single value.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AlarmState {
+    Ok,
+    Low,
+    High,
+}
+
+impl AlarmState {
+    /// All possible values, for the format of a Homie enum property.
+    pub const VALUES: &'static [&'static str] = &["ok", "low", "high"];
+}
+
+impl Display for AlarmState {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Ok => "ok",
+            Self::Low => "low",
+            Self::High => "high",
+        })
+    }
+}
+
+/// Low and high thresholds for a single value. Either may be absent.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Threshold {
+    pub low: Option<f32>,
+    pub high: Option<f32>,
+}
+
+impl Threshold {
+    /// Work out the alarm state for the given value, given the previous state. Once an alarm is
+    /// raised it is only cleared when the value moves back within the threshold by at least the
+    /// given hysteresis, to avoid it flapping when the value is close to the threshold.
+    pub fn check(&self, previous: AlarmState, value: f32, hysteresis: f32) -> AlarmState {
+        if let Some(high) = self.high {
+            if value > high || (previous == AlarmState::High && value > high - hysteresis) {
+                return AlarmState::High;
+            }
+        }
+        if let Some(low) = self.low {
+            if value < low || (previous == AlarmState::Low && value < low + hysteresis) {
+                return AlarmState::Low;
+            }
+        }
+        AlarmState::Ok
+    }
+}
+
+/// The thresholds for the readings of a single sensor.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Thresholds {
+    pub temperature: Threshold,
+    pub humidity: Threshold,
+}
+
+/// An error parsing `Thresholds` from a string.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParseThresholdsError(String);
+
+impl Display for ParseThresholdsError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Invalid threshold '{}', expected e.g. 'temperature<18' or 'humidity>70'",
+            self.0
+        )
+    }
+}
+
+impl Error for ParseThresholdsError {}
+
+impl FromStr for Thresholds {
+    type Err = ParseThresholdsError;
+
+    /// Parse a comma-separated list of thresholds like `temperature<18,temperature>26,humidity>70`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut thresholds = Thresholds::default();
+        for part in s.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            let error = || ParseThresholdsError(part.to_owned());
+            let (name, is_high, value) = if let Some(index) = part.find('<') {
+                (&part[..index], false, &part[index + 1..])
+            } else if let Some(index) = part.find('>') {
+                (&part[..index], true, &part[index + 1..])
+            } else {
+                return Err(error());
+            };
+            let threshold = match name.trim() {
+                "temperature" => &mut thresholds.temperature,
+                "humidity" => &mut thresholds.humidity,
+                _ => return Err(error()),
+            };
+            let value = value.trim().parse().map_err(|_| error())?;
+            if is_high {
+                threshold.high = Some(value);
+            } else {
+                threshold.low = Some(value);
+            }
+        }
+        Ok(thresholds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_thresholds() {
+        assert_eq!(
+            "temperature<18, temperature>26,humidity>70".parse(),
+            Ok(Thresholds {
+                temperature: Threshold {
+                    low: Some(18.0),
+                    high: Some(26.0)
+                },
+                humidity: Threshold {
+                    low: None,
+                    high: Some(70.0)
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn parse_invalid_thresholds() {
+        assert_eq!(
+            "temperature=18".parse::<Thresholds>(),
+            Err(ParseThresholdsError("temperature=18".to_owned()))
+        );
+        assert_eq!(
+            "pressure>1000".parse::<Thresholds>(),
+            Err(ParseThresholdsError("pressure>1000".to_owned()))
+        );
+        assert_eq!(
+            "humidity>lots".parse::<Thresholds>(),
+            Err(ParseThresholdsError("humidity>lots".to_owned()))
+        );
+    }
+
+    #[test]
+    fn check_with_hysteresis() {
+        let threshold = Threshold {
+            low: Some(18.0),
+            high: Some(26.0),
+        };
+        assert_eq!(threshold.check(AlarmState::Ok, 20.0, 0.5), AlarmState::Ok);
+        assert_eq!(threshold.check(AlarmState::Ok, 26.1, 0.5), AlarmState::High);
+        assert_eq!(
+            threshold.check(AlarmState::High, 25.6, 0.5),
+            AlarmState::High
+        );
+        assert_eq!(threshold.check(AlarmState::High, 25.5, 0.5), AlarmState::Ok);
+        assert_eq!(threshold.check(AlarmState::Ok, 17.9, 0.5), AlarmState::Low);
+        assert_eq!(threshold.check(AlarmState::Low, 18.4, 0.5), AlarmState::Low);
+        assert_eq!(threshold.check(AlarmState::Low, 18.5, 0.5), AlarmState::Ok);
+    }
+
+    #[test]
+    fn check_without_thresholds() {
+        assert_eq!(
+            Threshold::default().check(AlarmState::Ok, 100.0, 0.5),
+            AlarmState::Ok
+        );
+    }
+}
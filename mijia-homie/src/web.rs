@@ -0,0 +1,397 @@
+//! An optional web dashboard showing the state of every sensor, with buttons to trigger bridge
+//! commands, and a read-only JSON API for the current readings and stored history of each sensor.
+
+use crate::brokers::Incoming;
+use crate::status::{StatusReport, StatusVerbosity};
+use crate::{ConnectionStatus, Sensor, SensorState, BRIDGE_NODE_ID, PROPERTY_ID_COMMAND};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use itertools::Itertools;
+use mijia::{HistoryRecord, MacAddress, Readings};
+use serde::Serialize;
+use stable_eyre::eyre;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, Mutex};
+
+/// How often the dashboard page reloads itself, in seconds.
+const REFRESH_INTERVAL_SECONDS: u32 = 10;
+
+/// Serve the dashboard on the given address. Commands triggered from it are sent on the given
+/// channel, as if they had been set on the bridge node's `command` property over MQTT.
+pub async fn serve(
+    address: SocketAddr,
+    state: Arc<Mutex<SensorState>>,
+    commands: mpsc::UnboundedSender<Incoming>,
+) -> Result<(), eyre::Report> {
+    let mut listener = TcpListener::bind(address).await?;
+    tracing::info!("Serving dashboard on http://{}/", address);
+    loop {
+        let (stream, peer_address) = listener.accept().await?;
+        let state = state.clone();
+        let commands = commands.clone();
+        let service =
+            service_fn(move |request| handle_request(request, state.clone(), commands.clone()));
+        tokio::spawn(async move {
+            if let Err(e) = Http::new()
+                .http1_only(true)
+                .serve_connection(stream, service)
+                .await
+            {
+                tracing::warn!("Error serving {}: {:?}", peer_address, e);
+            }
+        });
+    }
+}
+
+async fn handle_request(
+    request: Request<Body>,
+    state: Arc<Mutex<SensorState>>,
+    commands: mpsc::UnboundedSender<Incoming>,
+) -> Result<Response<Body>, Infallible> {
+    let path: Vec<&str> = request
+        .uri()
+        .path()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    Ok(match (request.method(), path.as_slice()) {
+        (&Method::GET, []) => {
+            let page = dashboard(&*state.lock().await, SystemTime::now());
+            Response::builder()
+                .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+                .body(Body::from(page))
+                .unwrap()
+        }
+        (&Method::GET, ["sensors"]) => {
+            let state = state.lock().await;
+            let sensors: Vec<SensorJson> = state
+                .sensors
+                .values()
+                .sorted_by_key(|sensor| &sensor.name)
+                .map(SensorJson::from)
+                .collect();
+            json_response(&sensors)
+        }
+        (&Method::GET, ["status"]) => {
+            let state = state.lock().await;
+            json_response(&StatusReport::new(
+                state.sensors.values(),
+                StatusVerbosity::Sensors,
+            ))
+        }
+        (&Method::GET, ["sensors", mac_address, "readings"]) => {
+            let state = state.lock().await;
+            match find_sensor(&state, mac_address) {
+                Some(Sensor {
+                    last_readings: Some(readings),
+                    last_readings_time: Some(time),
+                    ..
+                }) => json_response(&ReadingsJson::new(readings, *time)),
+                _ => status_response(StatusCode::NOT_FOUND),
+            }
+        }
+        (&Method::GET, ["sensors", mac_address, "history"]) => {
+            let since = match parse_since(request.uri().query()) {
+                Ok(since) => since,
+                Err(()) => return Ok(status_response(StatusCode::BAD_REQUEST)),
+            };
+            let state = state.lock().await;
+            match (find_sensor(&state, mac_address), &state.store) {
+                (Some(sensor), Some(store)) => {
+                    match store.get_history(&sensor.mac_address, since) {
+                        Ok(records) => {
+                            let records: Vec<HistoryRecordJson> =
+                                records.iter().map(HistoryRecordJson::from).collect();
+                            json_response(&records)
+                        }
+                        Err(e) => {
+                            tracing::error!("Failed to read history: {:?}", e);
+                            status_response(StatusCode::INTERNAL_SERVER_ERROR)
+                        }
+                    }
+                }
+                _ => status_response(StatusCode::NOT_FOUND),
+            }
+        }
+        (&Method::POST, ["sensors", mac_address, action])
+            if *action == "reconnect" || *action == "download-history" =>
+        {
+            match mac_address.parse::<MacAddress>() {
+                Ok(mac_address) => {
+                    let command = Incoming::Set {
+                        node_id: BRIDGE_NODE_ID.to_owned(),
+                        property_id: PROPERTY_ID_COMMAND.to_owned(),
+                        value: format!("{} {}", action, mac_address),
+                    };
+                    if commands.send(command).is_err() {
+                        tracing::error!("Command channel closed");
+                    }
+                    // Redirect back to the dashboard.
+                    Response::builder()
+                        .status(StatusCode::SEE_OTHER)
+                        .header(header::LOCATION, "/")
+                        .body(Body::empty())
+                        .unwrap()
+                }
+                Err(_) => status_response(StatusCode::BAD_REQUEST),
+            }
+        }
+        _ => status_response(StatusCode::NOT_FOUND),
+    })
+}
+
+/// Find the sensor with the given MAC address, if it is known and the MAC address is valid.
+fn find_sensor<'a>(state: &'a SensorState, mac_address: &str) -> Option<&'a Sensor> {
+    let mac_address: MacAddress = mac_address.parse().ok()?;
+    state
+        .sensors
+        .values()
+        .find(|sensor| sensor.mac_address == mac_address)
+}
+
+/// Parse the optional `since` query parameter, a Unix timestamp in seconds. If it is missing then
+/// the Unix epoch is returned.
+fn parse_since(query: Option<&str>) -> Result<SystemTime, ()> {
+    let since = query
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("since="));
+    match since {
+        Some(since) => {
+            let seconds: u64 = since.parse().map_err(|_| ())?;
+            Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds))
+        }
+        None => Ok(SystemTime::UNIX_EPOCH),
+    }
+}
+
+fn json_response(value: &impl Serialize) -> Response<Body> {
+    match serde_json::to_string(value) {
+        Ok(json) => Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(json))
+            .unwrap(),
+        Err(e) => {
+            tracing::error!("Failed to serialise JSON: {:?}", e);
+            status_response(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(status.to_string()))
+        .unwrap()
+}
+
+/// Render the dashboard page for the given state.
+fn dashboard(state: &SensorState, now: SystemTime) -> String {
+    let rows: String = state
+        .sensors
+        .values()
+        .sorted_by_key(|sensor| &sensor.name)
+        .map(|sensor| sensor_row(sensor, now))
+        .collect();
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta http-equiv="refresh" content="{}">
+<title>Mijia bridge</title>
+<style>
+body {{ font-family: sans-serif; }}
+table {{ border-collapse: collapse; }}
+th, td {{ border: 1px solid #ccc; padding: 0.25em 0.5em; text-align: left; }}
+form {{ display: inline; }}
+</style>
+</head>
+<body>
+<h1>Mijia bridge</h1>
+<table>
+<tr><th>Name</th><th>MAC address</th><th>Location</th><th>Status</th><th>RSSI</th><th>Temperature</th><th>Humidity</th><th>Battery</th><th>Last readings</th><th></th></tr>
+{}</table>
+</body>
+</html>
+"#,
+        REFRESH_INTERVAL_SECONDS, rows
+    )
+}
+
+fn sensor_row(sensor: &Sensor, now: SystemTime) -> String {
+    let (temperature, humidity, battery) = match &sensor.last_readings {
+        Some(readings) => (
+            format!("{:.2}ºC", readings.temperature),
+            format!("{}%", readings.humidity),
+            format!("{}%", readings.battery_percent),
+        ),
+        None => (String::new(), String::new(), String::new()),
+    };
+    let last_readings = match sensor.last_readings_time {
+        Some(time) => format!(
+            "{}s ago",
+            now.duration_since(time).unwrap_or_default().as_secs()
+        ),
+        None => "never".to_owned(),
+    };
+    format!(
+        "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}{}</td></tr>\n",
+        escape_html(&sensor.name),
+        sensor.mac_address,
+        escape_html(sensor.location.as_deref().unwrap_or_default()),
+        status_label(sensor.connection_status),
+        sensor
+            .rssi
+            .map(|rssi| format!("{} dBm", rssi))
+            .unwrap_or_default(),
+        temperature,
+        humidity,
+        battery,
+        last_readings,
+        action_button(&sensor.mac_address, "reconnect", "Reconnect"),
+        action_button(&sensor.mac_address, "download-history", "Download history"),
+    )
+}
+
+fn action_button(mac_address: &MacAddress, action: &str, label: &str) -> String {
+    format!(
+        r#"<form method="post" action="/sensors/{}/{}"><button>{}</button></form>"#,
+        mac_address, action, label
+    )
+}
+
+fn status_label(status: ConnectionStatus) -> &'static str {
+    match status {
+        ConnectionStatus::Unknown => "Unknown",
+        ConnectionStatus::Connecting { .. } => "Connecting",
+        ConnectionStatus::Disconnected | ConnectionStatus::MarkedDisconnected => "Disconnected",
+        ConnectionStatus::Connected => "Connected",
+    }
+}
+
+/// A summary of a sensor for the JSON API.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+struct SensorJson {
+    mac_address: String,
+    name: String,
+    location: Option<String>,
+    status: &'static str,
+    rssi: Option<i16>,
+    readings: Option<ReadingsJson>,
+}
+
+impl From<&Sensor> for SensorJson {
+    fn from(sensor: &Sensor) -> Self {
+        Self {
+            mac_address: sensor.mac_address.to_string(),
+            name: sensor.name.clone(),
+            location: sensor.location.clone(),
+            status: status_label(sensor.connection_status),
+            rssi: sensor.rssi,
+            readings: match (&sensor.last_readings, sensor.last_readings_time) {
+                (Some(readings), Some(time)) => Some(ReadingsJson::new(readings, time)),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// A set of readings from a sensor for the JSON API.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+struct ReadingsJson {
+    /// The time at which the readings were received, as a Unix timestamp in seconds.
+    time: u64,
+    temperature: f32,
+    humidity: u8,
+    battery_voltage: u16,
+    battery_percent: u16,
+}
+
+impl ReadingsJson {
+    fn new(readings: &Readings, time: SystemTime) -> Self {
+        Self {
+            time: unix_timestamp(time),
+            temperature: readings.temperature,
+            humidity: readings.humidity,
+            battery_voltage: readings.battery_voltage,
+            battery_percent: readings.battery_percent,
+        }
+    }
+}
+
+/// A history record from a sensor for the JSON API, and for publishing over MQTT.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HistoryRecordJson {
+    index: u32,
+    /// The time at which the record was created, as a Unix timestamp in seconds.
+    time: u64,
+    temperature_min: f32,
+    temperature_max: f32,
+    humidity_min: u8,
+    humidity_max: u8,
+}
+
+impl From<&HistoryRecord> for HistoryRecordJson {
+    fn from(record: &HistoryRecord) -> Self {
+        Self {
+            index: record.index,
+            time: unix_timestamp(record.time),
+            temperature_min: record.temperature_min,
+            temperature_max: record.temperature_max,
+            humidity_min: record.humidity_min,
+            humidity_max: record.humidity_max,
+        }
+    }
+}
+
+fn unix_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Escape the given text for inclusion in HTML.
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn since() {
+        assert_eq!(parse_since(None), Ok(SystemTime::UNIX_EPOCH));
+        assert_eq!(
+            parse_since(Some("foo=bar&since=1582632000")),
+            Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(1582632000))
+        );
+        assert_eq!(parse_since(Some("since=yesterday")), Err(()));
+    }
+
+    #[test]
+    fn escape() {
+        assert_eq!(escape_html("Living room"), "Living room");
+        assert_eq!(
+            escape_html(r#"<b>"Tom & Jerry's"</b>"#),
+            "&lt;b&gt;&quot;Tom &amp; Jerry&#39;s&quot;&lt;/b&gt;"
+        );
+    }
+}
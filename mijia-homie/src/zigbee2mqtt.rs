@@ -0,0 +1,166 @@
+//! Publishing readings in the flat topic layout used by Zigbee2MQTT, with one topic per sensor and a
+//! single JSON payload, for dashboards and Home Assistant setups which are already built around it.
+
+use crate::messages::sensor_name;
+use crate::SensorState;
+use mijia::{MacAddress, MijiaEvent, Readings};
+use rumqttc::{AsyncClient, MqttOptions, QoS};
+use serde::Serialize;
+use stable_eyre::eyre;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::RecvError;
+use tokio::sync::Mutex;
+use tokio::time::delay_for;
+
+/// The weakest signal strength in dBm, which is reported as a link quality of 0.
+const RSSI_MIN: i16 = -100;
+/// The strongest signal strength in dBm, which is reported as a link quality of 255.
+const RSSI_MAX: i16 = -30;
+/// How long to wait before reconnecting after the connection fails.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+/// How many requests to queue for the MQTT client before publishing blocks.
+const REQUESTS_CAP: usize = 100;
+
+/// The JSON payload published for each set of readings, with the same fields Zigbee2MQTT uses for
+/// temperature and humidity sensors.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+struct Payload {
+    temperature: f32,
+    humidity: u8,
+    /// The battery level in %.
+    battery: u16,
+    /// The battery voltage in mV.
+    voltage: u16,
+    /// The signal strength from 0 to 255, if known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    linkquality: Option<u8>,
+}
+
+/// Configuration for publishing readings in the Zigbee2MQTT topic layout.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Zigbee2Mqtt {
+    /// The topic under which each sensor's readings are published, by its friendly name.
+    pub base_topic: String,
+}
+
+impl Zigbee2Mqtt {
+    /// Publish every set of readings from the bridge to `<base_topic>/<sensor name>` on the broker
+    /// with the given options, until the bridge stops.
+    pub async fn run(
+        &self,
+        mqtt_options: MqttOptions,
+        state: Arc<Mutex<SensorState>>,
+    ) -> Result<(), eyre::Report> {
+        tracing::info!("Publishing readings under {} too", self.base_topic);
+        let mut events = state.lock().await.events.subscribe();
+        let (client, mut event_loop) = AsyncClient::new(mqtt_options, REQUESTS_CAP);
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = event_loop.poll().await {
+                    tracing::warn!("Zigbee2MQTT-style connection failed: {}", e);
+                    delay_for(RECONNECT_INTERVAL).await;
+                }
+            }
+        });
+
+        loop {
+            let (mac_address, readings) = match events.recv().await {
+                Ok((mac_address, MijiaEvent::Readings { readings, .. })) => (mac_address, readings),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Zigbee2MQTT-style publishing missed {} events.", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            };
+            let (name, rssi) = {
+                let state = state.lock().await;
+                (
+                    sensor_name(&state, mac_address),
+                    sensor_rssi(&state, mac_address),
+                )
+            };
+            client
+                .publish(
+                    self.topic(&name),
+                    QoS::AtLeastOnce,
+                    false,
+                    serde_json::to_vec(&payload(&readings, rssi))?,
+                )
+                .await?;
+        }
+    }
+
+    /// Get the topic for the sensor with the given friendly name. As in Zigbee2MQTT, slashes in
+    /// the name make nested topics, but wildcard characters are replaced.
+    fn topic(&self, name: &str) -> String {
+        format!("{}/{}", self.base_topic, name.replace(&['+', '#'][..], "_"))
+    }
+}
+
+/// Get the last known signal strength of the sensor with the given MAC address, if any.
+fn sensor_rssi(state: &SensorState, mac_address: MacAddress) -> Option<i16> {
+    state
+        .sensors
+        .values()
+        .find(|sensor| sensor.mac_address == mac_address)
+        .and_then(|sensor| sensor.rssi)
+}
+
+fn payload(readings: &Readings, rssi: Option<i16>) -> Payload {
+    Payload {
+        temperature: readings.temperature,
+        humidity: readings.humidity,
+        battery: readings.battery_percent,
+        voltage: readings.battery_voltage,
+        linkquality: rssi.map(link_quality),
+    }
+}
+
+/// Scale the given signal strength in dBm to the 0 to 255 range which Zigbee2MQTT uses for link
+/// quality.
+fn link_quality(rssi: i16) -> u8 {
+    let clamped = rssi.clamp(RSSI_MIN, RSSI_MAX);
+    ((i32::from(clamped - RSSI_MIN) * 255) / i32::from(RSSI_MAX - RSSI_MIN)) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topic() {
+        let zigbee2mqtt = Zigbee2Mqtt {
+            base_topic: "zigbee2mqtt".to_owned(),
+        };
+        assert_eq!(zigbee2mqtt.topic("Living room"), "zigbee2mqtt/Living room");
+        assert_eq!(zigbee2mqtt.topic("Attic/#1"), "zigbee2mqtt/Attic/_1");
+    }
+
+    #[test]
+    fn payload_json() {
+        let readings = Readings {
+            temperature: 21.5,
+            humidity: 45,
+            battery_voltage: 3000,
+            battery_percent: 90,
+        };
+        assert_eq!(
+            serde_json::to_string(&payload(&readings, Some(-65))).unwrap(),
+            r#"{"temperature":21.5,"humidity":45,"battery":90,"voltage":3000,"linkquality":127}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&payload(&readings, None)).unwrap(),
+            r#"{"temperature":21.5,"humidity":45,"battery":90,"voltage":3000}"#
+        );
+    }
+
+    #[test]
+    fn link_quality_range() {
+        assert_eq!(link_quality(-120), 0);
+        assert_eq!(link_quality(-100), 0);
+        assert_eq!(link_quality(-30), 255);
+        assert_eq!(link_quality(-10), 255);
+    }
+}
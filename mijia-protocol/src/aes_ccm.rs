@@ -0,0 +1,257 @@
+//! A minimal implementation of AES-128 encryption and CCM mode decryption, as used for encrypted
+//! MiBeacon advertisements. Only what is needed for decryption is included: CCM uses the block
+//! cipher in the forward direction for both encryption and decryption.
+
+use alloc::vec::Vec;
+
+/// The AES S-box.
+const SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+/// The round constants for the AES-128 key schedule.
+const RCON: [u8; 10] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36];
+
+const BLOCK_SIZE: usize = 16;
+
+/// An AES-128 key, expanded into its round keys.
+pub struct Aes128 {
+    round_keys: [[u8; BLOCK_SIZE]; 11],
+}
+
+impl Aes128 {
+    pub fn new(key: &[u8; 16]) -> Self {
+        let mut words = [[0; 4]; 44];
+        for (i, word) in words.iter_mut().take(4).enumerate() {
+            word.copy_from_slice(&key[i * 4..i * 4 + 4]);
+        }
+        for i in 4..44 {
+            let mut word = words[i - 1];
+            if i % 4 == 0 {
+                word.rotate_left(1);
+                for byte in &mut word {
+                    *byte = SBOX[*byte as usize];
+                }
+                word[0] ^= RCON[i / 4 - 1];
+            }
+            for j in 0..4 {
+                word[j] ^= words[i - 4][j];
+            }
+            words[i] = word;
+        }
+
+        let mut round_keys = [[0; BLOCK_SIZE]; 11];
+        for (round, round_key) in round_keys.iter_mut().enumerate() {
+            for (j, word) in words[round * 4..round * 4 + 4].iter().enumerate() {
+                round_key[j * 4..j * 4 + 4].copy_from_slice(word);
+            }
+        }
+        Self { round_keys }
+    }
+
+    /// Encrypt a single block in place.
+    pub fn encrypt_block(&self, block: &mut [u8; BLOCK_SIZE]) {
+        add_round_key(block, &self.round_keys[0]);
+        for round_key in &self.round_keys[1..10] {
+            sub_bytes(block);
+            shift_rows(block);
+            mix_columns(block);
+            add_round_key(block, round_key);
+        }
+        sub_bytes(block);
+        shift_rows(block);
+        add_round_key(block, &self.round_keys[10]);
+    }
+}
+
+fn add_round_key(block: &mut [u8; BLOCK_SIZE], round_key: &[u8; BLOCK_SIZE]) {
+    for (byte, key_byte) in block.iter_mut().zip(round_key) {
+        *byte ^= key_byte;
+    }
+}
+
+fn sub_bytes(block: &mut [u8; BLOCK_SIZE]) {
+    for byte in block.iter_mut() {
+        *byte = SBOX[*byte as usize];
+    }
+}
+
+/// The block is stored in column-major order, so row `r` of column `c` is at index `r + 4 * c`.
+fn shift_rows(block: &mut [u8; BLOCK_SIZE]) {
+    let old = *block;
+    for row in 1..4 {
+        for column in 0..4 {
+            block[row + 4 * column] = old[row + 4 * ((column + row) % 4)];
+        }
+    }
+}
+
+fn mix_columns(block: &mut [u8; BLOCK_SIZE]) {
+    for column in block.chunks_exact_mut(4) {
+        let a = [column[0], column[1], column[2], column[3]];
+        let all = a[0] ^ a[1] ^ a[2] ^ a[3];
+        for i in 0..4 {
+            column[i] = a[i] ^ all ^ xtime(a[i] ^ a[(i + 1) % 4]);
+        }
+    }
+}
+
+/// Multiply by x in GF(2^8).
+fn xtime(byte: u8) -> u8 {
+    (byte << 1) ^ if byte & 0x80 != 0 { 0x1b } else { 0 }
+}
+
+/// Decrypt and authenticate a message encrypted with AES-128 in CCM mode, as specified by RFC 3610.
+///
+/// The nonce may be between 7 and 13 bytes long, and the tag between 4 and 16 bytes. Returns `None`
+/// if the parameters are invalid or the tag doesn't match.
+pub fn decrypt(
+    key: &[u8; 16],
+    nonce: &[u8],
+    associated_data: &[u8],
+    ciphertext: &[u8],
+    tag: &[u8],
+) -> Option<Vec<u8>> {
+    if !(7..=13).contains(&nonce.len())
+        || ![4, 6, 8, 10, 12, 14, 16].contains(&tag.len())
+        || associated_data.len() >= 0xff00
+    {
+        return None;
+    }
+    // The number of bytes used for the length of the message and the counter.
+    let length_size = 15 - nonce.len();
+    if length_size < 8 && ciphertext.len() >> (8 * length_size) != 0 {
+        return None;
+    }
+    let cipher = Aes128::new(key);
+
+    // Decrypt the message with the counter blocks starting from 1.
+    let counter_block = |counter: usize| {
+        let mut block = [0; BLOCK_SIZE];
+        block[0] = (length_size - 1) as u8;
+        block[1..=nonce.len()].copy_from_slice(nonce);
+        encode_length(&mut block[1 + nonce.len()..], counter);
+        cipher.encrypt_block(&mut block);
+        block
+    };
+    let mut plaintext = Vec::with_capacity(ciphertext.len());
+    for (i, chunk) in ciphertext.chunks(BLOCK_SIZE).enumerate() {
+        let keystream = counter_block(i + 1);
+        plaintext.extend(chunk.iter().zip(&keystream).map(|(c, k)| c ^ k));
+    }
+
+    // Calculate the CBC-MAC over the flags, nonce, length, associated data and plaintext.
+    let mut mac = [0; BLOCK_SIZE];
+    mac[0] = (if associated_data.is_empty() { 0 } else { 0x40 })
+        | (((tag.len() - 2) / 2) << 3) as u8
+        | (length_size - 1) as u8;
+    mac[1..=nonce.len()].copy_from_slice(nonce);
+    encode_length(&mut mac[1 + nonce.len()..], plaintext.len());
+    cipher.encrypt_block(&mut mac);
+    if !associated_data.is_empty() {
+        let mut authenticated = Vec::with_capacity(2 + associated_data.len());
+        authenticated.extend_from_slice(&(associated_data.len() as u16).to_be_bytes());
+        authenticated.extend_from_slice(associated_data);
+        cbc_mac_blocks(&cipher, &mut mac, &authenticated);
+    }
+    cbc_mac_blocks(&cipher, &mut mac, &plaintext);
+
+    let first_keystream = counter_block(0);
+    let mut difference = 0;
+    for ((mac_byte, keystream_byte), tag_byte) in mac.iter().zip(&first_keystream).zip(tag) {
+        difference |= mac_byte ^ keystream_byte ^ tag_byte;
+    }
+    if difference == 0 {
+        Some(plaintext)
+    } else {
+        None
+    }
+}
+
+/// Encode the given length or counter as big-endian into the whole of the given slice.
+fn encode_length(bytes: &mut [u8], length: usize) {
+    let length_bytes = (length as u64).to_be_bytes();
+    let size = bytes.len().min(length_bytes.len());
+    let start = bytes.len() - size;
+    bytes[start..].copy_from_slice(&length_bytes[length_bytes.len() - size..]);
+}
+
+/// Continue a CBC-MAC with the given data, padded with zeroes to a multiple of the block size.
+fn cbc_mac_blocks(cipher: &Aes128, mac: &mut [u8; BLOCK_SIZE], data: &[u8]) {
+    for chunk in data.chunks(BLOCK_SIZE) {
+        for (mac_byte, data_byte) in mac.iter_mut().zip(chunk) {
+            *mac_byte ^= data_byte;
+        }
+        cipher.encrypt_block(mac);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aes128_fips_197() {
+        let cipher = Aes128::new(&[
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
+            0x0e, 0x0f,
+        ]);
+        let mut block = [
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd,
+            0xee, 0xff,
+        ];
+        cipher.encrypt_block(&mut block);
+        assert_eq!(
+            block,
+            [
+                0x69, 0xc4, 0xe0, 0xd8, 0x6a, 0x7b, 0x04, 0x30, 0xd8, 0xcd, 0xb7, 0x80, 0x70, 0xb4,
+                0xc5, 0x5a
+            ]
+        );
+    }
+
+    /// Packet vector #1 from RFC 3610.
+    #[test]
+    fn ccm_rfc_3610() {
+        let key = [
+            0xc0, 0xc1, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xcb, 0xcc, 0xcd,
+            0xce, 0xcf,
+        ];
+        let nonce = [
+            0x00, 0x00, 0x00, 0x03, 0x02, 0x01, 0x00, 0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5,
+        ];
+        let associated_data = [0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07];
+        let ciphertext = [
+            0x58, 0x8c, 0x97, 0x9a, 0x61, 0xc6, 0x63, 0xd2, 0xf0, 0x66, 0xd0, 0xc2, 0xc0, 0xf9,
+            0x89, 0x80, 0x6d, 0x5f, 0x6b, 0x61, 0xda, 0xc3, 0x84,
+        ];
+        let tag = [0x17, 0xe8, 0xd1, 0x2c, 0xfd, 0xf9, 0x26, 0xe0];
+        let plaintext: Vec<u8> = (0x08..=0x1e).collect();
+        assert_eq!(
+            decrypt(&key, &nonce, &associated_data, &ciphertext, &tag),
+            Some(plaintext)
+        );
+
+        let mut wrong_tag = tag;
+        wrong_tag[0] ^= 1;
+        assert_eq!(
+            decrypt(&key, &nonce, &associated_data, &ciphertext, &wrong_tag),
+            None
+        );
+    }
+}
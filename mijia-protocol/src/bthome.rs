@@ -0,0 +1,289 @@
+//! Decoding and encoding of BTHome v2 advertisements, which sensors flashed with compatible custom
+//! firmware broadcast, and which Home Assistant supports natively.
+
+use crate::mibeacon::PartialReadings;
+use crate::{DecodeError, Readings};
+use alloc::borrow::ToOwned;
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// The UUID of the service data in which BTHome advertisements are sent.
+pub const BTHOME_SERVICE_UUID: &str = "0000fcd2-0000-1000-8000-00805f9b34fb";
+
+const DEVICE_INFO_ENCRYPTED: u8 = 0x01;
+const DEVICE_INFO_TRIGGER_BASED: u8 = 0x04;
+const VERSION_2: u8 = 2;
+
+const OBJECT_PACKET_ID: u8 = 0x00;
+const OBJECT_BATTERY: u8 = 0x01;
+const OBJECT_TEMPERATURE: u8 = 0x02;
+const OBJECT_HUMIDITY: u8 = 0x03;
+const OBJECT_VOLTAGE: u8 = 0x0c;
+const OBJECT_HUMIDITY_COARSE: u8 = 0x2e;
+const OBJECT_TEMPERATURE_COARSE: u8 = 0x45;
+const OBJECT_VOLTAGE_COARSE: u8 = 0x4a;
+const OBJECT_TEXT: u8 = 0x53;
+const OBJECT_RAW: u8 = 0x54;
+
+/// Get the length of the data of the BTHome object with the given ID, or `None` if it is unknown.
+/// Objects of variable length have a byte giving their length first, so return 0 for them.
+fn object_length(id: u8) -> Option<usize> {
+    Some(match id {
+        0x00 | 0x01 | 0x09 | 0x0f..=0x11 | 0x15..=0x2f | 0x3a | 0x46 => 1,
+        0x02 | 0x03 | 0x06..=0x08 | 0x0c..=0x0e | 0x12..=0x14 | 0x3c | 0x3d | 0x3f..=0x41 => 2,
+        0x43..=0x45 | 0x47..=0x4a | 0x51 | 0x52 => 2,
+        0x04 | 0x05 | 0x0a | 0x0b | 0x42 | 0x4b => 3,
+        0x3e | 0x4c..=0x50 | 0x55 => 4,
+        OBJECT_TEXT | OBJECT_RAW => 0,
+        _ => return None,
+    })
+}
+
+/// A value included in a BTHome advertisement.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BtHomeObject {
+    /// A counter which is incremented for each new advertisement, so that repeats can be ignored.
+    PacketId(u8),
+    /// Battery level in percent.
+    Battery(u8),
+    /// Temperature in ºC.
+    Temperature(f32),
+    /// Percent humidity.
+    Humidity(f32),
+    /// Battery voltage in millivolts.
+    Voltage(u16),
+    /// An object of a type which isn't decoded, with its raw data.
+    Other { id: u8, data: Vec<u8> },
+}
+
+/// A BTHome v2 advertisement.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BtHome {
+    /// Whether the device only sends advertisements when something happens, rather than regularly.
+    pub trigger_based: bool,
+    /// The values included in the advertisement.
+    pub objects: Vec<BtHomeObject>,
+}
+
+impl BtHome {
+    /// Decode a BTHome advertisement from its service data. Encrypted advertisements aren't
+    /// supported.
+    pub fn decode(service_data: &[u8]) -> Result<BtHome, DecodeError> {
+        let (&device_info, mut rest) = service_data
+            .split_first()
+            .ok_or_else(|| DecodeError::InvalidValue("Empty BTHome advertisement".to_owned()))?;
+        let version = device_info >> 5;
+        if version != VERSION_2 {
+            return Err(DecodeError::InvalidValue(format!(
+                "Unsupported BTHome version {}",
+                version
+            )));
+        }
+        if device_info & DEVICE_INFO_ENCRYPTED != 0 {
+            return Err(DecodeError::InvalidValue(
+                "Encrypted BTHome advertisements aren't supported".to_owned(),
+            ));
+        }
+
+        let mut objects = vec![];
+        while let Some((&id, data)) = rest.split_first() {
+            let truncated =
+                || DecodeError::InvalidValue(format!("Truncated BTHome object {:?}", rest));
+            let length = match object_length(id) {
+                Some(0) => *data.first().ok_or_else(truncated)? as usize + 1,
+                Some(length) => length,
+                None => {
+                    // The lengths of objects are implied by their IDs, so nothing after an unknown
+                    // one can be decoded.
+                    return Err(DecodeError::InvalidValue(format!(
+                        "Unknown BTHome object ID {:#04x}",
+                        id
+                    )));
+                }
+            };
+            let object_data = data.get(..length).ok_or_else(truncated)?;
+            objects.push(decode_object(id, object_data));
+            rest = &data[length..];
+        }
+
+        Ok(BtHome {
+            trigger_based: device_info & DEVICE_INFO_TRIGGER_BASED != 0,
+            objects,
+        })
+    }
+
+    /// Get the packet ID of the advertisement, if it includes one.
+    pub fn packet_id(&self) -> Option<u8> {
+        self.objects.iter().find_map(|object| match *object {
+            BtHomeObject::PacketId(packet_id) => Some(packet_id),
+            _ => None,
+        })
+    }
+}
+
+fn decode_object(id: u8, data: &[u8]) -> BtHomeObject {
+    let u16_le = |bytes: &[u8]| u16::from_le_bytes([bytes[0], bytes[1]]);
+    let i16_le = |bytes: &[u8]| i16::from_le_bytes([bytes[0], bytes[1]]);
+    match id {
+        OBJECT_PACKET_ID => BtHomeObject::PacketId(data[0]),
+        OBJECT_BATTERY => BtHomeObject::Battery(data[0]),
+        OBJECT_TEMPERATURE => BtHomeObject::Temperature(i16_le(data) as f32 / 100.0),
+        OBJECT_TEMPERATURE_COARSE => BtHomeObject::Temperature(i16_le(data) as f32 / 10.0),
+        OBJECT_HUMIDITY => BtHomeObject::Humidity(u16_le(data) as f32 / 100.0),
+        OBJECT_HUMIDITY_COARSE => BtHomeObject::Humidity(data[0] as f32),
+        OBJECT_VOLTAGE => BtHomeObject::Voltage(u16_le(data)),
+        OBJECT_VOLTAGE_COARSE => BtHomeObject::Voltage(u16_le(data).saturating_mul(100)),
+        _ => BtHomeObject::Other {
+            id,
+            data: data.to_owned(),
+        },
+    }
+}
+
+/// Encode the given readings as the service data of a BTHome v2 advertisement, with the given
+/// packet ID. The packet ID should be changed whenever the readings are.
+pub fn encode_readings(readings: &Readings, packet_id: u8) -> Vec<u8> {
+    // `f32::round` needs std, so round to the nearest hundredth of a degree by hand.
+    let scaled = readings.temperature * 100.0;
+    let temperature = (if scaled < 0.0 {
+        scaled - 0.5
+    } else {
+        scaled + 0.5
+    }) as i16;
+    let humidity = u16::from(readings.humidity) * 100;
+    let battery_percent = readings.battery_percent.min(100) as u8;
+    // Objects must be in order of their IDs.
+    let mut service_data = vec![
+        VERSION_2 << 5,
+        OBJECT_PACKET_ID,
+        packet_id,
+        OBJECT_BATTERY,
+        battery_percent,
+        OBJECT_TEMPERATURE,
+    ];
+    service_data.extend_from_slice(&temperature.to_le_bytes());
+    service_data.push(OBJECT_HUMIDITY);
+    service_data.extend_from_slice(&humidity.to_le_bytes());
+    service_data.push(OBJECT_VOLTAGE);
+    service_data.extend_from_slice(&readings.battery_voltage.to_le_bytes());
+    service_data
+}
+
+impl PartialReadings {
+    /// Update the readings with the values from the given BTHome advertisement. Returns whether any
+    /// of them changed.
+    pub fn update_from_bthome(&mut self, bthome: &BtHome) -> bool {
+        let previous = self.clone();
+        for object in &bthome.objects {
+            match *object {
+                BtHomeObject::Battery(battery_percent) => {
+                    self.battery_percent = Some(battery_percent)
+                }
+                BtHomeObject::Temperature(temperature) => self.temperature = Some(temperature),
+                BtHomeObject::Humidity(humidity) => self.humidity = Some(humidity),
+                BtHomeObject::Voltage(battery_voltage) => {
+                    self.battery_voltage = Some(battery_voltage)
+                }
+                _ => {}
+            }
+        }
+        *self != previous
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_example() {
+        // The example from the BTHome format documentation, with a temperature and humidity.
+        assert_eq!(
+            BtHome::decode(&[0x40, 0x02, 0xca, 0x09, 0x03, 0xbf, 0x13]),
+            Ok(BtHome {
+                trigger_based: false,
+                objects: vec![
+                    BtHomeObject::Temperature(25.06),
+                    BtHomeObject::Humidity(50.55),
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn decode_other_objects() {
+        let bthome = BtHome::decode(&[
+            0x44, 0x00, 0x07, 0x2e, 0x2a, 0x45, 0xeb, 0x00, 0x3a, 0x01, 0x54, 0x02, 0xab, 0xcd,
+        ])
+        .unwrap();
+        assert_eq!(
+            bthome,
+            BtHome {
+                trigger_based: true,
+                objects: vec![
+                    BtHomeObject::PacketId(7),
+                    BtHomeObject::Humidity(42.0),
+                    BtHomeObject::Temperature(23.5),
+                    BtHomeObject::Other {
+                        id: 0x3a,
+                        data: vec![0x01]
+                    },
+                    BtHomeObject::Other {
+                        id: 0x54,
+                        data: vec![0x02, 0xab, 0xcd]
+                    },
+                ],
+            }
+        );
+        assert_eq!(bthome.packet_id(), Some(7));
+    }
+
+    #[test]
+    fn decode_invalid() {
+        assert!(BtHome::decode(&[]).is_err());
+        // Version 1.
+        assert!(BtHome::decode(&[0x20, 0x02, 0xca, 0x09]).is_err());
+        // Encrypted.
+        assert!(BtHome::decode(&[0x41, 0x02, 0xca, 0x09]).is_err());
+        // Truncated.
+        assert!(BtHome::decode(&[0x40, 0x02, 0xca]).is_err());
+        assert!(BtHome::decode(&[0x40, 0x54]).is_err());
+        // Unknown object ID.
+        assert!(BtHome::decode(&[0x40, 0xff, 0x00]).is_err());
+    }
+
+    #[test]
+    fn encode_decode_readings() {
+        let readings = Readings {
+            temperature: 21.37,
+            humidity: 55,
+            battery_voltage: 2980,
+            battery_percent: 88,
+        };
+        let service_data = encode_readings(&readings, 42);
+        let bthome = BtHome::decode(&service_data).unwrap();
+        assert_eq!(bthome.packet_id(), Some(42));
+
+        let mut partial_readings = PartialReadings::default();
+        assert!(partial_readings.update_from_bthome(&bthome));
+        assert!(!partial_readings.update_from_bthome(&bthome));
+        assert_eq!(partial_readings.readings(), Some(readings));
+    }
+
+    #[test]
+    fn encode_decode_negative_temperature() {
+        let readings = Readings {
+            temperature: -5.27,
+            humidity: 80,
+            battery_voltage: 3100,
+            battery_percent: 100,
+        };
+        let service_data = encode_readings(&readings, 0);
+        assert_eq!(service_data[5..8], [OBJECT_TEMPERATURE, 0xf1, 0xfd]);
+
+        let mut partial_readings = PartialReadings::default();
+        partial_readings.update_from_bthome(&BtHome::decode(&service_data).unwrap());
+        assert_eq!(partial_readings.readings(), Some(readings));
+    }
+}
@@ -0,0 +1,341 @@
+use crate::temperature_unit::TemperatureUnit;
+use crate::{
+    check_length, decode_temperature, encode_temperature, DecodeError, EncodeError,
+    TEMPERATURE_MAX, TEMPERATURE_MIN,
+};
+use core::convert::TryInto;
+use core::fmt::{self, Display, Formatter};
+
+/// The highest percent humidity which makes sense for a comfort level.
+const HUMIDITY_MAX: u8 = 100;
+
+/// An error constructing a `ComfortLevel` which the sensor wouldn't accept.
+#[derive(Clone, Debug, PartialEq)]
+pub enum InvalidComfortLevel {
+    /// One of the temperatures is out of the range which the sensor can store.
+    TemperatureOutOfRange(f32),
+    /// One of the humidities is more than 100%.
+    HumidityOutOfRange(u8),
+    /// The minimum temperature is higher than the maximum.
+    TemperatureMinAboveMax { min: f32, max: f32 },
+    /// The minimum humidity is higher than the maximum.
+    HumidityMinAboveMax { min: u8, max: u8 },
+}
+
+impl Display for InvalidComfortLevel {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::TemperatureOutOfRange(temperature) => write!(
+                f,
+                "Temperature {}ºC out of range, must be between {}ºC and {}ºC.",
+                temperature, TEMPERATURE_MIN, TEMPERATURE_MAX
+            ),
+            Self::HumidityOutOfRange(humidity) => write!(
+                f,
+                "Humidity {}% out of range, must be at most {}%.",
+                humidity, HUMIDITY_MAX
+            ),
+            Self::TemperatureMinAboveMax { min, max } => write!(
+                f,
+                "Minimum temperature {}ºC is higher than maximum {}ºC.",
+                min, max
+            ),
+            Self::HumidityMinAboveMax { min, max } => write!(
+                f,
+                "Minimum humidity {}% is higher than maximum {}%.",
+                min, max
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for InvalidComfortLevel {}
+
+/// Configuration which determines when the sensor displays a happy face.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ComfortLevel {
+    /// Minimum comfortable temperature in ºC, with 2 decimal places of precision
+    pub temperature_min: f32,
+    /// Maximum comfortable temperature in ºC, with 2 decimal places of precision
+    pub temperature_max: f32,
+    /// Minimum comfortable percent humidity.
+    pub humidity_min: u8,
+    /// Maximum comfortable percent humidity.
+    pub humidity_max: u8,
+}
+
+impl ComfortLevel {
+    /// Construct a new comfort level with the given thresholds, checking that they are valid.
+    pub fn new(
+        temperature_min: f32,
+        temperature_max: f32,
+        humidity_min: u8,
+        humidity_max: u8,
+    ) -> Result<Self, InvalidComfortLevel> {
+        let comfort_level = Self {
+            temperature_min,
+            temperature_max,
+            humidity_min,
+            humidity_max,
+        };
+        comfort_level.validate()?;
+        Ok(comfort_level)
+    }
+
+    /// Construct a new comfort level with the given temperature thresholds in ºF and humidity
+    /// thresholds, checking that they are valid. The temperatures are converted to ºC, which is what
+    /// the sensor uses internally regardless of which unit it displays.
+    pub fn new_fahrenheit(
+        temperature_min: f32,
+        temperature_max: f32,
+        humidity_min: u8,
+        humidity_max: u8,
+    ) -> Result<Self, InvalidComfortLevel> {
+        let unit = TemperatureUnit::Fahrenheit;
+        Self::new(
+            unit.convert_to_celsius(temperature_min),
+            unit.convert_to_celsius(temperature_max),
+            humidity_min,
+            humidity_max,
+        )
+    }
+
+    /// Minimum comfortable temperature in ºF.
+    pub fn temperature_min_fahrenheit(&self) -> f32 {
+        TemperatureUnit::Fahrenheit.convert_from_celsius(self.temperature_min)
+    }
+
+    /// Maximum comfortable temperature in ºF.
+    pub fn temperature_max_fahrenheit(&self) -> f32 {
+        TemperatureUnit::Fahrenheit.convert_from_celsius(self.temperature_max)
+    }
+
+    /// Returns a value which displays the comfort level with temperatures in the given unit.
+    pub fn display_in(&self, unit: TemperatureUnit) -> impl Display + '_ {
+        ComfortLevelDisplay {
+            comfort_level: self,
+            unit,
+        }
+    }
+
+    /// Check that the comfort level is one which the sensor will accept.
+    pub fn validate(&self) -> Result<(), InvalidComfortLevel> {
+        for &temperature in &[self.temperature_min, self.temperature_max] {
+            if !(TEMPERATURE_MIN..=TEMPERATURE_MAX).contains(&temperature) {
+                return Err(InvalidComfortLevel::TemperatureOutOfRange(temperature));
+            }
+        }
+        for &humidity in &[self.humidity_min, self.humidity_max] {
+            if humidity > HUMIDITY_MAX {
+                return Err(InvalidComfortLevel::HumidityOutOfRange(humidity));
+            }
+        }
+        if self.temperature_min > self.temperature_max {
+            return Err(InvalidComfortLevel::TemperatureMinAboveMax {
+                min: self.temperature_min,
+                max: self.temperature_max,
+            });
+        }
+        if self.humidity_min > self.humidity_max {
+            return Err(InvalidComfortLevel::HumidityMinAboveMax {
+                min: self.humidity_min,
+                max: self.humidity_max,
+            });
+        }
+        Ok(())
+    }
+
+    /// Decode a comfort level from the raw bytes of the Bluetooth characteristic value, if it is
+    /// valid.
+    pub fn decode(value: &[u8]) -> Result<ComfortLevel, DecodeError> {
+        check_length(value.len(), 6)?;
+
+        let temperature_max = decode_temperature(value[0..2].try_into().unwrap());
+        let temperature_min = decode_temperature(value[2..4].try_into().unwrap());
+        let humidity_max = value[4];
+        let humidity_min = value[5];
+
+        Ok(ComfortLevel {
+            temperature_min,
+            temperature_max,
+            humidity_min,
+            humidity_max,
+        })
+    }
+
+    /// Encode the comfort level as the raw bytes of the Bluetooth characteristic value, if it is
+    /// valid.
+    pub fn encode(&self) -> Result<[u8; 6], EncodeError> {
+        self.validate()?;
+        let mut bytes = [0; 6];
+        bytes[0..2].copy_from_slice(&encode_temperature(self.temperature_max)?);
+        bytes[2..4].copy_from_slice(&encode_temperature(self.temperature_min)?);
+        bytes[4] = self.humidity_max;
+        bytes[5] = self.humidity_min;
+        Ok(bytes)
+    }
+}
+
+impl Display for ComfortLevel {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.display_in(TemperatureUnit::Celcius).fmt(f)
+    }
+}
+
+struct ComfortLevelDisplay<'a> {
+    comfort_level: &'a ComfortLevel,
+    unit: TemperatureUnit,
+}
+
+impl<'a> Display for ComfortLevelDisplay<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let comfort_level = self.comfort_level;
+        write!(
+            f,
+            "Temperature: {:.2}–{:.2}{} Humidity: {:?}–{:?}%",
+            self.unit
+                .convert_from_celsius(comfort_level.temperature_min),
+            self.unit
+                .convert_from_celsius(comfort_level.temperature_max),
+            self.unit,
+            comfort_level.humidity_min,
+            comfort_level.humidity_max
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn decode_too_short() {
+        assert_eq!(
+            ComfortLevel::decode(&[0x04, 0x02, 0x03, 0x01, 0x06]),
+            Err(DecodeError::WrongLength {
+                length: 5,
+                expected_length: 6
+            })
+        );
+    }
+
+    #[test]
+    fn decode_too_long() {
+        assert_eq!(
+            ComfortLevel::decode(&[0x04, 0x02, 0x03, 0x01, 0x06, 0x05, 0x07]),
+            Err(DecodeError::WrongLength {
+                length: 7,
+                expected_length: 6
+            })
+        );
+    }
+
+    #[test]
+    fn decode_valid() {
+        assert_eq!(
+            ComfortLevel::decode(&[0x04, 0x02, 0x03, 0x01, 0x06, 0x05]).unwrap(),
+            ComfortLevel {
+                temperature_min: 2.59,
+                temperature_max: 5.16,
+                humidity_min: 5,
+                humidity_max: 6,
+            }
+        );
+    }
+
+    #[test]
+    fn new_valid() {
+        assert_eq!(
+            ComfortLevel::new(19.0, 24.5, 40, 60),
+            Ok(ComfortLevel {
+                temperature_min: 19.0,
+                temperature_max: 24.5,
+                humidity_min: 40,
+                humidity_max: 60,
+            })
+        );
+    }
+
+    #[test]
+    fn new_out_of_range() {
+        assert_eq!(
+            ComfortLevel::new(19.0, 400.0, 40, 60),
+            Err(InvalidComfortLevel::TemperatureOutOfRange(400.0))
+        );
+        assert_eq!(
+            ComfortLevel::new(19.0, 24.0, 40, 101),
+            Err(InvalidComfortLevel::HumidityOutOfRange(101))
+        );
+    }
+
+    #[test]
+    fn new_min_above_max() {
+        assert_eq!(
+            ComfortLevel::new(25.0, 24.0, 40, 60),
+            Err(InvalidComfortLevel::TemperatureMinAboveMax {
+                min: 25.0,
+                max: 24.0
+            })
+        );
+        assert_eq!(
+            ComfortLevel::new(19.0, 24.0, 60, 40),
+            Err(InvalidComfortLevel::HumidityMinAboveMax { min: 60, max: 40 })
+        );
+    }
+
+    #[test]
+    fn fahrenheit() {
+        let comfort_level = ComfortLevel::new_fahrenheit(50.0, 77.0, 40, 60).unwrap();
+        assert_eq!(comfort_level.temperature_min, 10.0);
+        assert_eq!(comfort_level.temperature_max, 25.0);
+        assert_eq!(comfort_level.temperature_min_fahrenheit(), 50.0);
+        assert_eq!(comfort_level.temperature_max_fahrenheit(), 77.0);
+    }
+
+    #[test]
+    fn display_in_units() {
+        let comfort_level = ComfortLevel::new(10.0, 25.0, 40, 60).unwrap();
+        assert_eq!(
+            comfort_level.to_string(),
+            "Temperature: 10.00–25.00ºC Humidity: 40–60%"
+        );
+        assert_eq!(
+            comfort_level
+                .display_in(TemperatureUnit::Fahrenheit)
+                .to_string(),
+            "Temperature: 50.00–77.00ºF Humidity: 40–60%"
+        );
+    }
+
+    #[test]
+    fn encode_invalid() {
+        let comfort_level = ComfortLevel {
+            temperature_min: 19.0,
+            temperature_max: 24.0,
+            humidity_min: 60,
+            humidity_max: 40,
+        };
+        assert!(matches!(
+            comfort_level.encode(),
+            Err(EncodeError::InvalidComfortLevel(
+                InvalidComfortLevel::HumidityMinAboveMax { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn encode_decode() {
+        let comfort_level = ComfortLevel {
+            temperature_min: -5.1,
+            temperature_max: 99.5,
+            humidity_min: 3,
+            humidity_max: 42,
+        };
+        assert_eq!(
+            ComfortLevel::decode(&comfort_level.encode().unwrap()).unwrap(),
+            comfort_level
+        );
+    }
+}
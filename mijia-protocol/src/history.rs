@@ -1,12 +1,15 @@
-use crate::decode::time::decode_time;
-use crate::decode::{check_length, DecodeError};
-use std::convert::TryInto;
-use std::fmt::{self, Display, Formatter};
-use std::ops::Range;
+#[cfg(feature = "std")]
+use crate::time::{decode_time, ClockOffset};
+use crate::{check_length, DecodeError};
+use core::convert::TryInto;
+#[cfg(feature = "std")]
+use core::fmt::{self, Display, Formatter};
+use core::ops::Range;
+#[cfg(feature = "std")]
 use std::time::SystemTime;
 
 /// Decode a range of indices encoded as a last index and count into a Rust half-open `Range`.
-pub(crate) fn decode_range(value: &[u8]) -> Result<Range<u32>, DecodeError> {
+pub fn decode_range(value: &[u8]) -> Result<Range<u32>, DecodeError> {
     check_length(value.len(), 8)?;
 
     let last_index = u32::from_le_bytes(value[0..4].try_into().unwrap());
@@ -19,12 +22,16 @@ pub(crate) fn decode_range(value: &[u8]) -> Result<Range<u32>, DecodeError> {
 }
 
 /// A historical temperature/humidity record stored by a sensor.
+#[cfg(feature = "std")]
 #[derive(Clone, Debug, PartialEq)]
 pub struct HistoryRecord {
     /// The index of the record.
     pub index: u32,
-    /// The time at which the record was created.
+    /// The time at which the record was created. This is the same as `raw_time` unless it has been
+    /// corrected for the offset of the sensor's clock.
     pub time: SystemTime,
+    /// The time at which the record was created according to the sensor's clock.
+    pub raw_time: SystemTime,
     /// Minimum temperature in ºC, with 1 decimal place of precision
     pub temperature_min: f32,
     /// Maximum temperature in ºC, with 1 decimal place of precision
@@ -35,8 +42,11 @@ pub struct HistoryRecord {
     pub humidity_max: u8,
 }
 
+#[cfg(feature = "std")]
 impl HistoryRecord {
-    pub(crate) fn decode(value: &[u8]) -> Result<HistoryRecord, DecodeError> {
+    /// Decode a history record from the raw bytes of the Bluetooth characteristic value, if it is
+    /// valid.
+    pub fn decode(value: &[u8]) -> Result<HistoryRecord, DecodeError> {
         check_length(value.len(), 14)?;
 
         let index = u32::from_le_bytes(value[0..4].try_into().unwrap());
@@ -49,14 +59,22 @@ impl HistoryRecord {
         Ok(HistoryRecord {
             index,
             time,
+            raw_time: time,
             temperature_min,
             temperature_max,
             humidity_min,
             humidity_max,
         })
     }
+
+    /// Correct the time of the record to wall-clock time, given the offset of the sensor's clock
+    /// when the record was downloaded.
+    pub fn correct_time(&mut self, clock_offset: ClockOffset) {
+        self.time = clock_offset.correct(self.raw_time);
+    }
 }
 
+#[cfg(feature = "std")]
 impl Display for HistoryRecord {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         write!(
@@ -76,11 +94,12 @@ impl Display for HistoryRecord {
 ///
 /// For some reason this is stored with 1 decimal place rather than 2 like other temperature values,
 /// so we can't use the common `decode_temperature` function.
+#[cfg(feature = "std")]
 fn decode_history_temperature(bytes: [u8; 2]) -> f32 {
     i16::from_le_bytes(bytes) as f32 / 10.0
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use super::*;
     use std::time::Duration;
@@ -122,6 +141,7 @@ mod tests {
             HistoryRecord {
                 index: 329,
                 time: SystemTime::UNIX_EPOCH + Duration::from_secs(1582632000),
+                raw_time: SystemTime::UNIX_EPOCH + Duration::from_secs(1582632000),
                 temperature_min: 21.3,
                 temperature_max: 22.1,
                 humidity_min: 60,
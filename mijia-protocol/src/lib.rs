@@ -0,0 +1,129 @@
+//! Decoding and encoding of the values of the Bluetooth characteristics of Xiaomi Mijia 2
+//! temperature/humidity sensors.
+//!
+//! This has no dependencies on D-Bus or an async runtime, so values captured by any means, such as
+//! from a `btmon` dump or another Bluetooth stack, can be parsed.
+//!
+//! Without the default `std` feature the crate is `no_std` (but requires `alloc`), so the same
+//! parsers can be used on embedded devices. The APIs which deal with `SystemTime` and the
+//! `std::error::Error` implementations are only available with the `std` feature.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+mod aes_ccm;
+pub mod bthome;
+pub mod comfort_level;
+pub mod history;
+pub mod mibeacon;
+pub mod readings;
+pub mod temperature_unit;
+pub mod time;
+
+pub use bthome::{BtHome, BtHomeObject};
+pub use comfort_level::{ComfortLevel, InvalidComfortLevel};
+pub use history::decode_range;
+#[cfg(feature = "std")]
+pub use history::HistoryRecord;
+pub use mibeacon::{
+    product_name, BindKey, FrameControl, MiBeacon, MiBeaconObject, ParseBindKeyError,
+    PartialReadings,
+};
+pub use readings::Readings;
+pub use temperature_unit::{ParseTemperatureUnitError, TemperatureUnit};
+#[cfg(feature = "std")]
+pub use time::{decode_time, encode_time, ClockOffset};
+pub use time::{decode_timestamp, encode_timestamp};
+
+use alloc::string::String;
+use core::fmt::{self, Display, Formatter};
+#[cfg(feature = "std")]
+use std::time::SystemTime;
+
+pub(crate) const TEMPERATURE_MAX: f32 = i16::MAX as f32 * 0.01;
+pub(crate) const TEMPERATURE_MIN: f32 = i16::MIN as f32 * 0.01;
+
+/// An error decoding a property from a sensor.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DecodeError {
+    /// The value being decoded wasn't the expected length.
+    WrongLength {
+        length: usize,
+        expected_length: usize,
+    },
+    /// The value being decoded was invalid in some other way.
+    InvalidValue(String),
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::WrongLength {
+                length,
+                expected_length,
+            } => write!(f, "Wrong length {}, expected {}", length, expected_length),
+            Self::InvalidValue(message) => f.write_str(message),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DecodeError {}
+
+/// An error encoding a property to be sent to a sensor.
+#[derive(Clone, Debug)]
+pub enum EncodeError {
+    /// The temperature value given is out of the range which can be encoded.
+    TemperatureOutOfRange(f32),
+    /// The time value given is out of the range which can be encoded.
+    #[cfg(feature = "std")]
+    TimeOutOfRange(SystemTime),
+    /// The comfort level given isn't one which the sensor will accept.
+    InvalidComfortLevel(InvalidComfortLevel),
+}
+
+impl Display for EncodeError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::TemperatureOutOfRange(temperature) => {
+                write!(f, "Temperature {} out of range.", temperature)
+            }
+            #[cfg(feature = "std")]
+            Self::TimeOutOfRange(time) => write!(f, "Time {:?} out of range.", time),
+            Self::InvalidComfortLevel(e) => e.fmt(f),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for EncodeError {}
+
+impl From<InvalidComfortLevel> for EncodeError {
+    fn from(e: InvalidComfortLevel) -> Self {
+        Self::InvalidComfortLevel(e)
+    }
+}
+
+fn decode_temperature(bytes: [u8; 2]) -> f32 {
+    i16::from_le_bytes(bytes) as f32 / 100.0
+}
+
+fn encode_temperature(temperature: f32) -> Result<[u8; 2], EncodeError> {
+    if !(TEMPERATURE_MIN..=TEMPERATURE_MAX).contains(&temperature) {
+        return Err(EncodeError::TemperatureOutOfRange(temperature));
+    }
+    let temperature_fixed = (temperature * 100.0) as i16;
+    Ok(temperature_fixed.to_le_bytes())
+}
+
+fn check_length(length: usize, expected_length: usize) -> Result<(), DecodeError> {
+    if length != expected_length {
+        Err(DecodeError::WrongLength {
+            length,
+            expected_length,
+        })
+    } else {
+        Ok(())
+    }
+}
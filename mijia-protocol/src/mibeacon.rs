@@ -0,0 +1,633 @@
+//! Decoding of the MiBeacon advertisements which Xiaomi sensors with their stock firmware broadcast,
+//! including those encrypted with a per-device bind key.
+
+use crate::aes_ccm;
+use crate::{DecodeError, Readings};
+use alloc::borrow::ToOwned;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::fmt::{self, Debug, Display, Formatter};
+use core::str::FromStr;
+
+/// The UUID of the service data in which MiBeacon advertisements are sent.
+pub const MIBEACON_SERVICE_UUID: &str = "0000fe95-0000-1000-8000-00805f9b34fb";
+
+const FRAME_CONTROL_FACTORY_NEW: u16 = 0x0001;
+const FRAME_CONTROL_CONNECTED: u16 = 0x0002;
+const FRAME_CONTROL_CENTRAL: u16 = 0x0004;
+const FRAME_CONTROL_ENCRYPTED: u16 = 0x0008;
+const FRAME_CONTROL_MAC_ADDRESS: u16 = 0x0010;
+const FRAME_CONTROL_CAPABILITY: u16 = 0x0020;
+const FRAME_CONTROL_OBJECTS: u16 = 0x0040;
+const FRAME_CONTROL_MESH: u16 = 0x0080;
+const FRAME_CONTROL_REGISTERED: u16 = 0x0100;
+const FRAME_CONTROL_SOLICITED: u16 = 0x0200;
+const CAPABILITY_IO: u8 = 0x20;
+/// The associated data used for encrypting version 4 and 5 MiBeacon frames.
+const ENCRYPTION_ASSOCIATED_DATA: [u8; 1] = [0x11];
+/// The length of the extended frame counter sent after encrypted objects.
+const EXTENDED_COUNTER_LENGTH: usize = 3;
+/// The length of the message integrity check sent after encrypted objects.
+const MIC_LENGTH: usize = 4;
+
+const OBJECT_BUTTON: u16 = 0x1001;
+const OBJECT_TEMPERATURE: u16 = 0x1004;
+const OBJECT_HUMIDITY: u16 = 0x1006;
+const OBJECT_ILLUMINANCE: u16 = 0x1007;
+const OBJECT_MOISTURE: u16 = 0x1008;
+const OBJECT_CONDUCTIVITY: u16 = 0x1009;
+const OBJECT_BATTERY: u16 = 0x100a;
+const OBJECT_TEMPERATURE_AND_HUMIDITY: u16 = 0x100d;
+const OBJECT_FORMALDEHYDE: u16 = 0x1010;
+const OBJECT_CONSUMABLE: u16 = 0x1013;
+const OBJECT_NO_MOTION: u16 = 0x1017;
+
+/// The names of the devices with known product IDs.
+const PRODUCT_NAMES: [(u16, &str); 16] = [
+    (0x0098, "HHCCJCY01"),
+    (0x00db, "MMC-T201-1"),
+    (0x015d, "HHCCPOT002"),
+    (0x01aa, "LYWSDCGQ"),
+    (0x02df, "JQJCY01YM"),
+    (0x0347, "CGG1"),
+    (0x0387, "MHO-C401"),
+    (0x03b6, "YLKG07YL"),
+    (0x03bc, "GCLS002"),
+    (0x040a, "WX08ZM"),
+    (0x045b, "LYWSD02"),
+    (0x055b, "LYWSD03MMC"),
+    (0x0576, "CGD1"),
+    (0x066f, "CGDK2"),
+    (0x06d3, "MHO-C303"),
+    (0x0b48, "CGG1-ENCRYPTED"),
+];
+
+/// Get the model name of the device with the given MiBeacon product ID, if it is known.
+pub fn product_name(product_id: u16) -> Option<&'static str> {
+    PRODUCT_NAMES
+        .iter()
+        .find(|(id, _)| *id == product_id)
+        .map(|(_, name)| *name)
+}
+
+/// The key with which a device encrypts its MiBeacon advertisements, which is assigned when it is
+/// paired with the Xiaomi cloud.
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub struct BindKey(pub [u8; 16]);
+
+impl Debug for BindKey {
+    // Don't leak the key into logs.
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str("BindKey(..)")
+    }
+}
+
+/// An error parsing a bind key from a string.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParseBindKeyError(String);
+
+impl Display for ParseBindKeyError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Invalid bind key '{}', expected 32 hexadecimal digits",
+            self.0
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParseBindKeyError {}
+
+impl FromStr for BindKey {
+    type Err = ParseBindKeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || ParseBindKeyError(s.to_owned());
+        let s = s.trim();
+        if s.len() != 32 || !s.is_ascii() {
+            return Err(error());
+        }
+        let mut key = [0; 16];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).map_err(|_| error())?;
+        }
+        Ok(Self(key))
+    }
+}
+
+/// The frame control field at the start of every MiBeacon advertisement, which says which other
+/// fields are included and how.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FrameControl(pub u16);
+
+impl FrameControl {
+    /// Whether the device hasn't yet been paired.
+    pub fn is_factory_new(self) -> bool {
+        self.0 & FRAME_CONTROL_FACTORY_NEW != 0
+    }
+
+    /// Whether the device is currently connected.
+    pub fn is_connected(self) -> bool {
+        self.0 & FRAME_CONTROL_CONNECTED != 0
+    }
+
+    /// Whether the device is acting as a central rather than a peripheral.
+    pub fn is_central(self) -> bool {
+        self.0 & FRAME_CONTROL_CENTRAL != 0
+    }
+
+    /// Whether the objects are encrypted.
+    pub fn is_encrypted(self) -> bool {
+        self.0 & FRAME_CONTROL_ENCRYPTED != 0
+    }
+
+    /// Whether the MAC address of the device is included.
+    pub fn has_mac_address(self) -> bool {
+        self.0 & FRAME_CONTROL_MAC_ADDRESS != 0
+    }
+
+    /// Whether the capability byte is included.
+    pub fn has_capability(self) -> bool {
+        self.0 & FRAME_CONTROL_CAPABILITY != 0
+    }
+
+    /// Whether any objects are included.
+    pub fn has_objects(self) -> bool {
+        self.0 & FRAME_CONTROL_OBJECTS != 0
+    }
+
+    /// Whether the device is part of a BLE mesh network.
+    pub fn is_mesh(self) -> bool {
+        self.0 & FRAME_CONTROL_MESH != 0
+    }
+
+    /// Whether the device has been registered with the Xiaomi cloud.
+    pub fn is_registered(self) -> bool {
+        self.0 & FRAME_CONTROL_REGISTERED != 0
+    }
+
+    /// Whether the device is asking to be bound.
+    pub fn is_solicited(self) -> bool {
+        self.0 & FRAME_CONTROL_SOLICITED != 0
+    }
+
+    /// The authentication mode, from 0 to 3.
+    pub fn auth_mode(self) -> u8 {
+        ((self.0 >> 10) & 0x03) as u8
+    }
+
+    /// The version of the MiBeacon protocol.
+    pub fn version(self) -> u8 {
+        (self.0 >> 12) as u8
+    }
+}
+
+/// A value or event included in a MiBeacon advertisement.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MiBeaconObject {
+    /// A button was pressed. The meaning of the press type depends on the device, but is usually 0
+    /// for a single press, 1 for a double press and 2 for a long press.
+    Button { index: u16, press_type: u8 },
+    /// Temperature in ºC, with 1 decimal place of precision.
+    Temperature(f32),
+    /// Percent humidity, with 1 decimal place of precision.
+    Humidity(f32),
+    /// Illuminance in lux.
+    Illuminance(u32),
+    /// Soil moisture in percent.
+    Moisture(u8),
+    /// Soil conductivity in µS/cm.
+    Conductivity(u16),
+    /// Battery level in percent.
+    Battery(u8),
+    /// Temperature in ºC and percent humidity, both with 1 decimal place of precision.
+    TemperatureAndHumidity { temperature: f32, humidity: f32 },
+    /// Formaldehyde concentration in mg/m³, with 2 decimal places of precision.
+    Formaldehyde(f32),
+    /// Remaining life of a consumable such as a filter, in percent.
+    Consumable(u8),
+    /// The number of seconds since motion was last detected.
+    NoMotion(u32),
+    /// An object of a type which isn't decoded, with its raw data.
+    Other { id: u16, data: Vec<u8> },
+}
+
+/// A MiBeacon advertisement.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MiBeacon {
+    /// The frame control field, which says which fields were included.
+    pub frame_control: FrameControl,
+    /// The ID of the type of device which sent the advertisement, such as 0x055b for the LYWSD03MMC.
+    pub product_id: u16,
+    /// A counter which is incremented for each new advertisement, so that repeats can be ignored.
+    pub frame_counter: u8,
+    /// The MAC address of the device, if included in the advertisement.
+    pub mac_address: Option<[u8; 6]>,
+    /// The capabilities of the device, if included in the advertisement.
+    pub capability: Option<u8>,
+    /// The values included in the advertisement.
+    pub objects: Vec<MiBeaconObject>,
+}
+
+impl MiBeacon {
+    /// Returns whether the given MiBeacon service data is encrypted, in which case a bind key is
+    /// needed to decode its objects.
+    pub fn is_encrypted(service_data: &[u8]) -> bool {
+        service_data.len() >= 2
+            && FrameControl(u16::from_le_bytes([service_data[0], service_data[1]])).is_encrypted()
+    }
+
+    /// Get the model name of the device which sent the advertisement, if it is known.
+    pub fn product_name(&self) -> Option<&'static str> {
+        product_name(self.product_id)
+    }
+
+    /// Decode a MiBeacon advertisement from the service data sent by the device with the given MAC
+    /// address. The bind key is needed if the advertisement is encrypted.
+    pub fn decode(
+        service_data: &[u8],
+        mac_address: [u8; 6],
+        bind_key: Option<&BindKey>,
+    ) -> Result<MiBeacon, DecodeError> {
+        let too_short = || {
+            DecodeError::InvalidValue(format!(
+                "MiBeacon advertisement too short: {} bytes",
+                service_data.len()
+            ))
+        };
+        if service_data.len() < 5 {
+            return Err(too_short());
+        }
+        let frame_control = FrameControl(u16::from_le_bytes([service_data[0], service_data[1]]));
+        let product_id = u16::from_le_bytes([service_data[2], service_data[3]]);
+        let frame_counter = service_data[4];
+        let mut rest = &service_data[5..];
+
+        let included_mac_address = if frame_control.has_mac_address() {
+            let bytes = rest.get(..6).ok_or_else(too_short)?;
+            rest = &rest[6..];
+            Some(reverse_mac_address(bytes))
+        } else {
+            None
+        };
+        let capability = if frame_control.has_capability() {
+            let capability = *rest.first().ok_or_else(too_short)?;
+            // The capability is followed by 2 bytes of IO capability if that bit is set.
+            let capability_length = if capability & CAPABILITY_IO != 0 {
+                3
+            } else {
+                1
+            };
+            rest = rest.get(capability_length..).ok_or_else(too_short)?;
+            Some(capability)
+        } else {
+            None
+        };
+
+        let objects = if !frame_control.has_objects() {
+            vec![]
+        } else if frame_control.is_encrypted() {
+            if frame_control.version() < 4 {
+                return Err(DecodeError::InvalidValue(format!(
+                    "Unsupported encrypted MiBeacon version {}",
+                    frame_control.version()
+                )));
+            }
+            let bind_key = bind_key.ok_or_else(|| {
+                DecodeError::InvalidValue("MiBeacon advertisement is encrypted".to_owned())
+            })?;
+            let payload = decrypt(
+                rest,
+                included_mac_address.unwrap_or(mac_address),
+                product_id,
+                frame_counter,
+                bind_key,
+            )?;
+            decode_objects(&payload)?
+        } else {
+            decode_objects(rest)?
+        };
+
+        Ok(MiBeacon {
+            frame_control,
+            product_id,
+            frame_counter,
+            mac_address: included_mac_address,
+            capability,
+            objects,
+        })
+    }
+}
+
+/// MAC addresses are sent least significant byte first.
+fn reverse_mac_address(bytes: &[u8]) -> [u8; 6] {
+    let mut mac_address: [u8; 6] = bytes.try_into().unwrap();
+    mac_address.reverse();
+    mac_address
+}
+
+/// Decrypt the objects of a version 4 or 5 MiBeacon frame, which are followed by the extended frame
+/// counter and message integrity check.
+fn decrypt(
+    encrypted: &[u8],
+    mac_address: [u8; 6],
+    product_id: u16,
+    frame_counter: u8,
+    bind_key: &BindKey,
+) -> Result<Vec<u8>, DecodeError> {
+    if encrypted.len() < EXTENDED_COUNTER_LENGTH + MIC_LENGTH {
+        return Err(DecodeError::InvalidValue(
+            "Encrypted MiBeacon payload too short".to_owned(),
+        ));
+    }
+    let (ciphertext, rest) =
+        encrypted.split_at(encrypted.len() - EXTENDED_COUNTER_LENGTH - MIC_LENGTH);
+    let (extended_counter, mic) = rest.split_at(EXTENDED_COUNTER_LENGTH);
+
+    let mut nonce = [0; 12];
+    nonce[..6].copy_from_slice(&mac_address);
+    nonce[..6].reverse();
+    nonce[6..8].copy_from_slice(&product_id.to_le_bytes());
+    nonce[8] = frame_counter;
+    nonce[9..].copy_from_slice(extended_counter);
+
+    aes_ccm::decrypt(
+        &bind_key.0,
+        &nonce,
+        &ENCRYPTION_ASSOCIATED_DATA,
+        ciphertext,
+        mic,
+    )
+    .ok_or_else(|| DecodeError::InvalidValue("Failed to decrypt MiBeacon advertisement".to_owned()))
+}
+
+fn decode_objects(mut payload: &[u8]) -> Result<Vec<MiBeaconObject>, DecodeError> {
+    let mut objects = vec![];
+    while !payload.is_empty() {
+        if payload.len() < 3 {
+            return Err(DecodeError::InvalidValue(format!(
+                "Truncated MiBeacon object {:?}",
+                payload
+            )));
+        }
+        let id = u16::from_le_bytes([payload[0], payload[1]]);
+        let length = payload[2] as usize;
+        let data = payload.get(3..3 + length).ok_or_else(|| {
+            DecodeError::InvalidValue(format!("Truncated MiBeacon object {:?}", payload))
+        })?;
+        objects.push(decode_object(id, data));
+        payload = &payload[3 + length..];
+    }
+    Ok(objects)
+}
+
+/// Decode an object with the given ID from its data. Objects with an unknown ID or an unexpected
+/// length are kept as `MiBeaconObject::Other`, so supporting a new type of device is a matter of
+/// adding its object IDs here.
+fn decode_object(id: u16, data: &[u8]) -> MiBeaconObject {
+    let u16_le = |bytes: &[u8]| u16::from_le_bytes([bytes[0], bytes[1]]);
+    let u24_le = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]);
+    let tenths = |bytes: &[u8]| i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 10.0;
+    match (id, data.len()) {
+        (OBJECT_BUTTON, 3) => MiBeaconObject::Button {
+            index: u16_le(data),
+            press_type: data[2],
+        },
+        (OBJECT_TEMPERATURE, 2) => MiBeaconObject::Temperature(tenths(data)),
+        (OBJECT_HUMIDITY, 2) => MiBeaconObject::Humidity(tenths(data)),
+        (OBJECT_ILLUMINANCE, 3) => MiBeaconObject::Illuminance(u24_le(data)),
+        (OBJECT_MOISTURE, 1) => MiBeaconObject::Moisture(data[0]),
+        (OBJECT_CONDUCTIVITY, 2) => MiBeaconObject::Conductivity(u16_le(data)),
+        (OBJECT_BATTERY, 1) => MiBeaconObject::Battery(data[0]),
+        (OBJECT_TEMPERATURE_AND_HUMIDITY, 4) => MiBeaconObject::TemperatureAndHumidity {
+            temperature: tenths(&data[0..2]),
+            humidity: tenths(&data[2..4]),
+        },
+        (OBJECT_FORMALDEHYDE, 2) => MiBeaconObject::Formaldehyde(u16_le(data) as f32 / 100.0),
+        (OBJECT_CONSUMABLE, 1) => MiBeaconObject::Consumable(data[0]),
+        (OBJECT_NO_MOTION, 4) => {
+            MiBeaconObject::NoMotion(u32::from_le_bytes([data[0], data[1], data[2], data[3]]))
+        }
+        _ => MiBeaconObject::Other {
+            id,
+            data: data.to_owned(),
+        },
+    }
+}
+
+/// Readings accumulated from a series of MiBeacon advertisements, each of which usually only
+/// contains one value.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PartialReadings {
+    /// Temperature in ºC, if received.
+    pub temperature: Option<f32>,
+    /// Percent humidity, if received.
+    pub humidity: Option<f32>,
+    /// Battery level in percent, if received.
+    pub battery_percent: Option<u8>,
+    /// Battery voltage in millivolts, if received.
+    pub battery_voltage: Option<u16>,
+}
+
+impl PartialReadings {
+    /// Update the readings with the values from the given advertisement. Returns whether any of
+    /// them changed.
+    pub fn update(&mut self, mibeacon: &MiBeacon) -> bool {
+        let previous = self.clone();
+        for object in &mibeacon.objects {
+            match *object {
+                MiBeaconObject::Temperature(temperature) => self.temperature = Some(temperature),
+                MiBeaconObject::Humidity(humidity) => self.humidity = Some(humidity),
+                MiBeaconObject::Battery(battery_percent) => {
+                    self.battery_percent = Some(battery_percent)
+                }
+                MiBeaconObject::TemperatureAndHumidity {
+                    temperature,
+                    humidity,
+                } => {
+                    self.temperature = Some(temperature);
+                    self.humidity = Some(humidity);
+                }
+                _ => {}
+            }
+        }
+        *self != previous
+    }
+
+    /// Get a complete set of readings, if every value has been received.
+    ///
+    /// MiBeacon advertisements only include the battery level, so unless the battery voltage has
+    /// been received some other way it is estimated from the level by inverting the calculation in
+    /// `Readings::decode`.
+    pub fn readings(&self) -> Option<Readings> {
+        let battery_percent = u16::from(self.battery_percent?);
+        Some(Readings {
+            temperature: self.temperature?,
+            humidity: (self.humidity? + 0.5) as u8,
+            battery_voltage: self.battery_voltage.unwrap_or(2100 + battery_percent * 10),
+            battery_percent,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC_ADDRESS: [u8; 6] = [0xa4, 0xc1, 0x38, 0xd7, 0x21, 0x17];
+    const BIND_KEY: BindKey = BindKey([
+        0x81, 0x4a, 0xac, 0x74, 0xc4, 0xf1, 0x7b, 0x6c, 0x15, 0x81, 0xe1, 0xab, 0x87, 0x81, 0x6b,
+        0x99,
+    ]);
+
+    #[test]
+    fn parse_bind_key() {
+        assert_eq!("814aac74c4f17b6c1581e1ab87816b99".parse(), Ok(BIND_KEY));
+        assert!("814aac74c4f17b6c1581e1ab87816b9"
+            .parse::<BindKey>()
+            .is_err());
+        assert!("814aac74c4f17b6c1581e1ab87816bzz"
+            .parse::<BindKey>()
+            .is_err());
+        assert_eq!(format!("{:?}", BIND_KEY), "BindKey(..)");
+    }
+
+    #[test]
+    fn decode_encrypted() {
+        let temperature = [
+            0x58, 0x58, 0x5b, 0x05, 0xdb, 0x17, 0x21, 0xd7, 0x38, 0xc1, 0xa4, 0xeb, 0xeb, 0x62,
+            0x19, 0x48, 0x00, 0x00, 0x00, 0x39, 0x42, 0xd8, 0xd1,
+        ];
+        assert!(MiBeacon::is_encrypted(&temperature));
+        assert_eq!(
+            MiBeacon::decode(&temperature, MAC_ADDRESS, Some(&BIND_KEY)),
+            Ok(MiBeacon {
+                frame_control: FrameControl(0x5858),
+                product_id: 0x055b,
+                frame_counter: 0xdb,
+                mac_address: Some(MAC_ADDRESS),
+                capability: None,
+                objects: vec![MiBeaconObject::Temperature(23.5)],
+            })
+        );
+        assert!(MiBeacon::decode(&temperature, MAC_ADDRESS, None).is_err());
+        let wrong_key = BindKey([0; 16]);
+        assert!(MiBeacon::decode(&temperature, MAC_ADDRESS, Some(&wrong_key)).is_err());
+    }
+
+    #[test]
+    fn accumulate_readings() {
+        let adverts: [&[u8]; 3] = [
+            &[
+                0x58, 0x58, 0x5b, 0x05, 0xdb, 0x17, 0x21, 0xd7, 0x38, 0xc1, 0xa4, 0xeb, 0xeb, 0x62,
+                0x19, 0x48, 0x00, 0x00, 0x00, 0x39, 0x42, 0xd8, 0xd1,
+            ],
+            &[
+                0x58, 0x58, 0x5b, 0x05, 0xdb, 0x17, 0x21, 0xd7, 0x38, 0xc1, 0xa4, 0xe9, 0xeb, 0x62,
+                0x30, 0x49, 0x00, 0x00, 0x00, 0x0e, 0x23, 0x37, 0xeb,
+            ],
+            &[
+                0x58, 0x58, 0x5b, 0x05, 0xdb, 0x17, 0x21, 0xd7, 0x38, 0xc1, 0xa4, 0xe5, 0xeb, 0x61,
+                0xaf, 0x00, 0x00, 0x00, 0x9f, 0x74, 0x99, 0x70,
+            ],
+        ];
+        let mut partial_readings = PartialReadings::default();
+        for advert in &adverts {
+            assert_eq!(partial_readings.readings(), None);
+            let mibeacon = MiBeacon::decode(advert, MAC_ADDRESS, Some(&BIND_KEY)).unwrap();
+            assert!(partial_readings.update(&mibeacon));
+        }
+        assert_eq!(
+            partial_readings.readings(),
+            Some(Readings {
+                temperature: 23.5,
+                humidity: 45,
+                battery_voltage: 3030,
+                battery_percent: 93,
+            })
+        );
+    }
+
+    #[test]
+    fn decode_unencrypted() {
+        // A version 2 advertisement from a HHCCJCY01 with its MAC address, capability,
+        // illuminance and an unknown object.
+        let advert = [
+            0x71, 0x20, 0x98, 0x00, 0x12, 0x17, 0x21, 0xd7, 0x38, 0xc1, 0xa4, 0x0d, 0x07, 0x10,
+            0x03, 0x10, 0x27, 0x00, 0x34, 0x12, 0x01, 0x42,
+        ];
+        assert!(!MiBeacon::is_encrypted(&advert));
+        let mibeacon = MiBeacon::decode(&advert, MAC_ADDRESS, None).unwrap();
+        assert_eq!(
+            mibeacon,
+            MiBeacon {
+                frame_control: FrameControl(0x2071),
+                product_id: 0x0098,
+                frame_counter: 0x12,
+                mac_address: Some(MAC_ADDRESS),
+                capability: Some(0x0d),
+                objects: vec![
+                    MiBeaconObject::Illuminance(10000),
+                    MiBeaconObject::Other {
+                        id: 0x1234,
+                        data: vec![0x42]
+                    },
+                ],
+            }
+        );
+        assert_eq!(mibeacon.product_name(), Some("HHCCJCY01"));
+        assert_eq!(mibeacon.frame_control.version(), 2);
+        assert!(mibeacon.frame_control.is_factory_new());
+        assert!(!mibeacon.frame_control.is_registered());
+    }
+
+    #[test]
+    fn decode_objects_of_each_type() {
+        let payload = [
+            0x01, 0x10, 0x03, 0x02, 0x00, 0x01, // Button
+            0x08, 0x10, 0x01, 0x2a, // Moisture
+            0x09, 0x10, 0x02, 0xf4, 0x01, // Conductivity
+            0x10, 0x10, 0x02, 0x0c, 0x00, // Formaldehyde
+            0x13, 0x10, 0x01, 0x50, // Consumable
+            0x17, 0x10, 0x04, 0x78, 0x00, 0x00, 0x00, // No motion
+            0x04, 0x10, 0x01, 0x00, // Temperature with the wrong length
+        ];
+        assert_eq!(
+            decode_objects(&payload),
+            Ok(vec![
+                MiBeaconObject::Button {
+                    index: 2,
+                    press_type: 1
+                },
+                MiBeaconObject::Moisture(42),
+                MiBeaconObject::Conductivity(500),
+                MiBeaconObject::Formaldehyde(0.12),
+                MiBeaconObject::Consumable(80),
+                MiBeaconObject::NoMotion(120),
+                MiBeaconObject::Other {
+                    id: 0x1004,
+                    data: vec![0x00]
+                },
+            ])
+        );
+        assert!(decode_objects(&[0x04, 0x10, 0x02, 0x00]).is_err());
+    }
+
+    #[test]
+    fn product_names() {
+        assert_eq!(product_name(0x055b), Some("LYWSD03MMC"));
+        assert_eq!(product_name(0x0000), None);
+    }
+
+    #[test]
+    fn decode_too_short() {
+        assert!(MiBeacon::decode(&[0x58, 0x58, 0x5b], MAC_ADDRESS, None).is_err());
+        assert!(
+            MiBeacon::decode(&[0x58, 0x58, 0x5b, 0x05, 0xdb, 0x17], MAC_ADDRESS, None).is_err()
+        );
+    }
+}
@@ -1,7 +1,7 @@
-use crate::decode::{check_length, decode_temperature, DecodeError};
-use std::cmp::max;
-use std::convert::TryInto;
-use std::fmt::{self, Display, Formatter};
+use crate::{check_length, decode_temperature, DecodeError};
+use core::cmp::max;
+use core::convert::TryInto;
+use core::fmt::{self, Display, Formatter};
 
 /// A set of readings from a Mijia sensor.
 #[derive(Clone, Debug, PartialEq)]
@@ -29,8 +29,7 @@ impl Display for Readings {
 impl Readings {
     /// Decode the readings from the raw bytes of the Bluetooth characteristic value, if they are
     /// valid.
-    /// Returns `None` if the value is not valid.
-    pub(crate) fn decode(value: &[u8]) -> Result<Readings, DecodeError> {
+    pub fn decode(value: &[u8]) -> Result<Readings, DecodeError> {
         check_length(value.len(), 5)?;
 
         let mut temperature_array = [0; 2];
@@ -0,0 +1,149 @@
+use crate::{check_length, DecodeError};
+use alloc::borrow::ToOwned;
+use alloc::format;
+use alloc::string::String;
+use core::fmt::{self, Display, Formatter};
+use core::str::FromStr;
+
+/// The temperature unit which a Mijia sensor uses for its display.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TemperatureUnit {
+    /// ºC
+    Celcius,
+    /// ºF
+    Fahrenheit,
+}
+
+impl TemperatureUnit {
+    /// Decode a temperature unit from the raw bytes of the Bluetooth characteristic value, if it is
+    /// valid.
+    pub fn decode(value: &[u8]) -> Result<TemperatureUnit, DecodeError> {
+        check_length(value.len(), 1)?;
+
+        match value[0] {
+            0x00 => Ok(TemperatureUnit::Celcius),
+            0x01 => Ok(TemperatureUnit::Fahrenheit),
+            byte => Err(DecodeError::InvalidValue(format!(
+                "Invalid temperature unit value 0x{:x}",
+                byte
+            ))),
+        }
+    }
+
+    /// Encode the temperature unit as the raw bytes of the Bluetooth characteristic value.
+    pub fn encode(&self) -> [u8; 1] {
+        match self {
+            TemperatureUnit::Celcius => [0x00],
+            TemperatureUnit::Fahrenheit => [0x01],
+        }
+    }
+
+    /// Convert the given temperature in ºC to this unit.
+    pub fn convert_from_celsius(&self, celsius: f32) -> f32 {
+        match self {
+            Self::Celcius => celsius,
+            Self::Fahrenheit => celsius * 9.0 / 5.0 + 32.0,
+        }
+    }
+
+    /// Convert the given temperature in this unit to ºC.
+    pub fn convert_to_celsius(&self, temperature: f32) -> f32 {
+        match self {
+            Self::Celcius => temperature,
+            Self::Fahrenheit => (temperature - 32.0) * 5.0 / 9.0,
+        }
+    }
+
+    /// Returns the string representing this unit, either `"ºC"` or `"ºF"`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Celcius => "ºC",
+            Self::Fahrenheit => "ºF",
+        }
+    }
+}
+
+impl Display for TemperatureUnit {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// An error parsing a temperature unit from a string.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParseTemperatureUnitError(String);
+
+impl Display for ParseTemperatureUnitError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Invalid temperature unit '{}'", self.0)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParseTemperatureUnitError {}
+
+impl FromStr for TemperatureUnit {
+    type Err = ParseTemperatureUnitError;
+
+    /// Parse a temperature unit from a string such as `"C"`, `"ºF"` or `"fahrenheit"`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "c" | "ºc" | "°c" | "celsius" | "celcius" => Ok(Self::Celcius),
+            "f" | "ºf" | "°f" | "fahrenheit" => Ok(Self::Fahrenheit),
+            _ => Err(ParseTemperatureUnitError(s.to_owned())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn convert_fahrenheit() {
+        assert_eq!(
+            TemperatureUnit::Fahrenheit.convert_from_celsius(100.0),
+            212.0
+        );
+        assert_eq!(
+            TemperatureUnit::Fahrenheit.convert_from_celsius(-40.0),
+            -40.0
+        );
+        assert_eq!(TemperatureUnit::Fahrenheit.convert_to_celsius(32.0), 0.0);
+        assert_eq!(TemperatureUnit::Celcius.convert_to_celsius(21.5), 21.5);
+    }
+
+    #[test]
+    fn decode_encode() {
+        for unit in &[TemperatureUnit::Celcius, TemperatureUnit::Fahrenheit] {
+            assert_eq!(TemperatureUnit::decode(&unit.encode()).unwrap(), *unit);
+        }
+    }
+
+    #[test]
+    fn decode_invalid() {
+        assert_eq!(
+            TemperatureUnit::decode(&[0x02]),
+            Err(DecodeError::InvalidValue(
+                "Invalid temperature unit value 0x2".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_valid() {
+        assert_eq!("C".parse(), Ok(TemperatureUnit::Celcius));
+        assert_eq!("ºc".parse(), Ok(TemperatureUnit::Celcius));
+        assert_eq!("F".parse(), Ok(TemperatureUnit::Fahrenheit));
+        assert_eq!("Fahrenheit".parse(), Ok(TemperatureUnit::Fahrenheit));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(
+            "K".parse::<TemperatureUnit>(),
+            Err(ParseTemperatureUnitError("K".to_string()))
+        );
+    }
+}
@@ -0,0 +1,140 @@
+#[cfg(feature = "std")]
+use crate::EncodeError;
+use crate::{check_length, DecodeError};
+use core::convert::TryInto;
+#[cfg(feature = "std")]
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::time::SystemTime;
+
+/// Decode a little-endian number of seconds since the Unix epoch.
+pub fn decode_timestamp(value: &[u8]) -> Result<u32, DecodeError> {
+    check_length(value.len(), 4)?;
+
+    Ok(u32::from_le_bytes(value.try_into().unwrap()))
+}
+
+/// Encode a number of seconds since the Unix epoch as little-endian bytes.
+pub fn encode_timestamp(timestamp: u32) -> [u8; 4] {
+    timestamp.to_le_bytes()
+}
+
+/// Decode a time encoded as a little-endian number of seconds since the Unix epoch.
+#[cfg(feature = "std")]
+pub fn decode_time(value: &[u8]) -> Result<SystemTime, DecodeError> {
+    let timestamp = decode_timestamp(value)?;
+    Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(timestamp as u64))
+}
+
+/// Encode a time as a little-endian number of seconds since the Unix epoch, if it is in range.
+#[cfg(feature = "std")]
+pub fn encode_time(time: SystemTime) -> Result<[u8; 4], EncodeError> {
+    let timestamp = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|_| EncodeError::TimeOutOfRange(time))?
+        .as_secs()
+        .try_into()
+        .map_err(|_| EncodeError::TimeOutOfRange(time))?;
+    Ok(encode_timestamp(timestamp))
+}
+
+/// How far a sensor's clock is ahead of or behind wall-clock time, used to correct the times of
+/// history records which were stored according to the sensor's clock.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ClockOffset {
+    /// The sensor's clock is ahead of wall-clock time by the given amount.
+    Ahead(Duration),
+    /// The sensor's clock is behind wall-clock time by the given amount.
+    Behind(Duration),
+}
+
+#[cfg(feature = "std")]
+impl ClockOffset {
+    /// Work out the offset of a sensor's clock which read `sensor_time` at wall-clock time
+    /// `wall_time`.
+    pub fn new(sensor_time: SystemTime, wall_time: SystemTime) -> Self {
+        match sensor_time.duration_since(wall_time) {
+            Ok(ahead) => Self::Ahead(ahead),
+            Err(e) => Self::Behind(e.duration()),
+        }
+    }
+
+    /// Convert a time according to the sensor's clock to the corresponding wall-clock time.
+    pub fn correct(&self, sensor_time: SystemTime) -> SystemTime {
+        match self {
+            Self::Ahead(ahead) => sensor_time - *ahead,
+            Self::Behind(behind) => sensor_time + *behind,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn decode_valid() {
+        assert_eq!(
+            decode_time(&[0x01, 0x02, 0x03, 0x04]).unwrap(),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(0x04030201)
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn decode_too_short() {
+        assert_eq!(
+            decode_time(&[0x01, 0x02, 0x03]),
+            Err(DecodeError::WrongLength {
+                length: 3,
+                expected_length: 4
+            })
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn decode_too_long() {
+        assert_eq!(
+            decode_time(&[0x01, 0x02, 0x03, 0x04, 0x05]),
+            Err(DecodeError::WrongLength {
+                length: 5,
+                expected_length: 4
+            })
+        );
+    }
+
+    #[test]
+    fn encode_decode_timestamp() {
+        assert_eq!(encode_timestamp(0x04030201), [0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(decode_timestamp(&encode_timestamp(12345678)), Ok(12345678));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn encode_decode() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(12345678);
+        assert_eq!(decode_time(&encode_time(time).unwrap()).unwrap(), time);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn clock_offset() {
+        let wall_time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let ahead = ClockOffset::new(wall_time + Duration::from_secs(90), wall_time);
+        assert_eq!(ahead, ClockOffset::Ahead(Duration::from_secs(90)));
+        assert_eq!(
+            ahead.correct(wall_time),
+            wall_time - Duration::from_secs(90)
+        );
+
+        let behind = ClockOffset::new(wall_time - Duration::from_secs(30), wall_time);
+        assert_eq!(behind, ClockOffset::Behind(Duration::from_secs(30)));
+        assert_eq!(
+            behind.correct(wall_time),
+            wall_time + Duration::from_secs(30)
+        );
+    }
+}
@@ -6,7 +6,7 @@ const SCAN_DURATION: Duration = Duration::from_secs(5);
 
 #[tokio::main]
 async fn main() -> Result<(), eyre::Report> {
-    pretty_env_logger::init();
+    tracing_subscriber::fmt::init();
 
     let (_, session) = MijiaSession::new().await?;
 
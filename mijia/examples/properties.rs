@@ -9,7 +9,7 @@ const SCAN_DURATION: Duration = Duration::from_secs(5);
 
 #[tokio::main]
 async fn main() -> Result<(), Report> {
-    pretty_env_logger::init();
+    tracing_subscriber::fmt::init();
 
     let filters = parse_args()?;
 
@@ -31,14 +31,17 @@ async fn main() -> Result<(), Report> {
         if let Err(e) = session.bt_session.connect(&sensor.id).await {
             println!("Failed to connect to {}: {:?}", sensor.mac_address, e);
         } else {
-            let sensor_time: DateTime<Utc> = session.get_time(&sensor.id).await?.into();
-            let temperature_unit = session.get_temperature_unit(&sensor.id).await?;
-            let comfort_level = session.get_comfort_level(&sensor.id).await?;
+            let settings = session.get_settings(&sensor.id).await?;
+            let sensor_time: DateTime<Utc> = settings.time.into();
             let history_range = session.get_history_range(&sensor.id).await?;
             let last_record = session.get_last_history_record(&sensor.id).await?;
             println!(
                 "Time: {}, Unit: {}, Comfort level: {}, Range: {:?} Last value: {}",
-                sensor_time, temperature_unit, comfort_level, history_range, last_record
+                sensor_time,
+                settings.temperature_unit,
+                settings.comfort_level,
+                history_range,
+                last_record
             );
             let history = session.get_all_history(&sensor.id).await?;
             println!("History: {:?}", history);
@@ -11,7 +11,7 @@ const SCAN_DURATION: Duration = Duration::from_secs(5);
 
 #[tokio::main]
 async fn main() -> Result<(), Report> {
-    pretty_env_logger::init();
+    tracing_subscriber::fmt::init();
 
     let filters = parse_args()?;
 
@@ -41,15 +41,20 @@ async fn main() -> Result<(), Report> {
     println!("Readings:");
     while let Some(event) = events.next().await {
         match event {
-            MijiaEvent::Readings { id, readings } => {
-                println!("{:?}: {}", id, readings);
+            MijiaEvent::Readings {
+                mac_address,
+                readings,
+                time,
+                ..
+            } => {
+                println!("{} at {:?}: {}", mac_address, time, readings);
             }
             _ => println!("Event: {:?}", event),
         }
     }
     session
         .bt_session
-        .connection
+        .connection()
         .remove_match(msg_match.token())
         .await?;
 
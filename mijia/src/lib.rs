@@ -1,26 +1,47 @@
 //! A library for connecting to Xiaomi Mijia 2 Bluetooth temperature/humidity sensors.
 
 use core::future::Future;
-use dbus::nonblock::MsgMatch;
+use dbus::channel::Token;
+use dbus::message::MatchRule;
+use dbus::nonblock::{MsgMatch, SyncConnection};
 use dbus::Message;
-use futures::Stream;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::future::{self, Either};
+use futures::{Stream, StreamExt};
+use std::collections::{HashMap, VecDeque};
+use std::num::NonZeroUsize;
 use std::ops::Range;
-use std::time::{Duration, SystemTime};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use thiserror::Error;
-use tokio::stream::StreamExt;
-
-pub mod bluetooth;
-mod bluetooth_event;
-mod decode;
-pub use bluetooth::{BluetoothError, BluetoothSession, DeviceId, MacAddress, SpawnError};
-use bluetooth_event::BluetoothEvent;
-pub use decode::comfort_level::ComfortLevel;
+
+pub mod metric_names;
+#[cfg(feature = "test-utils")]
+pub mod test_utils;
+use bluetooth::runtime;
+use bluetooth::BluetoothEvent;
+use bluetooth::DEFAULT_MAX_CONCURRENT_CONNECTS;
+pub use bluetooth::{
+    AdapterId, BluetoothError, BluetoothSession, DeviceId, MacAddress, SpawnError,
+};
+pub use bluez_async as bluetooth;
+use decode::bthome::BTHOME_SERVICE_UUID;
+pub use decode::bthome::{BtHome, BtHomeObject};
+pub use decode::comfort_level::{ComfortLevel, InvalidComfortLevel};
 use decode::history::decode_range;
 pub use decode::history::HistoryRecord;
+use decode::mibeacon::MIBEACON_SERVICE_UUID;
+pub use decode::mibeacon::{
+    BindKey, FrameControl, MiBeacon, MiBeaconObject, ParseBindKeyError, PartialReadings,
+};
 pub use decode::readings::Readings;
-pub use decode::temperature_unit::TemperatureUnit;
+pub use decode::temperature_unit::{ParseTemperatureUnitError, TemperatureUnit};
+pub use decode::time::ClockOffset;
 use decode::time::{decode_time, encode_time};
 pub use decode::{DecodeError, EncodeError};
+pub use mijia_protocol as decode;
 
 const MIJIA_NAME: &str = "LYWSD03MMC";
 const CLOCK_CHARACTERISTIC_PATH: &str = "/service0021/char0022";
@@ -33,11 +54,42 @@ const SENSOR_READING_CHARACTERISTIC_PATH: &str = "/service0021/char0035";
 const HISTORY_DELETE_CHARACTERISTIC_PATH: &str = "/service0021/char003f";
 const COMFORT_LEVEL_CHARACTERISTIC_PATH: &str = "/service0021/char0042";
 const CONNECTION_INTERVAL_CHARACTERISTIC_PATH: &str = "/service0021/char0045";
-/// 500 in little-endian
-const CONNECTION_INTERVAL_500_MS: [u8; 3] = [0xF4, 0x01, 0x00];
 const HISTORY_DELETE_VALUE: [u8; 1] = [0x01];
-const DBUS_METHOD_CALL_TIMEOUT: Duration = Duration::from_secs(30);
-const HISTORY_RECORD_TIMEOUT: Duration = Duration::from_secs(2);
+const ADAPTER_INTERFACE: &str = "org.bluez.Adapter1";
+const DEFAULT_HISTORY_RECORD_TIMEOUT: Duration = Duration::from_secs(2);
+/// The connection interval requested after subscribing to readings, to save power.
+const DEFAULT_CONNECTION_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The progress of downloading history records from a sensor, as passed to the callback of
+/// `MijiaSession::get_all_history_with_progress`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HistoryProgress {
+    /// The range of record indices stored on the sensor, which are being downloaded.
+    pub range: Range<u32>,
+    /// The number of distinct records in the range received so far.
+    pub received: usize,
+    /// The index of the record most recently received, if any.
+    pub current_index: Option<u32>,
+    /// How long it has been since the download started.
+    pub elapsed: Duration,
+}
+
+impl HistoryProgress {
+    /// The number of records expected in total.
+    pub fn expected(&self) -> usize {
+        self.range.len()
+    }
+
+    /// Estimate how much longer the download will take, based on the rate at which records have
+    /// been received so far, or `None` if none have been received yet.
+    pub fn remaining(&self) -> Option<Duration> {
+        if self.received == 0 {
+            return None;
+        }
+        let remaining_records = self.expected().saturating_sub(self.received) as u32;
+        Some(self.elapsed / self.received as u32 * remaining_records)
+    }
+}
 
 /// An error interacting with a Mijia sensor.
 #[derive(Debug, Error)]
@@ -51,9 +103,16 @@ pub enum MijiaError {
     /// The error was with encoding a value to send to a sensor.
     #[error(transparent)]
     Encoding(#[from] EncodeError),
+    /// The sensor disconnected before all the requested records were received.
+    #[error("Sensor disconnected during history download")]
+    Disconnected,
+    /// The deadline for a history download passed before it finished.
+    #[error("Deadline passed before history download finished")]
+    DeadlineExceeded,
 }
 
-/// The MAC address and opaque connection ID of a Mijia sensor which was discovered.
+/// The MAC address, opaque connection ID and current status of a Mijia sensor which was
+/// discovered.
 #[derive(Clone, Debug)]
 pub struct SensorProps {
     /// An opaque identifier for the sensor, including a reference to which Bluetooth adapter it was
@@ -61,52 +120,123 @@ pub struct SensorProps {
     pub id: DeviceId,
     /// The MAC address of the sensor.
     pub mac_address: MacAddress,
+    /// The received signal strength of the sensor's advertisements in dBm, if available.
+    pub rssi: Option<i16>,
+    /// Whether the sensor is currently connected.
+    pub connected: bool,
+}
+
+/// The clock and display settings of a Mijia sensor.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SensorSettings {
+    /// The current time of the sensor's clock.
+    pub time: SystemTime,
+    /// The temperature unit which the sensor uses for its display.
+    pub temperature_unit: TemperatureUnit,
+    /// The comfort level configuration which determines when the sensor displays a happy face.
+    pub comfort_level: ComfortLevel,
+}
+
+/// How a set of readings was received from a sensor.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReadingsSource {
+    /// A notification from a connected sensor.
+    Notification,
+    /// An advertisement broadcast by the sensor, which doesn't mean that it is connected.
+    Advertisement,
 }
 
 /// An event from a Mijia sensor.
 #[non_exhaustive]
 #[derive(Clone, Debug)]
 pub enum MijiaEvent {
-    /// A sensor has sent a new set of readings.
-    Readings { id: DeviceId, readings: Readings },
+    /// A sensor has sent a new set of readings. `time` is when the notification or advertisement
+    /// was received, so that consumers which buffer or batch events still know when each set of
+    /// readings was taken.
+    Readings {
+        id: DeviceId,
+        mac_address: MacAddress,
+        readings: Readings,
+        time: SystemTime,
+        source: ReadingsSource,
+    },
     /// A sensor has sent a new historical record.
-    HistoryRecord { id: DeviceId, record: HistoryRecord },
+    HistoryRecord {
+        id: DeviceId,
+        mac_address: MacAddress,
+        record: HistoryRecord,
+    },
     /// The Bluetooth connection to a sensor has been lost.
-    Disconnected { id: DeviceId },
+    Disconnected {
+        id: DeviceId,
+        mac_address: MacAddress,
+    },
+    /// A Bluetooth adapter has been added to the system.
+    AdapterAdded { id: AdapterId },
+    /// A Bluetooth adapter has been removed from the system.
+    AdapterRemoved { id: AdapterId },
+    /// A Bluetooth adapter has been powered on or off.
+    AdapterPowered { id: AdapterId, powered: bool },
+    /// A Bluetooth adapter has started or stopped scanning for devices.
+    AdapterDiscovering { id: AdapterId, discovering: bool },
+    /// The D-Bus connection has been re-established or the BlueZ daemon has restarted, so all
+    /// connections to sensors and their notifications have been lost.
+    BusReset,
 }
 
 impl MijiaEvent {
-    fn from(conn_msg: Message) -> Option<Self> {
+    fn from(
+        conn_msg: Message,
+        mac_addresses: &MacAddresses,
+        advertisements: &mut Advertisements,
+        decode_failures: &DecodeFailures,
+    ) -> Option<Self> {
         match BluetoothEvent::from(conn_msg) {
             Some(BluetoothEvent::Value { object_path, value }) => {
                 if let Some(object_path) =
                     object_path.strip_suffix(SENSOR_READING_CHARACTERISTIC_PATH)
                 {
-                    match Readings::decode(&value) {
-                        Ok(readings) => Some(MijiaEvent::Readings {
-                            id: DeviceId::new(object_path),
-                            readings,
-                        }),
-                        Err(e) => {
-                            log::error!("Error decoding readings: {:?}", e);
-                            None
-                        }
-                    }
+                    let id = DeviceId::new(object_path);
+                    let mac_address = mac_addresses.get(&id)?;
+                    let readings = decode_readings_notification(&value, decode_failures)?;
+                    Some(MijiaEvent::Readings {
+                        id,
+                        mac_address,
+                        readings,
+                        time: SystemTime::now(),
+                        source: ReadingsSource::Notification,
+                    })
                 } else if let Some(object_path) =
                     object_path.strip_suffix(HISTORY_RECORDS_CHARACTERISTIC_PATH)
                 {
+                    let id = DeviceId::new(object_path);
+                    let mac_address = mac_addresses.get(&id)?;
                     match HistoryRecord::decode(&value) {
-                        Ok(record) => Some(MijiaEvent::HistoryRecord {
-                            id: DeviceId::new(object_path),
-                            record,
-                        }),
+                        Ok(record) => {
+                            metrics::counter!(
+                                metric_names::NOTIFICATIONS_RECEIVED,
+                                1,
+                                "kind" => "history"
+                            );
+                            Some(MijiaEvent::HistoryRecord {
+                                id,
+                                mac_address,
+                                record,
+                            })
+                        }
                         Err(e) => {
-                            log::error!("Error decoding historical record: {:?}", e);
+                            metrics::counter!(
+                                metric_names::DECODE_FAILURES,
+                                1,
+                                "kind" => "history"
+                            );
+                            decode_failures.increment();
+                            tracing::error!("Error decoding historical record: {:?}", e);
                             None
                         }
                     }
                 } else {
-                    log::trace!(
+                    tracing::trace!(
                         "Got BluetoothEvent::Value for object path {} with value {:?}",
                         object_path,
                         value
@@ -114,59 +244,407 @@ impl MijiaEvent {
                     None
                 }
             }
+            Some(BluetoothEvent::ServiceData {
+                object_path,
+                service_data,
+            }) => {
+                if !service_data.contains_key(MIBEACON_SERVICE_UUID)
+                    && !service_data.contains_key(BTHOME_SERVICE_UUID)
+                {
+                    return None;
+                }
+                let id = DeviceId::new(&object_path);
+                let mac_address = mac_addresses.get(&id)?;
+                let readings = advertisements.readings(mac_address, &service_data)?;
+                Some(MijiaEvent::Readings {
+                    id,
+                    mac_address,
+                    readings,
+                    time: SystemTime::now(),
+                    source: ReadingsSource::Advertisement,
+                })
+            }
             Some(BluetoothEvent::Connected {
                 object_path,
                 connected: false,
-            }) => Some(MijiaEvent::Disconnected {
-                id: DeviceId { object_path },
+            }) => {
+                metrics::counter!(metric_names::DISCONNECTIONS, 1);
+                let id = DeviceId::new(&object_path);
+                let mac_address = mac_addresses.get(&id)?;
+                Some(MijiaEvent::Disconnected { id, mac_address })
+            }
+            Some(BluetoothEvent::Powered {
+                object_path,
+                powered,
+            }) => Some(MijiaEvent::AdapterPowered {
+                id: AdapterId::new(&object_path),
+                powered,
+            }),
+            Some(BluetoothEvent::Discovering {
+                object_path,
+                discovering,
+            }) => Some(MijiaEvent::AdapterDiscovering {
+                id: AdapterId::new(&object_path),
+                discovering,
             }),
+            Some(BluetoothEvent::InterfacesAdded {
+                object_path,
+                interfaces,
+            }) if interfaces.iter().any(|i| i == ADAPTER_INTERFACE) => {
+                Some(MijiaEvent::AdapterAdded {
+                    id: AdapterId::new(&object_path),
+                })
+            }
+            Some(BluetoothEvent::InterfacesRemoved {
+                object_path,
+                interfaces,
+            }) if interfaces.iter().any(|i| i == ADAPTER_INTERFACE) => {
+                Some(MijiaEvent::AdapterRemoved {
+                    id: AdapterId::new(&object_path),
+                })
+            }
             _ => None,
         }
     }
 }
 
+/// The MAC addresses of sensors by their IDs, cached from discovery so that events can include them.
+#[derive(Clone, Debug, Default)]
+struct MacAddresses(Arc<Mutex<HashMap<DeviceId, MacAddress>>>);
+
+impl MacAddresses {
+    fn insert(&self, id: DeviceId, mac_address: MacAddress) {
+        self.0.lock().unwrap().insert(id, mac_address);
+    }
+
+    /// Get the MAC address of the device with the given ID. If it hasn't been discovered by this
+    /// session, such as if it was already connected from a previous run, then the MAC address is
+    /// taken from the ID instead.
+    fn get(&self, id: &DeviceId) -> Option<MacAddress> {
+        if let Some(mac_address) = self.0.lock().unwrap().get(id) {
+            return Some(*mac_address);
+        }
+        let mac_address = id.mac_address();
+        if mac_address.is_none() {
+            tracing::warn!("Couldn't find MAC address of device {:?}", id);
+        }
+        mac_address
+    }
+}
+
+/// The bind keys with which sensors encrypt their MiBeacon advertisements, keyed by MAC address.
+#[derive(Clone, Debug, Default)]
+struct BindKeys(Arc<Mutex<HashMap<MacAddress, BindKey>>>);
+
+/// The number of values from sensors which couldn't be decoded, shared by all clones of a session
+/// and the streams they return. They are also recorded in the metrics, with more detail.
+#[derive(Clone, Debug, Default)]
+struct DecodeFailures(Arc<AtomicU64>);
+
+impl DecodeFailures {
+    fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The state of decoding MiBeacon and BTHome advertisements for an event stream.
+#[derive(Debug, Default)]
+struct Advertisements {
+    bind_keys: BindKeys,
+    decode_failures: DecodeFailures,
+    /// The frame counter or packet ID of the last advertisement from each sensor, if it had one,
+    /// and the readings accumulated from its advertisements so far.
+    sensors: HashMap<MacAddress, (Option<u8>, PartialReadings)>,
+}
+
+impl Advertisements {
+    fn new(bind_keys: BindKeys, decode_failures: DecodeFailures) -> Self {
+        Self {
+            bind_keys,
+            decode_failures,
+            sensors: HashMap::new(),
+        }
+    }
+
+    /// Decode the given service data from the sensor with the given MAC address, and return its
+    /// latest readings if this is a new advertisement and all of them have now been received.
+    fn readings(
+        &mut self,
+        mac_address: MacAddress,
+        service_data: &HashMap<String, Vec<u8>>,
+    ) -> Option<Readings> {
+        if let Some(service_data) = service_data.get(MIBEACON_SERVICE_UUID) {
+            self.mibeacon_readings(mac_address, service_data)
+        } else if let Some(service_data) = service_data.get(BTHOME_SERVICE_UUID) {
+            self.bthome_readings(mac_address, service_data)
+        } else {
+            None
+        }
+    }
+
+    fn mibeacon_readings(
+        &mut self,
+        mac_address: MacAddress,
+        service_data: &[u8],
+    ) -> Option<Readings> {
+        let bind_key = self.bind_keys.0.lock().unwrap().get(&mac_address).copied();
+        if bind_key.is_none() && MiBeacon::is_encrypted(service_data) {
+            tracing::trace!(
+                "No bind key for encrypted advertisement from {}",
+                mac_address
+            );
+            return None;
+        }
+        let mibeacon = match MiBeacon::decode(service_data, mac_address.into(), bind_key.as_ref()) {
+            Ok(mibeacon) => mibeacon,
+            Err(e) => {
+                metrics::counter!(metric_names::DECODE_FAILURES, 1, "kind" => "advertisement");
+                self.decode_failures.increment();
+                tracing::warn!("Error decoding advertisement from {}: {:?}", mac_address, e);
+                return None;
+            }
+        };
+        self.accumulate(
+            mac_address,
+            Some(mibeacon.frame_counter),
+            |partial_readings| {
+                partial_readings.update(&mibeacon);
+            },
+        )
+    }
+
+    fn bthome_readings(
+        &mut self,
+        mac_address: MacAddress,
+        service_data: &[u8],
+    ) -> Option<Readings> {
+        let bthome = match BtHome::decode(service_data) {
+            Ok(bthome) => bthome,
+            Err(e) => {
+                metrics::counter!(metric_names::DECODE_FAILURES, 1, "kind" => "advertisement");
+                self.decode_failures.increment();
+                tracing::warn!("Error decoding advertisement from {}: {:?}", mac_address, e);
+                return None;
+            }
+        };
+        self.accumulate(mac_address, bthome.packet_id(), |partial_readings| {
+            partial_readings.update_from_bthome(&bthome);
+        })
+    }
+
+    /// Update the readings accumulated for the given sensor, unless the advertisement has the same
+    /// counter as the last one and so is a repeat.
+    fn accumulate(
+        &mut self,
+        mac_address: MacAddress,
+        counter: Option<u8>,
+        update: impl FnOnce(&mut PartialReadings),
+    ) -> Option<Readings> {
+        metrics::counter!(metric_names::NOTIFICATIONS_RECEIVED, 1, "kind" => "advertisement");
+        let (last_counter, partial_readings) = self.sensors.entry(mac_address).or_default();
+        if counter.is_some() && *last_counter == counter {
+            return None;
+        }
+        *last_counter = counter;
+        update(partial_readings);
+        partial_readings.readings()
+    }
+}
+
 /// A wrapper around a Bluetooth session which adds some methods for dealing with Mijia sensors.
-/// The underlying Bluetooth session may still be accessed.
+/// The underlying Bluetooth session may still be accessed. This can be cheaply cloned and passed
+/// around to be used from different places.
+#[derive(Clone)]
 pub struct MijiaSession {
     pub bt_session: BluetoothSession,
+    mac_addresses: MacAddresses,
+    bind_keys: BindKeys,
+    decode_failures: DecodeFailures,
+    options: Arc<SessionOptions>,
+}
+
+/// The tunable options of a `MijiaSession`.
+#[derive(Clone, Debug)]
+struct SessionOptions {
+    adapter: Option<AdapterId>,
+    history_record_timeout: Duration,
+    connection_interval: Duration,
+    event_buffer_size: Option<NonZeroUsize>,
+}
+
+/// Builder for a `MijiaSession` with options other than the defaults.
+#[derive(Clone, Debug)]
+pub struct MijiaSessionBuilder {
+    max_concurrent_connects: usize,
+    options: SessionOptions,
+}
+
+impl MijiaSessionBuilder {
+    /// Restrict the session to sensors discovered on the given Bluetooth adapter, so that
+    /// `MijiaSession::get_sensors` ignores those found by other adapters and
+    /// `MijiaSession::start_discovery` only scans on this one.
+    ///
+    /// By default all adapters are used.
+    pub fn set_adapter(&mut self, adapter: AdapterId) {
+        self.options.adapter = Some(adapter);
+    }
+
+    /// Set how many sensor connection attempts may be made at once. Any more wait until one of the
+    /// earlier attempts has finished. This must be at least 1, or `build` will fail with
+    /// `BluetoothError::InvalidConnectLimit`.
+    pub fn set_max_concurrent_connects(&mut self, max_concurrent_connects: usize) {
+        self.max_concurrent_connects = max_concurrent_connects;
+    }
+
+    /// Set how long to wait for the next record while downloading history before assuming that the
+    /// sensor has sent all it is going to. This is 2 seconds by default.
+    pub fn set_history_record_timeout(&mut self, timeout: Duration) {
+        self.options.history_record_timeout = timeout;
+    }
+
+    /// Set the connection interval which `MijiaSession::start_notify_sensor` asks sensors to use. A
+    /// longer interval saves sensors' batteries, at the cost of slower responses. This is 500 ms by
+    /// default, and is rounded down to whole milliseconds.
+    pub fn set_connection_interval(&mut self, interval: Duration) {
+        self.options.connection_interval = interval;
+    }
+
+    /// Limit how many BlueZ signals each stream from `MijiaSession::event_stream` buffers while
+    /// waiting to be polled. If a consumer falls further behind than this then the oldest signals
+    /// are dropped, rather than using ever more memory.
+    ///
+    /// By default the buffer is unbounded. A size of 0 would drop every signal, so the size must be
+    /// non-zero.
+    pub fn set_event_buffer_size(&mut self, event_buffer_size: NonZeroUsize) {
+        self.options.event_buffer_size = Some(event_buffer_size);
+    }
+
+    /// Connect to D-Bus and construct the session with the options set.
+    ///
+    /// Returns a tuple of (join handle, session), as for `MijiaSession::new`.
+    pub async fn build(
+        self,
+    ) -> Result<(impl Future<Output = Result<(), SpawnError>>, MijiaSession), BluetoothError> {
+        let (handle, bt_session) =
+            BluetoothSession::new_with_connect_limit(self.max_concurrent_connects).await?;
+        Ok((
+            handle,
+            MijiaSession {
+                bt_session,
+                mac_addresses: MacAddresses::default(),
+                bind_keys: BindKeys::default(),
+                decode_failures: DecodeFailures::default(),
+                options: Arc::new(self.options),
+            },
+        ))
+    }
 }
 
 impl MijiaSession {
     /// Returns a tuple of (join handle, Self).
-    /// If the join handle ever completes then you're in trouble and should
+    /// If the D-Bus connection is lost then it is re-established, and a `MijiaEvent::BusReset` sent
+    /// to event streams. If the join handle ever completes then you're in trouble and should
     /// probably restart the process.
     pub async fn new(
     ) -> Result<(impl Future<Output = Result<(), SpawnError>>, Self), BluetoothError> {
-        let (handle, bt_session) = BluetoothSession::new().await?;
-        Ok((handle, MijiaSession { bt_session }))
+        Self::builder().build().await
+    }
+
+    /// Like `new`, but allowing at most the given number of sensor connection attempts at once,
+    /// which must be at least 1.
+    pub async fn new_with_connect_limit(
+        max_concurrent_connects: usize,
+    ) -> Result<(impl Future<Output = Result<(), SpawnError>>, Self), BluetoothError> {
+        let mut builder = Self::builder();
+        builder.set_max_concurrent_connects(max_concurrent_connects);
+        builder.build().await
     }
 
-    /// Get a list of all Mijia sensors which have currently been discovered.
+    /// Create a builder to construct a new session with options other than the defaults.
+    pub fn builder() -> MijiaSessionBuilder {
+        MijiaSessionBuilder {
+            max_concurrent_connects: DEFAULT_MAX_CONCURRENT_CONNECTS,
+            options: SessionOptions {
+                adapter: None,
+                history_record_timeout: DEFAULT_HISTORY_RECORD_TIMEOUT,
+                connection_interval: DEFAULT_CONNECTION_INTERVAL,
+                event_buffer_size: None,
+            },
+        }
+    }
+
+    /// Power on and start scanning for sensors on the adapter which the session is restricted to, or
+    /// on all Bluetooth adapters if it isn't.
+    pub async fn start_discovery(&self) -> Result<(), BluetoothError> {
+        match &self.options.adapter {
+            Some(adapter) => self.bt_session.start_discovery_on_adapter(adapter).await,
+            None => self.bt_session.start_discovery().await,
+        }
+    }
+
+    /// Get a list of all Mijia sensors which have currently been discovered, on the adapter which
+    /// the session is restricted to if any.
     pub async fn get_sensors(&self) -> Result<Vec<SensorProps>, BluetoothError> {
         let devices = self.bt_session.get_devices().await?;
 
         let sensors = devices
             .into_iter()
+            .filter(|device| match &self.options.adapter {
+                Some(adapter) => device.id.adapter() == *adapter,
+                None => true,
+            })
             .filter_map(|device| {
-                log::trace!(
+                tracing::trace!(
                     "{} ({:?}): {:?}",
                     device.mac_address,
                     device.name,
                     device.service_data
                 );
                 if device.name.as_deref() == Some(MIJIA_NAME) {
+                    self.mac_addresses
+                        .insert(device.id.clone(), device.mac_address);
                     Some(SensorProps {
                         id: device.id,
                         mac_address: device.mac_address,
+                        rssi: device.rssi,
+                        connected: device.connected,
                     })
                 } else {
                     None
                 }
             })
-            .collect();
+            .collect::<Vec<_>>();
+        metrics::gauge!(metric_names::SENSORS_DISCOVERED, sensors.len() as i64);
         Ok(sensors)
     }
 
+    /// Set the bind key with which the sensor with the given MAC address encrypts its MiBeacon
+    /// advertisements, so that readings can be decoded from them without connecting to it.
+    ///
+    /// Readings decoded from advertisements are delivered as `MijiaEvent::Readings` with a source
+    /// of `ReadingsSource::Advertisement` by `MijiaSession::event_stream()` while discovery is
+    /// running, once the temperature, humidity and battery level have each been received.
+    /// Unencrypted MiBeacon advertisements and BTHome advertisements from sensors with custom
+    /// firmware are decoded without a bind key.
+    pub fn set_bind_key(&self, mac_address: MacAddress, bind_key: BindKey) {
+        self.bind_keys
+            .0
+            .lock()
+            .unwrap()
+            .insert(mac_address, bind_key);
+    }
+
+    /// Get the number of characteristic values and advertisements from sensors which this session
+    /// or any clone of it has failed to decode. The `DECODE_FAILURES` metric has the same counts
+    /// broken down by kind, for applications which install a metrics recorder.
+    pub fn decode_failures(&self) -> u64 {
+        self.decode_failures.get()
+    }
+
     /// Get the current time of the sensor.
     pub async fn get_time(&self, id: &DeviceId) -> Result<SystemTime, MijiaError> {
         let value = self
@@ -176,6 +654,36 @@ impl MijiaSession {
         Ok(decode_time(&value)?)
     }
 
+    /// Measure how far the sensor's clock is ahead of or behind the host's clock.
+    pub async fn get_clock_offset(&self, id: &DeviceId) -> Result<ClockOffset, MijiaError> {
+        let before = SystemTime::now();
+        let sensor_time = self.get_time(id).await?;
+        // Assume the sensor read its clock half way through the round trip.
+        let wall_time = before + SystemTime::now().duration_since(before).unwrap_or_default() / 2;
+        Ok(ClockOffset::new(sensor_time, wall_time))
+    }
+
+    /// Get the current time, temperature unit and comfort level of the sensor. The values are read
+    /// in a single batch, so this is faster than getting each in turn.
+    pub async fn get_settings(&self, id: &DeviceId) -> Result<SensorSettings, MijiaError> {
+        let values = self
+            .bt_session
+            .read_characteristic_values(
+                id,
+                &[
+                    CLOCK_CHARACTERISTIC_PATH,
+                    TEMPERATURE_UNIT_CHARACTERISTIC_PATH,
+                    COMFORT_LEVEL_CHARACTERISTIC_PATH,
+                ],
+            )
+            .await?;
+        Ok(SensorSettings {
+            time: decode_time(&values[0])?,
+            temperature_unit: TemperatureUnit::decode(&values[1])?,
+            comfort_level: ComfortLevel::decode(&values[2])?,
+        })
+    }
+
     /// Set the current time of the sensor.
     pub async fn set_time(&self, id: &DeviceId, time: SystemTime) -> Result<(), MijiaError> {
         let time_bytes = encode_time(time)?;
@@ -251,6 +759,30 @@ impl MijiaSession {
             .await
     }
 
+    /// Delete all historical data stored on the sensor, but only if the last record stored on it is
+    /// the one with the given index, so that no records newer than those already received are lost.
+    /// The caller is responsible for making sure that every record up to that one has been received
+    /// and persisted first.
+    ///
+    /// Returns whether the history was deleted.
+    pub async fn delete_history_up_to(
+        &self,
+        id: &DeviceId,
+        last_index: u32,
+    ) -> Result<bool, MijiaError> {
+        let history_range = self.get_history_range(id).await?;
+        if history_range.end.checked_sub(1) != Some(last_index) {
+            tracing::debug!(
+                "Not deleting history {:?}, last received record is {}",
+                history_range,
+                last_index
+            );
+            return Ok(false);
+        }
+        self.delete_history(id).await?;
+        Ok(true)
+    }
+
     /// Get the last historical record stored on the sensor.
     pub async fn get_last_history_record(
         &self,
@@ -299,27 +831,130 @@ impl MijiaSession {
     pub async fn get_all_history(
         &self,
         id: &DeviceId,
+    ) -> Result<Vec<Option<HistoryRecord>>, MijiaError> {
+        self.get_all_history_with_progress(id, |_| {}).await
+    }
+
+    /// Try to get all historical records for the sensor, with their times corrected for the offset
+    /// of the sensor's clock from the host's clock as measured before the download. The times
+    /// according to the sensor's clock are kept in `HistoryRecord::raw_time`.
+    ///
+    /// This assumes that the sensor's clock has drifted steadily rather than being reset since the
+    /// records were stored.
+    pub async fn get_all_history_corrected(
+        &self,
+        id: &DeviceId,
+    ) -> Result<Vec<Option<HistoryRecord>>, MijiaError> {
+        let clock_offset = self.get_clock_offset(id).await?;
+        tracing::debug!("Sensor clock offset {:?}", clock_offset);
+        let mut history = self.get_all_history(id).await?;
+        for record in history.iter_mut().flatten() {
+            record.correct_time(clock_offset);
+        }
+        Ok(history)
+    }
+
+    /// Try to get all historical records for the sensor, calling the given function with the
+    /// progress of the download before the first record and after each record is received. This
+    /// may take several minutes for a sensor with a full history.
+    ///
+    /// If the returned future is dropped before it completes then history notifications are stopped
+    /// in the background, so the download may safely be cancelled.
+    pub async fn get_all_history_with_progress(
+        &self,
+        id: &DeviceId,
+        progress: impl FnMut(&HistoryProgress),
+    ) -> Result<Vec<Option<HistoryRecord>>, MijiaError> {
+        self.get_all_history_until(id, None, progress).await
+    }
+
+    /// Like `get_all_history_with_progress`, but giving up with `MijiaError::DeadlineExceeded` if
+    /// the download hasn't finished by the given deadline.
+    pub async fn get_all_history_with_deadline(
+        &self,
+        id: &DeviceId,
+        deadline: Instant,
+        progress: impl FnMut(&HistoryProgress),
+    ) -> Result<Vec<Option<HistoryRecord>>, MijiaError> {
+        self.get_all_history_until(id, Some(deadline), progress)
+            .await
+    }
+
+    /// Download all historical records for the sensor, giving up at the deadline if there is one.
+    ///
+    /// This is cancel-safe: however the download ends, including by the future being dropped, history
+    /// notifications are stopped and the match for the event stream is removed.
+    #[tracing::instrument(skip(self, id, progress), fields(device = %id))]
+    async fn get_all_history_until(
+        &self,
+        id: &DeviceId,
+        deadline: Option<Instant>,
+        progress: impl FnMut(&HistoryProgress),
     ) -> Result<Vec<Option<HistoryRecord>>, MijiaError> {
         let history_range = self.get_history_range(&id).await?;
+        tracing::debug!("Downloading history records {:?}", history_range);
         // TODO: Get event stream that is filtered by D-Bus.
         let (msg_match, events) = self.event_stream().await?;
-        let mut events = events.timeout(HISTORY_RECORD_TIMEOUT);
+        let cleanup = HistoryDownloadCleanup::new(self, id, msg_match.token());
+
+        let download = self.receive_all_history(id, history_range, events, progress);
+        let result = match deadline {
+            Some(deadline) => {
+                runtime::timeout(deadline.saturating_duration_since(Instant::now()), download)
+                    .await
+                    .unwrap_or(Err(MijiaError::DeadlineExceeded))
+            }
+            None => download.await,
+        };
+
+        // Report an error from the download in preference to one from cleaning up after it.
+        let cleanup_result = cleanup.finish().await;
+        let history = result?;
+        cleanup_result?;
+        Ok(history)
+    }
+
+    /// Start history notifications from the sensor and receive records from the given event stream
+    /// until they stop arriving.
+    async fn receive_all_history(
+        &self,
+        id: &DeviceId,
+        history_range: Range<u32>,
+        mut events: impl Stream<Item = MijiaEvent> + Unpin,
+        mut progress: impl FnMut(&HistoryProgress),
+    ) -> Result<Vec<Option<HistoryRecord>>, MijiaError> {
+        let start = Instant::now();
+        let mut current_progress = HistoryProgress {
+            range: history_range.clone(),
+            received: 0,
+            current_index: None,
+            elapsed: Duration::default(),
+        };
+        progress(&current_progress);
         self.start_notify_history(&id, Some(0)).await?;
 
         let mut history = vec![None; history_range.len()];
-        while let Some(Ok(event)) = events.next().await {
+        while let Ok(Some(event)) =
+            runtime::timeout(self.options.history_record_timeout, events.next()).await
+        {
             match event {
                 MijiaEvent::HistoryRecord {
                     id: record_id,
                     record,
+                    ..
                 } => {
-                    log::trace!("{:?}: {}", record_id, record);
+                    tracing::trace!("{:?}: {}", record_id, record);
                     if record_id == *id {
                         if history_range.contains(&record.index) {
                             let offset = record.index - history_range.start;
-                            history[offset as usize] = Some(record);
+                            current_progress.current_index = Some(record.index);
+                            if history[offset as usize].replace(record).is_none() {
+                                current_progress.received += 1;
+                            }
+                            current_progress.elapsed = start.elapsed();
+                            progress(&current_progress);
                         } else {
-                            log::error!(
+                            tracing::error!(
                                 "Got record {:?} for sensor {:?} out of bounds {:?}",
                                 record,
                                 id,
@@ -327,21 +962,130 @@ impl MijiaSession {
                             );
                         }
                     } else {
-                        log::warn!("Got record for wrong sensor {:?}", record_id);
+                        tracing::warn!("Got record for wrong sensor {:?}", record_id);
                     }
                 }
-                _ => log::info!("Event: {:?}", event),
+                _ => tracing::info!("Event: {:?}", event),
             }
         }
 
-        self.stop_notify_history(&id).await?;
+        tracing::debug!(
+            "Received {} of {} history records",
+            current_progress.received,
+            history.len()
+        );
+        Ok(history)
+    }
+
+    /// Stop history notifications from the sensor and remove the match with the given token, after a
+    /// history download has finished.
+    async fn finish_history_download(
+        &self,
+        id: &DeviceId,
+        token: Token,
+    ) -> Result<(), BluetoothError> {
+        let stop_result = self.stop_notify_history(id).await;
         self.bt_session
-            .connection
-            .remove_match(msg_match.token())
+            .connection()
+            .remove_match(token)
             .await
-            .map_err(BluetoothError::DbusError)?;
+            .map_err(BluetoothError::from)?;
+        stop_result
+    }
 
-        Ok(history)
+    /// Try to get historical records for the sensor in chunks of up to `chunk_size` records,
+    /// starting from `start_index` or the first record stored on the sensor, whichever is later.
+    ///
+    /// After each chunk `on_chunk` is called with the records received and the index from which the
+    /// download should resume, so that the caller can save a checkpoint and pass it back as
+    /// `start_index` if the download is interrupted. Records missing from the sensor are skipped,
+    /// but if the sensor disconnects part way through a chunk then `MijiaError::Disconnected` is
+    /// returned without calling `on_chunk` for it.
+    ///
+    /// Returns the range of record indices stored on the sensor.
+    ///
+    /// Like `get_all_history_with_progress`, this is cancel-safe: however the download ends, history
+    /// notifications are stopped and the match for the event stream is removed.
+    #[tracing::instrument(skip(self, id, on_chunk), fields(device = %id))]
+    pub async fn get_history_in_chunks(
+        &self,
+        id: &DeviceId,
+        start_index: u32,
+        chunk_size: u32,
+        mut on_chunk: impl FnMut(Vec<HistoryRecord>, u32),
+    ) -> Result<Range<u32>, MijiaError> {
+        let history_range = self.get_history_range(id).await?;
+        let chunk_size = chunk_size.max(1);
+        tracing::debug!(
+            "Downloading history records {:?} from {} in chunks of {}",
+            history_range,
+            start_index,
+            chunk_size
+        );
+        let mut chunk_start = start_index.max(history_range.start);
+        if chunk_start >= history_range.end {
+            return Ok(history_range);
+        }
+        let (msg_match, mut events) = self.event_stream().await?;
+        let cleanup = HistoryDownloadCleanup::new(self, id, msg_match.token());
+
+        let result = async {
+            loop {
+                let chunk_end = chunk_start
+                    .saturating_add(chunk_size)
+                    .min(history_range.end);
+                self.start_notify_history(id, Some(chunk_start)).await?;
+                let mut chunk = vec![None; (chunk_end - chunk_start) as usize];
+                let mut received = 0;
+                while received < chunk.len() {
+                    match runtime::timeout(self.options.history_record_timeout, events.next()).await
+                    {
+                        Ok(Some(MijiaEvent::HistoryRecord {
+                            id: record_id,
+                            record,
+                            ..
+                        })) if record_id == *id => {
+                            if (chunk_start..chunk_end).contains(&record.index) {
+                                let offset = (record.index - chunk_start) as usize;
+                                if chunk[offset].replace(record).is_none() {
+                                    received += 1;
+                                }
+                            }
+                        }
+                        Ok(Some(MijiaEvent::Disconnected {
+                            id: disconnected_id,
+                            ..
+                        })) if disconnected_id == *id => return Err(MijiaError::Disconnected),
+                        Ok(Some(_)) => {}
+                        Ok(None) | Err(_) => break,
+                    }
+                }
+                if received < chunk.len() && !self.bt_session.is_connected(id).await? {
+                    return Err(MijiaError::Disconnected);
+                }
+                tracing::debug!(
+                    "Received {} of {} history records from {}",
+                    received,
+                    chunk.len(),
+                    chunk_start
+                );
+                on_chunk(chunk.into_iter().flatten().collect(), chunk_end);
+                chunk_start = chunk_end;
+                if chunk_start >= history_range.end {
+                    return Ok(());
+                }
+                // Notifications must be restarted for the sensor to start from the next chunk. After
+                // the last chunk they are stopped by the cleanup instead.
+                self.stop_notify_history(id).await?;
+            }
+        }
+        .await;
+
+        // Report an error from the download in preference to one from cleaning up after it.
+        let cleanup_result = cleanup.finish().await;
+        result?;
+        cleanup_result?;
+        Ok(history_range)
     }
 
     /// Assuming that the given device ID refers to a Mijia sensor device and that it has already
@@ -357,31 +1101,298 @@ impl MijiaSession {
             .write_characteristic_value(
                 id,
                 CONNECTION_INTERVAL_CHARACTERISTIC_PATH,
-                CONNECTION_INTERVAL_500_MS,
+                encode_connection_interval(self.options.connection_interval),
             )
             .await?;
         Ok(())
     }
 
-    /// Get a stream of reading/history/disconnected events for all sensors.
+    /// Assuming that the given device ID refers to a Mijia sensor device and that it has already
+    /// been connected, subscribe to notifications of temperature/humidity readings and return a
+    /// stream of them, and adjust the connection interval to save power.
+    ///
+    /// Unlike `start_notify_sensor` this doesn't need `event_stream()`, as the stream only contains
+    /// readings from the given sensor. Notifications are stopped and the D-Bus match rule removed
+    /// when the stream is dropped, which with Tokio must happen within a runtime. The stream doesn't
+    /// survive the D-Bus connection being re-established or BlueZ restarting, so should be
+    /// subscribed to again after `BluetoothSession::bus_resets()` yields.
+    pub async fn subscribe_readings(
+        &self,
+        id: &DeviceId,
+    ) -> Result<impl Stream<Item = Readings>, BluetoothError> {
+        let values = self
+            .bt_session
+            .notify_stream(id, SENSOR_READING_CHARACTERISTIC_PATH)
+            .await?;
+        self.bt_session
+            .write_characteristic_value(
+                id,
+                CONNECTION_INTERVAL_CHARACTERISTIC_PATH,
+                encode_connection_interval(self.options.connection_interval),
+            )
+            .await?;
+        let decode_failures = self.decode_failures.clone();
+        Ok(Box::pin(values.filter_map(move |value| {
+            future::ready(decode_readings_notification(&value, &decode_failures))
+        })))
+    }
+
+    /// Get a stream of reading/history/disconnected events for all sensors, and
+    /// added/removed/powered events for all Bluetooth adapters.
+    ///
+    /// If the D-Bus connection is re-established or BlueZ restarts then a `MijiaEvent::BusReset`
+    /// is sent, and the stream carries on with events from the new connection.
     ///
     /// If the MsgMatch is dropped then the Stream will close.
     pub async fn event_stream(
         &self,
     ) -> Result<(MsgMatch, impl Stream<Item = MijiaEvent>), BluetoothError> {
-        let mut rule = dbus::message::MatchRule::new();
-        rule.msg_type = Some(dbus::message::MessageType::Signal);
-        // BusName validation just checks that the length and format is valid, so it should never
-        // fail for a constant that we know is valid.
-        rule.sender = Some(dbus::strings::BusName::new("org.bluez").unwrap());
+        let connection = self.bt_session.connection();
+        let (msg_match, messages) = connection.add_match(event_rule()).await?.msg_stream();
+        let state = EventStreamState {
+            bt_session: self.bt_session.clone(),
+            mac_addresses: self.mac_addresses.clone(),
+            advertisements: Advertisements::new(
+                self.bind_keys.clone(),
+                self.decode_failures.clone(),
+            ),
+            decode_failures: self.decode_failures.clone(),
+            connection,
+            msg_match: None,
+            messages,
+            buffered: VecDeque::new(),
+            buffer_size: self.options.event_buffer_size,
+            bus_resets: Box::pin(self.bt_session.bus_resets()),
+        };
 
-        let (msg_match, events) = self
-            .bt_session
-            .connection
-            .add_match(rule)
-            .await?
-            .msg_stream();
+        let events = futures::stream::unfold(state, |mut state| async move {
+            loop {
+                let next = if let Some(message) = state.next_buffered() {
+                    Either::Left(Some(message))
+                } else {
+                    match future::select(state.messages.next(), state.bus_resets.next()).await {
+                        Either::Left((message, _)) => Either::Left(message),
+                        Either::Right((reset, _)) => Either::Right(reset),
+                    }
+                };
+                match next {
+                    Either::Left(Some(message)) => {
+                        if let Some(event) = MijiaEvent::from(
+                            message,
+                            &state.mac_addresses,
+                            &mut state.advertisements,
+                            &state.decode_failures,
+                        ) {
+                            return Some((event, state));
+                        }
+                    }
+                    Either::Right(Some(())) => {
+                        state.resubscribe().await;
+                        return Some((MijiaEvent::BusReset, state));
+                    }
+                    Either::Left(None) | Either::Right(None) => return None,
+                }
+            }
+        });
+        Ok((msg_match, Box::pin(events)))
+    }
+}
+
+/// Decode the value of a readings notification, recording it in the metrics.
+fn decode_readings_notification(
+    value: &[u8],
+    decode_failures: &DecodeFailures,
+) -> Option<Readings> {
+    match Readings::decode(value) {
+        Ok(readings) => {
+            metrics::counter!(metric_names::NOTIFICATIONS_RECEIVED, 1, "kind" => "readings");
+            Some(readings)
+        }
+        Err(e) => {
+            metrics::counter!(metric_names::DECODE_FAILURES, 1, "kind" => "readings");
+            decode_failures.increment();
+            tracing::error!("Error decoding readings: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Cleans up after a history download, stopping notifications and removing the match for the event
+/// stream used. If it is dropped before `finish` completes, because the download was cancelled, then
+/// it spawns a task to do so in the background.
+struct HistoryDownloadCleanup {
+    download: Option<(MijiaSession, DeviceId, Token)>,
+}
+
+impl HistoryDownloadCleanup {
+    fn new(session: &MijiaSession, id: &DeviceId, token: Token) -> Self {
+        Self {
+            download: Some((session.clone(), id.clone(), token)),
+        }
+    }
+
+    /// Clean up after the download has finished, whether or not it succeeded.
+    async fn finish(mut self) -> Result<(), BluetoothError> {
+        let result = match &self.download {
+            Some((session, id, token)) => session.finish_history_download(id, *token).await,
+            None => Ok(()),
+        };
+        self.download = None;
+        result
+    }
+}
+
+impl Drop for HistoryDownloadCleanup {
+    fn drop(&mut self) {
+        if let Some((session, id, token)) = self.download.take() {
+            if !runtime::in_runtime() {
+                tracing::warn!(
+                    "History download from {} dropped outside of a runtime, not stopping notifications.",
+                    id
+                );
+                return;
+            }
+            let _ = runtime::spawn(async move {
+                if let Err(e) = session.finish_history_download(&id, token).await {
+                    tracing::warn!(
+                        "Failed to clean up after cancelled history download from {}: {:?}",
+                        id,
+                        e
+                    );
+                }
+            });
+        }
+    }
+}
+
+/// Encode a connection interval as the sensor expects it, in milliseconds.
+fn encode_connection_interval(interval: Duration) -> [u8; 3] {
+    let millis = interval.as_millis().min(u16::MAX.into()) as u16;
+    let [low, high] = millis.to_le_bytes();
+    [low, high, 0x00]
+}
+
+/// The match rule for all signals from BlueZ.
+fn event_rule() -> MatchRule<'static> {
+    let mut rule = MatchRule::new();
+    rule.msg_type = Some(dbus::message::MessageType::Signal);
+    // BusName validation just checks that the length and format is valid, so it should never
+    // fail for a constant that we know is valid.
+    rule.sender = Some(dbus::strings::BusName::new("org.bluez").unwrap());
+    rule
+}
+
+/// The state of a stream returned by `MijiaSession::event_stream`.
+struct EventStreamState {
+    bt_session: BluetoothSession,
+    mac_addresses: MacAddresses,
+    advertisements: Advertisements,
+    decode_failures: DecodeFailures,
+    /// The D-Bus connection which `messages` are from.
+    connection: Arc<SyncConnection>,
+    /// The match for `messages` if it was added after the D-Bus connection was re-established,
+    /// which must be kept alive for messages to keep arriving.
+    msg_match: Option<MsgMatch>,
+    messages: UnboundedReceiver<Message>,
+    /// Messages taken from `messages` but not yet processed, if the buffer size is limited.
+    buffered: VecDeque<Message>,
+    /// The maximum number of messages to buffer, or `None` for no limit.
+    buffer_size: Option<NonZeroUsize>,
+    bus_resets: Pin<Box<dyn Stream<Item = ()> + Send>>,
+}
+
+impl EventStreamState {
+    /// If the buffer size is limited, take all the messages which are already waiting, dropping the
+    /// oldest beyond the limit, and return the oldest of those left.
+    fn next_buffered(&mut self) -> Option<Message> {
+        let buffer_size = self.buffer_size?;
+        let mut dropped = 0;
+        while let Ok(Some(message)) = self.messages.try_next() {
+            if push_bounded(&mut self.buffered, message, buffer_size) {
+                dropped += 1;
+            }
+        }
+        if dropped > 0 {
+            tracing::warn!("Event stream fell behind, dropped {} messages", dropped);
+        }
+        self.buffered.pop_front()
+    }
+
+    /// If the D-Bus connection has changed, add the match rule for events to the new one and start
+    /// receiving messages from it instead. The match rule is unaffected if only BlueZ restarted.
+    async fn resubscribe(&mut self) {
+        let connection = self.bt_session.connection();
+        if Arc::ptr_eq(&connection, &self.connection) {
+            return;
+        }
+        match connection.add_match(event_rule()).await {
+            Ok(msg_match) => {
+                let (msg_match, messages) = msg_match.msg_stream();
+                self.connection = connection;
+                self.msg_match = Some(msg_match);
+                self.messages = messages;
+            }
+            Err(e) => tracing::error!("Failed to resubscribe to events: {:?}", e),
+        }
+    }
+}
+
+/// Add the given item to the end of the buffer, dropping the oldest if it is then longer than the
+/// given size. Returns whether an item was dropped.
+fn push_bounded<T>(buffer: &mut VecDeque<T>, item: T, size: NonZeroUsize) -> bool {
+    buffer.push_back(item);
+    if buffer.len() > size.get() {
+        buffer.pop_front();
+        true
+    } else {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connection_interval() {
+        assert_eq!(
+            encode_connection_interval(DEFAULT_CONNECTION_INTERVAL),
+            [0xF4, 0x01, 0x00]
+        );
+        assert_eq!(
+            encode_connection_interval(Duration::from_secs(100)),
+            [0xFF, 0xFF, 0x00]
+        );
+    }
+
+    #[test]
+    fn history_progress_remaining() {
+        let mut progress = HistoryProgress {
+            range: 100..400,
+            received: 0,
+            current_index: None,
+            elapsed: Duration::default(),
+        };
+        assert_eq!(progress.expected(), 300);
+        assert_eq!(progress.remaining(), None);
+
+        progress.received = 100;
+        progress.current_index = Some(199);
+        progress.elapsed = Duration::from_secs(20);
+        assert_eq!(progress.remaining(), Some(Duration::from_secs(40)));
+    }
+
+    #[test]
+    fn bounded_buffer_keeps_newest() {
+        let size = NonZeroUsize::new(2).unwrap();
+        let mut buffer = VecDeque::new();
+        assert!(!push_bounded(&mut buffer, 1, size));
+        assert!(!push_bounded(&mut buffer, 2, size));
+        assert!(push_bounded(&mut buffer, 3, size));
+        assert_eq!(buffer, vec![2, 3]);
 
-        Ok((msg_match, Box::pin(events.filter_map(MijiaEvent::from))))
+        let size = NonZeroUsize::new(1).unwrap();
+        assert!(push_bounded(&mut buffer, 4, size));
+        assert_eq!(buffer, vec![3, 4]);
     }
 }
@@ -0,0 +1,15 @@
+//! Names of the metrics which this crate records via the [`metrics`](https://docs.rs/metrics)
+//! facade. They will be ignored unless the application installs a metrics recorder.
+
+pub use bluez_async::metric_names::{CONNECT_ATTEMPTS, DBUS_ERRORS};
+
+/// Counter of characteristic values from sensors which couldn't be decoded, labelled by `kind`
+/// (`"readings"`, `"history"` or `"advertisement"`).
+pub const DECODE_FAILURES: &str = "mijia_decode_failures_total";
+/// Counter of characteristic value notifications successfully received from sensors, labelled by
+/// `kind` (`"readings"`, `"history"` or `"advertisement"`).
+pub const NOTIFICATIONS_RECEIVED: &str = "mijia_notifications_received_total";
+/// Counter of disconnection events received for Bluetooth devices.
+pub const DISCONNECTIONS: &str = "mijia_disconnections_total";
+/// Gauge of the number of Mijia sensors found the last time `MijiaSession::get_sensors` was called.
+pub const SENSORS_DISCOVERED: &str = "mijia_sensors_discovered";
@@ -0,0 +1,139 @@
+//! Realistic sample values for writing unit tests of code which uses this crate, without needing
+//! captured traces from real sensors.
+//!
+//! This module is only available with the `test-utils` feature, which is intended to be enabled
+//! only in `dev-dependencies`.
+
+use crate::{DeviceId, HistoryRecord, MacAddress, MijiaEvent, Readings, ReadingsSource};
+use std::ops::Range;
+use std::time::{Duration, SystemTime};
+
+/// The object path of the sensor used in sample events.
+pub const SAMPLE_DEVICE_PATH: &str = "/org/bluez/hci0/dev_A4_C1_38_D7_21_17";
+
+/// The MAC address of the sensor used in sample events.
+pub const SAMPLE_MAC_ADDRESS: &str = "A4:C1:38:D7:21:17";
+
+/// The raw value of the readings characteristic, as sent in a notification. This decodes to
+/// `sample_readings()`.
+pub const READINGS_PAYLOAD: [u8; 5] = [0x59, 0x08, 0x34, 0x98, 0x0b];
+
+/// The raw value of a history record, as sent in a notification. This decodes to
+/// `sample_history_record()`.
+pub const HISTORY_RECORD_PAYLOAD: [u8; 14] = [
+    0x49, 0x01, 0x00, 0x00, 0x40, 0x0c, 0x55, 0x5e, 0xdd, 0x00, 0x43, 0xd5, 0x00, 0x3c,
+];
+
+/// The raw value of the history range characteristic. This decodes to `SAMPLE_HISTORY_RANGE`.
+pub const HISTORY_RANGE_PAYLOAD: [u8; 8] = [0x49, 0x01, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00];
+
+/// The range of history record indices encoded by `HISTORY_RANGE_PAYLOAD`.
+pub const SAMPLE_HISTORY_RANGE: Range<u32> = 298..330;
+
+/// The raw value of the clock characteristic. This decodes to `sample_time()`.
+pub const CLOCK_PAYLOAD: [u8; 4] = [0x40, 0x0c, 0x55, 0x5e];
+
+/// The time encoded by `CLOCK_PAYLOAD`, 2020-02-25 12:00:00 UTC.
+pub fn sample_time() -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(1582632000)
+}
+
+/// Construct readings with the given values, and the battery percentage derived from the voltage in
+/// the same way as when decoding them.
+pub fn readings(temperature: f32, humidity: u8, battery_voltage: u16) -> Readings {
+    Readings {
+        temperature,
+        humidity,
+        battery_voltage,
+        battery_percent: (battery_voltage.max(2100) - 2100) / 10,
+    }
+}
+
+/// The readings encoded by `READINGS_PAYLOAD`.
+pub fn sample_readings() -> Readings {
+    readings(21.37, 52, 2968)
+}
+
+/// Construct a history record with the given index, recorded the given number of hours after
+/// `sample_time()`, and with the given temperature and humidity ranges.
+pub fn history_record(
+    index: u32,
+    hours: u64,
+    temperature: Range<f32>,
+    humidity: Range<u8>,
+) -> HistoryRecord {
+    let time = sample_time() + Duration::from_secs(hours * 60 * 60);
+    HistoryRecord {
+        index,
+        time,
+        raw_time: time,
+        temperature_min: temperature.start,
+        temperature_max: temperature.end,
+        humidity_min: humidity.start,
+        humidity_max: humidity.end,
+    }
+}
+
+/// The history record encoded by `HISTORY_RECORD_PAYLOAD`.
+pub fn sample_history_record() -> HistoryRecord {
+    history_record(329, 0, 21.3..22.1, 60..67)
+}
+
+/// The ID of the sensor used in sample events.
+pub fn sample_device_id() -> DeviceId {
+    DeviceId::new(SAMPLE_DEVICE_PATH)
+}
+
+/// The MAC address of the sensor used in sample events.
+pub fn sample_mac_address() -> MacAddress {
+    SAMPLE_MAC_ADDRESS.parse().unwrap()
+}
+
+/// A `MijiaEvent::Readings` for the sample sensor with the given readings, received in a
+/// notification at `sample_time()`.
+pub fn readings_event(readings: Readings) -> MijiaEvent {
+    MijiaEvent::Readings {
+        id: sample_device_id(),
+        mac_address: sample_mac_address(),
+        readings,
+        time: sample_time(),
+        source: ReadingsSource::Notification,
+    }
+}
+
+/// A `MijiaEvent::HistoryRecord` for the sample sensor with the given record.
+pub fn history_record_event(record: HistoryRecord) -> MijiaEvent {
+    MijiaEvent::HistoryRecord {
+        id: sample_device_id(),
+        mac_address: sample_mac_address(),
+        record,
+    }
+}
+
+/// A `MijiaEvent::Disconnected` for the sample sensor.
+pub fn disconnected_event() -> MijiaEvent {
+    MijiaEvent::Disconnected {
+        id: sample_device_id(),
+        mac_address: sample_mac_address(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::{decode_range, decode_time};
+
+    #[test]
+    fn payloads_decode_to_samples() {
+        assert_eq!(Readings::decode(&READINGS_PAYLOAD), Ok(sample_readings()));
+        assert_eq!(
+            HistoryRecord::decode(&HISTORY_RECORD_PAYLOAD),
+            Ok(sample_history_record())
+        );
+        assert_eq!(
+            decode_range(&HISTORY_RANGE_PAYLOAD),
+            Ok(SAMPLE_HISTORY_RANGE)
+        );
+        assert_eq!(decode_time(&CLOCK_PAYLOAD), Ok(sample_time()));
+    }
+}